@@ -0,0 +1,749 @@
+//! Typed requests and responses for the ride-data Lambda, with an `invoke()` helper that calls it
+//! either directly (Lambda Invoke) or through its HTTP mode.
+
+use anyhow::{anyhow, bail, Result};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize, Serializer};
+use std::collections::{BTreeMap, HashMap};
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    #[default]
+    Aggregate,
+    Describe,
+    Finalize,
+    Reconcile,
+    DebugTrace,
+    RideCorrected,
+    RideVoided,
+    Replay,
+    Export,
+    AggregateAsOf,
+    DeviceDecommissioned,
+    Import,
+    RegulatoryReport,
+    EnforceRetention,
+    /// Stored rows for `imeis`, in the shape of `results`.
+    Read,
+    /// Writes a support bundle for one IMEI and `input_ride_month` to S3.
+    Diagnose,
+    /// Aggregates the seeded fixture dataset and fails unless its totals are exact; the post-deploy gate.
+    Selftest,
+    /// Distance and ride count changes per device between two months, from stored rows.
+    CompareMonths,
+    /// Copies the baseline table's `date`-keyed rows into the aggregates table.
+    MigrateLegacyRows,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Priority {
+    #[default]
+    Interactive,
+    Backfill,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Breakdown {
+    Firmware,
+    Source,
+    RideType,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogLevel {
+    Debug,
+    Info,
+    Warn,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Granularity {
+    Daily,
+    Weekly,
+    #[default]
+    Monthly,
+    /// One total over `start_date`..`end_date`; never stored.
+    Range,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputUnit {
+    #[default]
+    Km,
+    Mi,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NumberFormat {
+    #[default]
+    Float,
+    StringFixed2,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictPolicy {
+    Skip,
+    Overwrite,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Metric {
+    Distance,
+    RideCount,
+    Duration,
+    Speed,
+    Energy,
+    Histogram,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExportFormat {
+    #[default]
+    Csv,
+    Parquet,
+}
+
+/// S3 destination for an aggregation's results, one object per month under `<prefix>/ride_month=<YYYY-MM>/`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ResultsExport {
+    pub bucket: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prefix: Option<String>,
+    pub format: ExportFormat,
+    /// Wraps CSV, or sets Parquet's page codec (by default Snappy).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub compression: Option<ExportCompression>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExportCompression {
+    Gzip,
+    Zstd,
+    None,
+}
+
+/// UTC date range (`YYYY-MM-DD`, inclusive) whose rides are aggregated the legacy pipeline's way.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LegacyCompat {
+    pub from: String,
+    pub to: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SignatureMode {
+    Exclude,
+    Flag,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RideCorrection {
+    pub ride_id: String,
+    pub imei: String,
+    pub ride_start: u64,
+    pub old_distance: f64,
+    pub new_distance: f64,
+}
+
+/// One event for the Lambda; build it with struct update syntax over `Default::default()`.
+/// Field meanings match the `event` schema returned by `action: "describe"`.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RideDataRequest {
+    pub action: Action,
+    #[serde(serialize_with = "comma_separated")]
+    pub imeis: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub imeis_compressed: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub imeis_s3_uri: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub input_manifest_s3_uri: Option<String>,
+    /// `YYYY-MM`, or `current_month`, `previous_month` or `last_<n>_months`, resolved by the service.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub input_ride_month: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub start_date: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub end_date: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email_report: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub export: Option<ResultsExport>,
+    /// How `action: "export"` compresses its parts (default `none`).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub export_compression: Option<ExportCompression>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub presign_expiry_secs: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub post_summary: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preserve_final_months: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub force: Option<bool>,
+    /// Replace stored rows (default true); false only inserts missing rows.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub overwrite: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sample_size: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cohorts: Option<HashMap<String, Vec<String>>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cohort_tags: Option<Vec<String>>,
+    /// Named IMEI lists whose rows are also returned nested per group. Not with `fan_out`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub groups: Option<BTreeMap<String, Vec<String>>>,
+    /// Also return and store per-period totals over all requested devices.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub include_fleet_summary: Option<bool>,
+    /// Fleet rows are keyed `FLEET#<fleet_group_id>` (default `all`).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fleet_group_id: Option<String>,
+    /// Also return per-period totals in `by_month`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub include_by_month: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub breakdowns: Option<Vec<Breakdown>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub correction: Option<RideCorrection>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tenant_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub estimate: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_rides_per_imei: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_total_items: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub allow_prod_write: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fixed_now: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fill_gaps: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub log_level: Option<LogLevel>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fan_out: Option<bool>,
+    /// With `fan_out`: backfill shards yield to interactive ones.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub priority: Option<Priority>,
+    /// Aggregate in chunks of `BACKFILL_CHUNK_SIZE` IMEIs, each invocation queueing the next.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub backfill: Option<bool>,
+    /// Unit of the response's distances and speeds; rows are stored in km regardless.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output_unit: Option<OutputUnit>,
+    /// `float` (default) or `string_fixed2`: decimal stats in the response and exports as
+    /// two-decimal strings, which [`AggregationResponse`] does not parse.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub number_format: Option<NumberFormat>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub granularity: Option<Granularity>,    #[serde(skip_serializing_if = "Option::is_none")]
+    pub verify_writes: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub replay_run_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resume_run_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dry_run: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub verify_signatures: Option<SignatureMode>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fraud_checks: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub report_skipped_years: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub as_of: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub export_history: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub import_s3_uri: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub on_conflict: Option<ConflictPolicy>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub legacy_compat: Option<LegacyCompat>,
+    /// Types to count, filtered in the ride query: other rides are not read or explained.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ride_types: Option<Vec<String>>,
+    /// Types to count; other rides are still read and explained.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ride_types_include: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ride_types_exclude: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preflight: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metrics: Option<Vec<Metric>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed_fixture: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub compare_to_month: Option<String>,
+    /// Fail the run if more than this percentage of devices fail, instead of returning partial results.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fail_if_error_rate_above: Option<f64>,
+}
+
+fn comma_separated<S: Serializer>(imeis: &[String], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&imeis.join(","))
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct RowExplain {
+    pub rides_included: u64,
+    pub excluded_by_type: u64,
+    pub parse_failures: u64,
+    pub voided_rides: u64,
+    #[serde(default)]
+    pub overlapping_rides: u64,
+    #[serde(default)]
+    pub outlier_rides: u64,
+    #[serde(default)]
+    pub unverified_rides: u64,
+    #[serde(default)]
+    pub legacy_rides: u64,
+    /// Rides dropped for starting beyond the service's clock-skew tolerance.
+    #[serde(default)]
+    pub future_rides: u64,
+    /// Rides dropped for a distance above the service's `MAX_RIDE_DISTANCE_KM`.
+    #[serde(default)]
+    pub implausible_rides: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Anomaly {
+    pub imei: String,
+    pub ride_start: u64,
+    /// `future_start` or `implausible_distance`.
+    pub reason: String,
+    #[serde(default)]
+    pub distance: Option<f64>,
+    /// Left out of the totals, rather than only flagged.
+    #[serde(default)]
+    pub excluded: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct DistanceAlert {
+    /// The IMEI, or `FLEET#<fleet_group_id>`.
+    pub subject: String,
+    pub ride_month: String,
+    pub threshold_km: f64,
+    pub total_distance: f64,
+    #[serde(default)]
+    pub label: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct MaintenanceDue {
+    pub imei: String,
+    pub last_service_km: f64,
+    pub service_interval_km: f64,
+    pub lifetime_km: f64,
+    pub since_service_km: f64,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct DimensionStats {
+    pub total_distance: f64,
+    pub rides: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct MonthlyDistance {
+    pub imei: String,
+    #[serde(default)]
+    pub granularity: Granularity,
+    /// `YYYY-MM`, `YYYY-MM-DD` for daily and weekly rows, or `YYYY-MM-DD..YYYY-MM-DD` for range totals.
+    pub ride_month: String,
+    pub total_distance: f64,
+    /// Seconds.
+    #[serde(default)]
+    pub total_duration: Option<f64>,
+    #[serde(default)]
+    pub ride_count: Option<u64>,
+    /// km/h.
+    #[serde(default)]
+    pub average_speed: Option<f64>,
+    /// km/h.
+    #[serde(default)]
+    pub max_speed: Option<f64>,
+    /// Watt-hours.
+    #[serde(default)]
+    pub total_energy: Option<f64>,
+    /// Distance band (km) -> rides.
+    #[serde(default)]
+    pub distance_histogram: Option<BTreeMap<String, u64>>,
+    pub month_to_date: bool,
+    pub as_of: String,
+    /// `preserved_final`, `finalized`, `truncated`, `already_applied`, `dry_run`, `exists`, `unprocessed`, `partial_period` or `unchanged` when the stored row was left alone.
+    pub write_skipped: Option<String>,
+    /// Breakdown dimension -> value -> totals.
+    #[serde(default)]
+    pub breakdowns: BTreeMap<String, BTreeMap<String, DimensionStats>>,
+    pub explain: RowExplain,
+    #[serde(default)]
+    pub truncated: bool,
+    /// Each flag carries a `kind` (`duplicate_distance`, `teleportation`) and its evidence.
+    #[serde(default)]
+    pub fraud_flags: Vec<BTreeMap<String, serde_json::Value>>,
+    /// Experimental metrics the service has enabled, by `exp.` name; not part of the stable contract.
+    #[serde(default)]
+    pub experimental: BTreeMap<String, f64>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CohortTotals {
+    pub cohort: String,
+    pub ride_month: String,
+    pub total_distance: f64,
+    pub devices: usize,
+}
+
+/// Money amounts are decimal strings.
+#[derive(Debug, Clone, Deserialize)]
+pub struct TierCharge {
+    pub from_km: String,
+    pub to_km: Option<String>,
+    pub km: String,
+    pub rate_per_km: String,
+    pub amount: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Charge {
+    pub imei: String,
+    pub ride_month: String,
+    pub tenant_id: String,
+    pub currency: String,
+    pub tiers: Vec<TierCharge>,
+    pub subtotal: String,
+    pub minimum_applied: bool,
+    pub total: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct HotPartition {
+    pub imei: String,
+    pub latency_ms: u64,
+    pub throttled: bool,
+}
+
+/// One entry of `warnings`: a code such as `TRUNCATED_RESULTS` plus the fields describing it.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Warning {
+    pub code: String,
+    #[serde(flatten)]
+    pub context: BTreeMap<String, serde_json::Value>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct RunCost {
+    pub read_units: f64,
+    pub write_units: f64,
+    pub gb_seconds: f64,
+    pub estimated_usd: f64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PeriodTotal {
+    pub total_distance: f64,
+    /// Devices with at least one counted ride in the period.
+    pub devices: usize,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct DefaultedMonth {
+    /// `current_month`, `previous_month` or `all`.
+    pub default: String,
+    #[serde(default)]
+    pub input_ride_month: Option<String>,
+}
+
+/// A device left out of `results` because its rides could not be read.
+#[derive(Debug, Clone, Deserialize)]
+pub struct DeviceError {
+    pub imei: String,
+    #[serde(default)]
+    pub identity: Option<String>,
+    /// `throttled`, `timeout`, `network`, `access_denied`, `not_found`, `invalid`, `service` or `panic`.
+    pub kind: String,
+    pub retryable: bool,
+    pub message: String,
+}
+
+/// What a dry run would have done with one row.
+#[derive(Debug, Clone, Deserialize)]
+pub struct PlannedWrite {
+    pub imei: String,
+    #[serde(default)]
+    pub granularity: Granularity,
+    pub ride_month: String,
+    /// `insert`, `replace` or `skip`.
+    pub action: String,
+    #[serde(default)]
+    pub skipped: Option<String>,
+    #[serde(default)]
+    pub previous_distance: Option<f64>,
+    pub total_distance: f64,
+    /// Stat name -> stored against computed value, for the stats that differ.
+    #[serde(default)]
+    pub diff: BTreeMap<String, ValueDiff>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ValueDiff {
+    pub old: f64,
+    pub new: f64,
+    pub delta: f64,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct OperationRetries {
+    pub calls: u64,
+    pub attempts: u64,
+    pub retried_calls: u64,
+    pub max_attempts: u64,
+    pub backoff_ms: u64,
+    pub succeeded: u64,
+    pub failed: u64,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Diagnostics {
+    /// DynamoDB operation -> retry statistics.
+    #[serde(default)]
+    pub retries: BTreeMap<String, OperationRetries>,
+    #[serde(default)]
+    pub runtime: Option<RuntimeInfo>,
+    #[serde(default)]
+    pub query_plan: Option<QueryPlan>,
+    /// Table -> capacity mode, for the tables batch-written to.
+    #[serde(default)]
+    pub capacity_modes: BTreeMap<String, CapacityMode>,
+    /// Only reported by builds with the `alloc-stats` feature.
+    #[serde(default)]
+    pub memory: Option<MemoryStats>,
+}
+
+/// How an aggregation read its rides.
+#[derive(Debug, Clone, Deserialize)]
+pub struct QueryPlan {
+    /// `per_imei`, `date_index` or `scan`.
+    pub strategy: String,
+    #[serde(default)]
+    pub reason: Option<String>,
+    #[serde(default)]
+    pub estimates: Vec<StrategyEstimate>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct StrategyEstimate {
+    pub strategy: String,
+    pub read_capacity_units: f64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CapacityMode {
+    /// `on_demand`, `provisioned` or `unknown`.
+    pub mode: String,
+    #[serde(default)]
+    pub write_capacity_units: Option<i64>,
+}
+
+/// Build and cold-start metadata of the invocation that produced the response.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct RuntimeInfo {
+    pub version: String,
+    pub build_commit: String,
+    pub target_arch: String,
+    pub target_os: String,
+    #[serde(default)]
+    pub binary_bytes: Option<u64>,
+    #[serde(default)]
+    pub init_ms: Option<u64>,
+    pub cold_start: bool,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct MemoryStats {
+    pub allocations: u64,
+    pub allocated_bytes: u64,
+    pub peak_bytes: u64,
+    pub live_bytes: u64,
+}
+
+/// The response to `action: "read"`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ReadResponse {
+    /// Ordered by IMEI, then period; only what rows store is filled in.
+    pub results: Vec<MonthlyDistance>,
+    #[serde(default)]
+    pub imeis_without_rows: Vec<String>,
+    #[serde(default)]
+    pub output_unit: OutputUnit,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AggregationResponse {
+    /// Ordered by IMEI, then period. Empty when the response was spilled to `response_uri`.
+    #[serde(default)]
+    pub results: Vec<MonthlyDistance>,
+    /// `s3://` URI of the full response, when it had more than `RESPONSE_SPILL_ROWS` rows.
+    #[serde(default)]
+    pub response_uri: Option<String>,
+    /// Presigned GET URL of `response_uri`.
+    #[serde(default)]
+    pub response_url: Option<String>,
+    #[serde(default)]
+    pub truncated_imeis: Vec<String>,
+    #[serde(default)]
+    pub hot_partitions: Vec<HotPartition>,
+    #[serde(default)]
+    pub cohorts: Vec<CohortTotals>,
+    /// Group name -> the group's rows from `results`.
+    #[serde(default)]
+    pub groups: BTreeMap<String, Vec<MonthlyDistance>>,
+    /// Per-period totals over all requested devices, with `include_fleet_summary`.
+    #[serde(default)]
+    pub fleet_summary: Vec<MonthlyDistance>,
+    /// Period -> totals over `results`, with `include_by_month`.
+    #[serde(default)]
+    pub by_month: BTreeMap<String, PeriodTotal>,
+    #[serde(default)]
+    pub charges: Vec<Charge>,
+    #[serde(default)]
+    pub warnings: Vec<Warning>,
+    #[serde(default)]
+    pub normalized_values: BTreeMap<String, u64>,
+    #[serde(default)]
+    pub imei_aliases: BTreeMap<String, String>,
+    /// Parse error -> ride items left out of the totals.
+    #[serde(default)]
+    pub skipped_items: BTreeMap<String, u64>,
+    #[serde(default)]
+    pub anomalies: Vec<Anomaly>,
+    /// Monthly distance thresholds this run's rows newly reached.
+    #[serde(default)]
+    pub distance_alerts: Vec<DistanceAlert>,
+    /// Devices the run took past their service interval.
+    #[serde(default)]
+    pub maintenance_due: Vec<MaintenanceDue>,
+    /// Device IMEI -> year -> rides starting outside the ride window.
+    #[serde(default)]
+    pub skipped_years: BTreeMap<String, BTreeMap<String, u64>>,
+    #[serde(default)]
+    pub cached_imeis: Vec<String>,
+    #[serde(default)]
+    pub resumed_imeis: Vec<String>,
+    #[serde(default)]
+    pub write_plan: Vec<PlannedWrite>,
+    /// Set when the request named no period: how `DEFAULT_RIDE_MONTH` chose one.
+    #[serde(default)]
+    pub default_ride_month: Option<DefaultedMonth>,
+    /// Devices whose rides could not be read; resend those with `retryable` set.
+    #[serde(default)]
+    pub errors: Vec<DeviceError>,
+    #[serde(default)]
+    pub exported: Vec<String>,
+    /// Presigned GET URLs of the `exported` objects, in the same order.
+    #[serde(default)]
+    pub exported_urls: Vec<String>,
+    /// With `backfill`: the job's progress after this chunk.
+    #[serde(default)]
+    pub backfill: Option<BackfillProgress>,
+    #[serde(default)]
+    pub output_unit: OutputUnit,
+    #[serde(default)]
+    pub cost: RunCost,
+    #[serde(default)]
+    pub diagnostics: Diagnostics,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct BackfillProgress {
+    /// `backfill_jobs` key, the first chunk's run_id.
+    pub job_id: String,
+    pub chunk: usize,
+    pub imeis_remaining: usize,
+    /// `running`, `complete` or `already_recorded`.
+    pub status: String,
+}
+
+/// How to reach the service.
+#[derive(Debug, Clone)]
+pub enum Transport {
+    #[cfg(feature = "lambda")]
+    Lambda { client: aws_sdk_lambda::Client, function_name: String },
+    /// Base URL of a service running with `RIDE_DATA_MODE=http`.
+    #[cfg(feature = "http")]
+    Http { client: reqwest::Client, base_url: String },
+}
+
+#[derive(Debug, Clone)]
+pub struct RideDataClient {
+    transport: Transport,
+}
+
+impl RideDataClient {
+    pub fn new(transport: Transport) -> RideDataClient {
+        RideDataClient { transport }
+    }
+
+    /// Sends one request and decodes the response as `R`; an `{"error": ...}` response becomes an `Err`.
+    pub async fn invoke<R: DeserializeOwned>(&self, request: &RideDataRequest) -> Result<R> {
+        let body = serde_json::to_vec(request)?;
+        let value: serde_json::Value = match &self.transport {
+            #[cfg(feature = "lambda")]
+            Transport::Lambda { client, function_name } => {
+                let resp = client.invoke()
+                    .function_name(function_name)
+                    .payload(aws_sdk_lambda::primitives::Blob::new(body))
+                    .send()
+                    .await?;
+                let payload = resp.payload().map(|p| p.as_ref()).unwrap_or_default();
+                if let Some(function_error) = resp.function_error() {
+                    bail!("{}: {}", function_error, String::from_utf8_lossy(payload));
+                }
+                serde_json::from_slice(payload)?
+            }
+            #[cfg(feature = "http")]
+            Transport::Http { client, base_url } => {
+                let resp = client.post(format!("{}/ride-data", base_url.trim_end_matches('/')))
+                    .header("content-type", "application/json")
+                    .body(body)
+                    .send()
+                    .await?;
+                if !resp.status().is_success() {
+                    bail!("ride-data returned {}: {}", resp.status(), resp.text().await.unwrap_or_default());
+                }
+                resp.json().await?
+            }
+        };
+
+        if let Some(error) = value.get("error").and_then(|e| e.as_str()) {
+            return Err(anyhow!("ride-data rejected the request: {}", error));
+        }
+        Ok(serde_json::from_value(value)?)
+    }
+
+    pub async fn aggregate(&self, request: &RideDataRequest) -> Result<AggregationResponse> {
+        self.invoke(request).await
+    }
+
+    /// Reads the rows already stored for the request's IMEIs and period, whatever its `action`.
+    pub async fn read(&self, request: &RideDataRequest) -> Result<ReadResponse> {
+        self.invoke(&RideDataRequest { action: Action::Read, ..request.clone() }).await
+    }
+}
@@ -0,0 +1,1479 @@
+use aws_sdk_dynamodb::operation::put_item::PutItemError;
+use aws_sdk_dynamodb::operation::update_item::UpdateItemError;
+use aws_sdk_dynamodb::types::{AttributeValue, Put, ReturnConsumedCapacity, ReturnValue, ReturnValuesOnConditionCheckFailure};
+use aws_sdk_dynamodb::Client;
+use anyhow::Result;
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use utoipa::ToSchema;
+
+use crate::cost::CapacityMeter;
+use crate::import::ImportRow;
+use crate::time::Granularity;
+use crate::{batch, capacity, config, destination, experiments, fleet, metrics, stats, CustomOutput};
+
+/// The aggregates table, `AGGREGATES_TABLE` (default `ride_data_monthly_distance`).
+pub fn table_name() -> &'static str {
+    &config::get().aggregates_table
+}
+/// GSI on the aggregates table partitioned by `month` with `total_distance` as its sort key. Only
+/// monthly rows carry `month`, so the index holds one row per device per month, ordered by distance.
+pub const MONTH_INDEX: &str = "month-distance-index";
+
+/// Rows are keyed by `imei` and a `period` sort key of `period#<granularity>#<YYYY-MM[-DD]>`, so
+/// daily, weekly and monthly rows for a device coexist and each granularity range-queries in order.
+///
+/// The baseline table was keyed by `imei` and `date` (`YYYY-MM`) instead, which a table's key schema
+/// cannot be changed to. Its rows are copied into a table keyed this way by `action:
+/// "migrate_legacy_rows"`; until then, with `LEGACY_AGGREGATES_TABLE` naming the old table, monthly
+/// reads fall back to it for rows this table does not have yet.
+pub fn sort_key(granularity: Granularity, period: &str) -> String {
+    format!("period#{}#{}", granularity.as_str(), period)
+}
+
+/// The granularity and `YYYY-MM[-DD]` of a [`sort_key`]; None for other keys in the table, such as
+/// rollup contributions.
+pub fn parse_sort_key(key: &str) -> Option<(Granularity, &str)> {
+    let (granularity, period) = key.strip_prefix("period#")?.split_once('#')?;
+    Some((Granularity::parse(granularity)?, period))
+}
+
+/// A baseline row, keyed by `date`, as a monthly row of this table; None without a `date`.
+pub fn from_legacy(mut item: HashMap<String, AttributeValue>) -> Option<HashMap<String, AttributeValue>> {
+    let AttributeValue::S(month) = item.remove("date")? else {
+        return None;
+    };
+    item.insert("period".to_string(), monthly_key(&month));
+    item.insert("granularity".to_string(), AttributeValue::S(Granularity::Monthly.as_str().to_string()));
+    item.insert("month".to_string(), AttributeValue::S(month));
+    Some(item)
+}
+
+/// Puts a row of [`from_legacy`] unless the table already has one under its key; false if it had.
+pub async fn insert_legacy(client: &Client, item: HashMap<String, AttributeValue>, meter: &CapacityMeter) -> Result<bool> {
+    destination::verify(client).await?;
+    insert_row(client, item, meter).await
+}
+
+/// The (imei, month) row of the baseline table, if `LEGACY_AGGREGATES_TABLE` is set and has one.
+async fn legacy_item(client: &Client, imei: &str, ride_month: &str) -> Result<Option<HashMap<String, AttributeValue>>> {
+    let Some(table) = &config::get().legacy_aggregates_table else {
+        return Ok(None);
+    };
+    let resp = client.get_item()
+        .table_name(table)
+        .key("imei", AttributeValue::S(imei.to_string()))
+        .key("date", AttributeValue::S(ride_month.to_string()))
+        .send()
+        .await
+        .inspect_err(|_| metrics::dynamodb_error("get_item"))?;
+    Ok(resp.item.and_then(from_legacy))
+}
+
+/// The row this table has for the key, or for a monthly one the baseline table's.
+async fn with_legacy(
+    client: &Client,
+    item: Option<HashMap<String, AttributeValue>>,
+    imei: &str,
+    granularity: Granularity,
+    period: &str,
+) -> Result<Option<HashMap<String, AttributeValue>>> {
+    match item {
+        None if granularity == Granularity::Monthly => legacy_item(client, imei, period).await,
+        item => Ok(item),
+    }
+}
+
+fn monthly_key(ride_month: &str) -> AttributeValue {
+    AttributeValue::S(sort_key(Granularity::Monthly, ride_month))
+}
+
+#[derive(Debug, Clone)]
+pub struct PeriodRow {
+    /// `YYYY-MM` or `YYYY-MM-DD`, without the granularity prefix.
+    pub period: String,
+    pub total_distance: f64,
+}
+
+/// Stored rows of one granularity for one IMEI with `from <= period <= to`.
+pub async fn query_periods(client: &Client, imei: &str, granularity: Granularity, from: &str, to: &str) -> Result<Vec<PeriodRow>> {
+    Ok(query_period_items(client, imei, granularity, from, to).await?.iter().filter_map(|item| {
+        let (_, period) = parse_sort_key(item.get("period")?.as_s().ok()?)?;
+        let total_distance = item.get("total_distance")?.as_n().ok()?.parse().ok()?;
+        Some(PeriodRow { period: period.to_string(), total_distance })
+    }).collect())
+}
+
+/// [`query_periods`]'s rows in full, as results.
+pub async fn query_outputs(client: &Client, imei: &str, granularity: Granularity, from: &str, to: &str) -> Result<Vec<CustomOutput>> {
+    Ok(query_period_items(client, imei, granularity, from, to).await?.iter().filter_map(output_row).collect())
+}
+
+/// The device's rows in the range, with the baseline table's monthly rows this table does not have.
+async fn query_period_items(client: &Client, imei: &str, granularity: Granularity, from: &str, to: &str) -> Result<Vec<HashMap<String, AttributeValue>>> {
+    let mut items = query_table_periods(client, imei, granularity, from, to).await?;
+    let Some(legacy_table) = config::get().legacy_aggregates_table.as_ref().filter(|_| granularity == Granularity::Monthly) else {
+        return Ok(items);
+    };
+    let stored: BTreeSet<String> = items.iter().filter_map(|item| item.get("period")?.as_s().ok().cloned()).collect();
+    let legacy = client
+        .query()
+        .table_name(legacy_table)
+        .key_condition_expression("#imei = :imei AND #date BETWEEN :from AND :to")
+        .expression_attribute_names("#imei", "imei")
+        .expression_attribute_names("#date", "date")
+        .expression_attribute_values(":imei", AttributeValue::S(imei.to_string()))
+        .expression_attribute_values(":from", AttributeValue::S(from.to_string()))
+        .expression_attribute_values(":to", AttributeValue::S(to.to_string()))
+        .into_paginator()
+        .items()
+        .send()
+        .collect::<Result<Vec<_>, _>>()
+        .await
+        .inspect_err(|_| metrics::dynamodb_error("query"))?;
+    items.extend(legacy.into_iter().filter_map(from_legacy)
+        .filter(|item| item.get("period").and_then(|v| v.as_s().ok()).is_some_and(|period| !stored.contains(period))));
+    items.sort_by(|a, b| a.get("period").and_then(|v| v.as_s().ok()).cmp(&b.get("period").and_then(|v| v.as_s().ok())));
+    Ok(items)
+}
+
+async fn query_table_periods(client: &Client, imei: &str, granularity: Granularity, from: &str, to: &str) -> Result<Vec<HashMap<String, AttributeValue>>> {
+    Ok(client
+        .query()
+        .table_name(table_name())
+        .key_condition_expression("#imei = :imei AND #period BETWEEN :from AND :to")
+        .expression_attribute_names("#imei", "imei")
+        .expression_attribute_names("#period", "period")
+        .expression_attribute_values(":imei", AttributeValue::S(imei.to_string()))
+        .expression_attribute_values(":from", AttributeValue::S(sort_key(granularity, from)))
+        .expression_attribute_values(":to", AttributeValue::S(sort_key(granularity, to)))
+        .into_paginator()
+        .items()
+        .send()
+        .collect::<Result<Vec<_>, _>>()
+        .await
+        .inspect_err(|_| metrics::dynamodb_error("query"))?)
+}
+
+/// Distinct IMEIs present in the aggregates table, optionally restricted to a prefix, leaving out
+/// decommissioned devices and fleet rows.
+pub async fn scan_imeis(client: &Client, prefix: &str) -> Result<Vec<String>> {
+    let mut scan = client
+        .scan()
+        .table_name(table_name())
+        .projection_expression("#imei")
+        .expression_attribute_names("#imei", "imei")
+        .expression_attribute_names("#decommissioned", "decommissioned_at");
+    scan = if prefix.is_empty() {
+        scan.filter_expression("attribute_not_exists(#decommissioned)")
+    } else {
+        scan.filter_expression("begins_with(#imei, :prefix) AND attribute_not_exists(#decommissioned)")
+            .expression_attribute_values(":prefix", AttributeValue::S(prefix.to_string()))
+    };
+
+    let items = scan.into_paginator().items().send().collect::<Result<Vec<_>, _>>().await
+        .inspect_err(|_| metrics::dynamodb_error("scan"))?;
+    let imeis: BTreeSet<String> = items.iter()
+        .filter_map(|item| item.get("imei")?.as_s().ok().cloned())
+        .filter(|imei| !fleet::is_fleet_key(imei))
+        .collect();
+    Ok(imeis.into_iter().collect())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, JsonSchema, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum SkipReason {
+    /// The closed month was already stored after it ended (`preserve_final_months`).
+    PreservedFinal,
+    /// The row has been finalized and `force` was not set.
+    Finalized,
+    /// The IMEI's rides were cut off at a query limit; a partial total never replaces the stored row.
+    Truncated,
+    /// A redelivered fan-out shard: the row already carries this write's idempotency token.
+    AlreadyApplied,
+    /// `dry_run` was set, so nothing was written.
+    DryRun,
+    /// A row was already stored and `on_conflict` is `skip`, or `overwrite` is false.
+    Exists,
+    /// DynamoDB still left the write unprocessed after the batch retries.
+    Unprocessed,
+    /// The period is not wholly inside `start_date`..`end_date` or `input_ride_month`, or is a whole-range total.
+    PartialPeriod,
+    /// The stored row already holds exactly these values.
+    Unchanged,
+}
+
+/// What a write would do to the stored row.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, JsonSchema, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum WriteAction {
+    Insert,
+    Replace,
+    Skip,
+}
+
+/// One row of a dry run's write plan.
+#[derive(Debug, Clone, Serialize, JsonSchema, ToSchema)]
+pub struct PlannedWrite {
+    pub imei: String,
+    pub granularity: Granularity,
+    pub ride_month: String,
+    pub action: WriteAction,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub skipped: Option<SkipReason>,
+    /// Stored total the write would replace.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub previous_distance: Option<f64>,
+    pub total_distance: f64,
+    /// The stats that differ from the stored row's (`total_distance`, `ride_count`, `total_duration`,
+    /// `total_energy`), whether or not the row would be replaced; empty when none is stored.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub diff: BTreeMap<String, ValueDiff>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, JsonSchema, ToSchema)]
+pub struct ValueDiff {
+    pub old: f64,
+    pub new: f64,
+    pub delta: f64,
+}
+
+impl PlannedWrite {
+    pub fn new(row: &CustomOutput, outcome: &PutOutcome) -> PlannedWrite {
+        PlannedWrite {
+            imei: row.imei.clone(),
+            granularity: row.granularity,
+            ride_month: row.ride_month.clone(),
+            action: match (outcome.skipped, outcome.previous_distance) {
+                (Some(_), _) => WriteAction::Skip,
+                (None, Some(_)) => WriteAction::Replace,
+                (None, None) => WriteAction::Insert,
+            },
+            skipped: outcome.skipped,
+            previous_distance: outcome.previous_distance,
+            total_distance: row.total_distance,
+            diff: outcome.stored.map(|stored| diff(stored, RowTotals::of_row(row))).unwrap_or_default(),
+        }
+    }
+}
+
+fn diff(old: RowTotals, new: RowTotals) -> BTreeMap<String, ValueDiff> {
+    [
+        ("total_distance", old.distance, new.distance),
+        ("ride_count", old.ride_count, new.ride_count),
+        ("total_duration", old.duration, new.duration),
+        ("total_energy", old.energy, new.energy),
+    ]
+    .into_iter()
+    .filter(|(_, old, new)| (new - old).abs() > 1e-9)
+    .map(|(name, old, new)| (name.to_string(), ValueDiff { old, new, delta: new - old }))
+    .collect()
+}
+
+/// How [`put_rows`] writes, from `AGGREGATE_WRITE_MODE`: `put` (the default) replaces whole items in
+/// batches; `update` sets each row's attributes with its own `UpdateItem`, leaving attributes it does
+/// not carry alone, so runs computing different `metrics` for a row (distance, energy) do not clobber
+/// each other's. A metric no run computes any more then stays stored as last written.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WriteMode {
+    Put,
+    Update,
+}
+
+pub fn write_mode() -> WriteMode {
+    config::get().aggregate_write_mode
+}
+
+/// How [`put_rows`] treats the stored rows.
+#[derive(Debug, Clone, Copy)]
+pub struct WriteOptions<'a> {
+    pub preserve_final: bool,
+    pub force: bool,
+    /// Replace stored rows; when false, rows are only inserted where none is stored.
+    pub overwrite: bool,
+    pub token: Option<&'a str>,
+    /// Stored on each row as `source_invocation_id`.
+    pub invocation_id: &'a str,
+    /// Stored on each row as `last_updated_at`.
+    pub updated_at: &'a str,
+    /// The metrics the rows were computed with; in [`WriteMode::Update`] only theirs are set.
+    pub metrics: &'a [stats::Metric],
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PutOutcome {
+    pub skipped: Option<SkipReason>,
+    /// `total_distance` of the row this write replaced, if there was one.
+    pub previous_distance: Option<f64>,
+    /// The replaced row's additive stats, if there was one.
+    pub previous: Option<RowTotals>,
+    /// From [`plan_rows`]: the stored row's additive stats, even if it would be kept.
+    pub stored: Option<RowTotals>,
+}
+
+/// The stats of a row that add up across devices, missing ones counting as zero.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RowTotals {
+    pub distance: f64,
+    pub ride_count: f64,
+    pub duration: f64,
+    pub energy: f64,
+}
+
+impl RowTotals {
+    pub fn of_row(row: &CustomOutput) -> RowTotals {
+        RowTotals {
+            distance: row.total_distance,
+            ride_count: row.ride_count.unwrap_or(0) as f64,
+            duration: row.total_duration.unwrap_or(0.0),
+            energy: row.total_energy.unwrap_or(0.0),
+        }
+    }
+
+    fn of_item(item: &HashMap<String, AttributeValue>) -> RowTotals {
+        let number = |name: &str| item.get(name).and_then(|v| v.as_n().ok()).and_then(|n| n.parse().ok()).unwrap_or(0.0);
+        RowTotals {
+            distance: number("total_distance"),
+            ride_count: number("ride_count"),
+            duration: number("total_duration"),
+            energy: number("total_energy"),
+        }
+    }
+
+    pub fn minus(self, other: RowTotals) -> RowTotals {
+        RowTotals {
+            distance: self.distance - other.distance,
+            ride_count: self.ride_count - other.ride_count,
+            duration: self.duration - other.duration,
+            energy: self.energy - other.energy,
+        }
+    }
+}
+
+/// Writes rows, reporting per row why it was skipped if a condition kept the stored row. Finalized
+/// rows are only replaced with `force` (which clears the lock); with `preserve_final`, a closed month's
+/// row is only replaced while the stored copy is still missing or month-to-date. A `token` is stored as
+/// `write_token`, and a write whose token the row already carries is skipped, so retried work items
+/// apply once. A row whose stored values are already the same is not rewritten, so a rerun leaves its
+/// audit fields alone.
+///
+/// The stored rows are read first, to skip what would not be written, and DynamoDB checks the same
+/// conditions again at the write: the rows are put in `TransactWriteItems` chunks, each put carrying
+/// its conditions, so a row finalized or applied between the read and the write is still kept. Only a
+/// write with no conditions (`force`, no token, no `preserve_final`) goes through `BatchWriteItem`.
+/// Without `overwrite`, rows are put on `attribute_not_exists`, so a row stored in the meantime is
+/// never replaced. In [`WriteMode::Update`] each row is updated instead.
+pub async fn put_rows(client: &Client, rows: &[&CustomOutput], options: &WriteOptions<'_>, meter: &CapacityMeter) -> Result<Vec<PutOutcome>> {
+    destination::verify(client).await?;
+    let mut outcomes = plan_rows(client, rows, options, meter).await?;
+    if !options.overwrite {
+        for (row, outcome) in rows.iter().zip(outcomes.iter_mut()) {
+            if outcome.skipped.is_none() && !insert_row(client, row_item(row, options), meter).await? {
+                outcome.skipped = Some(SkipReason::Exists);
+            }
+        }
+        return Ok(outcomes);
+    }
+    let pending: Vec<usize> = (0..rows.len()).filter(|&i| outcomes[i].skipped.is_none()).collect();
+    match write_path(write_mode(), options) {
+        WritePath::Update => {
+            let writes = pending.clone().into_iter().map(|i| update_row(client, rows[i], options, meter));
+            let skipped = capacity::paced(client, table_name(), writes).await?;
+            for (i, reason) in pending.into_iter().zip(skipped) {
+                if reason.is_some() {
+                    outcomes[i] = PutOutcome { skipped: reason, ..Default::default() };
+                }
+            }
+            return Ok(outcomes);
+        }
+        WritePath::Transaction => {
+            let puts = pending.iter().map(|&i| conditional_put(rows[i], options)).collect::<Result<Vec<_>>>()?;
+            let results = batch::transact_put(client, table_name(), puts, meter).await?;
+            for (i, result) in pending.into_iter().zip(results) {
+                let skipped = match result {
+                    batch::TransactOutcome::Written => continue,
+                    batch::TransactOutcome::ConditionFailed(old) => failed_condition(old.as_ref(), options),
+                    batch::TransactOutcome::Unprocessed => SkipReason::Unprocessed,
+                };
+                outcomes[i] = PutOutcome { skipped: Some(skipped), ..Default::default() };
+            }
+            return Ok(outcomes);
+        }
+        WritePath::Batch => {}
+    }
+
+    let items = rows.iter().zip(&outcomes)
+        .filter(|(_, outcome)| outcome.skipped.is_none())
+        .map(|(row, _)| row_item(row, options))
+        .collect();
+
+    let unprocessed: BTreeSet<_> = batch::put(client, table_name(), items, meter).await?.iter()
+        .filter_map(|item| Some(key(item.get("imei")?.as_s().ok()?, item.get("period")?.as_s().ok()?)))
+        .collect();
+    for (row, outcome) in rows.iter().zip(outcomes.iter_mut()) {
+        if outcome.skipped.is_none() && unprocessed.contains(&key(&row.imei, &sort_key(row.granularity, &row.ride_month))) {
+            *outcome = PutOutcome { skipped: Some(SkipReason::Unprocessed), previous_distance: None, previous: None, stored: None };
+        }
+    }
+    Ok(outcomes)
+}
+
+/// How [`put_rows`] sends the rows it writes.
+#[derive(Debug, PartialEq)]
+enum WritePath {
+    /// One `UpdateItem` per row, in [`WriteMode::Update`].
+    Update,
+    /// Conditional puts in `TransactWriteItems` chunks.
+    Transaction,
+    /// `BatchWriteItem`, for puts with no condition.
+    Batch,
+}
+
+fn write_path(mode: WriteMode, options: &WriteOptions<'_>) -> WritePath {
+    match mode {
+        WriteMode::Update => WritePath::Update,
+        WriteMode::Put if write_condition(None, options).expression.is_some() => WritePath::Transaction,
+        WriteMode::Put => WritePath::Batch,
+    }
+}
+
+/// A write's condition expression with the names and values it uses; no expression, no condition.
+#[derive(Debug, Default, PartialEq)]
+struct Condition {
+    expression: Option<String>,
+    names: BTreeMap<String, String>,
+    values: BTreeMap<String, AttributeValue>,
+}
+
+/// The conditions [`skip_reason`] checks against the stored row, for DynamoDB to check at the write:
+/// the row does not carry the write's token, is not finalized (without `force`), and (with
+/// `preserve_final`, for a closed period) is missing or month-to-date. `row` None is any row.
+fn write_condition(row: Option<&CustomOutput>, options: &WriteOptions<'_>) -> Condition {
+    let mut condition = Condition::default();
+    let mut clauses = Vec::new();
+    if let Some(token) = options.token {
+        clauses.push("(attribute_not_exists(#token) OR #token <> :token)");
+        condition.names.insert("#token".to_string(), "write_token".to_string());
+        condition.values.insert(":token".to_string(), AttributeValue::S(token.to_string()));
+    }
+    if !options.force {
+        clauses.push("(attribute_not_exists(#fin) OR #fin = :false)");
+        condition.names.insert("#fin".to_string(), "finalized".to_string());
+        condition.values.insert(":false".to_string(), AttributeValue::Bool(false));
+    }
+    if options.preserve_final && !row.is_some_and(|row| row.month_to_date) {
+        clauses.push("(attribute_not_exists(#imei) OR #mtd = :true)");
+        condition.names.insert("#imei".to_string(), "imei".to_string());
+        condition.names.insert("#mtd".to_string(), "month_to_date".to_string());
+        condition.values.insert(":true".to_string(), AttributeValue::Bool(true));
+    }
+    condition.expression = (!clauses.is_empty()).then(|| clauses.join(" AND "));
+    condition
+}
+
+/// Which of [`write_condition`]'s conditions the stored row (as returned by the failed write) failed.
+fn failed_condition(old: Option<&HashMap<String, AttributeValue>>, options: &WriteOptions<'_>) -> SkipReason {
+    let old = old.cloned().unwrap_or_default();
+    if options.token.is_some() && old.get("write_token").and_then(|v| v.as_s().ok()).map(String::as_str) == options.token {
+        SkipReason::AlreadyApplied
+    } else if !options.force && old.get("finalized").and_then(|v| v.as_bool().ok()) == Some(&true) {
+        SkipReason::Finalized
+    } else {
+        SkipReason::PreservedFinal
+    }
+}
+
+/// The row's item as a put on [`write_condition`], returning the stored item if the condition fails.
+fn conditional_put(row: &CustomOutput, options: &WriteOptions<'_>) -> Result<Put> {
+    let condition = write_condition(Some(row), options);
+    Ok(Put::builder()
+        .table_name(table_name())
+        .set_item(Some(row_item(row, options)))
+        .set_condition_expression(condition.expression)
+        // DynamoDB rejects empty name and value maps.
+        .set_expression_attribute_names((!condition.names.is_empty()).then(|| condition.names.into_iter().collect()))
+        .set_expression_attribute_values((!condition.values.is_empty()).then(|| condition.values.into_iter().collect()))
+        .return_values_on_condition_check_failure(ReturnValuesOnConditionCheckFailure::AllOld)
+        .build()?)
+}
+
+/// An `UpdateItem`'s update and condition expressions, with the names and values they use.
+#[derive(Debug, PartialEq)]
+struct Update {
+    expression: String,
+    condition: Option<String>,
+    names: BTreeMap<String, String>,
+    values: BTreeMap<String, AttributeValue>,
+}
+
+/// The metric a stored attribute belongs to; None for the row's own state (`month_to_date`, `as_of`,
+/// audit fields), set by every write.
+fn attribute_metric(name: &str) -> Option<stats::Metric> {
+    match name {
+        "total_distance" | "voided_rides" | "applied_rides" => Some(stats::Metric::Distance),
+        "ride_count" => Some(stats::Metric::RideCount),
+        "total_duration" => Some(stats::Metric::Duration),
+        "average_speed" | "max_speed" => Some(stats::Metric::Speed),
+        "total_energy" => Some(stats::Metric::Energy),
+        "distance_histogram" => Some(stats::Metric::Histogram),
+        _ => None,
+    }
+}
+
+/// What an update sets: the row's attributes of the metrics the run computed, and its own state.
+fn update_attributes(row: &CustomOutput, options: &WriteOptions<'_>) -> BTreeMap<String, AttributeValue> {
+    row_item(row, options).into_iter()
+        .filter(|(name, _)| name != "imei" && name != "period")
+        .filter(|(name, _)| attribute_metric(name).is_none_or(|metric| options.metrics.contains(&metric)))
+        .collect()
+}
+
+/// `SET` per attribute of [`update_attributes`], on [`write_condition`]; with `force`, the lock is
+/// removed as a put would.
+fn update_expression(row: &CustomOutput, options: &WriteOptions<'_>) -> Update {
+    let Condition { expression: condition, mut names, mut values } = write_condition(Some(row), options);
+    let mut sets = Vec::new();
+    for (i, (name, value)) in update_attributes(row, options).into_iter().enumerate() {
+        sets.push(format!("#a{} = :v{}", i, i));
+        names.insert(format!("#a{}", i), name);
+        values.insert(format!(":v{}", i), value);
+    }
+    let mut expression = format!("SET {}", sets.join(", "));
+    if options.force {
+        expression.push_str(" REMOVE #fin, #fin_at");
+        names.insert("#fin".to_string(), "finalized".to_string());
+        names.insert("#fin_at".to_string(), "finalized_at".to_string());
+    }
+    Update { expression, condition, names, values }
+}
+
+/// Sets the row's [`update_attributes`] on the stored item, unless it was finalized (without `force`),
+/// already carries the write's token, or (with `preserve_final`) was closed meanwhile; returns why it
+/// was skipped and the units consumed.
+async fn update_row(client: &Client, row: &CustomOutput, options: &WriteOptions<'_>, meter: &CapacityMeter) -> Result<(Option<SkipReason>, f64)> {
+    let update = update_expression(row, options);
+    let result = client.update_item()
+        .table_name(table_name())
+        .key("imei", AttributeValue::S(row.imei.clone()))
+        .key("period", AttributeValue::S(sort_key(row.granularity, &row.ride_month)))
+        .update_expression(update.expression)
+        .set_condition_expression(update.condition)
+        .set_expression_attribute_names(Some(update.names.into_iter().collect()))
+        .set_expression_attribute_values(Some(update.values.into_iter().collect()))
+        .return_consumed_capacity(ReturnConsumedCapacity::Total)
+        .return_values_on_condition_check_failure(ReturnValuesOnConditionCheckFailure::AllOld)
+        .send()
+        .await;
+    match result {
+        Ok(resp) => {
+            meter.write(resp.consumed_capacity());
+            Ok((None, resp.consumed_capacity().and_then(|consumed| consumed.capacity_units()).unwrap_or_default()))
+        }
+        Err(err) => match err.as_service_error() {
+            Some(UpdateItemError::ConditionalCheckFailedException(failed)) => Ok((Some(failed_condition(failed.item(), options)), 0.0)),
+            _ => {
+                metrics::dynamodb_error("update_item");
+                Err(err.into())
+            }
+        },
+    }
+}
+
+fn key(imei: &str, period: &str) -> (String, String) {
+    (imei.to_string(), period.to_string())
+}
+
+/// Puts one item only if no row is stored under its key; false if one was.
+async fn insert_row(client: &Client, item: HashMap<String, AttributeValue>, meter: &CapacityMeter) -> Result<bool> {
+    let result = client.put_item()
+        .table_name(table_name())
+        .set_item(Some(item))
+        .condition_expression("attribute_not_exists(#imei)")
+        .expression_attribute_names("#imei", "imei")
+        .return_consumed_capacity(ReturnConsumedCapacity::Total)
+        .send()
+        .await;
+    match result {
+        Ok(resp) => {
+            meter.write(resp.consumed_capacity());
+            Ok(true)
+        }
+        Err(err) if err.as_service_error().is_some_and(|e| e.is_conditional_check_failed_exception()) => Ok(false),
+        Err(err) => {
+            metrics::dynamodb_error("put_item");
+            Err(err.into())
+        }
+    }
+}
+
+/// What [`put_rows`] would do with each row, from a read of the stored rows; `previous_distance` is
+/// only set for rows that would be written.
+pub async fn plan_rows(client: &Client, rows: &[&CustomOutput], options: &WriteOptions<'_>, meter: &CapacityMeter) -> Result<Vec<PutOutcome>> {
+    let keys = rows.iter().map(|row| HashMap::from([
+        ("imei".to_string(), AttributeValue::S(row.imei.clone())),
+        ("period".to_string(), AttributeValue::S(sort_key(row.granularity, &row.ride_month))),
+    ])).collect();
+    let stored: HashMap<_, _> = batch::get(client, table_name(), keys, meter).await?.into_iter()
+        .filter_map(|item| {
+            let imei = item.get("imei")?.as_s().ok()?.clone();
+            let period = item.get("period")?.as_s().ok()?.clone();
+            Some(((imei, period), item))
+        })
+        .collect();
+
+    Ok(rows.iter().map(|row| {
+        let old = stored.get(&key(&row.imei, &sort_key(row.granularity, &row.ride_month)));
+        let skipped = skip_reason(old, row, options);
+        PutOutcome {
+            skipped,
+            previous_distance: old
+                .filter(|_| skipped.is_none())
+                .and_then(|old| old.get("total_distance"))
+                .and_then(|v| v.as_n().ok())
+                .and_then(|n| n.parse().ok()),
+            previous: old.filter(|_| skipped.is_none()).map(RowTotals::of_item),
+            stored: old.map(RowTotals::of_item),
+        }
+    }).collect())
+}
+
+fn skip_reason(old: Option<&HashMap<String, AttributeValue>>, row: &CustomOutput, options: &WriteOptions<'_>) -> Option<SkipReason> {
+    let old = old?;
+    if options.token.is_some() && old.get("write_token").and_then(|v| v.as_s().ok()).map(String::as_str) == options.token {
+        return Some(SkipReason::AlreadyApplied);
+    }
+    if !options.force && old.get("finalized").and_then(|v| v.as_bool().ok()) == Some(&true) {
+        return Some(SkipReason::Finalized);
+    }
+    let stored_month_to_date = old.get("month_to_date").and_then(|v| v.as_bool().ok()) == Some(&true);
+    if options.preserve_final && !row.month_to_date && !stored_month_to_date {
+        return Some(SkipReason::PreservedFinal);
+    }
+    if !options.overwrite {
+        return Some(SkipReason::Exists);
+    }
+    let values = |item: &HashMap<String, AttributeValue>| item.len() - AUDIT_ATTRIBUTES.iter().filter(|name| item.contains_key(**name)).count();
+    // An update leaves the attributes it does not set alone, so only those it sets need to match.
+    let (item, same_attributes) = match write_mode() {
+        WriteMode::Update => (update_attributes(row, options).into_iter().collect(), true),
+        WriteMode::Put => {
+            let item = row_item(row, options);
+            let same = values(old) == values(&item);
+            (item, same)
+        }
+    };
+    let unchanged = same_attributes && item.iter()
+        .filter(|(name, _)| !AUDIT_ATTRIBUTES.contains(&name.as_str()))
+        .all(|(name, value)| old.get(name).is_some_and(|stored| same_value(stored, value)));
+    unchanged.then_some(SkipReason::Unchanged)
+}
+
+/// Attributes that record when and by which run a row was written, rather than its values.
+const AUDIT_ATTRIBUTES: [&str; 4] = ["as_of", "write_token", "last_updated_at", "source_invocation_id"];
+
+/// DynamoDB may return a number in a different form than it was written, e.g. `1` for `1.0`, and a
+/// set in any order.
+fn same_value(stored: &AttributeValue, new: &AttributeValue) -> bool {
+    match (stored, new) {
+        (AttributeValue::N(a), AttributeValue::N(b)) => a.parse::<f64>().ok() == b.parse::<f64>().ok(),
+        (AttributeValue::Ss(a), AttributeValue::Ss(b)) => a.iter().collect::<BTreeSet<_>>() == b.iter().collect::<BTreeSet<_>>(),
+        (AttributeValue::M(a), AttributeValue::M(b)) => {
+            a.len() == b.len() && b.iter().all(|(name, value)| a.get(name).is_some_and(|stored| same_value(stored, value)))
+        }
+        _ => stored == new,
+    }
+}
+
+fn row_item(row: &CustomOutput, options: &WriteOptions<'_>) -> HashMap<String, AttributeValue> {
+    let mut item = HashMap::from([
+        ("imei".to_string(), AttributeValue::S(row.imei.clone())),
+        ("period".to_string(), AttributeValue::S(sort_key(row.granularity, &row.ride_month))),
+        ("granularity".to_string(), AttributeValue::S(row.granularity.as_str().to_string())),
+        ("total_distance".to_string(), AttributeValue::N(row.total_distance.to_string())),
+        ("month_to_date".to_string(), AttributeValue::Bool(row.month_to_date)),
+        ("as_of".to_string(), AttributeValue::S(row.as_of.clone())),
+        ("voided_rides".to_string(), AttributeValue::N(row.explain.voided_rides.to_string())),
+        ("last_updated_at".to_string(), AttributeValue::S(options.updated_at.to_string())),
+        ("source_invocation_id".to_string(), AttributeValue::S(options.invocation_id.to_string())),
+    ]);
+    let ride_count = row.ride_count.map(|count| count as f64);
+    let metrics = [
+        ("total_duration", row.total_duration),
+        ("ride_count", ride_count),
+        ("average_speed", row.average_speed),
+        ("max_speed", row.max_speed),
+        ("total_energy", row.total_energy),
+    ];
+    for (name, value) in metrics {
+        if let Some(value) = value {
+            item.insert(name.to_string(), AttributeValue::N(value.to_string()));
+        }
+    }
+    if let Some(histogram) = &row.distance_histogram {
+        let bands = histogram.iter().map(|(band, rides)| (band.clone(), AttributeValue::N(rides.to_string()))).collect();
+        item.insert("distance_histogram".to_string(), AttributeValue::M(bands));
+    }
+    for (attribute, value) in &row.experimental {
+        item.insert(attribute.clone(), AttributeValue::N(value.to_string()));
+    }
+    // Fleet rows stay out of the month index, which ranks devices.
+    if row.granularity == Granularity::Monthly && !fleet::is_fleet_key(&row.imei) {
+        item.insert("month".to_string(), AttributeValue::S(row.ride_month.clone()));
+        // The rides stream increments list as they add them; a set cannot be empty.
+        if !row.applied_rides.is_empty() {
+            item.insert("applied_rides".to_string(), AttributeValue::Ss(row.applied_rides.iter().map(u64::to_string).collect()));
+        }
+    }
+    if let Some(token) = options.token {
+        item.insert("write_token".to_string(), AttributeValue::S(token.to_string()));
+    }
+    item
+}
+
+/// A stored row read back as written by [`row_item`]; what was never stored (breakdowns, most of
+/// `explain`, fraud flags) is left empty.
+fn output_row(item: &HashMap<String, AttributeValue>) -> Option<CustomOutput> {
+    let number = |name: &str| item.get(name).and_then(|v| v.as_n().ok()).and_then(|n| n.parse::<f64>().ok());
+    let (granularity, period) = parse_sort_key(item.get("period")?.as_s().ok()?)?;
+    let histogram = item.get("distance_histogram").and_then(|v| v.as_m().ok()).map(|bands| {
+        bands.iter().filter_map(|(band, rides)| Some((band.clone(), rides.as_n().ok()?.parse().ok()?))).collect()
+    });
+    Some(CustomOutput {
+        imei: item.get("imei")?.as_s().ok()?.clone(),
+        granularity,
+        ride_month: period.to_string(),
+        total_distance: number("total_distance")?,
+        total_duration: number("total_duration"),
+        ride_count: number("ride_count").map(|count| count as u64),
+        average_speed: number("average_speed"),
+        max_speed: number("max_speed"),
+        total_energy: number("total_energy"),
+        distance_histogram: histogram,
+        month_to_date: item.get("month_to_date").and_then(|v| v.as_bool().ok()).copied().unwrap_or(false),
+        as_of: item.get("as_of").and_then(|v| v.as_s().ok()).cloned().unwrap_or_default(),
+        write_skipped: None,
+        breakdowns: BTreeMap::new(),
+        explain: stats::RowExplain { voided_rides: number("voided_rides").unwrap_or(0.0) as u64, ..Default::default() },
+        truncated: false,
+        fraud_flags: Vec::new(),
+        experimental: experiments::of_item(item),
+        applied_rides: item.get("applied_rides").and_then(|v| v.as_ss().ok()).into_iter().flatten().filter_map(|ride| ride.parse().ok()).collect(),
+    })
+}
+
+/// Writes one externally computed row, tagged with its `imported_from` source. Unless `overwrite`, an
+/// existing row is kept; finalized rows are only replaced with `force`.
+pub async fn import_row(
+    client: &Client,
+    row: &ImportRow,
+    as_of: &str,
+    source: &str,
+    overwrite: bool,
+    force: bool,
+    meter: &CapacityMeter,
+) -> Result<Option<SkipReason>> {
+    destination::verify(client).await?;
+    let mut put = client.put_item()
+        .table_name(table_name())
+        .return_consumed_capacity(ReturnConsumedCapacity::Total)
+        .item("imei", AttributeValue::S(row.imei.clone()))
+        .item("period", AttributeValue::S(sort_key(row.granularity, &row.ride_month)))
+        .item("granularity", AttributeValue::S(row.granularity.as_str().to_string()))
+        .item("total_distance", AttributeValue::N(row.total_distance.to_string()))
+        .item("month_to_date", AttributeValue::Bool(false))
+        .item("as_of", AttributeValue::S(as_of.to_string()))
+        .item("voided_rides", AttributeValue::N("0".to_string()))
+        .item("imported_from", AttributeValue::S(source.to_string()));
+    if row.granularity == Granularity::Monthly {
+        put = put.item("month", AttributeValue::S(row.ride_month.clone()));
+    }
+    put = match (overwrite, force) {
+        (false, _) => put
+            .condition_expression("attribute_not_exists(#imei)")
+            .expression_attribute_names("#imei", "imei"),
+        (true, true) => put,
+        (true, false) => put
+            .condition_expression("attribute_not_exists(#fin) OR #fin = :false")
+            .expression_attribute_names("#fin", "finalized")
+            .expression_attribute_values(":false", AttributeValue::Bool(false)),
+    };
+
+    match put.return_values_on_condition_check_failure(ReturnValuesOnConditionCheckFailure::AllOld).send().await {
+        Ok(resp) => {
+            meter.write(resp.consumed_capacity());
+            Ok(None)
+        }
+        Err(err) => match err.as_service_error() {
+            Some(PutItemError::ConditionalCheckFailedException(failed)) => {
+                let finalized = failed.item().and_then(|item| item.get("finalized")).and_then(|v| v.as_bool().ok()) == Some(&true);
+                Ok(Some(if finalized && overwrite { SkipReason::Finalized } else { SkipReason::Exists }))
+            }
+            _ => {
+                metrics::dynamodb_error("put_item");
+                Err(err.into())
+            }
+        },
+    }
+}
+
+/// Marks an existing row as finalized; returns false if there is no row for that month.
+pub async fn finalize_row(client: &Client, imei: &str, ride_month: &str, finalized_at: &str) -> Result<bool> {
+    destination::verify(client).await?;
+    let result = client.update_item()
+        .table_name(table_name())
+        .key("imei", AttributeValue::S(imei.to_string()))
+        .key("period", monthly_key(ride_month))
+        .update_expression("SET #fin = :true, #fin_at = :at")
+        .condition_expression("attribute_exists(#imei)")
+        .expression_attribute_names("#imei", "imei")
+        .expression_attribute_names("#fin", "finalized")
+        .expression_attribute_names("#fin_at", "finalized_at")
+        .expression_attribute_values(":true", AttributeValue::Bool(true))
+        .expression_attribute_values(":at", AttributeValue::S(finalized_at.to_string()))
+        .send()
+        .await;
+
+    match result {
+        Ok(_) => Ok(true),
+        Err(err) if err.as_service_error().is_some_and(|e| e.is_conditional_check_failed_exception()) => Ok(false),
+        Err(err) => {
+            metrics::dynamodb_error("update_item");
+            Err(err.into())
+        }
+    }
+}
+
+/// Stored `total_distance` for one (imei, period), if the row exists. Strongly consistent, so a row
+/// written earlier in the same run reads back as written.
+pub async fn get_distance(client: &Client, imei: &str, granularity: Granularity, period: &str, meter: &CapacityMeter) -> Result<Option<f64>> {
+    let resp = client.get_item()
+        .table_name(table_name())
+        .key("imei", AttributeValue::S(imei.to_string()))
+        .key("period", AttributeValue::S(sort_key(granularity, period)))
+        .consistent_read(true)
+        .return_consumed_capacity(ReturnConsumedCapacity::Total)
+        .send()
+        .await
+        .inspect_err(|_| metrics::dynamodb_error("get_item"))?;
+    meter.read(resp.consumed_capacity());
+
+    Ok(with_legacy(client, resp.item, imei, granularity, period).await?.as_ref()
+        .and_then(|item| item.get("total_distance"))
+        .and_then(|v| v.as_n().ok())
+        .and_then(|n| n.parse().ok()))
+}
+
+/// IMEIs that have a stored row for `ride_month`, highest distance first.
+pub async fn query_imeis_for_month(client: &Client, ride_month: &str) -> Result<Vec<String>> {
+    let items = client
+        .query()
+        .table_name(table_name())
+        .index_name(MONTH_INDEX)
+        .key_condition_expression("#month = :month")
+        .projection_expression("#imei")
+        .expression_attribute_names("#imei", "imei")
+        .expression_attribute_names("#month", "month")
+        .expression_attribute_values(":month", AttributeValue::S(ride_month.to_string()))
+        .scan_index_forward(false)
+        .into_paginator()
+        .items()
+        .send()
+        .collect::<Result<Vec<_>, _>>()
+        .await
+        .inspect_err(|_| metrics::dynamodb_error("query"))?;
+
+    Ok(items.iter().filter_map(|item| item.get("imei")?.as_s().ok().cloned()).collect())
+}
+
+/// Stored `total_distance` and `as_of` of one (imei, month) row, if it exists.
+pub async fn get_row(client: &Client, imei: &str, ride_month: &str) -> Result<Option<(f64, String)>> {
+    let resp = client.get_item()
+        .table_name(table_name())
+        .key("imei", AttributeValue::S(imei.to_string()))
+        .key("period", monthly_key(ride_month))
+        .send()
+        .await
+        .inspect_err(|_| metrics::dynamodb_error("get_item"))?;
+
+    Ok(with_legacy(client, resp.item, imei, Granularity::Monthly, ride_month).await?.and_then(|item| {
+        let distance = item.get("total_distance")?.as_n().ok()?.parse().ok()?;
+        let as_of = item.get("as_of").and_then(|v| v.as_s().ok()).cloned().unwrap_or_default();
+        Some((distance, as_of))
+    }))
+}
+
+/// The stored (imei, month) row as it is, if there is one.
+pub async fn get_item(client: &Client, imei: &str, ride_month: &str) -> Result<Option<HashMap<String, AttributeValue>>> {
+    let resp = client.get_item()
+        .table_name(table_name())
+        .key("imei", AttributeValue::S(imei.to_string()))
+        .key("period", monthly_key(ride_month))
+        .consistent_read(true)
+        .send()
+        .await
+        .inspect_err(|_| metrics::dynamodb_error("get_item"))?;
+    with_legacy(client, resp.item, imei, Granularity::Monthly, ride_month).await
+}
+
+/// A stored row as read back from the table.
+#[derive(Debug, Clone, Serialize)]
+pub struct StoredRow {
+    pub imei: String,
+    pub granularity: Granularity,
+    /// `YYYY-MM`, or `YYYY-MM-DD` for daily and weekly rows.
+    pub ride_month: String,
+    pub total_distance: f64,
+    pub month_to_date: bool,
+    pub as_of: String,
+    pub finalized: bool,
+    pub voided_rides: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub decommissioned_at: Option<String>,
+}
+
+fn stored_row(item: &HashMap<String, AttributeValue>) -> Option<StoredRow> {
+    let text = |name: &str| item.get(name).and_then(|v| v.as_s().ok()).cloned();
+    let number = |name: &str| item.get(name).and_then(|v| v.as_n().ok()).and_then(|n| n.parse::<f64>().ok());
+    let flag = |name: &str| item.get(name).and_then(|v| v.as_bool().ok()).copied().unwrap_or(false);
+    let (granularity, period) = parse_sort_key(item.get("period")?.as_s().ok()?)?;
+    Some(StoredRow {
+        imei: text("imei")?,
+        granularity,
+        ride_month: period.to_string(),
+        total_distance: number("total_distance")?,
+        month_to_date: flag("month_to_date"),
+        as_of: text("as_of").unwrap_or_default(),
+        finalized: flag("finalized"),
+        voided_rides: number("voided_rides").unwrap_or(0.0) as u64,
+        decommissioned_at: text("decommissioned_at"),
+    })
+}
+
+/// Every monthly row stored for `ride_month`, in no particular order.
+pub async fn query_month(client: &Client, ride_month: &str) -> Result<Vec<StoredRow>> {
+    let items = client
+        .query()
+        .table_name(table_name())
+        .index_name(MONTH_INDEX)
+        .key_condition_expression("#month = :month")
+        .expression_attribute_names("#month", "month")
+        .expression_attribute_values(":month", AttributeValue::S(ride_month.to_string()))
+        .into_paginator()
+        .items()
+        .send()
+        .collect::<Result<Vec<_>, _>>()
+        .await
+        .inspect_err(|_| metrics::dynamodb_error("query"))?;
+
+    Ok(items.iter().filter_map(stored_row).collect())
+}
+
+/// Every row stored for one device, of every granularity.
+pub async fn query_device(client: &Client, imei: &str) -> Result<Vec<StoredRow>> {
+    let items = client
+        .query()
+        .table_name(table_name())
+        .key_condition_expression("#imei = :imei")
+        .expression_attribute_names("#imei", "imei")
+        .expression_attribute_values(":imei", AttributeValue::S(imei.to_string()))
+        .into_paginator()
+        .items()
+        .send()
+        .collect::<Result<Vec<_>, _>>()
+        .await
+        .inspect_err(|_| metrics::dynamodb_error("query"))?;
+
+    Ok(items.iter().filter_map(stored_row).collect())
+}
+
+/// Stamps a stored row with `decommissioned_at`.
+pub async fn mark_decommissioned(client: &Client, row: &StoredRow, decommissioned_at: &str) -> Result<()> {
+    destination::verify(client).await?;
+    client.update_item()
+        .table_name(table_name())
+        .key("imei", AttributeValue::S(row.imei.clone()))
+        .key("period", AttributeValue::S(sort_key(row.granularity, &row.ride_month)))
+        .update_expression("SET decommissioned_at = :at")
+        .expression_attribute_values(":at", AttributeValue::S(decommissioned_at.to_string()))
+        .send()
+        .await
+        .inspect_err(|_| metrics::dynamodb_error("update_item"))?;
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case", tag = "status")]
+pub enum AdjustOutcome {
+    Applied { total_distance: f64 },
+    /// There is no stored row for the month, so there is nothing to compensate.
+    Missing,
+    /// The row is finalized and `force` was not set.
+    Finalized,
+    /// Every ride was already added to the row.
+    AlreadyApplied,
+    /// Recounting the month left the stored row as it was, for `reason`.
+    Kept { reason: SkipReason },
+}
+
+/// Rides added by one `UpdateItem`, keeping its condition well within DynamoDB's expression size.
+const RIDES_PER_UPDATE: usize = 50;
+
+/// What one [`add_rides`] did.
+enum RidesAdded {
+    Applied { total_distance: f64 },
+    Finalized,
+    /// The row already lists some of the rides in `applied_rides`; these are all it lists.
+    Listed(BTreeSet<String>),
+}
+
+/// Adds new rides' distances, by ride id (the ride's `ride_start`), to a device's monthly row, creating
+/// the row if there is none; a finalized row is left alone. The row lists the rides added to it in
+/// `applied_rides`, and a ride it already lists is not added again, so a redelivered stream record
+/// does not count twice. Other metrics on the row are not updated. The added distance also goes to
+/// the `FLEET#*` rollup, as an aggregation's write would.
+pub async fn add_ride_distance(
+    client: &Client,
+    imei: &str,
+    ride_month: &str,
+    rides: &BTreeMap<String, f64>,
+    month_to_date: bool,
+    as_of: &str,
+    invocation_id: &str,
+) -> Result<AdjustOutcome> {
+    destination::verify(client).await?;
+    let mut outcome = AdjustOutcome::AlreadyApplied;
+    let ids: Vec<&str> = rides.keys().map(String::as_str).collect();
+    for chunk in ids.chunks(RIDES_PER_UPDATE) {
+        let mut pending = chunk.to_vec();
+        while !pending.is_empty() {
+            let batch: Vec<(&str, f64)> = pending.iter().map(|&id| (id, rides[id])).collect();
+            match add_rides(client, imei, ride_month, &batch, month_to_date, as_of, invocation_id).await? {
+                RidesAdded::Applied { total_distance } => {
+                    outcome = AdjustOutcome::Applied { total_distance };
+                    break;
+                }
+                RidesAdded::Finalized => return Ok(AdjustOutcome::Finalized),
+                RidesAdded::Listed(listed) => {
+                    let before = pending.len();
+                    pending.retain(|id| !listed.contains(*id));
+                    anyhow::ensure!(pending.len() < before, "adding rides to imei {} month {} failed its condition without a listed ride", imei, ride_month);
+                }
+            }
+        }
+    }
+    Ok(outcome)
+}
+
+/// The update expression, condition and values adding `rides` to a monthly row.
+fn rides_update(rides: &[(&str, f64)]) -> (String, String, HashMap<String, AttributeValue>) {
+    let distance: f64 = rides.iter().map(|(_, distance)| distance).sum();
+    let mut values = HashMap::from([
+        (":distance".to_string(), AttributeValue::N(distance.to_string())),
+        (":rides".to_string(), AttributeValue::Ss(rides.iter().map(|(id, _)| id.to_string()).collect())),
+    ]);
+    let mut unlisted = Vec::new();
+    for (i, (id, _)) in rides.iter().enumerate() {
+        unlisted.push(format!("NOT contains(#applied, :r{})", i));
+        values.insert(format!(":r{}", i), AttributeValue::S(id.to_string()));
+    }
+    let expression = "ADD total_distance :distance, #applied :rides SET granularity = :granularity, #month = :month, month_to_date = :mtd, \
+         as_of = :as_of, last_updated_at = :as_of, source_invocation_id = :invocation, voided_rides = if_not_exists(voided_rides, :zero)";
+    let condition = format!("(attribute_not_exists(#fin) OR #fin = :false) AND (attribute_not_exists(#applied) OR ({}))", unlisted.join(" AND "));
+    (expression.to_string(), condition, values)
+}
+
+async fn add_rides(
+    client: &Client,
+    imei: &str,
+    ride_month: &str,
+    rides: &[(&str, f64)],
+    month_to_date: bool,
+    as_of: &str,
+    invocation_id: &str,
+) -> Result<RidesAdded> {
+    let (expression, condition, values) = rides_update(rides);
+    let result = client.update_item()
+        .table_name(table_name())
+        .key("imei", AttributeValue::S(imei.to_string()))
+        .key("period", monthly_key(ride_month))
+        .update_expression(expression)
+        .condition_expression(condition)
+        .expression_attribute_names("#month", "month")
+        .expression_attribute_names("#fin", "finalized")
+        .expression_attribute_names("#applied", "applied_rides")
+        .set_expression_attribute_values(Some(values))
+        .expression_attribute_values(":granularity", AttributeValue::S(Granularity::Monthly.as_str().to_string()))
+        .expression_attribute_values(":month", AttributeValue::S(ride_month.to_string()))
+        .expression_attribute_values(":mtd", AttributeValue::Bool(month_to_date))
+        .expression_attribute_values(":as_of", AttributeValue::S(as_of.to_string()))
+        .expression_attribute_values(":invocation", AttributeValue::S(invocation_id.to_string()))
+        .expression_attribute_values(":zero", AttributeValue::N("0".to_string()))
+        .expression_attribute_values(":false", AttributeValue::Bool(false))
+        .return_values(ReturnValue::AllOld)
+        .return_values_on_condition_check_failure(ReturnValuesOnConditionCheckFailure::AllOld)
+        .send()
+        .await;
+
+    match result {
+        Ok(resp) => {
+            let old = resp.attributes().filter(|old| !old.is_empty());
+            let distance = rides.iter().map(|(_, distance)| distance).sum();
+            let Some(row) = row_with_rides(old, imei, ride_month, distance, month_to_date, as_of) else {
+                return Ok(RidesAdded::Applied { total_distance: distance });
+            };
+            fleet::maintain(client, &row, old.map(RowTotals::of_item), None, &CapacityMeter::default()).await;
+            Ok(RidesAdded::Applied { total_distance: row.total_distance })
+        }
+        Err(err) => match err.as_service_error() {
+            Some(UpdateItemError::ConditionalCheckFailedException(failed)) => {
+                let old = failed.item();
+                if old.and_then(|item| item.get("finalized")).and_then(|v| v.as_bool().ok()) == Some(&true) {
+                    return Ok(RidesAdded::Finalized);
+                }
+                let listed = old.and_then(|item| item.get("applied_rides")).and_then(|v| v.as_ss().ok());
+                Ok(RidesAdded::Listed(listed.into_iter().flatten().cloned().collect()))
+            }
+            _ => {
+                metrics::dynamodb_error("update_item");
+                Err(err.into())
+            }
+        },
+    }
+}
+
+/// The row [`rides_update`] left, from the one it updated (None: it created the row).
+fn row_with_rides(
+    old: Option<&HashMap<String, AttributeValue>>,
+    imei: &str,
+    ride_month: &str,
+    distance: f64,
+    month_to_date: bool,
+    as_of: &str,
+) -> Option<CustomOutput> {
+    let mut item = old.cloned().unwrap_or_else(|| HashMap::from([
+        ("imei".to_string(), AttributeValue::S(imei.to_string())),
+        ("period".to_string(), monthly_key(ride_month)),
+    ]));
+    let stored = item.get("total_distance").and_then(|v| v.as_n().ok()).and_then(|n| n.parse::<f64>().ok()).unwrap_or(0.0);
+    item.insert("total_distance".to_string(), AttributeValue::N((stored + distance).to_string()));
+    item.insert("month_to_date".to_string(), AttributeValue::Bool(month_to_date));
+    item.insert("as_of".to_string(), AttributeValue::S(as_of.to_string()));
+    output_row(&item)
+}
+
+/// What a rollup counts for one device row: the row's totals when they were last added, under a
+/// version each addition bumps. Stored in the device's partition under `fleet#<period key>`, which no
+/// period query or [`StoredRow`] reads.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Contribution {
+    pub totals: RowTotals,
+    pub version: u64,
+}
+
+/// A change to add to a rollup for one device row: its new `totals`, the `delta` from what the rollup
+/// counted, and the `devices` it adds.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RollupChange {
+    pub totals: RowTotals,
+    pub delta: RowTotals,
+    pub devices: i64,
+}
+
+fn contribution_key(row: &CustomOutput) -> AttributeValue {
+    AttributeValue::S(format!("fleet#{}", sort_key(row.granularity, &row.ride_month)))
+}
+
+/// The contribution recorded for the device row, read consistently.
+pub async fn get_contribution(client: &Client, row: &CustomOutput, meter: &CapacityMeter) -> Result<Option<Contribution>> {
+    let resp = client.get_item()
+        .table_name(table_name())
+        .key("imei", AttributeValue::S(row.imei.clone()))
+        .key("period", contribution_key(row))
+        .consistent_read(true)
+        .return_consumed_capacity(ReturnConsumedCapacity::Total)
+        .send()
+        .await
+        .inspect_err(|_| metrics::dynamodb_error("get_item"))?;
+    meter.read(resp.consumed_capacity());
+    Ok(resp.item().map(|item| Contribution {
+        totals: RowTotals::of_item(item),
+        version: item.get("version").and_then(|v| v.as_n().ok()).and_then(|n| n.parse().ok()).unwrap_or(0),
+    }))
+}
+
+/// Adds `change` to rollup `key`'s row for `row`'s period, in one transaction with recording the
+/// row's new contribution, conditional on the contribution still being at version `recorded` (None:
+/// not recorded yet). False when another write recorded one first, so nothing was added. The rollup
+/// row is created if there is none; rollup rows carry no `month`, keeping them out of [`MONTH_INDEX`].
+pub async fn add_to_rollup(client: &Client, key: &str, row: &CustomOutput, recorded: Option<u64>, change: &RollupChange, meter: &CapacityMeter) -> Result<bool> {
+    use aws_sdk_dynamodb::operation::transact_write_items::TransactWriteItemsError;
+    use aws_sdk_dynamodb::types::{Put, TransactWriteItem, Update};
+
+    destination::verify(client).await?;
+    let number = |value: f64| AttributeValue::N(value.to_string());
+    let mut contribution = Put::builder()
+        .table_name(table_name())
+        .item("imei", AttributeValue::S(row.imei.clone()))
+        .item("period", contribution_key(row))
+        .item("total_distance", number(change.totals.distance))
+        .item("ride_count", number(change.totals.ride_count))
+        .item("total_duration", number(change.totals.duration))
+        .item("total_energy", number(change.totals.energy))
+        .item("version", AttributeValue::N((recorded.unwrap_or(0) + 1).to_string()))
+        .item("as_of", AttributeValue::S(row.as_of.clone()))
+        .expression_attribute_names("#version", "version");
+    contribution = match recorded {
+        Some(version) => contribution
+            .condition_expression("#version = :version")
+            .expression_attribute_values(":version", AttributeValue::N(version.to_string())),
+        None => contribution.condition_expression("attribute_not_exists(#version)"),
+    };
+    let rollup = Update::builder()
+        .table_name(table_name())
+        .key("imei", AttributeValue::S(key.to_string()))
+        .key("period", AttributeValue::S(sort_key(row.granularity, &row.ride_month)))
+        .update_expression(
+            "ADD total_distance :distance, ride_count :rides, total_duration :duration, total_energy :energy, devices :devices \
+             SET granularity = :granularity, as_of = :as_of, last_updated_at = :as_of",
+        )
+        .expression_attribute_values(":distance", number(change.delta.distance))
+        .expression_attribute_values(":rides", number(change.delta.ride_count))
+        .expression_attribute_values(":duration", number(change.delta.duration))
+        .expression_attribute_values(":energy", number(change.delta.energy))
+        .expression_attribute_values(":devices", AttributeValue::N(change.devices.to_string()))
+        .expression_attribute_values(":granularity", AttributeValue::S(row.granularity.as_str().to_string()))
+        .expression_attribute_values(":as_of", AttributeValue::S(row.as_of.clone()));
+    let result = client.transact_write_items()
+        .transact_items(TransactWriteItem::builder().put(contribution.build()?).build())
+        .transact_items(TransactWriteItem::builder().update(rollup.build()?).build())
+        .return_consumed_capacity(ReturnConsumedCapacity::Total)
+        .send()
+        .await;
+    match result {
+        Ok(resp) => {
+            for consumed in resp.consumed_capacity() {
+                meter.write(Some(consumed));
+            }
+            Ok(true)
+        }
+        Err(err) => match err.as_service_error() {
+            Some(TransactWriteItemsError::TransactionCanceledException(canceled))
+                if canceled.cancellation_reasons().first().and_then(|reason| reason.code()) == Some("ConditionalCheckFailed") => Ok(false),
+            _ => {
+                metrics::dynamodb_error("transact_write_items");
+                Err(err.into())
+            }
+        },
+    }
+}
+
+/// A compensating change to a stored monthly row, for a corrected ride.
+pub struct Adjustment<'a> {
+    pub imei: &'a str,
+    pub ride_month: &'a str,
+    /// Listed in the row's `corrected_rides` once applied, so a redelivered correction is not.
+    pub correction_id: &'a str,
+    pub delta: f64,
+    pub as_of: &'a str,
+    pub force: bool,
+}
+
+/// The update applying `adjustment` to its row, unless the row is missing, already lists the
+/// correction or (without `force`) is finalized.
+fn adjustment_update(adjustment: &Adjustment<'_>) -> Update {
+    let mut condition = "attribute_exists(#imei) AND (attribute_not_exists(#corrected) OR NOT contains(#corrected, :correction))".to_string();
+    let mut names = BTreeMap::from([("#imei".to_string(), "imei".to_string()), ("#corrected".to_string(), "corrected_rides".to_string())]);
+    let mut values = BTreeMap::from([
+        (":delta".to_string(), AttributeValue::N(adjustment.delta.to_string())),
+        (":as_of".to_string(), AttributeValue::S(adjustment.as_of.to_string())),
+        (":correction".to_string(), AttributeValue::S(adjustment.correction_id.to_string())),
+        (":corrections".to_string(), AttributeValue::Ss(vec![adjustment.correction_id.to_string()])),
+    ]);
+    if !adjustment.force {
+        condition.push_str(" AND (attribute_not_exists(#fin) OR #fin = :false)");
+        names.insert("#fin".to_string(), "finalized".to_string());
+        values.insert(":false".to_string(), AttributeValue::Bool(false));
+    }
+    Update { expression: "ADD total_distance :delta, #corrected :corrections SET as_of = :as_of".to_string(), condition: Some(condition), names, values }
+}
+
+/// Why [`adjustment_update`]'s condition failed, from the stored row the failed update returned.
+fn failed_adjustment(old: Option<&HashMap<String, AttributeValue>>, correction_id: &str) -> AdjustOutcome {
+    let Some(old) = old else {
+        return AdjustOutcome::Missing;
+    };
+    let listed = old.get("corrected_rides").and_then(|v| v.as_ss().ok()).is_some_and(|ids| ids.iter().any(|id| id == correction_id));
+    if listed { AdjustOutcome::AlreadyApplied } else { AdjustOutcome::Finalized }
+}
+
+/// Applies a compensating `delta` to a stored row's `total_distance` without recomputing the month,
+/// then adds the change to the `FLEET#*` rollup as an aggregation's write would.
+pub async fn adjust_row(client: &Client, adjustment: &Adjustment<'_>, meter: &CapacityMeter) -> Result<AdjustOutcome> {
+    destination::verify(client).await?;
+    let update = adjustment_update(adjustment);
+    let result = client.update_item()
+        .table_name(table_name())
+        .key("imei", AttributeValue::S(adjustment.imei.to_string()))
+        .key("period", monthly_key(adjustment.ride_month))
+        .update_expression(update.expression)
+        .set_condition_expression(update.condition)
+        .set_expression_attribute_names(Some(update.names.into_iter().collect()))
+        .set_expression_attribute_values(Some(update.values.into_iter().collect()))
+        .return_values(ReturnValue::AllNew)
+        .return_values_on_condition_check_failure(ReturnValuesOnConditionCheckFailure::AllOld)
+        .send()
+        .await;
+    match result {
+        Ok(resp) => {
+            let row = resp.attributes().and_then(output_row);
+            if let Some(row) = &row {
+                let mut previous = RowTotals::of_row(row);
+                previous.distance -= adjustment.delta;
+                fleet::maintain(client, row, Some(previous), None, meter).await;
+            }
+            Ok(AdjustOutcome::Applied { total_distance: row.map_or(0.0, |row| row.total_distance) })
+        }
+        Err(err) => match err.as_service_error() {
+            Some(UpdateItemError::ConditionalCheckFailedException(failed)) => Ok(failed_adjustment(failed.item(), adjustment.correction_id)),
+            _ => {
+                metrics::dynamodb_error("update_item");
+                Err(err.into())
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(force: bool, token: Option<&str>, preserve_final: bool) -> WriteOptions<'_> {
+        WriteOptions { preserve_final, force, overwrite: true, token, invocation_id: "run", updated_at: "2024-04-01T00:00:00Z", metrics: &stats::DEFAULT_METRICS }
+    }
+
+    #[test]
+    fn writes_are_conditional_unless_forced_without_token() {
+        assert_eq!(write_condition(None, &options(true, None, false)), Condition::default());
+        let condition = write_condition(None, &options(false, Some("job#1"), true));
+        assert_eq!(
+            condition.expression.as_deref(),
+            Some("(attribute_not_exists(#token) OR #token <> :token) AND (attribute_not_exists(#fin) OR #fin = :false) AND (attribute_not_exists(#imei) OR #mtd = :true)"),
+        );
+        assert_eq!(condition.names.keys().collect::<Vec<_>>(), ["#fin", "#imei", "#mtd", "#token"]);
+        assert_eq!(condition.values.keys().collect::<Vec<_>>(), [":false", ":token", ":true"]);
+        // A month-to-date row may always replace the stored one.
+        let mut row = CustomOutput::fixture("350000000000001", "2024-04", 3.0);
+        row.month_to_date = true;
+        assert_eq!(write_condition(Some(&row), &options(true, None, true)), Condition::default());
+    }
+
+    #[test]
+    fn default_writes_are_batched_in_transactions() {
+        assert_eq!(write_path(WriteMode::Put, &options(false, None, false)), WritePath::Transaction);
+        assert_eq!(write_path(WriteMode::Put, &options(true, Some("job#1"), false)), WritePath::Transaction);
+        assert_eq!(write_path(WriteMode::Put, &options(true, None, false)), WritePath::Batch);
+        assert_eq!(write_path(WriteMode::Update, &options(false, None, false)), WritePath::Update);
+    }
+
+    #[test]
+    fn failed_conditions_map_to_skip_reasons() {
+        let stored = |attributes: &[(&str, AttributeValue)]| attributes.iter().map(|(name, value)| (name.to_string(), value.clone())).collect::<HashMap<_, _>>();
+        let applied = stored(&[("write_token", AttributeValue::S("job#1".to_string())), ("finalized", AttributeValue::Bool(true))]);
+        assert_eq!(failed_condition(Some(&applied), &options(false, Some("job#1"), false)), SkipReason::AlreadyApplied);
+        assert_eq!(failed_condition(Some(&applied), &options(false, Some("job#2"), false)), SkipReason::Finalized);
+        let closed = stored(&[("month_to_date", AttributeValue::Bool(false))]);
+        assert_eq!(failed_condition(Some(&closed), &options(false, None, true)), SkipReason::PreservedFinal);
+    }
+
+    #[test]
+    fn updates_set_only_the_computed_metrics() {
+        let mut row = CustomOutput::fixture("350000000000001", "2024-04", 3.0);
+        row.ride_count = Some(2);
+        row.total_energy = Some(1.5);
+        let set = |update: &Update| {
+            let mut names: Vec<String> = update.values.keys()
+                .filter_map(|value| update.names.get(&value.replacen(":v", "#a", 1)).cloned())
+                .collect();
+            names.sort();
+            names
+        };
+        let energy = WriteOptions { metrics: &[stats::Metric::Energy], ..options(true, None, false) };
+        let update = update_expression(&row, &energy);
+        assert_eq!(set(&update), ["as_of", "granularity", "last_updated_at", "month", "month_to_date", "source_invocation_id", "total_energy"]);
+        assert!(update.expression.ends_with(" REMOVE #fin, #fin_at"));
+        assert_eq!(update.condition, None);
+        let distance = WriteOptions { metrics: &[stats::Metric::Distance, stats::Metric::RideCount], ..options(false, Some("job#1"), false) };
+        let update = update_expression(&row, &distance);
+        assert_eq!(set(&update), ["as_of", "granularity", "last_updated_at", "month", "month_to_date", "ride_count", "source_invocation_id", "total_distance", "voided_rides", "write_token"]);
+        assert!(!update.expression.contains("REMOVE"));
+        assert_eq!(update.condition, write_condition(Some(&row), &distance).expression);
+    }
+
+    #[test]
+    fn sort_keys_carry_the_granularity() {
+        assert_eq!(sort_key(Granularity::Daily, "2024-04-02"), "period#daily#2024-04-02");
+        assert_eq!(parse_sort_key("period#weekly#2024-04-01"), Some((Granularity::Weekly, "2024-04-01")));
+        assert_eq!(parse_sort_key("fleet#period#monthly#2024-04"), None);
+        assert_eq!(parse_sort_key("monthly#2024-04"), None);
+    }
+
+    #[test]
+    fn baseline_rows_become_monthly_rows() {
+        let baseline = HashMap::from([
+            ("imei".to_string(), AttributeValue::S("350000000000001".to_string())),
+            ("date".to_string(), AttributeValue::S("2023-11".to_string())),
+            ("total_distance".to_string(), AttributeValue::N("42.5".to_string())),
+        ]);
+        let row = stored_row(&from_legacy(baseline).unwrap()).unwrap();
+        assert_eq!((row.granularity, row.ride_month.as_str(), row.total_distance), (Granularity::Monthly, "2023-11", 42.5));
+        assert_eq!(from_legacy(HashMap::new()), None);
+    }
+
+    #[test]
+    fn rides_are_added_unless_listed() {
+        let (expression, condition, values) = rides_update(&[("1712000000", 2.0), ("1712003600", 3.5)]);
+        assert!(expression.starts_with("ADD total_distance :distance, #applied :rides "));
+        assert_eq!(condition, "(attribute_not_exists(#fin) OR #fin = :false) AND (attribute_not_exists(#applied) OR (NOT contains(#applied, :r0) AND NOT contains(#applied, :r1)))");
+        assert_eq!(values[":distance"], AttributeValue::N("5.5".to_string()));
+        assert_eq!(values[":rides"], AttributeValue::Ss(vec!["1712000000".to_string(), "1712003600".to_string()]));
+        assert_eq!(values[":r1"], AttributeValue::S("1712003600".to_string()));
+    }
+
+    #[test]
+    fn recomputed_rows_list_the_rides_they_counted() {
+        let mut row = CustomOutput::fixture("350000000000001", "2024-04", 5.5);
+        row.applied_rides = BTreeSet::from([1712000000, 1712003600]);
+        let item = row_item(&row, &options(false, None, false));
+        let listed = AttributeValue::Ss(vec!["1712000000".to_string(), "1712003600".to_string()]);
+        assert_eq!(item["applied_rides"], listed);
+        // So a stream record for a ride the recompute counted fails the increment's condition.
+        let stored = AttributeValue::Ss(vec!["1712003600".to_string(), "1712000000".to_string()]);
+        assert!(same_value(&stored, &listed));
+        assert_eq!(output_row(&item).unwrap().applied_rides, row.applied_rides);
+    }
+
+    #[tokio::test]
+    async fn stream_increments_move_the_fleet_rollup() {
+        let old = serde_json::json!({
+            "imei": {"S": "111"},
+            "period": {"S": sort_key(Granularity::Monthly, "2024-04")},
+            "total_distance": {"N": "10"},
+            "ride_count": {"N": "3"},
+        });
+        let fake = crate::testing::Fake::new(move |request| match request.is("UpdateItem") {
+            true => crate::testing::ok(serde_json::json!({"Attributes": old})),
+            false => crate::testing::ok(serde_json::json!({})),
+        });
+        let rides = BTreeMap::from([("1712003600".to_string(), 2.5)]);
+        let outcome = add_ride_distance(&fake.dynamodb(), "111", "2024-04", &rides, false, "2024-04-10T00:00:00Z", "run").await.unwrap();
+
+        assert_eq!(outcome, AdjustOutcome::Applied { total_distance: 12.5 });
+        let rollup = &fake.sent("TransactWriteItems")[0]["TransactItems"];
+        let add = rollup.as_array().unwrap().iter().find_map(|item| item.get("Update")).unwrap();
+        assert_eq!(add["Key"]["imei"], serde_json::json!({"S": fleet::ROLLUP_KEY}));
+        // The row was stored before its contribution, so only the increment is added.
+        assert!(add["ExpressionAttributeValues"].to_string().contains("\"N\":\"2.5\""), "{}", add);
+        assert!(!add["ExpressionAttributeValues"].to_string().contains("\"N\":\"3\""), "{}", add);
+    }
+}
@@ -0,0 +1,81 @@
+//! Re-flashed devices report a new IMEI; `ride_data_imei_aliases` maps each retired `old_imei` to the
+//! `new_imei` it became. A logical device is named by its newest IMEI and its rides are read under
+//! every IMEI it has reported.
+
+use aws_sdk_dynamodb::types::AttributeValue;
+use aws_sdk_dynamodb::Client;
+use anyhow::{bail, Result};
+
+use crate::metrics;
+
+pub const TABLE_NAME: &str = "ride_data_imei_aliases";
+/// GSI on the alias table partitioned by `new_imei`, listing the IMEIs a device reported before.
+pub const NEW_IMEI_INDEX: &str = "new-imei-index";
+/// Alias chains longer than this are taken to be a loop.
+const MAX_HOPS: usize = 10;
+
+/// One logical device and every IMEI its rides are stored under, current one first.
+#[derive(Debug, Clone)]
+pub struct Device {
+    pub imei: String,
+    pub identities: Vec<String>,
+}
+
+/// The devices behind the requested IMEIs, in request order; IMEIs of the same device collapse into one.
+pub async fn resolve(client: &Client, imeis: &[String]) -> Result<Vec<Device>> {
+    let mut devices: Vec<Device> = Vec::new();
+    for imei in imeis {
+        let current = current_imei(client, imei).await?;
+        if devices.iter().any(|device| device.imei == current) {
+            continue;
+        }
+        let identities = identities(client, &current).await?;
+        devices.push(Device { imei: current, identities });
+    }
+    Ok(devices)
+}
+
+async fn current_imei(client: &Client, imei: &str) -> Result<String> {
+    let mut current = imei.to_string();
+    for _ in 0..MAX_HOPS {
+        let resp = client.get_item()
+            .table_name(TABLE_NAME)
+            .key("old_imei", AttributeValue::S(current.clone()))
+            .send()
+            .await
+            .inspect_err(|_| metrics::dynamodb_error("get_item"))?;
+        match resp.item().and_then(|item| item.get("new_imei")).and_then(|v| v.as_s().ok()) {
+            Some(new_imei) => current = new_imei.clone(),
+            None => return Ok(current),
+        }
+    }
+    bail!("IMEI aliases of {} form a chain longer than {}", imei, MAX_HOPS)
+}
+
+async fn identities(client: &Client, imei: &str) -> Result<Vec<String>> {
+    let mut identities = vec![imei.to_string()];
+    let mut next = 0;
+    while next < identities.len() {
+        if identities.len() > MAX_HOPS {
+            bail!("IMEI {} has more than {} aliases", imei, MAX_HOPS);
+        }
+        let items = client.query()
+            .table_name(TABLE_NAME)
+            .index_name(NEW_IMEI_INDEX)
+            .key_condition_expression("new_imei = :imei")
+            .expression_attribute_values(":imei", AttributeValue::S(identities[next].clone()))
+            .into_paginator()
+            .items()
+            .send()
+            .collect::<Result<Vec<_>, _>>()
+            .await
+            .inspect_err(|_| metrics::dynamodb_error("query"))?;
+        for old in items.iter().filter_map(|item| item.get("old_imei")?.as_s().ok()) {
+            if !identities.contains(old) {
+                identities.push(old.clone());
+            }
+        }
+        next += 1;
+    }
+    Ok(identities)
+}
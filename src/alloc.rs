@@ -0,0 +1,93 @@
+//! Allocation tracking (the `alloc-stats` feature): a counting wrapper around the system allocator
+//! whose per-request figures are reported under the response's `diagnostics.memory`. Counters are
+//! process-wide, so in HTTP mode concurrent requests show up in each other's figures.
+
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicU64, Ordering};
+use utoipa::ToSchema;
+
+struct Counting;
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+static ALLOCATED: AtomicU64 = AtomicU64::new(0);
+static LIVE: AtomicU64 = AtomicU64::new(0);
+static PEAK: AtomicU64 = AtomicU64::new(0);
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+fn allocated(size: usize) {
+    let size = size as u64;
+    ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+    ALLOCATED.fetch_add(size, Ordering::Relaxed);
+    let live = LIVE.fetch_add(size, Ordering::Relaxed) + size;
+    PEAK.fetch_max(live, Ordering::Relaxed);
+}
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            allocated(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc_zeroed(layout);
+        if !ptr.is_null() {
+            allocated(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        LIVE.fetch_sub(layout.size() as u64, Ordering::Relaxed);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new = System.realloc(ptr, layout, new_size);
+        if !new.is_null() {
+            LIVE.fetch_sub(layout.size() as u64, Ordering::Relaxed);
+            allocated(new_size);
+        }
+        new
+    }
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema, ToSchema)]
+pub struct MemoryStats {
+    /// Allocations made while handling the request, and their total size.
+    pub allocations: u64,
+    pub allocated_bytes: u64,
+    /// Highest live heap size while handling the request.
+    pub peak_bytes: u64,
+    /// Live heap size when the response was built.
+    pub live_bytes: u64,
+}
+
+/// Counters at the start of a request; resets the peak to the current live size.
+#[derive(Debug, Clone, Copy)]
+pub struct Tracker {
+    allocations: u64,
+    allocated: u64,
+}
+
+impl Tracker {
+    pub fn start() -> Tracker {
+        PEAK.store(LIVE.load(Ordering::Relaxed), Ordering::Relaxed);
+        Tracker { allocations: ALLOCATIONS.load(Ordering::Relaxed), allocated: ALLOCATED.load(Ordering::Relaxed) }
+    }
+
+    pub fn stats(&self) -> MemoryStats {
+        MemoryStats {
+            allocations: ALLOCATIONS.load(Ordering::Relaxed) - self.allocations,
+            allocated_bytes: ALLOCATED.load(Ordering::Relaxed) - self.allocated,
+            peak_bytes: PEAK.load(Ordering::Relaxed),
+            live_bytes: LIVE.load(Ordering::Relaxed),
+        }
+    }
+}
@@ -0,0 +1,108 @@
+//! Rides too implausible to trust: starting beyond the clock-skew tolerance, or longer than
+//! `MAX_RIDE_DISTANCE_KM` (unset, no limit). `ANOMALY_ACTION` chooses whether they are excluded
+//! from the totals (`exclude`, the default) or counted and only reported (`flag`). Either way they
+//! are listed in the response's `anomalies`, and published to `ANOMALY_TOPIC_ARN` if it is set.
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::info;
+use utoipa::ToSchema;
+
+use crate::config;
+use crate::ride::{self, Exclusion, RideDecision};
+
+/// Anomalies per SNS message, well below its 256 KB limit.
+const PUBLISH_CHUNK: usize = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize, JsonSchema, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Reason {
+    /// Starts further in the future than `CLOCK_SKEW_TOLERANCE_SECS` allows.
+    FutureStart,
+    /// Longer than `MAX_RIDE_DISTANCE_KM`.
+    ImplausibleDistance,
+}
+
+impl Reason {
+    pub fn exclusion(self) -> Exclusion {
+        match self {
+            Reason::FutureStart => Exclusion::ClockSkew,
+            Reason::ImplausibleDistance => Exclusion::ImplausibleDistance,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    Exclude,
+    Flag,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema, ToSchema)]
+pub struct Anomaly {
+    pub imei: String,
+    pub ride_start: u64,
+    pub reason: Reason,
+    /// km.
+    pub distance: Option<f64>,
+    /// Left out of the totals, rather than only flagged.
+    pub excluded: bool,
+}
+
+/// The configured rules.
+#[derive(Debug, Clone, Copy)]
+pub struct Rules {
+    max_skew_secs: i64,
+    max_ride_distance_km: Option<f64>,
+    pub action: Action,
+}
+
+impl Rules {
+    pub fn from_env() -> Rules {
+        Rules {
+            max_skew_secs: ride::clock_skew_tolerance().as_secs() as i64,
+            max_ride_distance_km: max_ride_distance_km(),
+            action: config::get().anomaly_action,
+        }
+    }
+
+    /// Whether a ride starting at `ride_start` starts beyond the clock-skew tolerance of `now` (epoch seconds).
+    pub fn is_future(&self, ride_start: u64, now: i64) -> bool {
+        ride_start as i64 > now + self.max_skew_secs
+    }
+
+    /// What is implausible about the ride, if anything.
+    pub fn check(&self, decision: &RideDecision, now: i64) -> Option<Reason> {
+        if decision.ride_start.is_some_and(|start| self.is_future(start, now)) {
+            return Some(Reason::FutureStart);
+        }
+        match (decision.distance, self.max_ride_distance_km) {
+            (Some(distance), Some(max)) if distance > max => Some(Reason::ImplausibleDistance),
+            _ => None,
+        }
+    }
+}
+
+/// From `MAX_RIDE_DISTANCE_KM`.
+pub fn max_ride_distance_km() -> Option<f64> {
+    config::get().max_ride_distance_km
+}
+
+/// Publishes the anomalies to `ANOMALY_TOPIC_ARN`, in messages of up to a hundred; nothing when it is unset.
+pub async fn publish(shared_config: &aws_config::SdkConfig, anomalies: &[Anomaly], run_id: &str) -> anyhow::Result<()> {
+    let Some(topic_arn) = &config::get().anomaly_topic_arn else {
+        return Ok(());
+    };
+    let sns = aws_sdk_sns::Client::new(shared_config);
+    for chunk in anomalies.chunks(PUBLISH_CHUNK) {
+        sns.publish()
+            .topic_arn(topic_arn)
+            .subject("Ride anomalies")
+            .message(json!({ "run_id": run_id, "anomalies": chunk }).to_string())
+            .send()
+            .await?;
+    }
+    info!("Published {} ride anomalies", anomalies.len());
+    Ok(())
+}
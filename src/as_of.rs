@@ -0,0 +1,63 @@
+//! `action: "aggregate_as_of"`: what the stored aggregate for one (imei, `input_ride_month`) was at
+//! the `as_of` instant, reconstructed from the revisions table, for disputes over older statements.
+//! Only restatements above `REVISION_THRESHOLD_KM` are recorded, so values are exact to that threshold.
+
+use aws_sdk_dynamodb::Client;
+use chrono::{DateTime, SecondsFormat, Utc};
+use lambda_runtime::Error;
+use schemars::JsonSchema;
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::time::Granularity;
+use crate::{aggregates, history, CustomEvent, ErrorOutput};
+
+/// Where the reconstructed value comes from.
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case", tag = "source")]
+pub enum AsOfBasis {
+    /// The last restatement before `as_of`.
+    Revision { revised_at: String, run_id: String, reason: String },
+    /// The value the first restatement after `as_of` replaced.
+    BeforeRevision { revised_at: String },
+    /// The aggregate was never restated, so the stored row is the answer.
+    Unrevised { stored_as_of: String },
+    /// No row or revision exists for the aggregate.
+    Missing,
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct AsOfOutput {
+    imei: String,
+    ride_month: String,
+    as_of: String,
+    total_distance: Option<f64>,
+    basis: AsOfBasis,
+}
+
+pub async fn aggregate_as_of(client: &Client, payload: &CustomEvent) -> Result<Value, Error> {
+    let (Some(ride_month), [imei]) = (&payload.input_ride_month, payload.imeis.as_slice()) else {
+        return Ok(json!(ErrorOutput { error: "aggregate_as_of needs exactly one imei and input_ride_month".to_string() }));
+    };
+    let as_of = match payload.as_of.as_deref().map(DateTime::parse_from_rfc3339) {
+        Some(Ok(as_of)) => as_of.with_timezone(&Utc).to_rfc3339_opts(SecondsFormat::Secs, true),
+        _ => return Ok(json!(ErrorOutput { error: "aggregate_as_of needs an RFC 3339 as_of".to_string() })),
+    };
+
+    // Revisions sort by `<revised_at>#<run_id>`; everything written within the `as_of` second sorts after it.
+    let (total_distance, basis) = match history::around(client, imei, Granularity::Monthly, ride_month, &as_of).await? {
+        (Some(earlier), _) => (
+            Some(earlier.new_value),
+            AsOfBasis::Revision { revised_at: earlier.revised_at, run_id: earlier.run_id, reason: earlier.reason },
+        ),
+        (None, Some(later)) => (Some(later.previous_value), AsOfBasis::BeforeRevision { revised_at: later.revised_at }),
+        (None, None) => match aggregates::get_row(client, imei, ride_month).await? {
+            Some((distance, stored_as_of)) => (Some(distance), AsOfBasis::Unrevised { stored_as_of }),
+            None => (None, AsOfBasis::Missing),
+        },
+    };
+
+    let mut output = json!(AsOfOutput { imei: imei.to_string(), ride_month: ride_month.clone(), as_of, total_distance, basis });
+    payload.number_format.unwrap_or_default().apply(&mut output);
+    Ok(output)
+}
@@ -0,0 +1,76 @@
+use aws_sdk_dynamodb::types::AttributeValue;
+use aws_sdk_dynamodb::Client;
+use anyhow::Result;
+use serde_json::Value;
+
+use crate::cost::RunCost;
+use crate::metrics;
+use crate::startup::RuntimeInfo;
+
+pub const TABLE_NAME: &str = "aggregation_runs";
+
+#[derive(Debug, Clone)]
+pub struct RunRecord<'a> {
+    pub run_id: &'a str,
+    pub started_at: &'a str,
+    pub request: &'a Value,
+    /// `config::echo()` at the time of the run.
+    pub config: &'a Value,
+    pub rows: usize,
+    pub cost: RunCost,
+    pub runtime: &'a RuntimeInfo,
+}
+
+/// Runs are keyed by `run_id` with `started_at` as the sort key, since SQS and array batches
+/// share one run_id across several requests.
+pub async fn record(client: &Client, run: &RunRecord<'_>) -> Result<()> {
+    client.put_item()
+        .table_name(TABLE_NAME)
+        .item("run_id", AttributeValue::S(run.run_id.to_string()))
+        .item("started_at", AttributeValue::S(run.started_at.to_string()))
+        .item("request", AttributeValue::S(run.request.to_string()))
+        .item("config", AttributeValue::S(run.config.to_string()))
+        .item("rows", AttributeValue::N(run.rows.to_string()))
+        .item("read_units", AttributeValue::N(run.cost.read_units.to_string()))
+        .item("write_units", AttributeValue::N(run.cost.write_units.to_string()))
+        .item("gb_seconds", AttributeValue::N(run.cost.gb_seconds.to_string()))
+        .item("estimated_usd", AttributeValue::N(run.cost.estimated_usd.to_string()))
+        .item("runtime", AttributeValue::S(serde_json::to_string(run.runtime)?))
+        .send()
+        .await
+        .inspect_err(|_| metrics::dynamodb_error("put_item"))?;
+    Ok(())
+}
+
+/// A request as it was recorded, for replaying.
+#[derive(Debug, Clone)]
+pub struct RecordedRequest {
+    pub started_at: String,
+    pub request: Value,
+    /// Missing on runs recorded before configs were stored.
+    pub config: Option<Value>,
+}
+
+/// Every request recorded under `run_id`, in the order they started.
+pub async fn load(client: &Client, run_id: &str) -> Result<Vec<RecordedRequest>> {
+    let items = client.query()
+        .table_name(TABLE_NAME)
+        .key_condition_expression("run_id = :run_id")
+        .expression_attribute_values(":run_id", AttributeValue::S(run_id.to_string()))
+        .consistent_read(true)
+        .into_paginator()
+        .items()
+        .send()
+        .collect::<Result<Vec<_>, _>>()
+        .await
+        .inspect_err(|_| metrics::dynamodb_error("query"))?;
+
+    items.iter().map(|item| {
+        let text = |name: &str| item.get(name).and_then(|v| v.as_s().ok()).map(String::as_str);
+        Ok(RecordedRequest {
+            started_at: text("started_at").unwrap_or_default().to_string(),
+            request: serde_json::from_str(text("request").unwrap_or("null"))?,
+            config: text("config").map(serde_json::from_str).transpose()?,
+        })
+    }).collect()
+}
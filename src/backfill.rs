@@ -0,0 +1,151 @@
+//! Self-chunking backfills for IMEI lists too long for one invocation. A request with `backfill: true`
+//! aggregates the first `BACKFILL_CHUNK_SIZE` IMEIs (default 500), then queues the rest on
+//! `BACKFILL_QUEUE_URL` as a continuation of the same request, which the function consumes like any
+//! SQS message; chunks run one after another until the list is done. Progress is kept in the
+//! `backfill_jobs` table, keyed by the first invocation's run_id.
+//!
+//! Continuations carry the remaining IMEIs in `imeis_compressed` and the period the first chunk
+//! resolved, so a job crossing a month boundary keeps aggregating the same period. Cohorts are not
+//! reported. A redelivered chunk is aggregated again but neither counted nor continued twice.
+
+use anyhow::{anyhow, Result};
+use aws_sdk_dynamodb::types::AttributeValue;
+use base64::Engine;
+use chrono::{SecondsFormat, Utc};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::io::Write;
+use tracing::{info, warn};
+use utoipa::ToSchema;
+
+use crate::{config, metrics, retries, CustomEvent};
+
+pub const TABLE_NAME: &str = "backfill_jobs";
+
+/// Set on continuations: the job and which chunk of it this invocation runs.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema, ToSchema)]
+pub struct BackfillJob {
+    pub job_id: String,
+    pub chunk: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, JsonSchema, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum BackfillStatus {
+    /// A continuation is queued for the remaining IMEIs.
+    Running,
+    Complete,
+    /// This chunk was already recorded by an earlier delivery, which queued the continuation.
+    AlreadyRecorded,
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema, ToSchema)]
+pub struct BackfillProgress {
+    pub job_id: String,
+    pub chunk: usize,
+    pub imeis_remaining: usize,
+    pub status: BackfillStatus,
+}
+
+/// IMEIs per invocation, from `BACKFILL_CHUNK_SIZE` (default 500).
+pub fn chunk_size() -> usize {
+    config::get().backfill_chunk_size
+}
+
+/// Creates the job's progress row; its first chunk is chunk 0.
+pub async fn start(shared_config: &aws_config::SdkConfig, imeis: usize, run_id: &str) -> Result<BackfillJob> {
+    if config::get().backfill_queue_url.is_none() {
+        return Err(anyhow!("backfill needs BACKFILL_QUEUE_URL"));
+    }
+    retries::dynamodb(shared_config).put_item()
+        .table_name(TABLE_NAME)
+        .item("job_id", AttributeValue::S(run_id.to_string()))
+        .item("total_imeis", AttributeValue::N(imeis.to_string()))
+        .item("chunk_size", AttributeValue::N(chunk_size().to_string()))
+        .item("processed_imeis", AttributeValue::N("0".to_string()))
+        .item("device_errors", AttributeValue::N("0".to_string()))
+        .item("last_chunk", AttributeValue::N("-1".to_string()))
+        .item("status", AttributeValue::S("running".to_string()))
+        .item("created_at", AttributeValue::S(Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true)))
+        .condition_expression("attribute_not_exists(job_id)")
+        .send()
+        .await
+        .inspect_err(|_| metrics::dynamodb_error("put_item"))?;
+    info!("Backfill job {} started for {} imeis", run_id, imeis);
+    Ok(BackfillJob { job_id: run_id.to_string(), chunk: 0 })
+}
+
+/// Records a finished chunk and queues the continuation for `remaining`, unless an earlier delivery
+/// of the same chunk already did.
+pub async fn advance(
+    shared_config: &aws_config::SdkConfig,
+    request: &Value,
+    payload: &CustomEvent,
+    job: &BackfillJob,
+    processed: usize,
+    device_errors: usize,
+    remaining: &[String],
+) -> Result<BackfillProgress> {
+    let (status, status_name) = match remaining.is_empty() {
+        true => (BackfillStatus::Complete, "complete"),
+        false => (BackfillStatus::Running, "running"),
+    };
+    let recorded = retries::dynamodb(shared_config).update_item()
+        .table_name(TABLE_NAME)
+        .key("job_id", AttributeValue::S(job.job_id.clone()))
+        .update_expression("SET last_chunk = :chunk, #status = :status, updated_at = :at ADD processed_imeis :processed, device_errors :errors")
+        .condition_expression("last_chunk < :chunk")
+        .expression_attribute_names("#status", "status")
+        .expression_attribute_values(":chunk", AttributeValue::N(job.chunk.to_string()))
+        .expression_attribute_values(":status", AttributeValue::S(status_name.to_string()))
+        .expression_attribute_values(":at", AttributeValue::S(Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true)))
+        .expression_attribute_values(":processed", AttributeValue::N(processed.to_string()))
+        .expression_attribute_values(":errors", AttributeValue::N(device_errors.to_string()))
+        .send()
+        .await;
+    let progress = |status| BackfillProgress { job_id: job.job_id.clone(), chunk: job.chunk, imeis_remaining: remaining.len(), status };
+    match recorded {
+        Ok(_) => {}
+        Err(err) if err.as_service_error().is_some_and(|e| e.is_conditional_check_failed_exception()) => {
+            warn!("Backfill job {} chunk {} was already recorded", job.job_id, job.chunk);
+            return Ok(progress(BackfillStatus::AlreadyRecorded));
+        }
+        Err(err) => {
+            metrics::dynamodb_error("update_item");
+            return Err(err.into());
+        }
+    }
+    if remaining.is_empty() {
+        info!("Backfill job {} complete after {} chunks", job.job_id, job.chunk + 1);
+        return Ok(progress(status));
+    }
+
+    let queue_url = config::get().backfill_queue_url.clone().ok_or_else(|| anyhow!("backfill needs BACKFILL_QUEUE_URL"))?;
+    let next = BackfillJob { job_id: job.job_id.clone(), chunk: job.chunk + 1 };
+    let body = continuation(request, payload, &next, remaining)?;
+    aws_sdk_sqs::Client::new(shared_config).send_message().queue_url(queue_url).message_body(body.to_string()).send().await?;
+    info!("Backfill job {} queued chunk {} with {} imeis left", job.job_id, next.chunk, remaining.len());
+    Ok(progress(status))
+}
+
+/// The original request for the remaining IMEIs and the period this job resolved to.
+fn continuation(request: &Value, payload: &CustomEvent, next: &BackfillJob, remaining: &[String]) -> Result<Value> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(remaining.join(",").as_bytes())?;
+    let compressed = base64::engine::general_purpose::STANDARD.encode(encoder.finish()?);
+
+    let mut request = request.clone();
+    let fields = request.as_object_mut().ok_or_else(|| anyhow!("backfill request is not an object"))?;
+    for field in ["backfill", "fan_out", "cohorts", "cohort_tags", "imeis", "imeis_s3_uri", "input_manifest_s3_uri"] {
+        fields.remove(field);
+    }
+    fields.insert("imeis_compressed".to_string(), json!(compressed));
+    fields.insert("backfill_job".to_string(), json!(next));
+    fields.insert("input_ride_month".to_string(), json!(payload.input_ride_month));
+    fields.insert("start_date".to_string(), json!(payload.start_date));
+    fields.insert("end_date".to_string(), json!(payload.end_date));
+    Ok(request)
+}
@@ -0,0 +1,296 @@
+//! `BatchGetItem` / `BatchWriteItem` in request-sized chunks, retrying the keys and items DynamoDB
+//! leaves unprocessed with the same attempts and backoff as the client's own retries. Conditional
+//! puts go in `TransactWriteItems` chunks instead, as a batch write cannot carry a condition. Writes
+//! are paced by the table's capacity mode; see [`capacity`].
+
+use anyhow::{bail, Result};
+use aws_sdk_dynamodb::operation::transact_write_items::TransactWriteItemsError;
+use aws_sdk_dynamodb::types::{DeleteRequest, KeysAndAttributes, Put, PutRequest, ReturnConsumedCapacity, TransactWriteItem, WriteRequest};
+use aws_sdk_dynamodb::Client;
+use futures::stream::{self, StreamExt, TryStreamExt};
+use std::time::{Duration, Instant};
+
+use crate::capacity::{self, CapacityMode};
+use crate::cost::CapacityMeter;
+use crate::{metrics, retries};
+use crate::pagination::Item;
+
+const GET_CHUNK: usize = 100;
+const WRITE_CHUNK: usize = 25;
+const TRANSACT_CHUNK: usize = 100;
+
+/// How one put of a [`transact_put`] ended.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TransactOutcome {
+    Written,
+    /// Its condition failed; the stored item, if the put asked for it back.
+    ConditionFailed(Option<Item>),
+    /// Its chunk was still cancelled, by conflicts or throttling, once the retries ran out.
+    Unprocessed,
+}
+/// The base delay doubling per attempt, with up to as much again of jitter.
+async fn backoff(attempt: u32) {
+    let millis = (retries::base_delay().as_millis() as u64) << attempt.min(16);
+    tokio::time::sleep(Duration::from_millis(millis + fastrand::u64(0..=millis))).await;
+}
+
+/// Strongly consistent reads of the items with these keys; missing items are simply absent.
+pub async fn get(client: &Client, table: &str, keys: Vec<Item>, meter: &CapacityMeter) -> Result<Vec<Item>> {
+    let mut items = Vec::with_capacity(keys.len());
+    for chunk in keys.chunks(GET_CHUNK) {
+        let mut pending = chunk.to_vec();
+        for attempt in 0.. {
+            if pending.is_empty() {
+                break;
+            }
+            if attempt == retries::max_attempts() {
+                bail!("{} keys of {} still unprocessed after {} batch reads", pending.len(), table, attempt);
+            }
+            if attempt > 0 {
+                backoff(attempt).await;
+            }
+            let request = KeysAndAttributes::builder().set_keys(Some(pending)).consistent_read(true).build()?;
+            let resp = client.batch_get_item()
+                .request_items(table, request)
+                .return_consumed_capacity(ReturnConsumedCapacity::Total)
+                .send()
+                .await
+                .inspect_err(|_| metrics::dynamodb_error("batch_get_item"))?;
+            for consumed in resp.consumed_capacity() {
+                meter.read(Some(consumed));
+            }
+            items.extend(resp.responses.and_then(|mut responses| responses.remove(table)).unwrap_or_default());
+            pending = resp.unprocessed_keys
+                .and_then(|mut unprocessed| unprocessed.remove(table))
+                .map(|keys| keys.keys)
+                .unwrap_or_default();
+        }
+    }
+    Ok(items)
+}
+
+/// Puts the items, returning those still unprocessed once the retries ran out.
+pub async fn put(client: &Client, table: &str, items: Vec<Item>, meter: &CapacityMeter) -> Result<Vec<Item>> {
+    let requests = items.into_iter()
+        .map(|item| Ok(WriteRequest::builder().put_request(PutRequest::builder().set_item(Some(item)).build()?).build()))
+        .collect::<Result<Vec<_>>>()?;
+    let unprocessed = write(client, table, requests, meter).await?;
+    Ok(unprocessed.into_iter().filter_map(|request| request.put_request.map(|put| put.item)).collect())
+}
+
+/// Deletes the items with these keys, returning the keys still unprocessed once the retries ran out.
+pub async fn delete(client: &Client, table: &str, keys: Vec<Item>, meter: &CapacityMeter) -> Result<Vec<Item>> {
+    let requests = keys.into_iter()
+        .map(|key| Ok(WriteRequest::builder().delete_request(DeleteRequest::builder().set_key(Some(key)).build()?).build()))
+        .collect::<Result<Vec<_>>>()?;
+    let unprocessed = write(client, table, requests, meter).await?;
+    Ok(unprocessed.into_iter().filter_map(|request| request.delete_request.map(|delete| delete.key)).collect())
+}
+
+async fn write(client: &Client, table: &str, requests: Vec<WriteRequest>, meter: &CapacityMeter) -> Result<Vec<WriteRequest>> {
+    let chunks = requests.chunks(WRITE_CHUNK).map(<[WriteRequest]>::to_vec);
+    let mode = capacity::mode(client, table).await;
+    if mode == CapacityMode::OnDemand {
+        let unprocessed: Vec<Vec<WriteRequest>> = stream::iter(chunks)
+            .map(|chunk| async move { Ok::<_, anyhow::Error>(write_chunk(client, table, chunk, meter).await?.0) })
+            .buffer_unordered(capacity::ON_DEMAND_CHUNKS)
+            .try_collect()
+            .await?;
+        return Ok(unprocessed.into_iter().flatten().collect());
+    }
+
+    let mut unprocessed = Vec::new();
+    for chunk in chunks {
+        let started = Instant::now();
+        let (pending, units) = write_chunk(client, table, chunk, meter).await?;
+        unprocessed.extend(pending);
+        tokio::time::sleep(mode.pause(units, started.elapsed())).await;
+    }
+    Ok(unprocessed)
+}
+
+/// Conditional puts, with outcomes in the puts' order. A transaction is cancelled as a whole when one
+/// put's condition fails, so that put is dropped from its chunk and the rest are sent again.
+pub async fn transact_put(client: &Client, table: &str, puts: Vec<Put>, meter: &CapacityMeter) -> Result<Vec<TransactOutcome>> {
+    let chunks = puts.chunks(TRANSACT_CHUNK).map(<[Put]>::to_vec);
+    let mode = capacity::mode(client, table).await;
+    if mode == CapacityMode::OnDemand {
+        let outcomes: Vec<Vec<TransactOutcome>> = stream::iter(chunks)
+            .map(|chunk| async move { Ok::<_, anyhow::Error>(transact_chunk(client, chunk, meter).await?.0) })
+            .buffered(capacity::ON_DEMAND_CHUNKS)
+            .try_collect()
+            .await?;
+        return Ok(outcomes.into_iter().flatten().collect());
+    }
+
+    let mut outcomes = Vec::with_capacity(puts.len());
+    for chunk in chunks {
+        let started = Instant::now();
+        let (chunk_outcomes, units) = transact_chunk(client, chunk, meter).await?;
+        outcomes.extend(chunk_outcomes);
+        tokio::time::sleep(mode.pause(units, started.elapsed())).await;
+    }
+    Ok(outcomes)
+}
+
+/// Writes one transaction chunk, returning each put's outcome and the capacity units consumed.
+async fn transact_chunk(client: &Client, puts: Vec<Put>, meter: &CapacityMeter) -> Result<(Vec<TransactOutcome>, f64)> {
+    let mut outcomes = vec![TransactOutcome::Unprocessed; puts.len()];
+    let mut pending: Vec<usize> = (0..puts.len()).collect();
+    let mut units = 0.0;
+    let mut contended = false;
+    for attempt in 0..retries::max_attempts() {
+        if pending.is_empty() {
+            break;
+        }
+        if contended {
+            backoff(attempt).await;
+        }
+        let items = pending.iter().map(|&i| TransactWriteItem::builder().put(puts[i].clone()).build()).collect();
+        let result = client.transact_write_items()
+            .set_transact_items(Some(items))
+            .return_consumed_capacity(ReturnConsumedCapacity::Total)
+            .send()
+            .await;
+        let cancelled = match result {
+            Ok(resp) => {
+                for consumed in resp.consumed_capacity() {
+                    units += consumed.capacity_units().unwrap_or_default();
+                    meter.write(Some(consumed));
+                }
+                for i in pending.drain(..) {
+                    outcomes[i] = TransactOutcome::Written;
+                }
+                break;
+            }
+            Err(err) => match err.into_service_error() {
+                TransactWriteItemsError::TransactionCanceledException(cancelled) => cancelled,
+                err => {
+                    metrics::dynamodb_error("transact_write_items");
+                    return Err(err.into());
+                }
+            },
+        };
+        // One reason per put, in order; "None" for the puts that were not at fault.
+        let reasons = cancelled.cancellation_reasons();
+        let failed: Vec<bool> = pending.iter().enumerate()
+            .map(|(n, _)| reasons.get(n).and_then(|reason| reason.code()) == Some("ConditionalCheckFailed"))
+            .collect();
+        contended = !failed.contains(&true);
+        let mut retried = Vec::with_capacity(pending.len());
+        for (n, i) in pending.into_iter().enumerate() {
+            match failed[n] {
+                true => outcomes[i] = TransactOutcome::ConditionFailed(reasons[n].item().cloned()),
+                false => retried.push(i),
+            }
+        }
+        pending = retried;
+    }
+    Ok((outcomes, units))
+}
+
+/// Writes one chunk, returning the requests still unprocessed and the capacity units consumed.
+async fn write_chunk(client: &Client, table: &str, mut pending: Vec<WriteRequest>, meter: &CapacityMeter) -> Result<(Vec<WriteRequest>, f64)> {
+    let mut units = 0.0;
+    for attempt in 0..retries::max_attempts() {
+        if attempt > 0 {
+            backoff(attempt).await;
+        }
+        let resp = client.batch_write_item()
+            .request_items(table, pending)
+            .return_consumed_capacity(ReturnConsumedCapacity::Total)
+            .send()
+            .await
+            .inspect_err(|_| metrics::dynamodb_error("batch_write_item"))?;
+        for consumed in resp.consumed_capacity() {
+            units += consumed.capacity_units().unwrap_or_default();
+            meter.write(Some(consumed));
+        }
+        pending = resp.unprocessed_items.and_then(|mut unprocessed| unprocessed.remove(table)).unwrap_or_default();
+        if pending.is_empty() {
+            break;
+        }
+    }
+    Ok((pending, units))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aws_sdk_dynamodb::types::{AttributeValue, ProvisionedThroughputDescription, TableDescription};
+    use serde_json::{json, Value};
+    use std::collections::VecDeque;
+    use std::sync::Mutex;
+
+    use crate::testing::{self, Fake};
+
+    /// Answers the requests with `responses` in turn, then with `{}`.
+    fn client(responses: Vec<testing::Response>) -> (Client, Fake) {
+        let responses = Mutex::new(VecDeque::from(responses));
+        let fake = Fake::new(move |_| responses.lock().unwrap().pop_front().unwrap_or_else(|| testing::ok(json!({}))));
+        // Provisioned, so chunks are written in order.
+        let table = TableDescription::builder()
+            .provisioned_throughput(ProvisionedThroughputDescription::builder().write_capacity_units(1000).build())
+            .build();
+        capacity::record("batched", Some(&table));
+        (fake.dynamodb(), fake)
+    }
+
+    /// How many writes each request carried.
+    fn sizes(fake: &Fake) -> Vec<usize> {
+        fake.requests().iter()
+            .map(|request| {
+                let body = request.json();
+                body.get("TransactItems").unwrap_or(&body["RequestItems"]["batched"]).as_array().unwrap().len()
+            })
+            .collect()
+    }
+
+    fn items(count: usize) -> Vec<Item> {
+        (0..count).map(|n| Item::from([("imei".to_string(), AttributeValue::S(n.to_string()))])).collect()
+    }
+
+    fn unprocessed(items: &[Item]) -> Value {
+        let requests: Vec<Value> = items.iter()
+            .map(|item| json!({"PutRequest": {"Item": {"imei": {"S": item["imei"].as_s().unwrap()}}}}))
+            .collect();
+        json!({"UnprocessedItems": {"batched": requests}})
+    }
+
+    #[tokio::test]
+    async fn retries_the_items_a_chunk_left_unprocessed() {
+        let items = items(30);
+        let (client, replay) = client(vec![testing::ok(unprocessed(&items[23..25]))]);
+        let left = put(&client, "batched", items, &CapacityMeter::default()).await.unwrap();
+        assert!(left.is_empty());
+        assert_eq!(sizes(&replay), vec![WRITE_CHUNK, 2, 5]);
+    }
+
+    #[tokio::test]
+    async fn returns_what_is_still_unprocessed_after_the_last_attempt() {
+        let items = items(3);
+        let stuck = testing::ok(unprocessed(&items[2..]));
+        let (client, replay) = client(vec![stuck; retries::max_attempts() as usize]);
+        let left = put(&client, "batched", items, &CapacityMeter::default()).await.unwrap();
+        assert_eq!(left.len(), 1);
+        assert_eq!(left[0]["imei"].as_s().unwrap(), "2");
+        assert_eq!(replay.requests().len(), retries::max_attempts() as usize);
+    }
+
+    #[tokio::test]
+    async fn a_failed_condition_drops_only_its_put_from_the_transaction() {
+        let puts: Vec<Put> = items(3).into_iter().map(|item| Put::builder().table_name("batched").set_item(Some(item)).build().unwrap()).collect();
+        let cancelled = testing::error("TransactionCanceledException", json!({
+            "CancellationReasons": [
+                {"Code": "None"},
+                {"Code": "ConditionalCheckFailed", "Item": {"imei": {"S": "1"}, "finalized": {"BOOL": true}}},
+                {"Code": "None"},
+            ],
+        }));
+        let (client, replay) = client(vec![cancelled]);
+        let outcomes = transact_put(&client, "batched", puts, &CapacityMeter::default()).await.unwrap();
+        let stored = Item::from([("imei".to_string(), AttributeValue::S("1".to_string())), ("finalized".to_string(), AttributeValue::Bool(true))]);
+        assert_eq!(outcomes, [TransactOutcome::Written, TransactOutcome::ConditionFailed(Some(stored)), TransactOutcome::Written]);
+        assert_eq!(sizes(&replay), vec![3, 2]);
+    }
+}
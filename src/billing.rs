@@ -0,0 +1,142 @@
+use aws_sdk_dynamodb::types::AttributeValue;
+use aws_sdk_dynamodb::Client;
+use anyhow::{anyhow, Result};
+use rust_decimal::Decimal;
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::str::FromStr;
+use utoipa::ToSchema;
+
+use crate::{metrics, CustomOutput};
+
+pub const TABLE_NAME: &str = "ride_data_billing";
+pub const RATE_CARDS_TABLE: &str = "ride_data_rate_cards";
+
+/// Distance billed for one (imei, month), if billing has a record for it.
+pub async fn billed_distance(client: &Client, imei: &str, ride_month: &str) -> Result<Option<f64>> {
+    let resp = client.get_item()
+        .table_name(TABLE_NAME)
+        .key("imei", AttributeValue::S(imei.to_string()))
+        .key("date", AttributeValue::S(ride_month.to_string()))
+        .send()
+        .await
+        .inspect_err(|_| metrics::dynamodb_error("get_item"))?;
+
+    Ok(resp.item()
+        .and_then(|item| item.get("billed_distance"))
+        .and_then(|v| v.as_n().ok())
+        .and_then(|n| n.parse().ok()))
+}
+
+/// Tier of a rate card: `rate_per_km` applies to the distance up to `up_to_km`
+/// (unbounded for the last tier).
+#[derive(Debug, Clone)]
+pub struct RateTier {
+    pub up_to_km: Option<Decimal>,
+    pub rate_per_km: Decimal,
+}
+
+/// A tenant's pricing, stored in `ride_data_rate_cards` keyed by `tenant_id` with `currency`,
+/// `minimum_charge` and a `tiers` list of `{up_to_km, rate_per_km}` maps in ascending order.
+#[derive(Debug, Clone)]
+pub struct RateCard {
+    pub tenant_id: String,
+    pub currency: String,
+    pub minimum_charge: Decimal,
+    pub tiers: Vec<RateTier>,
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema, ToSchema)]
+pub struct TierCharge {
+    from_km: Decimal,
+    to_km: Option<Decimal>,
+    km: Decimal,
+    rate_per_km: Decimal,
+    amount: Decimal,
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema, ToSchema)]
+pub struct ChargeOutput {
+    imei: String,
+    ride_month: String,
+    tenant_id: String,
+    currency: String,
+    tiers: Vec<TierCharge>,
+    subtotal: Decimal,
+    minimum_applied: bool,
+    total: Decimal,
+}
+
+pub async fn load_rate_card(client: &Client, tenant_id: &str) -> Result<RateCard> {
+    let resp = client.get_item()
+        .table_name(RATE_CARDS_TABLE)
+        .key("tenant_id", AttributeValue::S(tenant_id.to_string()))
+        .send()
+        .await
+        .inspect_err(|_| metrics::dynamodb_error("get_item"))?;
+    let item = resp.item().ok_or_else(|| anyhow!("no rate card for tenant {}", tenant_id))?;
+
+    let tiers = item.get("tiers").and_then(|v| v.as_l().ok())
+        .ok_or_else(|| anyhow!("rate card for tenant {} has no tiers", tenant_id))?
+        .iter()
+        .map(|tier| {
+            let tier = tier.as_m().map_err(|_| anyhow!("rate card tier is not a map"))?;
+            Ok(RateTier {
+                up_to_km: number(tier, "up_to_km")?,
+                rate_per_km: number(tier, "rate_per_km")?.ok_or_else(|| anyhow!("rate card tier has no rate_per_km"))?,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(RateCard {
+        tenant_id: tenant_id.to_string(),
+        currency: item.get("currency").and_then(|v| v.as_s().ok()).cloned().unwrap_or_else(|| "INR".to_string()),
+        minimum_charge: number(item, "minimum_charge")?.unwrap_or_default(),
+        tiers,
+    })
+}
+
+fn number(item: &HashMap<String, AttributeValue>, name: &str) -> Result<Option<Decimal>> {
+    item.get(name)
+        .map(|v| {
+            let n = v.as_n().map_err(|_| anyhow!("{} is not a number", name))?;
+            Decimal::from_str(n).map_err(|err| anyhow!("{} is not a decimal: {}", name, err))
+        })
+        .transpose()
+}
+
+/// Prices one device-month against the tenant's tiers, rounding each tier to 2 decimal places.
+pub fn charge(card: &RateCard, row: &CustomOutput) -> ChargeOutput {
+    let distance = Decimal::from_f64_retain(row.total_distance).unwrap_or_default();
+    let mut tiers = Vec::new();
+    let mut from_km = Decimal::ZERO;
+    for tier in &card.tiers {
+        if distance <= from_km {
+            break;
+        }
+        let to_km = tier.up_to_km.map(|up_to| up_to.min(distance)).unwrap_or(distance);
+        let km = to_km - from_km;
+        tiers.push(TierCharge {
+            from_km,
+            to_km: tier.up_to_km,
+            km: km.round_dp(3),
+            rate_per_km: tier.rate_per_km,
+            amount: (km * tier.rate_per_km).round_dp(2),
+        });
+        from_km = to_km;
+    }
+
+    let subtotal: Decimal = tiers.iter().map(|tier| tier.amount).sum();
+    let minimum_applied = subtotal < card.minimum_charge;
+    ChargeOutput {
+        imei: row.imei.clone(),
+        ride_month: row.ride_month.clone(),
+        tenant_id: card.tenant_id.clone(),
+        currency: card.currency.clone(),
+        tiers,
+        subtotal,
+        minimum_applied,
+        total: if minimum_applied { card.minimum_charge } else { subtotal },
+    }
+}
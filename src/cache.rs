@@ -0,0 +1,183 @@
+//! With `AGGREGATE_CACHE=true`, a device's totals for a month are cached in `aggregate_cache`, keyed
+//! by IMEI, month and a hash of everything else that shapes them (the query's filters and metrics,
+//! the ride window, anomaly rules, unit mappings, decimal separator and the crate version). The stream handler stamps
+//! each device-month with when its rides last changed; a cached entry is reused, without reading
+//! any ride, only while that stamp is the one it was computed at. Device-months the stream has
+//! never stamped are always recomputed, as are queries with a limit, signature checks, a date
+//! range or another granularity, and months with rides beyond the clock-skew tolerance.
+
+use aws_sdk_dynamodb::types::AttributeValue;
+use aws_sdk_dynamodb::Client;
+use anyhow::{anyhow, Result};
+use chrono::Utc;
+use lambda_runtime::Error;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+use tracing::{debug, warn};
+
+use crate::cost::CapacityMeter;
+use crate::{anomalies, config, metrics, monthly_stats, normalize, ride, stats, time, warnings, ImeiStats, StatsQuery};
+
+pub const TABLE_NAME: &str = "aggregate_cache";
+
+const TTL_SECS: i64 = 35 * 86_400;
+
+pub fn enabled() -> bool {
+    config::get().aggregate_cache
+}
+
+#[derive(Serialize, Deserialize)]
+struct CachedStats {
+    months: HashMap<String, stats::MonthStats>,
+    warnings: Vec<warnings::Warning>,
+    normalized: BTreeMap<String, u64>,
+    skipped: ride::SkippedItems,
+    anomalies: Vec<anomalies::Anomaly>,
+}
+
+/// The IMEI's stats for `query`, from the cache when nothing changed since they were computed.
+/// Entries are only written when `store` is set; cache errors fall back to reading the rides.
+pub async fn imei_stats(client: &Client, imei: &str, query: &StatsQuery<'_>, meter: &CapacityMeter, store: bool) -> Result<ImeiStats, Error> {
+    let (Some(ride_month), Some(key)) = (query.input_ride_month.filter(|_| enabled()), key(imei, query)) else {
+        return monthly_stats(client, imei, query, meter).await;
+    };
+    let changed_at = match changed_at(client, imei, ride_month).await {
+        Ok(changed_at) => changed_at,
+        Err(err) => {
+            warn!("Error reading the change stamp of imei {} month {}: {:?}", imei, ride_month, err);
+            return monthly_stats(client, imei, query, meter).await;
+        }
+    };
+    if let Some(changed_at) = changed_at {
+        match get(client, &key, changed_at).await {
+            Ok(Some(cached)) => {
+                debug!(imei, ride_month, "Reusing cached stats");
+                return Ok(ImeiStats {
+                    months: cached.months,
+                    items_read: 0,
+                    query_latency: Duration::ZERO,
+                    failure: None,
+                    warnings: cached.warnings,
+                    normalized: cached.normalized,
+                    skipped: cached.skipped,
+                    anomalies: cached.anomalies,
+                    cached: true,
+                });
+            }
+            Ok(None) => {}
+            Err(err) => warn!("Error reading cached stats for imei {} month {}: {:?}", imei, ride_month, err),
+        }
+    }
+
+    let imei_stats = monthly_stats(client, imei, query, meter).await?;
+    let future = imei_stats.warnings.iter().any(|warning| matches!(warning, warnings::Warning::ClockSkew { .. }));
+    if let Some(changed_at) = changed_at.filter(|_| store && imei_stats.failure.is_none() && !future) {
+        let cached = CachedStats {
+            months: imei_stats.months.clone(),
+            warnings: imei_stats.warnings.clone(),
+            normalized: imei_stats.normalized.clone(),
+            skipped: imei_stats.skipped.clone(),
+            anomalies: imei_stats.anomalies.clone(),
+        };
+        if let Err(err) = put(client, &key, changed_at, &cached).await {
+            warn!("Error caching stats for imei {} month {}: {:?}", imei, ride_month, err);
+        }
+    }
+    Ok(imei_stats)
+}
+
+/// `<imei>#<month>#<hash>`, or None for queries that are not cached.
+fn key(imei: &str, query: &StatsQuery<'_>) -> Option<String> {
+    let StatsQuery { input_ride_month, date_range, granularity, breakdowns, max_rides, now: _, signatures, fraud_checks, legacy_compat, ride_types, type_filter, metrics } = *query;
+    let ride_month = input_ride_month?;
+    if date_range.is_some() || granularity != time::Granularity::Monthly || max_rides.is_some() || signatures.is_some() {
+        return None;
+    }
+    let mut hash = Sha256::new();
+    for part in [
+        env!("CARGO_PKG_VERSION").to_string(),
+        format!("{:?}", breakdowns),
+        fraud_checks.to_string(),
+        format!("{:?}", legacy_compat),
+        format!("{:?}", ride_types.counted()),
+        format!("{:?}", type_filter),
+        format!("{:?}", metrics),
+        ride::window().label(),
+        format!("{:?}", anomalies::Rules::from_env()),
+        format!("{:?}", config::get().firmware_units),
+        format!("{:?}", normalize::DecimalSeparator::from_env()),
+    ] {
+        hash.update(part);
+        hash.update(b"|");
+    }
+    Some(format!("{}#{}#{}", imei, ride_month, &hex::encode(hash.finalize())[..16]))
+}
+
+fn stamp_key(imei: &str, ride_month: &str) -> String {
+    format!("CHANGED#{}#{}", imei, ride_month)
+}
+
+/// When the device-month's rides last changed (epoch milliseconds), as stamped by [`record_change`].
+async fn changed_at(client: &Client, imei: &str, ride_month: &str) -> Result<Option<i64>> {
+    let resp = client.get_item()
+        .table_name(TABLE_NAME)
+        .key("cache_key", AttributeValue::S(stamp_key(imei, ride_month)))
+        .consistent_read(true)
+        .send()
+        .await
+        .inspect_err(|_| metrics::dynamodb_error("get_item"))?;
+    Ok(resp.item().and_then(|item| item.get("changed_at")?.as_n().ok()?.parse().ok()))
+}
+
+async fn get(client: &Client, key: &str, changed_at: i64) -> Result<Option<CachedStats>> {
+    let resp = client.get_item()
+        .table_name(TABLE_NAME)
+        .key("cache_key", AttributeValue::S(key.to_string()))
+        .send()
+        .await
+        .inspect_err(|_| metrics::dynamodb_error("get_item"))?;
+    let Some(item) = resp.item() else {
+        return Ok(None);
+    };
+    let computed_at: Option<i64> = item.get("changed_at").and_then(|v| v.as_n().ok()).and_then(|n| n.parse().ok());
+    if computed_at != Some(changed_at) {
+        return Ok(None);
+    }
+    let stats = item.get("stats").and_then(|v| v.as_s().ok()).ok_or_else(|| anyhow!("cache entry {} has no stats", key))?;
+    Ok(Some(serde_json::from_str(stats)?))
+}
+
+async fn put(client: &Client, key: &str, changed_at: i64, cached: &CachedStats) -> Result<()> {
+    client.put_item()
+        .table_name(TABLE_NAME)
+        .item("cache_key", AttributeValue::S(key.to_string()))
+        .item("changed_at", AttributeValue::N(changed_at.to_string()))
+        .item("stats", AttributeValue::S(serde_json::to_string(cached)?))
+        .item("expires_at", AttributeValue::N((Utc::now().timestamp() + TTL_SECS).to_string()))
+        .send()
+        .await
+        .inspect_err(|_| metrics::dynamodb_error("put_item"))?;
+    Ok(())
+}
+
+/// Stamps the device-month as changed at `at` (epoch milliseconds), unless it already has a later stamp.
+pub async fn record_change(client: &Client, imei: &str, ride_month: &str, at: i64) -> Result<()> {
+    let result = client.update_item()
+        .table_name(TABLE_NAME)
+        .key("cache_key", AttributeValue::S(stamp_key(imei, ride_month)))
+        .update_expression("SET changed_at = :at")
+        .condition_expression("attribute_not_exists(changed_at) OR changed_at < :at")
+        .expression_attribute_values(":at", AttributeValue::N(at.to_string()))
+        .send()
+        .await;
+    match result {
+        Ok(_) => Ok(()),
+        Err(err) if err.as_service_error().is_some_and(|e| e.is_conditional_check_failed_exception()) => Ok(()),
+        Err(err) => {
+            metrics::dynamodb_error("update_item");
+            Err(err.into())
+        }
+    }
+}
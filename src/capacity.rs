@@ -0,0 +1,153 @@
+//! Batch writes paced by the destination table's capacity mode, read with `DescribeTable` once per
+//! table per process. An on-demand table takes `ON_DEMAND_CHUNKS` `BatchWriteItem` chunks at a
+//! time; a provisioned one is written a chunk at a time, each followed by a pause long enough that
+//! the capacity it consumed fits the table's provisioned WCU. A table that cannot be described is
+//! written a chunk at a time without pauses. The modes are reported in `diagnostics.capacity_modes`.
+
+use anyhow::Result;
+use aws_sdk_dynamodb::types::{BillingMode, TableDescription};
+use aws_sdk_dynamodb::Client;
+use futures::stream::{self, StreamExt, TryStreamExt};
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+use utoipa::ToSchema;
+
+use crate::metrics;
+
+/// Chunks written to an on-demand table at once.
+pub const ON_DEMAND_CHUNKS: usize = 4;
+/// Single-item writes sent to an on-demand table at once, a chunk's worth.
+pub const ON_DEMAND_ITEMS: usize = 25;
+
+static MODES: Mutex<BTreeMap<String, CapacityMode>> = Mutex::new(BTreeMap::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, JsonSchema, ToSchema)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum CapacityMode {
+    OnDemand,
+    Provisioned { write_capacity_units: i64 },
+    Unknown,
+}
+
+impl CapacityMode {
+    /// How long to wait after a chunk that consumed `units` WCU and took `elapsed`.
+    pub fn pause(self, units: f64, elapsed: Duration) -> Duration {
+        match self {
+            CapacityMode::Provisioned { write_capacity_units } => {
+                Duration::from_secs_f64(units / write_capacity_units as f64).saturating_sub(elapsed)
+            }
+            CapacityMode::OnDemand | CapacityMode::Unknown => Duration::ZERO,
+        }
+    }
+}
+
+/// The table's capacity mode, described the first time it is asked for.
+pub async fn mode(client: &Client, table: &str) -> CapacityMode {
+    if let Some(mode) = MODES.lock().unwrap().get(table) {
+        return *mode;
+    }
+    match client.describe_table().table_name(table).send().await {
+        Ok(resp) => record(table, resp.table()),
+        Err(err) => {
+            metrics::dynamodb_error("describe_table");
+            warn!("Error describing {}, writing it unpaced: {}", table, aws_sdk_dynamodb::error::DisplayErrorContext(&err));
+            MODES.lock().unwrap().insert(table.to_string(), CapacityMode::Unknown);
+            CapacityMode::Unknown
+        }
+    }
+}
+
+/// Settles the table's mode from a description read elsewhere, so [`mode`] does not describe it again.
+pub fn record(table: &str, description: Option<&TableDescription>) -> CapacityMode {
+    let mode = from_description(description);
+    info!(table, ?mode, "Table capacity mode");
+    MODES.lock().unwrap().insert(table.to_string(), mode);
+    mode
+}
+
+/// Tables created provisioned may have no billing mode summary, only their throughput.
+fn from_description(table: Option<&TableDescription>) -> CapacityMode {
+    let Some(table) = table else {
+        return CapacityMode::Unknown;
+    };
+    if table.billing_mode_summary().and_then(|summary| summary.billing_mode()) == Some(&BillingMode::PayPerRequest) {
+        return CapacityMode::OnDemand;
+    }
+    match table.provisioned_throughput().and_then(|throughput| throughput.write_capacity_units()) {
+        Some(units) if units > 0 => CapacityMode::Provisioned { write_capacity_units: units },
+        _ => CapacityMode::Unknown,
+    }
+}
+
+/// Runs single-item writes, each returning its result and the capacity units it consumed, paced as
+/// batch chunks are: [`ON_DEMAND_ITEMS`] at a time on an on-demand table, else one at a time, each
+/// followed by the mode's pause. Results are in the writes' order.
+pub async fn paced<T, W>(client: &Client, table: &str, writes: impl IntoIterator<Item = W>) -> Result<Vec<T>>
+where
+    W: Future<Output = Result<(T, f64)>>,
+{
+    let mode = mode(client, table).await;
+    if mode == CapacityMode::OnDemand {
+        return stream::iter(writes).buffered(ON_DEMAND_ITEMS).map_ok(|(result, _)| result).try_collect().await;
+    }
+    let mut results = Vec::new();
+    for write in writes {
+        let started = Instant::now();
+        let (result, units) = write.await?;
+        results.push(result);
+        tokio::time::sleep(mode.pause(units, started.elapsed())).await;
+    }
+    Ok(results)
+}
+
+/// The modes described so far, by table.
+pub fn snapshot() -> BTreeMap<String, CapacityMode> {
+    MODES.lock().unwrap().clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aws_sdk_dynamodb::types::{BillingModeSummary, ProvisionedThroughputDescription};
+
+    #[tokio::test]
+    async fn a_recorded_description_settles_the_mode() {
+        let table = TableDescription::builder()
+            .billing_mode_summary(BillingModeSummary::builder().billing_mode(BillingMode::PayPerRequest).build())
+            .build();
+        assert_eq!(record("prewarmed", Some(&table)), CapacityMode::OnDemand);
+        // No endpoint is configured, so a describe would fail and report the mode unknown.
+        let client = Client::from_conf(aws_sdk_dynamodb::Config::builder().behavior_version_latest().build());
+        assert_eq!(mode(&client, "prewarmed").await, CapacityMode::OnDemand);
+        assert_eq!(CapacityMode::Provisioned { write_capacity_units: 10 }.pause(5.0, Duration::from_millis(100)), Duration::from_millis(400));
+    }
+
+    #[test]
+    fn provisioned_tables_without_a_billing_summary_are_read_from_their_throughput() {
+        let provisioned = |units| TableDescription::builder()
+            .provisioned_throughput(ProvisionedThroughputDescription::builder().write_capacity_units(units).build())
+            .build();
+        assert_eq!(from_description(Some(&provisioned(20))), CapacityMode::Provisioned { write_capacity_units: 20 });
+        assert_eq!(from_description(Some(&provisioned(0))), CapacityMode::Unknown);
+        assert_eq!(from_description(None), CapacityMode::Unknown);
+    }
+
+    #[tokio::test]
+    async fn provisioned_writes_run_in_order_and_pause_for_their_capacity() {
+        let table = TableDescription::builder()
+            .provisioned_throughput(ProvisionedThroughputDescription::builder().write_capacity_units(100).build())
+            .build();
+        record("paced", Some(&table));
+        let client = Client::from_conf(aws_sdk_dynamodb::Config::builder().behavior_version_latest().build());
+        let started = Instant::now();
+        let written = paced(&client, "paced", (0..3).map(|n| async move { Ok((n, 10.0)) })).await.unwrap();
+        assert_eq!(written, vec![0, 1, 2]);
+        // 10 WCU of a 100 WCU table is a tenth of a second each.
+        assert!(started.elapsed() >= Duration::from_millis(300));
+    }
+}
@@ -0,0 +1,84 @@
+//! Fault injection for resilience testing (the `chaos` feature). Ride queries fail as throttled with
+//! `CHAOS_ERROR_RATE`, are delayed by `CHAOS_LATENCY_MS` with `CHAOS_LATENCY_RATE`, and each item read
+//! is corrupted with `CHAOS_MALFORMED_RATE`; rates are probabilities in `[0, 1]` and default to 0.
+
+use aws_sdk_dynamodb::config::http::HttpResponse;
+use aws_sdk_dynamodb::error::SdkError;
+use aws_sdk_dynamodb::operation::query::QueryError;
+use aws_sdk_dynamodb::types::error::ProvisionedThroughputExceededException;
+use aws_sdk_dynamodb::types::AttributeValue;
+use aws_smithy_runtime_api::http::StatusCode;
+use aws_smithy_types::body::SdkBody;
+use std::collections::HashMap;
+use std::sync::LazyLock;
+use std::time::Duration;
+use tracing::{debug, warn};
+
+use crate::config::{self, Config, Environment};
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Settings {
+    error_rate: f64,
+    latency: Duration,
+    latency_rate: f64,
+    malformed_rate: f64,
+}
+
+impl Settings {
+    fn enabled(&self) -> bool {
+        self.error_rate > 0.0 || self.latency_rate > 0.0 || self.malformed_rate > 0.0
+    }
+}
+
+impl Settings {
+    fn from_config(config: &Config) -> Settings {
+        Settings {
+            error_rate: config.chaos_error_rate,
+            latency: config.chaos_latency,
+            latency_rate: config.chaos_latency_rate,
+            malformed_rate: config.chaos_malformed_rate,
+        }
+    }
+}
+
+static SETTINGS: LazyLock<Settings> = LazyLock::new(|| Settings::from_config(config::get()));
+
+fn roll(rate: f64) -> bool {
+    rate > 0.0 && fastrand::f64() < rate
+}
+
+/// Injection is never allowed against prod tables.
+pub fn validate(problems: &mut Vec<String>, config: &Config) {
+    let settings = Settings::from_config(config);
+    if config.environment == Environment::Prod && settings.enabled() {
+        problems.push("CHAOS_* fault injection cannot be enabled with ENVIRONMENT=prod".to_string());
+    } else if settings.enabled() {
+        warn!("Chaos fault injection enabled: {:?}", settings);
+    }
+}
+
+/// Called before each ride query: maybe sleeps, then maybe fails the query as throttled.
+pub async fn before_query(imei: &str) -> Result<(), SdkError<QueryError>> {
+    if roll(SETTINGS.latency_rate) {
+        debug!(imei, "Chaos: delaying ride query by {:?}", SETTINGS.latency);
+        tokio::time::sleep(SETTINGS.latency).await;
+    }
+    if roll(SETTINGS.error_rate) {
+        debug!(imei, "Chaos: failing ride query");
+        let err = ProvisionedThroughputExceededException::builder().message("injected by chaos").build();
+        let raw = HttpResponse::new(StatusCode::try_from(400).expect("400 is a status code"), SdkBody::empty());
+        return Err(SdkError::service_error(QueryError::ProvisionedThroughputExceededException(err), raw));
+    }
+    Ok(())
+}
+
+/// Corrupts some of the items read: drops `ride_stats`, or makes `ride_start` unparseable.
+pub fn corrupt(items: &mut [HashMap<String, AttributeValue>]) {
+    for item in items.iter_mut().filter(|_| roll(SETTINGS.malformed_rate)) {
+        if fastrand::bool() {
+            item.remove("ride_stats");
+        } else {
+            item.insert("ride_start".to_string(), AttributeValue::S("chaos".to_string()));
+        }
+    }
+}
@@ -0,0 +1,75 @@
+//! Resumable runs: each device whose rows a run wrote in full is recorded under the run, and a retry
+//! of the same run (Lambda's own retry, or a new request with `resume_run_id`) skips those devices.
+//! Records are keyed by run_id plus a hash of the IMEIs, period and granularity, since batched
+//! requests share one run_id, and expire after a week.
+
+use aws_sdk_dynamodb::types::{AttributeValue, ReturnConsumedCapacity};
+use aws_sdk_dynamodb::Client;
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+
+use crate::cost::CapacityMeter;
+use crate::{metrics, pagination, CustomEvent};
+
+pub const TABLE_NAME: &str = "aggregation_checkpoints";
+
+const TTL_SECS: i64 = 7 * 86_400;
+
+#[derive(Debug, Clone)]
+pub struct Checkpoint {
+    /// `run_id#<hash>`, the `checkpoint_key` partition key.
+    run: String,
+}
+
+impl Checkpoint {
+    pub fn new(run_id: &str, payload: &CustomEvent, imeis: &[String]) -> Checkpoint {
+        let mut imeis = imeis.to_vec();
+        imeis.sort();
+        let mut hash = Sha256::new();
+        hash.update(imeis.join(","));
+        for part in [
+            payload.input_ride_month.clone(),
+            payload.start_date.map(|date| date.to_string()),
+            payload.end_date.map(|date| date.to_string()),
+            Some(payload.granularity.unwrap_or_default().as_str().to_string()),
+        ] {
+            hash.update(b"|");
+            hash.update(part.unwrap_or_default());
+        }
+        let run_id = payload.resume_run_id.as_deref().unwrap_or(run_id);
+        Checkpoint { run: format!("{}#{}", run_id, &hex::encode(hash.finalize())[..16]) }
+    }
+
+    /// Devices an earlier attempt of the run completed.
+    pub async fn completed(&self, client: &Client, meter: &CapacityMeter) -> Result<HashSet<String>> {
+        let stream = client.query()
+            .table_name(TABLE_NAME)
+            .key_condition_expression("checkpoint_key = :run")
+            .expression_attribute_values(":run", AttributeValue::S(self.run.clone()))
+            .projection_expression("imei")
+            .consistent_read(true)
+            .return_consumed_capacity(ReturnConsumedCapacity::Total)
+            .into_paginator()
+            .send();
+        let items = pagination::collect(&mut pagination::QueryPages { stream, meter }, None).await
+            .inspect_err(|_| metrics::dynamodb_error("query"))?;
+        Ok(items.iter().filter_map(|item| item.get("imei")?.as_s().ok().cloned()).collect())
+    }
+
+    pub async fn record(&self, client: &Client, imei: &str, now: DateTime<Utc>, meter: &CapacityMeter) -> Result<()> {
+        let resp = client.put_item()
+            .table_name(TABLE_NAME)
+            .item("checkpoint_key", AttributeValue::S(self.run.clone()))
+            .item("imei", AttributeValue::S(imei.to_string()))
+            .item("completed_at", AttributeValue::S(now.to_rfc3339()))
+            .item("expires_at", AttributeValue::N((now.timestamp() + TTL_SECS).to_string()))
+            .return_consumed_capacity(ReturnConsumedCapacity::Total)
+            .send()
+            .await
+            .inspect_err(|_| metrics::dynamodb_error("put_item"))?;
+        meter.write(resp.consumed_capacity());
+        Ok(())
+    }
+}
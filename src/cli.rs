@@ -0,0 +1,121 @@
+//! Command-line mode for development and backfills without Lambda: when the binary is started with
+//! arguments, e.g. `bootstrap aggregate --imeis 356..01,356..02 --month 2024-03 --dry-run`, the
+//! request is built from them, run through the same event path as an invocation, and its results
+//! printed as a table or JSON. `--endpoint-url` points the AWS clients at e.g. DynamoDB Local.
+//! Logs go to stderr so the output can be piped.
+
+use clap::{Parser, Subcommand, ValueEnum};
+use lambda_runtime::Error;
+use serde_json::{json, Map, Value};
+
+#[derive(Debug, Parser)]
+#[command(name = "ride-data", about = "Aggregate ride data outside Lambda")]
+struct Cli {
+    /// Endpoint for every AWS client, e.g. `http://localhost:8000` for DynamoDB Local.
+    #[arg(long, global = true)]
+    endpoint_url: Option<String>,
+    #[arg(long, global = true, value_enum, default_value_t = Output::Table)]
+    output: Output,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Aggregate devices' rides into period totals, as `action: "aggregate"` does.
+    Aggregate {
+        /// Comma-separated IMEIs.
+        #[arg(long, required = true)]
+        imeis: String,
+        /// `YYYY-MM`, or a keyword such as `previous_month`.
+        #[arg(long)]
+        month: Option<String>,
+        /// With `--end-date`: a `YYYY-MM-DD` range instead of a month.
+        #[arg(long, requires = "end_date")]
+        start_date: Option<String>,
+        #[arg(long, requires = "start_date")]
+        end_date: Option<String>,
+        /// `daily`, `weekly`, `monthly` or `range`.
+        #[arg(long)]
+        granularity: Option<String>,
+        /// Compute without writing rows; the JSON output includes the write plan.
+        #[arg(long)]
+        dry_run: bool,
+        /// Required to write when `ENVIRONMENT=prod`.
+        #[arg(long)]
+        allow_prod_write: bool,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+enum Output {
+    Table,
+    Json,
+}
+
+/// Whether the process was started as a command rather than by the Lambda runtime.
+pub fn requested() -> bool {
+    std::env::args_os().len() > 1
+}
+
+pub async fn run() -> Result<(), Error> {
+    let cli = Cli::parse();
+    let mut shared_config = crate::load_aws_config().await;
+    if let Some(endpoint) = &cli.endpoint_url {
+        shared_config = shared_config.to_builder().endpoint_url(endpoint).build();
+    }
+
+    let event = event(&cli.command);
+    let run_id = uuid::Uuid::new_v4().to_string();
+    let response = crate::handle_event(&shared_config, event, &run_id).await?;
+    if let Some(error) = response.get("error").and_then(Value::as_str) {
+        return Err(error.into());
+    }
+    match cli.output {
+        Output::Json => println!("{}", serde_json::to_string_pretty(&response)?),
+        Output::Table => print_table(&response),
+    }
+    Ok(())
+}
+
+fn event(command: &Command) -> Value {
+    let Command::Aggregate { imeis, month, start_date, end_date, granularity, dry_run, allow_prod_write } = command;
+    let mut event = Map::new();
+    event.insert("imeis".to_string(), json!(imeis));
+    let optional = [("input_ride_month", month), ("start_date", start_date), ("end_date", end_date), ("granularity", granularity)];
+    for (name, value) in optional {
+        if let Some(value) = value {
+            event.insert(name.to_string(), json!(value));
+        }
+    }
+    event.insert("dry_run".to_string(), json!(dry_run));
+    event.insert("allow_prod_write".to_string(), json!(allow_prod_write));
+    Value::Object(event)
+}
+
+fn print_table(response: &Value) {
+    let empty = Vec::new();
+    let list = |name: &str| response[name].as_array().unwrap_or(&empty);
+    println!("{:<20} {:<24} {:>14} {:>8}  notes", "imei", "period", "distance_km", "rides");
+    for row in list("results") {
+        let notes: Vec<&str> = [
+            (row["month_to_date"] == true).then_some("month-to-date"),
+            (row["truncated"] == true).then_some("truncated"),
+            row["write_skipped"].as_str(),
+        ].into_iter().flatten().collect();
+        println!(
+            "{:<20} {:<24} {:>14.3} {:>8}  {}",
+            row["imei"].as_str().unwrap_or_default(),
+            row["ride_month"].as_str().unwrap_or_default(),
+            row["total_distance"].as_f64().unwrap_or_default(),
+            row["ride_count"].as_u64().map_or("-".to_string(), |count| count.to_string()),
+            notes.join(", "),
+        );
+    }
+    for error in list("errors") {
+        eprintln!("{}: {} ({})", error["imei"].as_str().unwrap_or_default(), error["message"].as_str().unwrap_or_default(), error["kind"]);
+    }
+    for warning in list("warnings") {
+        eprintln!("warning: {}", warning);
+    }
+}
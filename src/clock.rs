@@ -0,0 +1,27 @@
+use chrono::{DateTime, Utc};
+
+/// Source of "now" for month-to-date decisions and `as_of` stamps, so tests and replays can pin the date.
+#[derive(Debug, Clone, Copy)]
+pub enum Clock {
+    Real,
+    Fixed(DateTime<Utc>),
+}
+
+impl Clock {
+    /// `fixed_now` from the event, else `FIXED_NOW`, else the system clock; both are RFC 3339 instants.
+    pub fn resolve(fixed_now: Option<&str>) -> Result<Clock, String> {
+        match fixed_now {
+            Some(fixed_now) => DateTime::parse_from_rfc3339(fixed_now)
+                .map(|now| Clock::Fixed(now.with_timezone(&Utc)))
+                .map_err(|err| format!("fixed_now {:?} is not an RFC 3339 time: {}", fixed_now, err)),
+            None => Ok(crate::config::get().fixed_now.map_or(Clock::Real, Clock::Fixed)),
+        }
+    }
+
+    pub fn now(&self) -> DateTime<Utc> {
+        match self {
+            Clock::Real => Utc::now(),
+            Clock::Fixed(now) => *now,
+        }
+    }
+}
@@ -0,0 +1,84 @@
+//! Compression for exported objects, chosen per request: `gzip` for the legacy scripts, `zstd` for
+//! the Spark consumers, or `none`. Parquet compresses its own pages, so a Parquet object gets the
+//! codec inside it instead of around it.
+
+use anyhow::Result;
+use flate2::write::GzEncoder;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use utoipa::ToSchema;
+
+pub trait Codec: Sync {
+    /// Appended to the object key, e.g. `.gz`; empty when the body is left as it is.
+    fn extension(&self) -> &'static str;
+    fn compress(&self, body: Vec<u8>) -> Result<Vec<u8>>;
+    /// The same codec for Parquet pages.
+    fn parquet(&self) -> parquet::basic::Compression;
+}
+
+struct Gzip;
+struct Zstd;
+struct Identity;
+
+impl Codec for Gzip {
+    fn extension(&self) -> &'static str {
+        ".gz"
+    }
+
+    fn compress(&self, body: Vec<u8>) -> Result<Vec<u8>> {
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&body)?;
+        Ok(encoder.finish()?)
+    }
+
+    fn parquet(&self) -> parquet::basic::Compression {
+        parquet::basic::Compression::GZIP(Default::default())
+    }
+}
+
+impl Codec for Zstd {
+    fn extension(&self) -> &'static str {
+        ".zst"
+    }
+
+    fn compress(&self, body: Vec<u8>) -> Result<Vec<u8>> {
+        Ok(zstd::encode_all(body.as_slice(), 0)?)
+    }
+
+    fn parquet(&self) -> parquet::basic::Compression {
+        parquet::basic::Compression::ZSTD(Default::default())
+    }
+}
+
+impl Codec for Identity {
+    fn extension(&self) -> &'static str {
+        ""
+    }
+
+    fn compress(&self, body: Vec<u8>) -> Result<Vec<u8>> {
+        Ok(body)
+    }
+
+    fn parquet(&self) -> parquet::basic::Compression {
+        parquet::basic::Compression::UNCOMPRESSED
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize, JsonSchema, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ExportCompression {
+    Gzip,
+    Zstd,
+    None,
+}
+
+impl ExportCompression {
+    pub fn codec(self) -> &'static dyn Codec {
+        match self {
+            ExportCompression::Gzip => &Gzip,
+            ExportCompression::Zstd => &Zstd,
+            ExportCompression::None => &Identity,
+        }
+    }
+}
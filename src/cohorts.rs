@@ -0,0 +1,97 @@
+use aws_sdk_dynamodb::types::AttributeValue;
+use aws_sdk_dynamodb::Client;
+use anyhow::Result;
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use utoipa::ToSchema;
+
+use crate::{metrics, CustomEvent, CustomOutput};
+
+pub const DEVICES_TABLE: &str = "devices";
+
+#[derive(Debug, Clone, Serialize, JsonSchema, ToSchema)]
+pub struct CohortOutput {
+    cohort: String,
+    ride_month: String,
+    total_distance: f64,
+    /// Cohort members with at least one trip in the month.
+    devices: usize,
+}
+
+/// Explicit `cohorts` from the event plus one cohort per `cohort_tags` entry, whose members are the
+/// devices carrying that tag in their `tags` attribute.
+pub async fn resolve(client: &Client, payload: &CustomEvent) -> Result<BTreeMap<String, Vec<String>>> {
+    let mut cohorts: BTreeMap<String, Vec<String>> = payload.cohorts.clone().unwrap_or_default().into_iter().collect();
+    for tag in payload.cohort_tags.iter().flatten() {
+        cohorts.insert(tag.clone(), devices_with_tag(client, tag).await?);
+    }
+    Ok(cohorts)
+}
+
+async fn devices_with_tag(client: &Client, tag: &str) -> Result<Vec<String>> {
+    let items = client
+        .scan()
+        .table_name(DEVICES_TABLE)
+        .projection_expression("#imei")
+        .filter_expression("contains(#tags, :tag)")
+        .expression_attribute_names("#imei", "imei")
+        .expression_attribute_names("#tags", "tags")
+        .expression_attribute_values(":tag", AttributeValue::S(tag.to_string()))
+        .into_paginator()
+        .items()
+        .send()
+        .collect::<Result<Vec<_>, _>>()
+        .await
+        .inspect_err(|_| metrics::dynamodb_error("scan"))?;
+
+    Ok(items.iter().filter_map(|item| item.get("imei")?.as_s().ok().cloned()).collect())
+}
+
+/// Members by the IMEI their rows carry: an old IMEI in `aliases` (old to current) stands for the
+/// device's current one.
+fn members<'a>(members: &'a [String], aliases: &'a BTreeMap<String, String>) -> BTreeSet<&'a str> {
+    members.iter().map(|member| aliases.get(member).unwrap_or(member).as_str()).collect()
+}
+
+/// Each `groups` entry's rows, in `rows`' order; a member with no rows is simply absent.
+pub fn nest(groups: &BTreeMap<String, Vec<String>>, aliases: &BTreeMap<String, String>, rows: &[CustomOutput]) -> BTreeMap<String, Vec<CustomOutput>> {
+    groups.iter().map(|(group, members)| {
+        let members = self::members(members, aliases);
+        (group.clone(), rows.iter().filter(|row| members.contains(row.imei.as_str())).cloned().collect())
+    }).collect()
+}
+
+/// Monthly totals per cohort, summed from the per-device rows.
+pub fn rollup(cohorts: &BTreeMap<String, Vec<String>>, aliases: &BTreeMap<String, String>, rows: &[CustomOutput]) -> Vec<CohortOutput> {
+    let mut output = Vec::new();
+    for (cohort, members) in cohorts {
+        let members = self::members(members, aliases);
+        let mut months: BTreeMap<&str, (f64, usize)> = BTreeMap::new();
+        for row in rows.iter().filter(|row| members.contains(row.imei.as_str())) {
+            let entry = months.entry(row.ride_month.as_str()).or_insert((0.0, 0));
+            entry.0 += row.total_distance;
+            entry.1 += 1;
+        }
+        for (ride_month, (total_distance, devices)) in months {
+            output.push(CohortOutput { cohort: cohort.clone(), ride_month: ride_month.to_string(), total_distance, devices });
+        }
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn groups_collect_rows_of_members_given_by_an_old_imei() {
+        let rows = [CustomOutput::fixture("350000000000001", "2024-03", 12.5), CustomOutput::fixture("350000000000027", "2024-03", 4.0)];
+        let groups = BTreeMap::from([("east".to_string(), vec!["350000000000019".to_string()])]);
+        let aliases = BTreeMap::from([("350000000000019".to_string(), "350000000000001".to_string())]);
+        let nested = nest(&groups, &aliases, &rows);
+        assert_eq!(nested["east"].iter().map(|row| row.imei.as_str()).collect::<Vec<_>>(), ["350000000000001"]);
+        assert!(nest(&groups, &BTreeMap::new(), &rows)["east"].is_empty());
+        assert_eq!(rollup(&groups, &aliases, &rows)[0].total_distance, 12.5);
+    }
+}
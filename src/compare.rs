@@ -0,0 +1,166 @@
+//! `action: "compare_months"`: what changed for `imeis` between `compare_to_month` (default the
+//! month before) and `input_ride_month`, from their stored monthly rows: each device's distance and
+//! ride count deltas and whether it became active or inactive, plus the totals. A device is active
+//! in a month with a stored row counting any distance or ride; one with no row is inactive.
+
+use chrono::Months;
+use futures::stream::{self, StreamExt, TryStreamExt};
+use lambda_runtime::Error;
+use schemars::JsonSchema;
+use serde::Serialize;
+use serde_json::{json, Value};
+use tracing::info;
+
+use crate::time::{self, Granularity};
+use crate::{aggregates, imei_concurrency_from_env, retries, units, CustomEvent, CustomOutput, ErrorOutput};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Active,
+    NewlyActive,
+    NewlyInactive,
+    Inactive,
+}
+
+/// One device's month, as stored.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, JsonSchema)]
+pub struct MonthTotals {
+    total_distance: f64,
+    ride_count: u64,
+}
+
+impl MonthTotals {
+    fn of_row(row: &CustomOutput) -> MonthTotals {
+        MonthTotals { total_distance: row.total_distance, ride_count: row.ride_count.unwrap_or(0) }
+    }
+
+    fn active(&self) -> bool {
+        self.total_distance > 0.0 || self.ride_count > 0
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, JsonSchema)]
+pub struct DeviceChange {
+    imei: String,
+    status: Status,
+    /// `compare_to_month`'s row, if one is stored.
+    before: Option<MonthTotals>,
+    /// `input_ride_month`'s row, if one is stored.
+    after: Option<MonthTotals>,
+    distance_delta: f64,
+    ride_count_delta: i64,
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct CompareOutput {
+    ride_month: String,
+    compare_to_month: String,
+    before: MonthTotals,
+    after: MonthTotals,
+    distance_delta: f64,
+    ride_count_delta: i64,
+    /// Requested IMEIs, in order.
+    devices: Vec<DeviceChange>,
+    newly_active: Vec<String>,
+    newly_inactive: Vec<String>,
+    output_unit: units::OutputUnit,
+}
+
+pub fn change(imei: &str, before: Option<MonthTotals>, after: Option<MonthTotals>) -> DeviceChange {
+    let (was, is) = (before.is_some_and(|m| m.active()), after.is_some_and(|m| m.active()));
+    let (from, to) = (before.unwrap_or_default(), after.unwrap_or_default());
+    DeviceChange {
+        imei: imei.to_string(),
+        status: match (was, is) {
+            (true, true) => Status::Active,
+            (false, true) => Status::NewlyActive,
+            (true, false) => Status::NewlyInactive,
+            (false, false) => Status::Inactive,
+        },
+        before,
+        after,
+        distance_delta: to.total_distance - from.total_distance,
+        ride_count_delta: to.ride_count as i64 - from.ride_count as i64,
+    }
+}
+
+fn previous_month(month: &str) -> Result<String, time::TimeError> {
+    let start = time::month_start(month, 0)?.date_naive();
+    Ok(start.checked_sub_months(Months::new(1)).unwrap_or(start).format("%Y-%m").to_string())
+}
+
+pub async fn compare_months(shared_config: &aws_config::SdkConfig, payload: &CustomEvent) -> Result<Value, Error> {
+    if payload.imeis.is_empty() {
+        return Ok(json!(ErrorOutput { error: "IMEI cannot be empty".to_string() }));
+    }
+    let Some(ride_month) = payload.input_ride_month.clone() else {
+        return Ok(json!(ErrorOutput { error: "input_ride_month is required to compare months".to_string() }));
+    };
+    let compare_to_month = match &payload.compare_to_month {
+        Some(month) => month.clone(),
+        None => previous_month(&ride_month)?,
+    };
+    if time::month_start(&compare_to_month, 0).is_err() {
+        return Ok(json!(ErrorOutput { error: format!("invalid compare_to_month {:?}", compare_to_month) }));
+    }
+    if compare_to_month == ride_month {
+        return Ok(json!(ErrorOutput { error: "compare_to_month must differ from input_ride_month".to_string() }));
+    }
+
+    let client = retries::dynamodb(shared_config);
+    let output_unit = payload.output_unit.unwrap_or_default();
+    let (from, to) = (compare_to_month.as_str().min(&ride_month).to_string(), compare_to_month.as_str().max(&ride_month).to_string());
+    let (client, from, to, months) = (&client, &from, &to, (&compare_to_month, &ride_month));
+    let devices: Vec<DeviceChange> = stream::iter(payload.imeis.clone())
+        .map(|imei| async move {
+            let mut rows = aggregates::query_outputs(client, &imei, Granularity::Monthly, from, to).await?;
+            for row in &mut rows {
+                units::convert(row, output_unit);
+            }
+            let month = |month: &str| rows.iter().find(|row| row.ride_month == month).map(MonthTotals::of_row);
+            Ok::<_, anyhow::Error>(change(&imei, month(months.0), month(months.1)))
+        })
+        .buffered(imei_concurrency_from_env())
+        .try_collect()
+        .await?;
+
+    let total = |side: fn(&DeviceChange) -> Option<MonthTotals>| devices.iter().filter_map(side).fold(MonthTotals::default(), |sum, month| MonthTotals {
+        total_distance: sum.total_distance + month.total_distance,
+        ride_count: sum.ride_count + month.ride_count,
+    });
+    let (before, after) = (total(|device| device.before), total(|device| device.after));
+    let with_status = |status: Status| devices.iter().filter(|device| device.status == status).map(|device| device.imei.clone()).collect::<Vec<_>>();
+    let output = CompareOutput {
+        ride_month: ride_month.clone(),
+        compare_to_month: compare_to_month.clone(),
+        before,
+        after,
+        distance_delta: after.total_distance - before.total_distance,
+        ride_count_delta: after.ride_count as i64 - before.ride_count as i64,
+        newly_active: with_status(Status::NewlyActive),
+        newly_inactive: with_status(Status::NewlyInactive),
+        devices,
+        output_unit,
+    };
+    info!("Compared {} with {} for {} imeis: {} newly inactive", ride_month, compare_to_month, payload.imeis.len(), output.newly_inactive.len());
+    let mut output = json!(output);
+    payload.number_format.unwrap_or_default().apply(&mut output);
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_devices_by_activity_in_each_month() {
+        let month = |total_distance: f64, ride_count: u64| Some(MonthTotals { total_distance, ride_count });
+        let gone = change("1", month(12.5, 3), None);
+        assert_eq!((gone.status, gone.distance_delta, gone.ride_count_delta), (Status::NewlyInactive, -12.5, -3));
+        assert_eq!(change("1", month(0.0, 0), month(2.0, 1)).status, Status::NewlyActive);
+        assert_eq!(change("1", month(4.0, 2), month(0.0, 0)).status, Status::NewlyInactive);
+        assert_eq!(change("1", None, None).status, Status::Inactive);
+        assert_eq!(previous_month("2024-01").unwrap(), "2023-12");
+    }
+}
@@ -0,0 +1,750 @@
+//! Everything read from the environment, parsed once into [`Config`]. [`validate`] runs the same
+//! parse at cold start, so a bad value fails the init phase with a precise message instead of
+//! surfacing lazily in the middle of a request; modules read their settings through [`get`].
+
+use chrono::{DateTime, FixedOffset, NaiveDate, Offset, Utc};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::sync::LazyLock;
+use std::time::Duration;
+use tracing_subscriber::filter::LevelFilter;
+
+use crate::{aggregates, aliases, anomalies, audit, backfill, billing, cache, checkpoint, cohorts, event, experiments, fanout, history};
+use crate::{normalize, partitions, planner, ride, sentry, time, units, webhook};
+
+/// Deployment settings, read once. Each field names the variable it comes from and its default;
+/// a variable set to an invalid value is reported by [`validate`] and otherwise reads as unset.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// `ENVIRONMENT` (default `dev`).
+    pub environment: Environment,
+    /// `RIDE_DATA_MODE` (default `lambda`).
+    pub mode: Mode,
+    /// `RIDE_TABLE` (default `ride_data`).
+    pub ride_table: String,
+    /// `AGGREGATES_TABLE` (default `ride_data_monthly_distance`).
+    pub aggregates_table: String,
+    /// `LEGACY_AGGREGATES_TABLE`, the baseline table keyed by `date`, if it is still read.
+    pub legacy_aggregates_table: Option<String>,
+    /// `RIDE_DATA_REGION` (default `ap-south-1`).
+    pub region: String,
+    /// `REPORTING_UTC_OFFSET` (default `+05:30`), the offset rides are bucketed into periods in.
+    pub utc_offset: FixedOffset,
+    /// `FIXED_NOW`, an RFC 3339 instant standing in for the system clock.
+    pub fixed_now: Option<DateTime<Utc>>,
+    /// `PORT` (default 8080), in HTTP mode.
+    pub port: u16,
+    /// `AWS_LAMBDA_FUNCTION_NAME`, set by the Lambda runtime.
+    pub function_name: Option<String>,
+    /// `AWS_LAMBDA_FUNCTION_MEMORY_SIZE` in MB, set by the Lambda runtime (0 elsewhere).
+    pub function_memory_mb: f64,
+
+    /// `LOG_LEVEL` (default `info`).
+    pub log_level: LevelFilter,
+    /// `LOG_FORMAT` is `text` rather than `json` (the default).
+    pub log_text: bool,
+    /// `SENTRY_DSN`.
+    pub sentry_dsn: Option<sentry::Dsn>,
+    /// `SENTRY_ENVIRONMENT` (default `ENVIRONMENT`).
+    pub sentry_environment: String,
+    /// `DATADOG_EXPORTER`.
+    #[cfg(feature = "datadog")]
+    pub datadog_exporter: Option<crate::datadog::Exporter>,
+    /// `DD_SERVICE` (default `ride-data`).
+    #[cfg(feature = "datadog")]
+    pub datadog_service: String,
+    /// `DD_ENV` (default `ENVIRONMENT`).
+    #[cfg(feature = "datadog")]
+    pub datadog_env: String,
+    /// `OTEL_EXPORTER_OTLP_ENDPOINT` without a trailing slash (default `http://127.0.0.1:4318`).
+    #[cfg(feature = "datadog")]
+    pub otlp_endpoint: String,
+
+    /// `EVENT_PARSING` (default `lenient`).
+    pub event_parsing: event::ParsingMode,
+    /// `DEFAULT_RIDE_MONTH` (default `all`).
+    pub default_ride_month: time::DefaultMonth,
+    /// `RIDE_WINDOW_START` and `RIDE_WINDOW_END` (`YYYY-MM-DD`, open by default).
+    pub ride_window: ride::RideWindow,
+    /// `CLOCK_SKEW_TOLERANCE_SECS` (default 300).
+    pub clock_skew_tolerance: Duration,
+    /// `QUERY_STRATEGY`, unless it is `auto` (the default).
+    pub query_strategy: Option<planner::Strategy>,
+    /// `IMEI_CONCURRENCY` (default 8).
+    pub imei_concurrency: usize,
+    /// `IMEI_PATTERN_LIMIT` (default 10000).
+    pub imei_pattern_limit: usize,
+    /// `DEVICES_TAC_INDEX` (default `tac-imei-index`).
+    pub devices_tac_index: String,
+    /// `HOT_PARTITION_LATENCY_MS` (default 2000).
+    pub hot_partition_latency: Duration,
+    /// `DYNAMODB_MAX_ATTEMPTS` (default 5).
+    pub dynamodb_max_attempts: u32,
+    /// `DYNAMODB_BASE_DELAY_MS` (default 50).
+    pub dynamodb_base_delay: Duration,
+    /// `SERVICE_QUOTAS` (default `true`).
+    pub service_quotas: bool,
+    /// `FIRMWARE_DISTANCE_UNITS`, comma-separated `firmware_version=unit` pairs.
+    pub firmware_units: BTreeMap<String, units::DistanceUnit>,
+    /// `DECIMAL_SEPARATOR` (default `auto`).
+    pub decimal_separator: normalize::DecimalSeparator,
+    /// `MAX_RIDE_DISTANCE_KM`.
+    pub max_ride_distance_km: Option<f64>,
+    /// `ANOMALY_ACTION` (default `exclude`).
+    pub anomaly_action: anomalies::Action,
+    /// `ANOMALY_TOPIC_ARN`.
+    pub anomaly_topic_arn: Option<String>,
+    /// `EXPERIMENTS`, comma-separated.
+    pub experiments: Vec<experiments::Experiment>,
+    /// `FRAUD_DUPLICATE_MIN_RIDES` (default 5).
+    pub fraud_duplicate_min_rides: u64,
+    /// `FRAUD_DUPLICATE_SHARE` (default 0.5).
+    pub fraud_duplicate_share: f64,
+    /// `FRAUD_MAX_REPOSITION_KMH` (default 150).
+    pub fraud_max_reposition_kmh: f64,
+    /// `TIMEZONE_DRIFT_THRESHOLD_MINS` (default 30).
+    pub timezone_drift_threshold_mins: i64,
+    /// `DIAGNOSE_SAMPLE_RIDES` (default 25).
+    pub diagnose_sample_rides: usize,
+    /// `ESTIMATE_SAMPLE_LIMIT` (default 25).
+    pub estimate_sample_limit: i32,
+    /// `PREFLIGHT_RIDES_PER_SECOND` (default 2000).
+    pub preflight_rides_per_second: f64,
+    /// `SELFTEST_MONTH` (default `2024-01`).
+    pub selftest_month: String,
+
+    /// `AGGREGATE_WRITE_MODE` (default `put`).
+    pub aggregate_write_mode: aggregates::WriteMode,
+    /// `AGGREGATE_CACHE` (default `false`).
+    pub aggregate_cache: bool,
+    /// `REVISION_THRESHOLD_KM` (default 0.01).
+    pub revision_threshold_km: f64,
+    /// `RECONCILE_TOLERANCE_KM` (default 0.01).
+    pub reconcile_tolerance_km: f64,
+    /// `STREAM_FLUSH_SECS`; stream records are written as they arrive unless it is set.
+    pub stream_flush_every: Option<Duration>,
+    /// `STREAM_FLUSH_RECORDS` (default 1000).
+    pub stream_flush_records: usize,
+    /// `STREAM_DEDUPE_SECS` (default 300, 0 to turn deduplication off).
+    pub stream_dedupe_window: Duration,
+    /// `STREAM_DEDUPE_CAPACITY` (default 10000).
+    pub stream_dedupe_capacity: usize,
+    /// `RIDE_RETENTION_MONTHS`.
+    pub ride_retention_months: Option<u32>,
+    /// `DISTANCE_ALERT_TOPIC_ARN`.
+    pub distance_alert_topic_arn: Option<String>,
+    /// `DISTANCE_ALERT_HYSTERESIS_PCT` (default 5).
+    pub distance_alert_hysteresis_pct: f64,
+    /// `LIFECYCLE_EVENT_BUS`.
+    pub lifecycle_event_bus: Option<String>,
+    /// `MAINTENANCE_EVENT_BUS`.
+    pub maintenance_event_bus: Option<String>,
+    /// `SERVICE_INTERVAL_KM`.
+    pub service_interval_km: Option<f64>,
+
+    /// `FANOUT_QUEUE_URL`.
+    pub fanout_queue_url: Option<String>,
+    /// `FANOUT_BACKFILL_QUEUE_URL`.
+    pub fanout_backfill_queue_url: Option<String>,
+    /// `FANOUT_SHARD_SIZE` (default 25).
+    pub fanout_shard_size: usize,
+    /// `FANOUT_BACKFILL_DEFER_SECS` (default 60).
+    pub fanout_backfill_defer_secs: i32,
+    /// `FANOUT_MAX_DEFERRALS` (default 5).
+    pub fanout_max_deferrals: u32,
+    /// `BACKFILL_QUEUE_URL`.
+    pub backfill_queue_url: Option<String>,
+    /// `BACKFILL_CHUNK_SIZE` (default 500).
+    pub backfill_chunk_size: usize,
+    /// `MANIFEST_SHARD_SIZE` (default 100).
+    pub manifest_shard_size: usize,
+
+    /// `REPORT_BUCKET`.
+    pub report_bucket: Option<String>,
+    /// `ARCHIVE_BUCKET`.
+    pub archive_bucket: Option<String>,
+    /// `RESPONSE_BUCKET`.
+    pub response_bucket: Option<String>,
+    /// `RESPONSE_SPILL_ROWS` (default 5000).
+    pub response_spill_rows: usize,
+    /// `MULTIPART_THRESHOLD_BYTES` (default 64 MiB).
+    pub multipart_threshold_bytes: usize,
+    /// `UPLOAD_CONCURRENCY` (default 4).
+    pub upload_concurrency: usize,
+    /// `PRESIGN_EXPIRY_SECS`.
+    pub presign_expiry_secs: Option<u64>,
+    /// `EXPORT_PART_ROWS` (default 10000).
+    pub export_part_rows: usize,
+    /// `EXPORT_KMS_KEY_ID`.
+    pub export_kms_key_id: Option<String>,
+    /// `FAIL_IF_ERROR_RATE_ABOVE`.
+    pub fail_if_error_rate_above: Option<f64>,
+    /// `RIDE_SIGNING_KEY_PREFIX` (default `ride-data/device-keys/`).
+    pub ride_signing_key_prefix: String,
+    /// `REPORT_EMAIL_SENDER`.
+    pub report_email_sender: Option<String>,
+    /// `REPORT_EMAIL_RECIPIENTS`, comma-separated.
+    pub report_email_recipients: Vec<String>,
+    /// `SUMMARY_WEBHOOK_URL`.
+    pub summary_webhook_url: Option<String>,
+    /// `SUMMARY_WEBHOOK_KIND` (default `slack`).
+    pub summary_webhook_kind: webhook::WebhookKind,
+
+    /// `DYNAMODB_READ_PRICE_PER_MILLION` (default 0.285).
+    pub dynamodb_read_price_per_million: f64,
+    /// `DYNAMODB_WRITE_PRICE_PER_MILLION` (default 1.4225).
+    pub dynamodb_write_price_per_million: f64,
+    /// `LAMBDA_PRICE_PER_GB_SECOND` (default 0.0000166667).
+    pub lambda_price_per_gb_second: f64,
+
+    /// `SHADOW_FUNCTION_NAME`.
+    pub shadow_function_name: Option<String>,
+    /// `SHADOW_SAMPLE_PERCENT` (default 10).
+    pub shadow_sample_percent: f64,
+    /// `SHADOW_TOLERANCE_KM` (default 0.01).
+    pub shadow_tolerance_km: f64,
+    /// `SHADOW_TIMEOUT_SECS` (default 30).
+    pub shadow_timeout: Duration,
+
+    /// `SOAK_IMEIS`, comma-separated.
+    pub soak_imeis: Vec<String>,
+    /// `SOAK_REQUESTS_PER_MINUTE` (default 6).
+    pub soak_requests_per_minute: f64,
+    /// `SOAK_MONTHS` (default 3).
+    pub soak_months: u32,
+    /// `SOAK_DURATION_SECS` (default: until stopped).
+    pub soak_duration: Option<Duration>,
+
+    /// `CHAOS_ERROR_RATE` (default 0).
+    #[cfg(feature = "chaos")]
+    pub chaos_error_rate: f64,
+    /// `CHAOS_LATENCY_MS` (default 0).
+    #[cfg(feature = "chaos")]
+    pub chaos_latency: Duration,
+    /// `CHAOS_LATENCY_RATE` (default 0).
+    #[cfg(feature = "chaos")]
+    pub chaos_latency_rate: f64,
+    /// `CHAOS_MALFORMED_RATE` (default 0).
+    #[cfg(feature = "chaos")]
+    pub chaos_malformed_rate: f64,
+}
+
+impl Config {
+    /// Invalid values are reported in `problems` and read as unset.
+    fn from_env(problems: &mut Vec<String>) -> Config {
+        let environment = one_of(problems, "ENVIRONMENT", &[("dev", Environment::Dev), ("staging", Environment::Staging), ("prod", Environment::Prod)])
+            .unwrap_or(Environment::Dev);
+        let offset = var("REPORTING_UTC_OFFSET").unwrap_or_else(|| "+05:30".to_string());
+        let utc_offset = offset.parse::<FixedOffset>().unwrap_or_else(|_| {
+            problems.push(format!("REPORTING_UTC_OFFSET must be an offset like +05:30 (got {:?})", offset));
+            Utc.fix()
+        });
+        let fixed_now = var("FIXED_NOW").and_then(|value| match DateTime::parse_from_rfc3339(&value) {
+            Ok(now) => Some(now.with_timezone(&Utc)),
+            Err(err) => {
+                problems.push(format!("FIXED_NOW must be an RFC 3339 time (got {:?}): {}", value, err));
+                None
+            }
+        });
+        let sentry_dsn = var("SENTRY_DSN").and_then(|dsn| {
+            let parsed = sentry::Dsn::parse(&dsn);
+            if parsed.is_none() {
+                problems.push("SENTRY_DSN must be a https://<key>@<host>/<project_id> DSN".to_string());
+            }
+            parsed
+        });
+        let ride_window = ride::RideWindow { start: date(problems, "RIDE_WINDOW_START"), end: date(problems, "RIDE_WINDOW_END") };
+        let firmware_units = var("FIRMWARE_DISTANCE_UNITS").map_or_else(BTreeMap::new, |spec| {
+            units::firmware_units(&spec).unwrap_or_else(|err| {
+                problems.push(format!("FIRMWARE_DISTANCE_UNITS: {}", err));
+                BTreeMap::new()
+            })
+        });
+        let experiments = var("EXPERIMENTS").unwrap_or_default();
+        let unknown = experiments::unknown(&experiments);
+        if !unknown.is_empty() {
+            let known: Vec<&str> = experiments::Experiment::ALL.iter().map(|experiment| experiment.name()).collect();
+            problems.push(format!("EXPERIMENTS must list experiments among {} (got {})", known.join(", "), unknown.join(", ")));
+        }
+        let selftest_month = var("SELFTEST_MONTH").unwrap_or_else(|| "2024-01".to_string());
+        // Not `time::month_range`, which reads the offset from the config being built.
+        if NaiveDate::parse_from_str(&format!("{}-01", selftest_month), "%Y-%m-%d").is_err() {
+            problems.push(format!("SELFTEST_MONTH must be a YYYY-MM month (got {:?})", selftest_month));
+        }
+        let summary_webhook_url = var("SUMMARY_WEBHOOK_URL");
+        if summary_webhook_url.as_ref().is_some_and(|url| !url.starts_with("https://")) {
+            problems.push("SUMMARY_WEBHOOK_URL must be an https:// URL".to_string());
+        }
+        let km = |km: &f64| *km >= 0.0;
+        let positive = |n: &usize| *n > 0;
+        let price = |price: &f64| *price >= 0.0;
+        let percent = |percent: &f64| (0.0..=100.0).contains(percent);
+
+        Config {
+            environment,
+            mode: one_of(problems, "RIDE_DATA_MODE", &[("lambda", Mode::Lambda), ("http", Mode::Http), ("soak", Mode::Soak)]).unwrap_or(Mode::Lambda),
+            ride_table: var("RIDE_TABLE").unwrap_or_else(|| "ride_data".to_string()),
+            aggregates_table: var("AGGREGATES_TABLE").unwrap_or_else(|| "ride_data_monthly_distance".to_string()),
+            legacy_aggregates_table: var("LEGACY_AGGREGATES_TABLE"),
+            region: var("RIDE_DATA_REGION").unwrap_or_else(|| "ap-south-1".to_string()),
+            utc_offset,
+            fixed_now,
+            port: number(problems, "PORT", |_| true, "a port number").unwrap_or(8080),
+            function_name: var("AWS_LAMBDA_FUNCTION_NAME"),
+            function_memory_mb: number(problems, "AWS_LAMBDA_FUNCTION_MEMORY_SIZE", |mb| *mb > 0.0, "a positive size in MB").unwrap_or(0.0),
+
+            log_level: one_of(problems, "LOG_LEVEL", &[
+                ("trace", LevelFilter::TRACE),
+                ("debug", LevelFilter::DEBUG),
+                ("info", LevelFilter::INFO),
+                ("warn", LevelFilter::WARN),
+                ("error", LevelFilter::ERROR),
+            ]).unwrap_or(LevelFilter::INFO),
+            log_text: one_of(problems, "LOG_FORMAT", &[("json", false), ("text", true)]).unwrap_or(false),
+            sentry_dsn,
+            sentry_environment: var("SENTRY_ENVIRONMENT").unwrap_or_else(|| environment.as_str().to_string()),
+            #[cfg(feature = "datadog")]
+            datadog_exporter: one_of(problems, "DATADOG_EXPORTER", &[("extension", crate::datadog::Exporter::Extension), ("otlp", crate::datadog::Exporter::Otlp)]),
+            #[cfg(feature = "datadog")]
+            datadog_service: var("DD_SERVICE").unwrap_or_else(|| "ride-data".to_string()),
+            #[cfg(feature = "datadog")]
+            datadog_env: var("DD_ENV").unwrap_or_else(|| environment.as_str().to_string()),
+            #[cfg(feature = "datadog")]
+            otlp_endpoint: var("OTEL_EXPORTER_OTLP_ENDPOINT").unwrap_or_else(|| "http://127.0.0.1:4318".to_string()).trim_end_matches('/').to_string(),
+
+            event_parsing: one_of(problems, "EVENT_PARSING", &[("strict", event::ParsingMode::Strict), ("lenient", event::ParsingMode::Lenient)])
+                .unwrap_or(event::ParsingMode::Lenient),
+            default_ride_month: one_of(problems, "DEFAULT_RIDE_MONTH", &[
+                ("current_month", time::DefaultMonth::CurrentMonth),
+                ("previous_month", time::DefaultMonth::PreviousMonth),
+                ("all", time::DefaultMonth::All),
+            ]).unwrap_or_default(),
+            ride_window,
+            clock_skew_tolerance: Duration::from_secs(number(problems, "CLOCK_SKEW_TOLERANCE_SECS", |_| true, "a whole number of seconds").unwrap_or(300)),
+            query_strategy: one_of(problems, "QUERY_STRATEGY", &[
+                ("auto", None),
+                ("per_imei", Some(planner::Strategy::PerImei)),
+                ("date_index", Some(planner::Strategy::DateIndex)),
+                ("scan", Some(planner::Strategy::Scan)),
+            ]).flatten(),
+            imei_concurrency: number(problems, "IMEI_CONCURRENCY", positive, "a positive whole number").unwrap_or(8),
+            imei_pattern_limit: number(problems, "IMEI_PATTERN_LIMIT", positive, "a positive whole number").unwrap_or(10_000),
+            devices_tac_index: var("DEVICES_TAC_INDEX").unwrap_or_else(|| "tac-imei-index".to_string()),
+            hot_partition_latency: Duration::from_millis(number(problems, "HOT_PARTITION_LATENCY_MS", |_| true, "a whole number of milliseconds").unwrap_or(2000)),
+            dynamodb_max_attempts: number(problems, "DYNAMODB_MAX_ATTEMPTS", |n| *n > 0, "a positive whole number").unwrap_or(5),
+            dynamodb_base_delay: Duration::from_millis(number(problems, "DYNAMODB_BASE_DELAY_MS", |_| true, "a whole number of milliseconds").unwrap_or(50)),
+            service_quotas: one_of(problems, "SERVICE_QUOTAS", &[("true", true), ("false", false)]).unwrap_or(true),
+            firmware_units,
+            decimal_separator: one_of(problems, "DECIMAL_SEPARATOR", &[
+                ("auto", normalize::DecimalSeparator::Auto),
+                ("comma", normalize::DecimalSeparator::Comma),
+                ("dot", normalize::DecimalSeparator::Dot),
+            ]).unwrap_or(normalize::DecimalSeparator::Auto),
+            max_ride_distance_km: number(problems, "MAX_RIDE_DISTANCE_KM", |km| *km > 0.0, "a positive number of km"),
+            anomaly_action: one_of(problems, "ANOMALY_ACTION", &[("exclude", anomalies::Action::Exclude), ("flag", anomalies::Action::Flag)])
+                .unwrap_or(anomalies::Action::Exclude),
+            anomaly_topic_arn: var("ANOMALY_TOPIC_ARN"),
+            experiments: experiments::parse(&experiments),
+            fraud_duplicate_min_rides: number(problems, "FRAUD_DUPLICATE_MIN_RIDES", |n| *n > 0, "a positive whole number").unwrap_or(5),
+            fraud_duplicate_share: number(problems, "FRAUD_DUPLICATE_SHARE", |share| (0.0..=1.0).contains(share), "a share between 0 and 1").unwrap_or(0.5),
+            fraud_max_reposition_kmh: number(problems, "FRAUD_MAX_REPOSITION_KMH", |kmh| *kmh > 0.0, "a positive speed in km/h").unwrap_or(150.0),
+            timezone_drift_threshold_mins: number(problems, "TIMEZONE_DRIFT_THRESHOLD_MINS", |mins| *mins >= 0, "a non-negative number of minutes").unwrap_or(30),
+            diagnose_sample_rides: number(problems, "DIAGNOSE_SAMPLE_RIDES", |_| true, "a whole number of rides").unwrap_or(25),
+            estimate_sample_limit: number(problems, "ESTIMATE_SAMPLE_LIMIT", |limit| *limit > 0, "a positive whole number").unwrap_or(25),
+            preflight_rides_per_second: number(problems, "PREFLIGHT_RIDES_PER_SECOND", |rate| *rate > 0.0, "a positive number of rides per second").unwrap_or(2000.0),
+            selftest_month,
+
+            aggregate_write_mode: one_of(problems, "AGGREGATE_WRITE_MODE", &[("put", aggregates::WriteMode::Put), ("update", aggregates::WriteMode::Update)])
+                .unwrap_or(aggregates::WriteMode::Put),
+            aggregate_cache: one_of(problems, "AGGREGATE_CACHE", &[("true", true), ("false", false)]).unwrap_or(false),
+            revision_threshold_km: number(problems, "REVISION_THRESHOLD_KM", km, "a non-negative number of km").unwrap_or(0.01),
+            reconcile_tolerance_km: number(problems, "RECONCILE_TOLERANCE_KM", km, "a non-negative number of km").unwrap_or(0.01),
+            stream_flush_every: number(problems, "STREAM_FLUSH_SECS", |_| true, "a whole number of seconds").map(Duration::from_secs),
+            stream_flush_records: number(problems, "STREAM_FLUSH_RECORDS", positive, "a positive whole number").unwrap_or(1000),
+            stream_dedupe_window: Duration::from_secs(number(problems, "STREAM_DEDUPE_SECS", |_| true, "a whole number of seconds").unwrap_or(300)),
+            stream_dedupe_capacity: number(problems, "STREAM_DEDUPE_CAPACITY", positive, "a positive whole number").unwrap_or(10_000),
+            ride_retention_months: number(problems, "RIDE_RETENTION_MONTHS", |_| true, "a whole number of months"),
+            distance_alert_topic_arn: var("DISTANCE_ALERT_TOPIC_ARN"),
+            distance_alert_hysteresis_pct: number(problems, "DISTANCE_ALERT_HYSTERESIS_PCT", |percent| (0.0..100.0).contains(percent), "a percentage from 0 to under 100")
+                .unwrap_or(5.0),
+            lifecycle_event_bus: var("LIFECYCLE_EVENT_BUS"),
+            maintenance_event_bus: var("MAINTENANCE_EVENT_BUS"),
+            service_interval_km: number(problems, "SERVICE_INTERVAL_KM", |km| *km > 0.0, "a positive number of km"),
+
+            fanout_queue_url: var("FANOUT_QUEUE_URL"),
+            fanout_backfill_queue_url: var("FANOUT_BACKFILL_QUEUE_URL"),
+            fanout_shard_size: number(problems, "FANOUT_SHARD_SIZE", positive, "a positive whole number").unwrap_or(25),
+            fanout_backfill_defer_secs: number(problems, "FANOUT_BACKFILL_DEFER_SECS", |secs| (0..=900).contains(secs), "a delay from 0 to 900 seconds").unwrap_or(60),
+            fanout_max_deferrals: number(problems, "FANOUT_MAX_DEFERRALS", |_| true, "a whole number").unwrap_or(5),
+            backfill_queue_url: var("BACKFILL_QUEUE_URL"),
+            backfill_chunk_size: number(problems, "BACKFILL_CHUNK_SIZE", positive, "a positive whole number").unwrap_or(500),
+            manifest_shard_size: number(problems, "MANIFEST_SHARD_SIZE", positive, "a positive whole number").unwrap_or(100),
+
+            report_bucket: var("REPORT_BUCKET"),
+            archive_bucket: var("ARCHIVE_BUCKET"),
+            response_bucket: var("RESPONSE_BUCKET"),
+            response_spill_rows: number(problems, "RESPONSE_SPILL_ROWS", |_| true, "a whole number of rows").unwrap_or(5000),
+            multipart_threshold_bytes: number(problems, "MULTIPART_THRESHOLD_BYTES", |_| true, "a whole number of bytes").unwrap_or(64 * 1024 * 1024),
+            upload_concurrency: number(problems, "UPLOAD_CONCURRENCY", positive, "a positive whole number").unwrap_or(4),
+            presign_expiry_secs: number(problems, "PRESIGN_EXPIRY_SECS", |secs| (1..=crate::s3::MAX_PRESIGN_EXPIRY_SECS).contains(secs), "a number of seconds up to a week"),
+            export_part_rows: number(problems, "EXPORT_PART_ROWS", positive, "a positive whole number").unwrap_or(10_000),
+            export_kms_key_id: var("EXPORT_KMS_KEY_ID"),
+            fail_if_error_rate_above: number(problems, "FAIL_IF_ERROR_RATE_ABOVE", percent, "a percentage from 0 to 100"),
+            ride_signing_key_prefix: var("RIDE_SIGNING_KEY_PREFIX").unwrap_or_else(|| "ride-data/device-keys/".to_string()),
+            report_email_sender: var("REPORT_EMAIL_SENDER"),
+            report_email_recipients: list("REPORT_EMAIL_RECIPIENTS"),
+            summary_webhook_url,
+            summary_webhook_kind: one_of(problems, "SUMMARY_WEBHOOK_KIND", &[("slack", webhook::WebhookKind::Slack), ("teams", webhook::WebhookKind::Teams)])
+                .unwrap_or(webhook::WebhookKind::Slack),
+
+            dynamodb_read_price_per_million: number(problems, "DYNAMODB_READ_PRICE_PER_MILLION", price, "a non-negative price in USD").unwrap_or(0.285),
+            dynamodb_write_price_per_million: number(problems, "DYNAMODB_WRITE_PRICE_PER_MILLION", price, "a non-negative price in USD").unwrap_or(1.4225),
+            lambda_price_per_gb_second: number(problems, "LAMBDA_PRICE_PER_GB_SECOND", price, "a non-negative price in USD").unwrap_or(0.0000166667),
+
+            shadow_function_name: var("SHADOW_FUNCTION_NAME"),
+            shadow_sample_percent: number(problems, "SHADOW_SAMPLE_PERCENT", percent, "a percentage from 0 to 100").unwrap_or(10.0),
+            shadow_tolerance_km: number(problems, "SHADOW_TOLERANCE_KM", km, "a non-negative number of km").unwrap_or(0.01),
+            shadow_timeout: Duration::from_secs(number(problems, "SHADOW_TIMEOUT_SECS", |secs| *secs > 0, "a positive number of seconds").unwrap_or(30)),
+
+            soak_imeis: list("SOAK_IMEIS"),
+            soak_requests_per_minute: number(problems, "SOAK_REQUESTS_PER_MINUTE", |n| *n > 0.0, "a positive number").unwrap_or(6.0),
+            soak_months: number(problems, "SOAK_MONTHS", |n| *n > 0, "a positive whole number").unwrap_or(3),
+            soak_duration: number(problems, "SOAK_DURATION_SECS", |n| *n > 0.0, "a positive number").map(Duration::from_secs_f64),
+
+            #[cfg(feature = "chaos")]
+            chaos_error_rate: number(problems, "CHAOS_ERROR_RATE", rate, "a rate between 0 and 1").unwrap_or(0.0),
+            #[cfg(feature = "chaos")]
+            chaos_latency: Duration::from_millis(number(problems, "CHAOS_LATENCY_MS", |_| true, "a number of milliseconds").unwrap_or(0)),
+            #[cfg(feature = "chaos")]
+            chaos_latency_rate: number(problems, "CHAOS_LATENCY_RATE", rate, "a rate between 0 and 1").unwrap_or(0.0),
+            #[cfg(feature = "chaos")]
+            chaos_malformed_rate: number(problems, "CHAOS_MALFORMED_RATE", rate, "a rate between 0 and 1").unwrap_or(0.0),
+        }
+    }
+}
+
+#[cfg(feature = "chaos")]
+fn rate(rate: &f64) -> bool {
+    (0.0..=1.0).contains(rate)
+}
+
+static CONFIG: LazyLock<Config> = LazyLock::new(|| Config::from_env(&mut Vec::new()));
+
+pub fn get() -> &'static Config {
+    &CONFIG
+}
+
+fn tables(config: &Config) -> [&str; 14] {
+    [
+        &config.ride_table,
+        &config.aggregates_table,
+        history::TABLE_NAME,
+        billing::TABLE_NAME,
+        billing::RATE_CARDS_TABLE,
+        cohorts::DEVICES_TABLE,
+        audit::TABLE_NAME,
+        fanout::JOBS_TABLE,
+        backfill::TABLE_NAME,
+        aliases::TABLE_NAME,
+        checkpoint::TABLE_NAME,
+        cache::TABLE_NAME,
+        crate::thresholds::TABLE_NAME,
+        crate::thresholds::STATE_TABLE,
+    ]
+}
+
+/// Deployment tier from `ENVIRONMENT` (default `dev`); `prod` refuses table writes unless the event opts in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Environment {
+    Dev,
+    Staging,
+    Prod,
+}
+
+impl Environment {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Environment::Dev => "dev",
+            Environment::Staging => "staging",
+            Environment::Prod => "prod",
+        }
+    }
+}
+
+/// How the process serves, from `RIDE_DATA_MODE` (default `lambda`).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+    Lambda,
+    Http,
+    Soak,
+}
+
+/// Every problem in the resolved configuration, one per line; empty when it is usable.
+pub fn validate() -> Result<(), String> {
+    let mut problems = Vec::new();
+    let config = Config::from_env(&mut problems);
+
+    if var("DATADOG_EXPORTER").is_some() && !cfg!(feature = "datadog") {
+        problems.push("DATADOG_EXPORTER is set but this build lacks the datadog feature".to_string());
+    }
+    if let ride::RideWindow { start: Some(start), end: Some(end) } = config.ride_window {
+        if start > end {
+            problems.push("RIDE_WINDOW_START must not be after RIDE_WINDOW_END".to_string());
+        }
+    }
+    match (&config.report_email_sender, config.report_email_recipients.as_slice()) {
+        (Some(_), []) => problems.push("REPORT_EMAIL_SENDER is set but REPORT_EMAIL_RECIPIENTS is not".to_string()),
+        (None, [_, ..]) => problems.push("REPORT_EMAIL_RECIPIENTS is set but REPORT_EMAIL_SENDER is not".to_string()),
+        (Some(sender), recipients) => {
+            for address in std::iter::once(sender).chain(recipients) {
+                if !address.contains('@') {
+                    problems.push(format!("REPORT_EMAIL_SENDER/REPORT_EMAIL_RECIPIENTS: {:?} is not an email address", address));
+                }
+            }
+        }
+        (None, []) => {}
+    }
+
+    #[cfg(feature = "chaos")]
+    crate::chaos::validate(&mut problems, &config);
+
+    if config.mode == Mode::Soak {
+        crate::soak::validate(&mut problems, &config);
+    }
+
+    if config.fanout_queue_url.is_some() && config.report_bucket.is_none() {
+        problems.push("FANOUT_QUEUE_URL is set but REPORT_BUCKET, where fan-out jobs keep their shards, is not".to_string());
+    }
+    if config.legacy_aggregates_table.as_ref() == Some(&config.aggregates_table) {
+        problems.push("LEGACY_AGGREGATES_TABLE must name the baseline table, not AGGREGATES_TABLE".to_string());
+    }
+    for table in tables(&config).into_iter().chain(config.legacy_aggregates_table.as_deref()) {
+        let valid_chars = table.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'));
+        if !(3..=255).contains(&table.len()) || !valid_chars {
+            problems.push(format!("table name {:?} is not a valid DynamoDB table name", table));
+        }
+    }
+
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("invalid configuration:\n  {}", problems.join("\n  ")))
+    }
+}
+
+/// The resolved configuration with secrets masked, for debug logs.
+pub fn echo() -> Value {
+    let config = get();
+    json!({
+        "environment": config.environment.as_str(),
+        "log_level": var("LOG_LEVEL").unwrap_or_else(|| "info".to_string()),
+        "mode": var("RIDE_DATA_MODE").unwrap_or_else(|| "lambda".to_string()),
+        "region": config.region,
+        "timezone": config.utc_offset.to_string(),
+        "fixed_now": config.fixed_now.map(|now| now.to_rfc3339()),
+        "tables": tables(config),
+        "legacy_aggregates_table": config.legacy_aggregates_table,
+        "event_parsing": var("EVENT_PARSING").unwrap_or_else(|| "lenient".to_string()),
+        "default_ride_month": config.default_ride_month.as_str(),
+        "ride_window": config.ride_window.label(),
+        "revision_threshold_km": config.revision_threshold_km,
+        "hot_partition_latency_ms": partitions::latency_threshold_from_env().as_millis() as u64,
+        "clock_skew_tolerance_secs": config.clock_skew_tolerance.as_secs(),
+        "timezone_drift_threshold_mins": config.timezone_drift_threshold_mins,
+        "selftest_month": config.selftest_month,
+        "firmware_distance_units": var("FIRMWARE_DISTANCE_UNITS"),
+        "decimal_separator": var("DECIMAL_SEPARATOR").unwrap_or_else(|| "auto".to_string()),
+        "max_ride_distance_km": config.max_ride_distance_km,
+        "anomaly_action": var("ANOMALY_ACTION").unwrap_or_else(|| "exclude".to_string()),
+        "anomaly_topic_arn": config.anomaly_topic_arn,
+        "distance_alert_topic_arn": config.distance_alert_topic_arn,
+        "distance_alert_hysteresis_pct": config.distance_alert_hysteresis_pct,
+        "stream_flush_secs": config.stream_flush_every.map(|every| every.as_secs()),
+        "lifecycle_event_bus": config.lifecycle_event_bus,
+        "devices_tac_index": config.devices_tac_index,
+        "imei_pattern_limit": config.imei_pattern_limit,
+        "maintenance_event_bus": config.maintenance_event_bus,
+        "service_interval_km": config.service_interval_km,
+        "stream_flush_records": config.stream_flush_records,
+        "stream_dedupe_secs": config.stream_dedupe_window.as_secs(),
+        "stream_dedupe_capacity": config.stream_dedupe_capacity,
+        "imei_concurrency": crate::imei_concurrency_from_env(),
+        "service_quotas": crate::quotas::get(),
+        "dynamodb_max_attempts": config.dynamodb_max_attempts,
+        "dynamodb_base_delay_ms": config.dynamodb_base_delay.as_millis() as u64,
+        "ride_retention_months": config.ride_retention_months,
+        "report_bucket": config.report_bucket,
+        "archive_bucket": config.archive_bucket,
+        "response_bucket": config.response_bucket,
+        "response_spill_rows": config.response_spill_rows,
+        "multipart_threshold_bytes": config.multipart_threshold_bytes,
+        "upload_concurrency": config.upload_concurrency,
+        "presign_expiry_secs": crate::s3::presign_expiry(None).as_secs(),
+        "export_part_rows": config.export_part_rows,
+        "export_kms_key_id": config.export_kms_key_id,
+        "fail_if_error_rate_above": config.fail_if_error_rate_above,
+        "fanout_queue_url": config.fanout_queue_url,
+        "fanout_backfill_queue_url": config.fanout_backfill_queue_url,
+        "fanout_backfill_defer_secs": config.fanout_backfill_defer_secs,
+        "fanout_max_deferrals": config.fanout_max_deferrals,
+        "backfill_queue_url": config.backfill_queue_url,
+        "backfill_chunk_size": config.backfill_chunk_size,
+        "aggregate_cache": config.aggregate_cache,
+        "datadog_exporter": var("DATADOG_EXPORTER"),
+        "sentry": config.sentry_dsn.is_some(),
+        "experiments": config.experiments.iter().map(|experiment| experiment.name()).collect::<Vec<_>>(),
+        "shadow": config.shadow_function_name.as_ref().map(|function| json!({
+            "function_name": function,
+            "sample_percent": config.shadow_sample_percent,
+            "tolerance_km": config.shadow_tolerance_km,
+        })),
+        "query_strategy": var("QUERY_STRATEGY").unwrap_or_else(|| "auto".to_string()),
+        "aggregate_write_mode": var("AGGREGATE_WRITE_MODE").unwrap_or_else(|| "put".to_string()),
+        "ride_signing_key_prefix": config.ride_signing_key_prefix,
+        "report_email_sender": config.report_email_sender,
+        "report_email_recipients": config.report_email_recipients,
+        "summary_webhook_url": config.summary_webhook_url.as_deref().map(mask_url),
+        "summary_webhook_kind": var("SUMMARY_WEBHOOK_KIND").unwrap_or_else(|| "slack".to_string()),
+    })
+}
+
+#[cfg(test)]
+thread_local! {
+    /// Read instead of the process environment while set, so tests neither race nor change it.
+    static TEST_VARS: std::cell::RefCell<Option<std::collections::HashMap<String, String>>> = const { std::cell::RefCell::new(None) };
+}
+
+fn var(name: &str) -> Option<String> {
+    #[cfg(test)]
+    if let Some(value) = TEST_VARS.with_borrow(|vars| vars.as_ref().map(|vars| vars.get(name).cloned())) {
+        return value.filter(|v| !v.is_empty());
+    }
+    std::env::var(name).ok().filter(|v| !v.is_empty())
+}
+
+/// The comma-separated entries of `name`, trimmed.
+fn list(name: &str) -> Vec<String> {
+    var(name).unwrap_or_default().split(',').map(str::trim).filter(|entry| !entry.is_empty()).map(str::to_string).collect()
+}
+
+fn one_of<T: Copy>(problems: &mut Vec<String>, name: &str, allowed: &[(&str, T)]) -> Option<T> {
+    let value = var(name)?;
+    let found = allowed.iter().find(|(allowed, _)| *allowed == value).map(|(_, parsed)| *parsed);
+    if found.is_none() {
+        let names: Vec<&str> = allowed.iter().map(|(allowed, _)| *allowed).collect();
+        problems.push(format!("{} must be one of {} (got {:?})", name, names.join(", "), value));
+    }
+    found
+}
+
+fn number<T: FromStr>(problems: &mut Vec<String>, name: &str, valid: impl Fn(&T) -> bool, expected: &str) -> Option<T> {
+    let value = var(name)?;
+    let parsed = value.parse::<T>().ok().filter(|parsed| valid(parsed));
+    if parsed.is_none() {
+        problems.push(format!("{} must be {} (got {:?})", name, expected, value));
+    }
+    parsed
+}
+
+fn date(problems: &mut Vec<String>, name: &str) -> Option<NaiveDate> {
+    let value = var(name)?;
+    let parsed = NaiveDate::parse_from_str(&value, "%Y-%m-%d").ok();
+    if parsed.is_none() {
+        problems.push(format!("{} must be a YYYY-MM-DD date (got {:?})", name, value));
+    }
+    parsed
+}
+
+/// Webhook URLs carry their credentials in the path, so only the host is echoed.
+fn mask_url(url: &str) -> String {
+    let host_end = url.find("://").map(|i| i + 3).and_then(|start| url[start..].find('/').map(|i| start + i));
+    match host_end {
+        Some(end) => format!("{}/***", &url[..end]),
+        None => "***".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs `f` with `vars` as the whole environment.
+    fn with_env<T>(vars: &[(&str, &str)], f: impl FnOnce() -> T) -> T {
+        let vars = vars.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect();
+        TEST_VARS.set(Some(vars));
+        let result = f();
+        TEST_VARS.set(None);
+        result
+    }
+
+    fn from_env(vars: &[(&str, &str)]) -> (Config, Vec<String>) {
+        with_env(vars, || {
+            let mut problems = Vec::new();
+            (Config::from_env(&mut problems), problems)
+        })
+    }
+
+    #[test]
+    fn unset_and_empty_variables_read_as_their_defaults() {
+        let (config, problems) = from_env(&[("RIDE_TABLE", "")]);
+        assert!(problems.is_empty(), "{:?}", problems);
+        assert_eq!((config.ride_table.as_str(), config.aggregates_table.as_str()), ("ride_data", "ride_data_monthly_distance"));
+        assert_eq!(config.region, "ap-south-1");
+        assert_eq!(config.utc_offset, FixedOffset::east_opt(5 * 3600 + 30 * 60).unwrap());
+        assert_eq!((config.imei_concurrency, config.aggregate_write_mode), (8, aggregates::WriteMode::Put));
+        assert_eq!(config.query_strategy, None);
+    }
+
+    #[test]
+    fn set_variables_override_the_defaults() {
+        let (config, problems) = from_env(&[
+            ("RIDE_TABLE", "staging_rides"),
+            ("AGGREGATES_TABLE", "staging_monthly"),
+            ("RIDE_DATA_REGION", "eu-west-1"),
+            ("REPORTING_UTC_OFFSET", "+01:00"),
+            ("IMEI_CONCURRENCY", "2"),
+        ]);
+        assert!(problems.is_empty(), "{:?}", problems);
+        assert_eq!((config.ride_table.as_str(), config.aggregates_table.as_str()), ("staging_rides", "staging_monthly"));
+        assert_eq!(config.region, "eu-west-1");
+        assert_eq!(config.utc_offset, FixedOffset::east_opt(3600).unwrap());
+        assert_eq!(config.imei_concurrency, 2);
+    }
+
+    #[test]
+    fn invalid_values_are_each_reported_and_read_as_unset() {
+        let (config, problems) = from_env(&[("IMEI_CONCURRENCY", "many"), ("REPORTING_UTC_OFFSET", "IST"), ("RIDE_RETENTION_MONTHS", "-3")]);
+        assert_eq!(problems, [
+            r#"REPORTING_UTC_OFFSET must be an offset like +05:30 (got "IST")"#,
+            r#"IMEI_CONCURRENCY must be a positive whole number (got "many")"#,
+            r#"RIDE_RETENTION_MONTHS must be a whole number of months (got "-3")"#,
+        ]);
+        assert_eq!(config.imei_concurrency, 8);
+        assert_eq!(config.utc_offset, Utc.fix());
+        assert_eq!(config.ride_retention_months, None);
+    }
+
+    #[test]
+    fn the_defaults_are_valid() {
+        assert_eq!(with_env(&[], validate), Ok(()));
+    }
+
+    #[test]
+    fn bad_values_are_reported_rather_than_defaulted() {
+        let cases: &[(&[(&str, &str)], &str)] = &[
+            (&[("IMEI_CONCURRENCY", "eight")], r#"IMEI_CONCURRENCY must be a positive whole number (got "eight")"#),
+            (&[("IMEI_CONCURRENCY", "0")], r#"IMEI_CONCURRENCY must be a positive whole number (got "0")"#),
+            (&[("QUERY_STRATEGY", "fastest")], r#"QUERY_STRATEGY must be one of auto, per_imei, date_index, scan (got "fastest")"#),
+            (&[("AGGREGATE_WRITE_MODE", "upsert")], r#"AGGREGATE_WRITE_MODE must be one of put, update (got "upsert")"#),
+            (&[("REPORTING_UTC_OFFSET", "IST")], r#"REPORTING_UTC_OFFSET must be an offset like +05:30 (got "IST")"#),
+            (&[("FANOUT_QUEUE_URL", "https://sqs.ap-south-1.amazonaws.com/1/fanout")], "FANOUT_QUEUE_URL is set but REPORT_BUCKET, where fan-out jobs keep their shards, is not"),
+        ];
+        for (vars, problem) in cases {
+            let error = with_env(vars, validate).unwrap_err();
+            assert_eq!(error, format!("invalid configuration:\n  {}", problem), "{:?}", vars);
+        }
+        let fan_out = [("FANOUT_QUEUE_URL", "https://sqs.ap-south-1.amazonaws.com/1/fanout"), ("REPORT_BUCKET", "reports")];
+        assert_eq!(with_env(&fan_out, validate), Ok(()));
+    }
+}
@@ -0,0 +1,216 @@
+use aws_sdk_dynamodb::Client;
+use chrono::SecondsFormat;
+use lambda_runtime::Error;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tracing::warn;
+use utoipa::ToSchema;
+
+use crate::aggregates::{self, AdjustOutcome, SkipReason};
+use crate::clock::Clock;
+use crate::cost::CapacityMeter;
+use crate::store::RideStore;
+use crate::time::Granularity;
+use crate::{aggregate_ride_data, history, ride, Action, CustomEvent, ErrorOutput};
+
+/// A ride whose distance was corrected, or that was voided, after it had been aggregated.
+#[derive(Debug, Clone, Deserialize, JsonSchema, ToSchema)]
+pub struct RideCorrection {
+    pub ride_id: String,
+    pub imei: String,
+    /// Epoch seconds; locates the month the ride was counted in.
+    pub ride_start: u64,
+    /// Distance the aggregate currently includes for this ride.
+    pub old_distance: f64,
+    /// Corrected distance; ignored (treated as 0) for `ride_voided`.
+    #[serde(default)]
+    pub new_distance: f64,
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct CorrectionOutput {
+    ride_id: String,
+    imei: String,
+    ride_month: String,
+    delta: f64,
+    #[serde(flatten)]
+    outcome: AdjustOutcome,
+}
+
+/// `action: "ride_corrected"`: moves the affected (imei, month) total by `new - old` with a single
+/// compensating update instead of recomputing the month. A redelivered event finds its correction
+/// already listed on the row and is not applied again.
+///
+/// `action: "ride_voided"`: the ride table already marks the ride deleted, so the month is recounted
+/// (see [`recount_month`]); `delta` reports the ride's distance leaving the total.
+pub async fn compensate_ride(client: &Client, payload: &CustomEvent, run_id: &str, clock: &Clock) -> Result<Value, Error> {
+    let Some(correction) = &payload.correction else {
+        return Ok(json!(ErrorOutput { error: "correction is required".to_string() }));
+    };
+    let Some(ride_month) = ride::ride_month(correction.ride_start).filter(|_| ride::window().contains(correction.ride_start)) else {
+        return Ok(json!(ErrorOutput { error: "corrected ride is outside the aggregated window".to_string() }));
+    };
+
+    if payload.action == Action::RideVoided {
+        let outcome = recount_month(client, payload, correction, &ride_month, run_id, clock).await?;
+        if !matches!(outcome, AdjustOutcome::Applied { .. }) {
+            warn!("Recount for voided ride {} not applied: {:?}", correction.ride_id, outcome);
+        }
+        return Ok(json!(CorrectionOutput {
+            ride_id: correction.ride_id.clone(),
+            imei: correction.imei.clone(),
+            ride_month,
+            delta: -correction.old_distance,
+            outcome,
+        }));
+    }
+
+    let delta = correction.new_distance - correction.old_distance;
+    let as_of = clock.now().to_rfc3339_opts(SecondsFormat::Secs, true);
+    let correction_id = format!("ride_corrected:{}:{}:{}", correction.ride_id, correction.old_distance, correction.new_distance);
+    let adjustment = aggregates::Adjustment {
+        imei: &correction.imei,
+        ride_month: &ride_month,
+        correction_id: &correction_id,
+        delta,
+        as_of: &as_of,
+        force: payload.force.unwrap_or(false),
+    };
+    let outcome = aggregates::adjust_row(client, &adjustment, &CapacityMeter::default()).await?;
+
+    if let AdjustOutcome::Applied { total_distance } = outcome {
+        if delta.abs() > history::threshold_from_env() {
+            let reason = format!("ride_corrected:{}", correction.ride_id);
+            let revision = history::Revision {
+                imei: &correction.imei,
+                granularity: Granularity::Monthly,
+                ride_month: &ride_month,
+                previous_value: total_distance - delta,
+                new_value: total_distance,
+                run_id,
+                reason: &reason,
+                revised_at: &as_of,
+            };
+            history::record(client, &revision, &CapacityMeter::default()).await?;
+        }
+    } else {
+        warn!("Compensation for ride {} not applied: {:?}", correction.ride_id, outcome);
+    }
+
+    Ok(json!(CorrectionOutput {
+        ride_id: correction.ride_id.clone(),
+        imei: correction.imei.clone(),
+        ride_month,
+        delta,
+        outcome,
+    }))
+}
+
+/// Recounts the voided ride's month as a run would, so its count, duration and averages drop with
+/// its distance, and the `FLEET#*` rollup and revision history follow the row. A redelivered event
+/// recounts to the same row and leaves it unchanged.
+pub(crate) async fn recount_month(
+    store: &impl RideStore,
+    payload: &CustomEvent,
+    correction: &RideCorrection,
+    ride_month: &str,
+    run_id: &str,
+    clock: &Clock,
+) -> Result<AdjustOutcome, Error> {
+    let imeis = [correction.imei.clone()];
+    let month_payload = CustomEvent {
+        imeis: imeis.to_vec(),
+        input_ride_month: Some(ride_month.to_string()),
+        granularity: None,
+        start_date: None,
+        end_date: None,
+        // A month left without rides is still written, as zeros.
+        fill_gaps: Some(true),
+        reason: Some(payload.reason.clone().unwrap_or_else(|| format!("ride_voided:{}", correction.ride_id))),
+        ..payload.clone()
+    };
+    let aggregation = aggregate_ride_data(store, &month_payload, &imeis, run_id, clock, None, &CapacityMeter::default()).await?;
+    if let Some(error) = aggregation.errors.first() {
+        return Err(format!("Recounting imei {} month {} failed: {:?}", correction.imei, ride_month, error.failure).into());
+    }
+    let row = aggregation.rows.iter().find(|row| row.granularity == Granularity::Monthly && row.ride_month == ride_month);
+    Ok(match row.map(|row| (row.total_distance, row.write_skipped)) {
+        None => AdjustOutcome::Missing,
+        Some((total_distance, None)) => AdjustOutcome::Applied { total_distance },
+        Some((_, Some(SkipReason::Finalized | SkipReason::PreservedFinal))) => AdjustOutcome::Finalized,
+        Some((_, Some(SkipReason::Unchanged))) => AdjustOutcome::AlreadyApplied,
+        Some((_, Some(reason))) => AdjustOutcome::Kept { reason },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, Fake};
+
+    const APRIL: u64 = 1_712_707_200;
+
+    fn corrected(old_distance: f64, new_distance: f64) -> CustomEvent {
+        CustomEvent {
+            action: Action::RideCorrected,
+            correction: Some(RideCorrection { ride_id: "r1".to_string(), imei: "111".to_string(), ride_start: APRIL, old_distance, new_distance }),
+            ..Default::default()
+        }
+    }
+
+    fn clock() -> Clock {
+        Clock::resolve(Some("2024-06-01T00:00:00Z")).unwrap()
+    }
+
+    fn row(total_distance: f64, extra: Value) -> Value {
+        let mut row = json!({
+            "imei": {"S": "111"},
+            "period": {"S": aggregates::sort_key(Granularity::Monthly, "2024-04")},
+            "total_distance": {"N": total_distance.to_string()},
+        });
+        row.as_object_mut().unwrap().extend(extra.as_object().unwrap().clone());
+        row
+    }
+
+    /// Answers the adjustment's `UpdateItem` with `update`, and everything else with `{}`.
+    fn fake(update: testing::Response) -> Fake {
+        Fake::new(move |request| match request.is("UpdateItem") {
+            true => update.clone(),
+            false => testing::ok(json!({})),
+        })
+    }
+
+    #[tokio::test]
+    async fn a_redelivered_correction_is_not_applied_again() {
+        let stored = row(12.0, json!({"corrected_rides": {"SS": ["ride_corrected:r1:10:12"]}}));
+        let fake = fake(testing::error("ConditionalCheckFailedException", json!({"Item": stored})));
+        let output = compensate_ride(&fake.dynamodb(), &corrected(10.0, 12.0), "run", &clock()).await.unwrap();
+        assert_eq!(output["status"], "already_applied");
+        let update = &fake.sent("UpdateItem")[0];
+        assert_eq!(update["ExpressionAttributeValues"][":corrections"], json!({"SS": ["ride_corrected:r1:10:12"]}));
+        assert!(update["ConditionExpression"].as_str().unwrap().contains("NOT contains(#corrected, :correction)"));
+        assert!(fake.sent("TransactWriteItems").is_empty());
+        assert!(fake.sent("PutItem").is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_finalized_month_is_not_corrected() {
+        let stored = row(10.0, json!({"finalized": {"BOOL": true}, "corrected_rides": {"SS": ["ride_corrected:r2:3:4"]}}));
+        let fake = fake(testing::error("ConditionalCheckFailedException", json!({"Item": stored})));
+        let output = compensate_ride(&fake.dynamodb(), &corrected(10.0, 12.0), "run", &clock()).await.unwrap();
+        assert_eq!(output["status"], "finalized");
+        assert!(fake.sent("TransactWriteItems").is_empty());
+    }
+
+    #[tokio::test]
+    async fn an_applied_correction_moves_the_fleet_rollup_by_its_delta() {
+        let fake = fake(testing::ok(json!({"Attributes": row(12.0, json!({}))})));
+        let output = compensate_ride(&fake.dynamodb(), &corrected(10.0, 12.0), "run", &clock()).await.unwrap();
+        assert_eq!(output["status"], "applied");
+        let rollup = &fake.sent("TransactWriteItems")[0]["TransactItems"];
+        let add = rollup.as_array().unwrap().iter().find_map(|item| item.get("Update")).unwrap();
+        assert!(add["Key"]["imei"]["S"].as_str().unwrap().starts_with("FLEET#"));
+        assert!(add["ExpressionAttributeValues"].to_string().contains("\"N\":\"2\""), "{}", add);
+    }
+}
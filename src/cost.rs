@@ -0,0 +1,68 @@
+use aws_sdk_dynamodb::types::ConsumedCapacity;
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::sync::Mutex;
+use std::time::Duration;
+use utoipa::ToSchema;
+
+/// Totals the DynamoDB capacity units reported back by requests sent with `ReturnConsumedCapacity::Total`.
+#[derive(Debug, Default)]
+pub struct CapacityMeter {
+    units: Mutex<(f64, f64)>,
+}
+
+impl CapacityMeter {
+    pub fn read(&self, consumed: Option<&ConsumedCapacity>) {
+        let units = consumed.and_then(|c| c.capacity_units()).unwrap_or_default();
+        self.units.lock().unwrap().0 += units;
+    }
+
+    pub fn write(&self, consumed: Option<&ConsumedCapacity>) {
+        let units = consumed.and_then(|c| c.capacity_units()).unwrap_or_default();
+        self.units.lock().unwrap().1 += units;
+    }
+
+    /// Prices the capacity used so far plus `elapsed` of Lambda compute at the function's configured memory.
+    pub fn run_cost(&self, elapsed: Duration) -> RunCost {
+        let (read_units, write_units) = *self.units.lock().unwrap();
+        let pricing = Pricing::from_env();
+        let gb_seconds = pricing.memory_mb / 1024.0 * elapsed.as_secs_f64();
+        let estimated_usd = read_units * pricing.read_per_million / 1e6
+            + write_units * pricing.write_per_million / 1e6
+            + gb_seconds * pricing.per_gb_second;
+        RunCost { read_units, write_units, gb_seconds, estimated_usd }
+    }
+}
+
+/// On-demand prices in USD (ap-south-1 defaults), overridable with `DYNAMODB_READ_PRICE_PER_MILLION`,
+/// `DYNAMODB_WRITE_PRICE_PER_MILLION` and `LAMBDA_PRICE_PER_GB_SECOND`. Memory comes from
+/// `AWS_LAMBDA_FUNCTION_MEMORY_SIZE`, so compute is not charged outside Lambda.
+struct Pricing {
+    read_per_million: f64,
+    write_per_million: f64,
+    per_gb_second: f64,
+    memory_mb: f64,
+}
+
+impl Pricing {
+    fn from_env() -> Pricing {
+        let config = crate::config::get();
+        Pricing {
+            read_per_million: config.dynamodb_read_price_per_million,
+            write_per_million: config.dynamodb_write_price_per_million,
+            per_gb_second: config.lambda_price_per_gb_second,
+            memory_mb: config.function_memory_mb,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, JsonSchema, ToSchema)]
+pub struct RunCost {
+    /// DynamoDB read capacity units consumed.
+    pub read_units: f64,
+    /// DynamoDB write capacity units consumed.
+    pub write_units: f64,
+    /// Lambda compute used by this request.
+    pub gb_seconds: f64,
+    pub estimated_usd: f64,
+}
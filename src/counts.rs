@@ -0,0 +1,111 @@
+//! `metrics: ["ride_count"]`: counts each device-month's rides with `Select=COUNT` queries instead
+//! of reading them. The filters run in DynamoDB, so `ride_type` must match exactly: un-normalized
+//! values such as `Trip ` are not counted, unlike in a full aggregation.
+
+use aws_sdk_dynamodb::types::{AttributeValue, ReturnConsumedCapacity, Select};
+use aws_sdk_dynamodb::Client;
+use chrono::Utc;
+use futures::stream::{self, StreamExt, TryStreamExt};
+use lambda_runtime::Error;
+use schemars::JsonSchema;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::time::Instant;
+
+use crate::cost::{CapacityMeter, RunCost};
+use crate::{aliases, imei_concurrency_from_env, metrics, ride, ride_query, time, CustomEvent, ErrorOutput};
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct RideCountOutput {
+    imei: String,
+    ride_month: String,
+    ride_count: u64,
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct RideCountResponse {
+    results: Vec<RideCountOutput>,
+    cost: RunCost,
+}
+
+pub async fn ride_counts(client: &Client, payload: &CustomEvent, imeis: &[String]) -> Result<Value, Error> {
+    let started = Instant::now();
+    let ranges = match (&payload.input_ride_month, payload.date_range()) {
+        (Some(month), _) => time::month_range(month).map(|range| vec![(month.clone(), range)]),
+        (None, Some(range)) if payload.granularity == Some(time::Granularity::Range) => range.bounds().map(|bounds| vec![(range.label(), bounds)]),
+        (None, Some(range)) => range.months(),
+        (None, None) => match ride::window().months_until(&time::month_of(Utc::now())?)? {
+            Some(months) => months.into_iter().map(|month| time::month_range(&month).map(|range| (month, range))).collect(),
+            None => return Ok(json!(ErrorOutput { error: "ride counts without a period need RIDE_WINDOW_START".to_string() })),
+        },
+    };
+    let ranges = match ranges {
+        Ok(ranges) => ranges,
+        Err(err) => return Ok(json!(ErrorOutput { error: err.to_string() })),
+    };
+    let ride_types = payload.ride_types();
+    let counted = ride_types.counted();
+    if counted.is_empty() {
+        return Ok(json!(ErrorOutput { error: "ride_types_exclude leaves no ride_type to count".to_string() }));
+    }
+
+    let meter = CapacityMeter::default();
+    let mut queries = Vec::new();
+    for device in aliases::resolve(client, imeis).await? {
+        for identity in &device.identities {
+            for (month, range) in &ranges {
+                queries.push((device.imei.clone(), identity.clone(), month.clone(), *range));
+            }
+        }
+    }
+    let (meter_ref, counted) = (&meter, &counted);
+    let counts: Vec<(String, String, u64)> = stream::iter(queries)
+        .map(|(device, identity, month, range)| async move {
+            let rides = count(client, &identity, range, counted, meter_ref).await?;
+            Ok::<_, Error>((device, month, rides))
+        })
+        .buffered(imei_concurrency_from_env())
+        .try_collect()
+        .await?;
+
+    let mut totals: BTreeMap<(String, String), u64> = BTreeMap::new();
+    for (device, month, rides) in counts {
+        *totals.entry((device, month)).or_default() += rides;
+    }
+    let results = totals.into_iter()
+        .filter(|(_, ride_count)| *ride_count > 0)
+        .map(|((imei, ride_month), ride_count)| RideCountOutput { imei, ride_month, ride_count })
+        .collect();
+    Ok(json!(RideCountResponse { results, cost: meter.run_cost(started.elapsed()) }))
+}
+
+/// Rides of one IMEI starting in `[from, to)` whose `ride_type` is counted and that are not voided.
+async fn count(client: &Client, imei: &str, range: (i64, i64), counted: &[&str], meter: &CapacityMeter) -> Result<u64, Error> {
+    let placeholders: Vec<String> = (0..counted.len()).map(|i| format!(":type{}", i)).collect();
+    let mut request = ride_query(client, imei, Some(range))
+        .select(Select::Count)
+        .filter_expression(format!(
+            "#ride_type IN ({}) AND (attribute_not_exists(#deleted) OR NOT #deleted IN (:true, :true_s)) \
+             AND (attribute_not_exists(#tombstone) OR NOT #tombstone IN (:true, :true_s))",
+            placeholders.join(", "),
+        ))
+        .expression_attribute_names("#ride_type", "ride_type")
+        .expression_attribute_names("#deleted", "deleted")
+        .expression_attribute_names("#tombstone", "tombstone")
+        .expression_attribute_values(":true", AttributeValue::Bool(true))
+        .expression_attribute_values(":true_s", AttributeValue::S("true".to_string()))
+        .return_consumed_capacity(ReturnConsumedCapacity::Total);
+    for (placeholder, ride_type) in placeholders.iter().zip(counted) {
+        request = request.expression_attribute_values(placeholder, AttributeValue::S(ride_type.to_string()));
+    }
+
+    let mut pages = request.into_paginator().send();
+    let mut rides = 0;
+    while let Some(page) = pages.next().await {
+        let page = page.inspect_err(|_| metrics::dynamodb_error("query"))?;
+        meter.read(page.consumed_capacity());
+        rides += page.count() as u64;
+    }
+    Ok(rides)
+}
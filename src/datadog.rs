@@ -0,0 +1,286 @@
+//! Metrics and traces for Datadog (the `datadog` feature), sent after each invocation while the
+//! Lambda is still running. `DATADOG_EXPORTER=extension` hands them to the Datadog Lambda extension:
+//! metrics as DogStatsD on 127.0.0.1:8125 and traces to its trace agent on 127.0.0.1:8126.
+//! `DATADOG_EXPORTER=otlp` posts both as OTLP/HTTP JSON to `OTEL_EXPORTER_OTLP_ENDPOINT` (default
+//! `http://127.0.0.1:4318`), for a collector or the extension's OTLP receiver. Unset, nothing is sent.
+//!
+//! Metrics are the Prometheus registry's, counters and histogram sums and counts as the increase
+//! since the last send; traces are this crate's `tracing` spans (`request`, `imei`, ...) with
+//! their fields as tags. Everything is tagged with `DD_SERVICE` (default `ride-data`) and `DD_ENV`
+//! (default `ENVIRONMENT`).
+
+use prometheus::proto::MetricType;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{warn, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+const DOGSTATSD_ADDR: &str = "127.0.0.1:8125";
+const TRACE_AGENT_URL: &str = "http://127.0.0.1:8126/v0.4/traces";
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Exporter {
+    Extension,
+    Otlp,
+}
+
+fn exporter() -> Option<Exporter> {
+    crate::config::get().datadog_exporter
+}
+
+fn service() -> String {
+    crate::config::get().datadog_service.clone()
+}
+
+fn env() -> String {
+    crate::config::get().datadog_env.clone()
+}
+
+fn otlp_endpoint() -> String {
+    crate::config::get().otlp_endpoint.clone()
+}
+
+fn nanos(at: SystemTime) -> u64 {
+    at.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_nanos() as u64)
+}
+
+/// A span that has closed, waiting for the next send.
+#[derive(Debug, Clone)]
+struct FinishedSpan {
+    trace_id: u128,
+    span_id: u64,
+    parent_id: u64,
+    name: &'static str,
+    start: u64,
+    end: u64,
+    tags: BTreeMap<String, String>,
+}
+
+static SPANS: Mutex<Vec<FinishedSpan>> = Mutex::new(Vec::new());
+
+/// Counter values as last sent, by metric name and labels.
+static SENT: Mutex<Option<HashMap<String, f64>>> = Mutex::new(None);
+
+/// Timing and fields of an open span, kept in its extensions.
+struct OpenSpan {
+    trace_id: u128,
+    span_id: u64,
+    parent_id: u64,
+    start: SystemTime,
+    tags: BTreeMap<String, String>,
+}
+
+struct Tags<'a>(&'a mut BTreeMap<String, String>);
+
+impl Visit for Tags<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), value.to_string());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.0.insert(field.name().to_string(), format!("{:?}", value));
+    }
+}
+
+/// Records this crate's spans for [`flush`]; a no-op without `DATADOG_EXPORTER`.
+pub struct SpanLayer {
+    enabled: bool,
+}
+
+pub fn layer() -> SpanLayer {
+    SpanLayer { enabled: exporter().is_some() }
+}
+
+impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for SpanLayer {
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        if !self.enabled || !attrs.metadata().target().starts_with(env!("CARGO_CRATE_NAME")) {
+            return;
+        }
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let parent = span.scope().skip(1).find_map(|parent| parent.extensions().get::<OpenSpan>().map(|open| (open.trace_id, open.span_id)));
+        let (trace_id, parent_id) = parent.unwrap_or_else(|| (fastrand::u128(1..), 0));
+        let mut tags = BTreeMap::new();
+        attrs.record(&mut Tags(&mut tags));
+        span.extensions_mut().insert(OpenSpan { trace_id, span_id: fastrand::u64(1..), parent_id, start: SystemTime::now(), tags });
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(open) = span.extensions_mut().get_mut::<OpenSpan>() {
+                values.record(&mut Tags(&mut open.tags));
+            }
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(&id) else {
+            return;
+        };
+        let Some(open) = span.extensions_mut().remove::<OpenSpan>() else {
+            return;
+        };
+        SPANS.lock().unwrap().push(FinishedSpan {
+            trace_id: open.trace_id,
+            span_id: open.span_id,
+            parent_id: open.parent_id,
+            name: span.name(),
+            start: nanos(open.start),
+            end: nanos(SystemTime::now()),
+            tags: open.tags,
+        });
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Count,
+    Gauge,
+}
+
+struct Sample {
+    name: String,
+    labels: Vec<(String, String)>,
+    kind: Kind,
+    value: f64,
+}
+
+/// The registry's values, counters as their increase since the last call.
+fn samples() -> Vec<Sample> {
+    let mut current = Vec::new();
+    for family in prometheus::gather() {
+        let name = family.get_name().replacen("ride_data_", "ride_data.", 1);
+        for metric in family.get_metric() {
+            let labels: Vec<(String, String)> = metric.get_label().iter().map(|label| (label.get_name().to_string(), label.get_value().to_string())).collect();
+            match family.get_field_type() {
+                MetricType::COUNTER => current.push((name.clone(), labels, Kind::Count, metric.get_counter().get_value())),
+                MetricType::GAUGE => current.push((name.clone(), labels, Kind::Gauge, metric.get_gauge().get_value())),
+                MetricType::HISTOGRAM => {
+                    let histogram = metric.get_histogram();
+                    current.push((format!("{}.sum", name), labels.clone(), Kind::Count, histogram.get_sample_sum()));
+                    current.push((format!("{}.count", name), labels, Kind::Count, histogram.get_sample_count() as f64));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    let mut sent = SENT.lock().unwrap();
+    let sent = sent.get_or_insert_with(HashMap::new);
+    current.into_iter().filter_map(|(name, labels, kind, value)| {
+        if kind == Kind::Gauge {
+            return Some(Sample { name, labels, kind, value });
+        }
+        let key = format!("{}{:?}", name, labels);
+        let delta = value - sent.insert(key, value).unwrap_or(0.0);
+        (delta > 0.0).then_some(Sample { name, labels, kind, value: delta })
+    }).collect()
+}
+
+/// Sends the metrics and the spans closed since the last call.
+pub async fn flush() {
+    let Some(exporter) = exporter() else {
+        return;
+    };
+    let samples = samples();
+    let spans = std::mem::take(&mut *SPANS.lock().unwrap());
+    let result = match exporter {
+        Exporter::Extension => send_to_extension(&samples, &spans).await,
+        Exporter::Otlp => send_otlp(&samples, &spans).await,
+    };
+    if let Err(err) = result {
+        warn!("Error sending metrics and traces to Datadog: {:?}", err);
+    }
+}
+
+async fn send_to_extension(samples: &[Sample], spans: &[FinishedSpan]) -> anyhow::Result<()> {
+    let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await?;
+    let common = format!("service:{},env:{}", service(), env());
+    for sample in samples {
+        let tags: String = sample.labels.iter().map(|(name, value)| format!(",{}:{}", name, value)).collect();
+        let kind = match sample.kind {
+            Kind::Count => "c",
+            Kind::Gauge => "g",
+        };
+        socket.send_to(format!("{}:{}|{}|#{}{}", sample.name, sample.value, kind, common, tags).as_bytes(), DOGSTATSD_ADDR).await?;
+    }
+    if spans.is_empty() {
+        return Ok(());
+    }
+
+    let (service, env) = (service(), env());
+    let mut traces: BTreeMap<u64, Vec<Value>> = BTreeMap::new();
+    for span in spans {
+        let mut meta = span.tags.clone();
+        meta.insert("env".to_string(), env.clone());
+        traces.entry(span.trace_id as u64).or_default().push(json!({
+            "trace_id": span.trace_id as u64,
+            "span_id": span.span_id,
+            "parent_id": span.parent_id,
+            "name": span.name,
+            "resource": span.name,
+            "service": service,
+            "type": "serverless",
+            "start": span.start,
+            "duration": span.end.saturating_sub(span.start),
+            "meta": meta,
+        }));
+    }
+    let traces: Vec<Vec<Value>> = traces.into_values().collect();
+    reqwest::Client::new().put(TRACE_AGENT_URL).timeout(TIMEOUT).json(&traces).send().await?.error_for_status()?;
+    Ok(())
+}
+
+fn otlp_attributes<'a>(pairs: impl IntoIterator<Item = (&'a String, &'a String)>) -> Vec<Value> {
+    pairs.into_iter().map(|(key, value)| json!({"key": key, "value": {"stringValue": value}})).collect()
+}
+
+async fn send_otlp(samples: &[Sample], spans: &[FinishedSpan]) -> anyhow::Result<()> {
+    let client = reqwest::Client::new();
+    let (service, env) = (service(), env());
+    let resource = json!({"attributes": [
+        {"key": "service.name", "value": {"stringValue": service}},
+        {"key": "deployment.environment", "value": {"stringValue": env}},
+    ]});
+    let now = nanos(SystemTime::now()).to_string();
+    let scope = json!({"name": env!("CARGO_PKG_NAME"), "version": env!("CARGO_PKG_VERSION")});
+
+    if !samples.is_empty() {
+        let metrics: Vec<Value> = samples.iter().map(|sample| {
+            let point = json!({
+                "asDouble": sample.value,
+                "timeUnixNano": now,
+                "attributes": otlp_attributes(sample.labels.iter().map(|(name, value)| (name, value))),
+            });
+            match sample.kind {
+                Kind::Count => json!({"name": sample.name, "sum": {"dataPoints": [point], "aggregationTemporality": 1, "isMonotonic": true}}),
+                Kind::Gauge => json!({"name": sample.name, "gauge": {"dataPoints": [point]}}),
+            }
+        }).collect();
+        let body = json!({"resourceMetrics": [{"resource": resource, "scopeMetrics": [{"scope": scope, "metrics": metrics}]}]});
+        client.post(format!("{}/v1/metrics", otlp_endpoint())).timeout(TIMEOUT).json(&body).send().await?.error_for_status()?;
+    }
+
+    if !spans.is_empty() {
+        let spans: Vec<Value> = spans.iter().map(|span| json!({
+            "traceId": format!("{:032x}", span.trace_id),
+            "spanId": format!("{:016x}", span.span_id),
+            "parentSpanId": if span.parent_id == 0 { String::new() } else { format!("{:016x}", span.parent_id) },
+            "name": span.name,
+            "kind": 1,
+            "startTimeUnixNano": span.start.to_string(),
+            "endTimeUnixNano": span.end.to_string(),
+            "attributes": otlp_attributes(&span.tags),
+        })).collect();
+        let body = json!({"resourceSpans": [{"resource": resource, "scopeSpans": [{"scope": scope, "spans": spans}]}]});
+        client.post(format!("{}/v1/traces", otlp_endpoint())).timeout(TIMEOUT).json(&body).send().await?.error_for_status()?;
+    }
+    Ok(())
+}
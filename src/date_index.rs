@@ -0,0 +1,96 @@
+//! The ride table's `date-imei` GSI (partition key `date`, the UTC `YYYY-MM-DD` a ride starts on;
+//! sort key `imei`; all attributes projected): every device's rides of a month, read with a query
+//! per day instead of one per device. [`planner`](crate::planner) decides when that is cheaper.
+
+use aws_sdk_dynamodb::error::SdkError;
+use aws_sdk_dynamodb::operation::query::QueryError;
+use aws_sdk_dynamodb::types::{AttributeValue, ReturnConsumedCapacity};
+use aws_sdk_dynamodb::Client;
+use chrono::Days;
+use futures::stream::{self, StreamExt, TryStreamExt};
+use std::collections::HashMap;
+
+use crate::cost::CapacityMeter;
+use crate::pagination::{self, Item};
+use crate::{config, imei_concurrency_from_env, time};
+
+pub const INDEX_NAME: &str = "date-imei";
+
+/// The UTC dates of `[from, to)`, as the index's `date` keys.
+pub fn utc_days(from: i64, to: i64) -> Vec<String> {
+    let (Ok(first), Ok(last)) = (time::instant(from), time::instant(to - 1)) else {
+        return Vec::new();
+    };
+    let (first, last) = (first.date_naive(), last.date_naive());
+    std::iter::successors(Some(first), |day| day.checked_add_days(Days::new(1)).filter(|day| *day <= last))
+        .map(|day| day.format("%Y-%m-%d").to_string())
+        .collect()
+}
+
+/// Every ride starting within `ride_starts` (epoch seconds, `[from, to)`), of a `ride_types` type if any.
+pub async fn read(
+    client: &Client,
+    ride_starts: (i64, i64),
+    ride_types: &[String],
+    projection: &str,
+    meter: &CapacityMeter,
+) -> Result<Vec<Item>, SdkError<QueryError>> {
+    let days: Vec<Vec<Item>> = stream::iter(utc_days(ride_starts.0, ride_starts.1))
+        .map(|day| async move { day_rides(client, &day, ride_starts, ride_types, projection, meter).await })
+        .buffer_unordered(imei_concurrency_from_env())
+        .try_collect()
+        .await?;
+    Ok(days.into_iter().flatten().collect())
+}
+
+async fn day_rides(
+    client: &Client,
+    day: &str,
+    (from, to): (i64, i64),
+    ride_types: &[String],
+    projection: &str,
+    meter: &CapacityMeter,
+) -> Result<Vec<Item>, SdkError<QueryError>> {
+    let filter = RideFilter::new(from, to, ride_types);
+    let stream = client
+        .query()
+        .table_name(&config::get().ride_table)
+        .index_name(INDEX_NAME)
+        .key_condition_expression("#date = :date")
+        .filter_expression(filter.expression)
+        .set_expression_attribute_names(Some(filter.names))
+        .expression_attribute_names("#date", "date")
+        .set_expression_attribute_values(Some(filter.values))
+        .expression_attribute_values(":date", AttributeValue::S(day.to_string()))
+        .projection_expression(format!("#imei, {}", projection))
+        .return_consumed_capacity(ReturnConsumedCapacity::Total)
+        .into_paginator()
+        .send();
+    pagination::collect(&mut pagination::QueryPages { stream, meter }, None).await
+}
+
+/// A filter keeping the rides within `[from, to)` of a `ride_types` type if any, naming the
+/// attributes a ride projection refers to.
+pub struct RideFilter {
+    pub expression: String,
+    pub names: HashMap<String, String>,
+    pub values: HashMap<String, AttributeValue>,
+}
+
+impl RideFilter {
+    pub fn new(from: i64, to: i64, ride_types: &[String]) -> RideFilter {
+        let mut expression = "ride_start BETWEEN :from AND :to".to_string();
+        let mut names = HashMap::from([("#imei".to_string(), "imei".to_string()), ("#source".to_string(), "source".to_string())]);
+        let mut values = HashMap::from([
+            (":from".to_string(), AttributeValue::N(from.to_string())),
+            (":to".to_string(), AttributeValue::N((to - 1).to_string())),
+        ]);
+        if !ride_types.is_empty() {
+            let placeholders: Vec<String> = (0..ride_types.len()).map(|i| format!(":type{}", i)).collect();
+            expression = format!("{} AND #ride_type IN ({})", expression, placeholders.join(", "));
+            names.insert("#ride_type".to_string(), "ride_type".to_string());
+            values.extend(placeholders.into_iter().zip(ride_types).map(|(placeholder, ride_type)| (placeholder, AttributeValue::S(ride_type.clone()))));
+        }
+        RideFilter { expression, names, values }
+    }
+}
@@ -0,0 +1,84 @@
+//! `action: "device_decommissioned"`: for each IMEI, recomputes and finalizes the month in progress,
+//! optionally writes every stored row to `REPORT_BUCKET` (`export_history`), and stamps all its rows
+//! with `decommissioned_at` so device listings leave it out. Re-aggregating the device later rewrites
+//! its rows without the stamp.
+
+use aws_sdk_s3::primitives::ByteStream;
+use chrono::SecondsFormat;
+use lambda_runtime::Error;
+use schemars::JsonSchema;
+use serde::Serialize;
+use serde_json::{json, Value};
+use tracing::{info, warn};
+
+use crate::clock::Clock;
+use crate::cost::CapacityMeter;
+use crate::{aggregate_ride_data, aggregates, config, retries, time, CustomEvent, ErrorOutput};
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct DecommissionOutput {
+    imei: String,
+    open_month: String,
+    /// False when the device had no row for the open month to finalize.
+    finalized: bool,
+    rows_marked: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    history_uri: Option<String>,
+}
+
+pub async fn decommission(shared_config: &aws_config::SdkConfig, payload: &CustomEvent, run_id: &str, clock: &Clock) -> Result<Value, Error> {
+    let imeis = payload.imeis.clone();
+    if imeis.is_empty() {
+        return Ok(json!(ErrorOutput { error: "IMEI cannot be empty".to_string() }));
+    }
+    let bucket = config::get().report_bucket.clone();
+    if payload.export_history.unwrap_or(false) && bucket.is_none() {
+        return Ok(json!(ErrorOutput { error: "REPORT_BUCKET is not set".to_string() }));
+    }
+
+    let client = retries::dynamodb(shared_config);
+    let now = clock.now();
+    let decommissioned_at = now.to_rfc3339_opts(SecondsFormat::Secs, true);
+    let open_month = time::month_of(now)?;
+
+    // The open month's row only counts rides up to its last run, so bring it up to date before locking it.
+    let month_payload = CustomEvent { input_ride_month: Some(open_month.clone()), granularity: None, start_date: None, end_date: None, ..payload.clone() };
+    aggregate_ride_data(&client, &month_payload, &imeis, run_id, clock, None, &CapacityMeter::default()).await?;
+
+    let s3 = aws_sdk_s3::Client::new(shared_config);
+    let mut output = Vec::with_capacity(imeis.len());
+    for imei in imeis {
+        let finalized = aggregates::finalize_row(&client, &imei, &open_month, &decommissioned_at).await?;
+        if !finalized {
+            warn!("No aggregate row to finalize for decommissioned imei {} month {}", imei, open_month);
+        }
+
+        let rows = aggregates::query_device(&client, &imei).await?;
+        let history_uri = match bucket.as_deref().filter(|_| payload.export_history.unwrap_or(false)) {
+            Some(bucket) => {
+                let mut body = String::new();
+                for row in &rows {
+                    body.push_str(&serde_json::to_string(row)?);
+                    body.push('\n');
+                }
+                let key = format!("decommissioned/{}/{}.jsonl", imei, run_id);
+                s3.put_object()
+                    .bucket(bucket)
+                    .key(&key)
+                    .content_type("application/x-ndjson")
+                    .body(ByteStream::from(body.into_bytes()))
+                    .send()
+                    .await?;
+                Some(format!("s3://{}/{}", bucket, key))
+            }
+            None => None,
+        };
+
+        for row in &rows {
+            aggregates::mark_decommissioned(&client, row, &decommissioned_at).await?;
+        }
+        info!("Decommissioned imei {}: {} rows marked", imei, rows.len());
+        output.push(DecommissionOutput { imei, open_month: open_month.clone(), finalized, rows_marked: rows.len(), history_uri });
+    }
+    Ok(json!(output))
+}
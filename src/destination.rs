@@ -0,0 +1,110 @@
+//! Checks the aggregates table before a run writes to it: it must exist and be keyed by `imei`
+//! (HASH, string) and `period` (RANGE, string), as every read of it assumes. A table created with
+//! another key, say a `ride_month` sort key, would accept the writes and only break its readers, so
+//! a mismatch fails the write, naming what the table has instead. Every write to the table goes
+//! through [`verify`] first (aggregation, import, finalize, stream records, rollups), and a cold start
+//! checks it ahead of the first invocation with [`prewarm`]. A table that passes is not described
+//! again by the process, and its description also settles its capacity mode; one that cannot be
+//! described for another reason (throttling, say) is written anyway, as before, also without being
+//! described again.
+
+use anyhow::Result;
+use aws_sdk_dynamodb::operation::describe_table::DescribeTableError;
+use aws_sdk_dynamodb::types::{KeyType, ScalarAttributeType, TableDescription};
+use aws_sdk_dynamodb::Client;
+use std::collections::BTreeSet;
+use std::sync::Mutex;
+use tracing::{error, info, warn};
+
+use crate::{aggregates, capacity, metrics};
+
+const EXPECTED_KEY: [(&str, KeyType); 2] = [("imei", KeyType::Hash), ("period", KeyType::Range)];
+
+static VERIFIED: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+
+/// What is wrong with the table's key schema, if anything.
+pub fn mismatch(table: &TableDescription) -> Option<String> {
+    let key: Vec<(String, KeyType, Option<ScalarAttributeType>)> = table.key_schema().iter()
+        .map(|element| {
+            let attribute_type = table.attribute_definitions().iter()
+                .find(|definition| definition.attribute_name() == element.attribute_name())
+                .map(|definition| definition.attribute_type().clone());
+            (element.attribute_name().to_string(), element.key_type().clone(), attribute_type)
+        })
+        .collect();
+    let matches = key.len() == EXPECTED_KEY.len() && EXPECTED_KEY.iter().all(|(name, key_type)| {
+        key.iter().any(|(n, k, t)| n == name && k == key_type && *t == Some(ScalarAttributeType::S))
+    });
+    if matches {
+        return None;
+    }
+    let found: Vec<String> = key.iter()
+        .map(|(name, key_type, attribute_type)| {
+            let attribute_type = attribute_type.as_ref().map_or("undefined", |t| t.as_str());
+            format!("{} ({}, {})", name, key_type.as_str(), attribute_type)
+        })
+        .collect();
+    Some(format!("expected imei (HASH, S) and period (RANGE, S), found {}", found.join(" and ")))
+}
+
+/// Fails when the aggregates table is missing or keyed differently than expected.
+pub async fn verify(client: &Client) -> Result<()> {
+    let table = aggregates::table_name();
+    if VERIFIED.lock().unwrap().contains(table) {
+        return Ok(());
+    }
+    let description = match client.describe_table().table_name(table).send().await {
+        Ok(resp) => resp.table,
+        Err(err) => {
+            metrics::dynamodb_error("describe_table");
+            if let Some(DescribeTableError::ResourceNotFoundException(_)) = err.as_service_error() {
+                anyhow::bail!("aggregates table {} does not exist", table);
+            }
+            warn!("Error describing {}, writing without checking its key schema: {}", table, aws_sdk_dynamodb::error::DisplayErrorContext(&err));
+            VERIFIED.lock().unwrap().insert(table.to_string());
+            return Ok(());
+        }
+    };
+    if let Some(mismatch) = description.as_ref().and_then(mismatch) {
+        anyhow::bail!("aggregates table {} has the wrong key schema: {}", table, mismatch);
+    }
+    info!(table, "Aggregates table key schema verified");
+    capacity::record(table, description.as_ref());
+    VERIFIED.lock().unwrap().insert(table.to_string());
+    Ok(())
+}
+
+/// Verifies the table at cold start, so the first invocation does not wait on `DescribeTable`. A
+/// mismatch is only logged here: invocations that do not write still serve, and each write fails.
+pub async fn prewarm(client: &Client) {
+    if let Err(err) = verify(client).await {
+        error!("{:#}", err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aws_sdk_dynamodb::types::{AttributeDefinition, KeySchemaElement};
+
+    fn table(key: &[(&str, KeyType, ScalarAttributeType)]) -> TableDescription {
+        let mut table = TableDescription::builder();
+        for (name, key_type, attribute_type) in key {
+            table = table
+                .key_schema(KeySchemaElement::builder().attribute_name(*name).key_type(key_type.clone()).build().unwrap())
+                .attribute_definitions(AttributeDefinition::builder().attribute_name(*name).attribute_type(attribute_type.clone()).build().unwrap());
+        }
+        table.build()
+    }
+
+    #[test]
+    fn names_the_key_the_table_has() {
+        assert_eq!(mismatch(&table(&[("imei", KeyType::Hash, ScalarAttributeType::S), ("period", KeyType::Range, ScalarAttributeType::S)])), None);
+        assert_eq!(
+            mismatch(&table(&[("imei", KeyType::Hash, ScalarAttributeType::S), ("ride_month", KeyType::Range, ScalarAttributeType::S)])).unwrap(),
+            "expected imei (HASH, S) and period (RANGE, S), found imei (HASH, S) and ride_month (RANGE, S)",
+        );
+        assert!(mismatch(&table(&[("imei", KeyType::Hash, ScalarAttributeType::S), ("period", KeyType::Range, ScalarAttributeType::N)])).is_some());
+        assert!(mismatch(&table(&[("imei", KeyType::Hash, ScalarAttributeType::S)])).is_some());
+    }
+}
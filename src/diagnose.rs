@@ -0,0 +1,166 @@
+//! `action: "diagnose"`: everything support needs about one IMEI's `input_ride_month`, in one JSON
+//! bundle written to `REPORT_BUCKET` under `diagnostics/<imei>/<month>/<run_id>.json`:
+//!
+//! - the ride query (range, projection, items read, RCU, latency) and its first
+//!   `DIAGNOSE_SAMPLE_RIDES` items (default 25) as read, in DynamoDB JSON;
+//! - parse statistics: values normalized, items skipped by parse error, rides per exclusion, and
+//!   the month's total as aggregation would compute it;
+//! - the stored aggregate row, in DynamoDB JSON;
+//! - its latest revisions, and the recorded requests of the runs behind them and of the row's last write;
+//! - `config::echo()` and the runtime.
+//!
+//! Nothing is written but the bundle. The response has its URI, a presigned URL, and a summary.
+
+use chrono::SecondsFormat;
+use lambda_runtime::Error;
+use schemars::JsonSchema;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::Instant;
+use tracing::info;
+
+use crate::clock::Clock;
+use crate::cost::CapacityMeter;
+use crate::{aggregates, audit, config, export, history, kms, metrics, normalize, query_ride_new, retention, retries, ride, s3, startup, time};
+use crate::{CustomEvent, ErrorOutput, RIDE_PROJECTION};
+
+/// Revisions of the row included, newest first.
+const REVISIONS: usize = 10;
+
+fn sample_rides() -> usize {
+    config::get().diagnose_sample_rides
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct ParseStats {
+    items_read: usize,
+    normalized_values: BTreeMap<String, u64>,
+    skipped_items: ride::SkippedItems,
+    /// Rides of the month per exclusion reason.
+    excluded: BTreeMap<String, u64>,
+    rides_included: u64,
+    /// What aggregation would total for the month, before overlap checks and anomaly rules.
+    computed_distance: f64,
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct DiagnoseOutput {
+    imei: String,
+    ride_month: String,
+    bundle_uri: String,
+    bundle_url: String,
+    items_read: usize,
+    rides_included: u64,
+    computed_distance: f64,
+    /// The stored row's `total_distance`, if there is a row.
+    stored_distance: Option<f64>,
+    revisions: usize,
+    runs: usize,
+}
+
+pub async fn diagnose(shared_config: &aws_config::SdkConfig, payload: &CustomEvent, run_id: &str, clock: &Clock) -> Result<Value, Error> {
+    let (Some(ride_month), [imei]) = (&payload.input_ride_month, payload.imeis.as_slice()) else {
+        return Ok(json!(ErrorOutput { error: "diagnose needs exactly one imei and input_ride_month".to_string() }));
+    };
+    let Some(bucket) = config::get().report_bucket.clone() else {
+        return Ok(json!(ErrorOutput { error: "REPORT_BUCKET is not set".to_string() }));
+    };
+    let Ok(ride_starts) = time::month_range(ride_month) else {
+        return Ok(json!(ErrorOutput { error: format!("invalid input_ride_month {:?}", ride_month) }));
+    };
+    let client = retries::dynamodb(shared_config);
+
+    let meter = CapacityMeter::default();
+    let started = Instant::now();
+    let mut items = query_ride_new(&client, imei, Some(ride_starts), &[], None, RIDE_PROJECTION, &meter).await
+        .inspect_err(|_| metrics::dynamodb_error("query"))?;
+    let query = json!({
+        "ride_starts": ride_starts,
+        "projection": RIDE_PROJECTION,
+        "items_read": items.len(),
+        "read_units": meter.run_cost(started.elapsed()).read_units,
+        "latency_ms": started.elapsed().as_millis() as u64,
+    });
+    let samples: Vec<Value> = items.iter().take(sample_rides())
+        .map(|item| item.iter().map(|(name, value)| (name.clone(), retention::dynamodb_json(value))).collect())
+        .collect();
+
+    let normalized_values = normalize::normalize(&mut items);
+    let ride_types = payload.ride_types();
+    let mut stats = ParseStats {
+        items_read: items.len(),
+        normalized_values,
+        skipped_items: ride::SkippedItems::new(),
+        excluded: BTreeMap::new(),
+        rides_included: 0,
+        computed_distance: 0.0,
+    };
+    for item in &items {
+        let decision = ride::classify(item, Some(ride_month), &ride_types);
+        if decision.skipped() {
+            for error in &decision.parse_errors {
+                *stats.skipped_items.entry(*error).or_default() += 1;
+            }
+        }
+        match (decision.exclusion, decision.distance) {
+            (None, Some(distance)) => {
+                stats.rides_included += 1;
+                stats.computed_distance += distance;
+            }
+            (Some(exclusion), _) => {
+                let reason = serde_json::to_value(exclusion)?.as_str().unwrap_or_default().to_string();
+                *stats.excluded.entry(reason).or_default() += 1;
+            }
+            _ => {}
+        }
+    }
+
+    let row = aggregates::get_item(&client, imei, ride_month).await?;
+    let revisions = history::recent(&client, imei, time::Granularity::Monthly, ride_month, REVISIONS).await?;
+    let last_write = row.as_ref().and_then(|row| row.get("source_invocation_id")).and_then(|v| v.as_s().ok()).cloned();
+    let run_ids: BTreeSet<String> = revisions.iter().map(|revision| revision.run_id.clone()).chain(last_write).filter(|id| !id.is_empty()).collect();
+    let mut runs = BTreeMap::new();
+    for id in &run_ids {
+        let requests: Vec<Value> = audit::load(&client, id).await?.into_iter()
+            .map(|recorded| json!({"started_at": recorded.started_at, "request": recorded.request, "config": recorded.config}))
+            .collect();
+        runs.insert(id.clone(), requests);
+    }
+
+    let stored_distance = row.as_ref().and_then(|row| row.get("total_distance")).and_then(|v| v.as_n().ok()).and_then(|n| n.parse().ok());
+    let bundle = json!({
+        "imei": imei,
+        "ride_month": ride_month,
+        "run_id": run_id,
+        "collected_at": clock.now().to_rfc3339_opts(SecondsFormat::Millis, true),
+        "query": query,
+        "sample_rides": samples,
+        "parse_stats": stats,
+        "aggregate_row": row.map(|row| row.iter().map(|(name, value)| (name.clone(), retention::dynamodb_json(value))).collect::<serde_json::Map<_, _>>()),
+        "revisions": revisions,
+        "runs": runs,
+        "config": config::echo(),
+        "runtime": startup::runtime_info(),
+    });
+
+    let key = format!("diagnostics/{}/{}/{}.json", imei, ride_month, run_id);
+    let s3_client = aws_sdk_s3::Client::new(shared_config);
+    export::put_once(&s3_client, &bucket, &key, "application/json", serde_json::to_vec_pretty(&bundle)?, &kms::Encryption::for_request(payload)).await?;
+    let bundle_uri = format!("s3://{}/{}", bucket, key);
+    let bundle_url = s3::presign(&s3_client, &bundle_uri, s3::presign_expiry(payload.presign_expiry_secs)).await?;
+    info!("Wrote the diagnostics bundle for {} to {}", ride_month, bundle_uri);
+
+    Ok(json!(DiagnoseOutput {
+        imei: imei.to_string(),
+        ride_month: ride_month.clone(),
+        bundle_uri,
+        bundle_url,
+        items_read: stats.items_read,
+        rides_included: stats.rides_included,
+        computed_distance: stats.computed_distance,
+        stored_distance,
+        revisions: revisions.len(),
+        runs: run_ids.len(),
+    }))
+}
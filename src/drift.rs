@@ -0,0 +1,97 @@
+//! Device clock drift against the reporting timezone. A ride may carry `ride_start_local`, the
+//! wall-clock time the device showed at `ride_start` (`YYYY-MM-DDTHH:MM:SS`; any offset suffix is
+//! ignored). Its difference from `ride_start` in `REPORTING_UTC_OFFSET` is the device's offset:
+//! beyond `TIMEZONE_DRIFT_THRESHOLD_MINS` (default 30), rides near midnight on the 1st land in a
+//! different month than the rider saw. Each such device gets one `TIMEZONE_DRIFT` warning per run.
+
+use aws_sdk_dynamodb::types::AttributeValue;
+use chrono::{DateTime, NaiveDateTime};
+use std::collections::HashMap;
+
+use crate::{config, time};
+use crate::warnings::Warning;
+
+pub const ATTRIBUTE: &str = "ride_start_local";
+
+pub fn threshold_minutes() -> i64 {
+    config::get().timezone_drift_threshold_mins
+}
+
+/// The device's wall-clock time at the ride's start.
+fn device_local(item: &HashMap<String, AttributeValue>) -> Option<NaiveDateTime> {
+    let local = item.get(ATTRIBUTE)?.as_s().ok()?.trim();
+    DateTime::parse_from_rfc3339(local).map(|at| at.naive_local()).ok()
+        .or_else(|| NaiveDateTime::parse_from_str(local, "%Y-%m-%dT%H:%M:%S%.f").ok())
+        .or_else(|| NaiveDateTime::parse_from_str(local, "%Y-%m-%d %H:%M:%S%.f").ok())
+}
+
+/// One device's offsets over a run's rides.
+#[derive(Debug, Default)]
+pub struct Tracker {
+    rides: u64,
+    drifted: u64,
+    /// Drifted rides whose device-local month is not the month they were counted in.
+    shifted_months: u64,
+    /// Largest offset seen, in minutes, signed (device ahead is positive).
+    max_offset: i64,
+}
+
+impl Tracker {
+    pub fn record(&mut self, item: &HashMap<String, AttributeValue>, ride_start: u64) {
+        let Some(device) = device_local(item) else {
+            return;
+        };
+        let Some(reported) = i64::try_from(ride_start).ok().and_then(|secs| time::local(secs).ok()).map(|at| at.naive_local()) else {
+            return;
+        };
+        self.rides += 1;
+        let offset = (device - reported).num_minutes();
+        if offset.abs() <= threshold_minutes() {
+            return;
+        }
+        self.drifted += 1;
+        if offset.abs() > self.max_offset.abs() {
+            self.max_offset = offset;
+        }
+        if device.format("%Y-%m").to_string() != reported.format("%Y-%m").to_string() {
+            self.shifted_months += 1;
+        }
+    }
+
+    pub fn warning(&self, imei: &str) -> Option<Warning> {
+        (self.drifted > 0).then(|| Warning::TimezoneDrift {
+            imei: imei.to_string(),
+            rides_checked: self.rides,
+            drifted_rides: self.drifted,
+            max_offset_minutes: self.max_offset,
+            shifted_month_rides: self.shifted_months,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ride(local: &str) -> HashMap<String, AttributeValue> {
+        HashMap::from([(ATTRIBUTE.to_string(), AttributeValue::S(local.to_string()))])
+    }
+
+    #[test]
+    fn reports_devices_offset_past_the_threshold() {
+        // 2024-05-31T18:45:00Z is 2024-06-01 00:15 IST.
+        let ride_start = 1_717_181_100;
+        let mut tracker = Tracker::default();
+        tracker.record(&ride("2024-06-01T00:20:00"), ride_start);
+        assert_eq!(tracker.warning("1"), None);
+        // Still on UTC: shown on the 31st, counted in June.
+        tracker.record(&ride("2024-05-31T18:45:00Z"), ride_start);
+        assert_eq!(tracker.warning("1"), Some(Warning::TimezoneDrift {
+            imei: "1".to_string(),
+            rides_checked: 2,
+            drifted_rides: 1,
+            max_offset_minutes: -330,
+            shifted_month_rides: 1,
+        }));
+    }
+}
@@ -0,0 +1,124 @@
+use aws_sdk_sesv2::primitives::Blob;
+use aws_sdk_sesv2::types::{Destination, EmailContent, RawMessage};
+use aws_sdk_sesv2::Client;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use anyhow::Result;
+
+const BOUNDARY: &str = "ride-data-report";
+
+/// Sender and recipients for emailed reports, read from
+/// `REPORT_EMAIL_SENDER` and the comma-separated `REPORT_EMAIL_RECIPIENTS`.
+pub struct EmailSettings {
+    pub sender: String,
+    pub recipients: Vec<String>,
+}
+
+impl EmailSettings {
+    pub fn from_env() -> Option<EmailSettings> {
+        let config = crate::config::get();
+        let sender = config.report_email_sender.clone()?;
+        if config.report_email_recipients.is_empty() {
+            return None;
+        }
+        Some(EmailSettings { sender, recipients: config.report_email_recipients.clone() })
+    }
+}
+
+pub async fn send_report(client: &Client, settings: &EmailSettings, subject: &str, csv: &str) -> Result<()> {
+    let message = build_message(settings, subject, csv);
+
+    client.send_email()
+        .from_email_address(&settings.sender)
+        .destination(Destination::builder().set_to_addresses(Some(settings.recipients.clone())).build())
+        .content(EmailContent::builder()
+            .raw(RawMessage::builder().data(Blob::new(message)).build()?)
+            .build())
+        .send()
+        .await?;
+
+    Ok(())
+}
+
+/// A header value that cannot end its header: control characters (CR and LF above all, which
+/// would start a new header or the body) are dropped, and non-ASCII text is RFC 2047 encoded.
+fn header_value(value: &str) -> String {
+    let value: String = value.chars().filter(|c| !c.is_control()).collect();
+    if value.is_ascii() {
+        value
+    } else {
+        format!("=?UTF-8?B?{}?=", STANDARD.encode(value.as_bytes()))
+    }
+}
+
+fn build_message(settings: &EmailSettings, subject: &str, csv: &str) -> String {
+    let rows = csv.lines().count().saturating_sub(1);
+    let encoded = STANDARD.encode(csv.as_bytes());
+    // RFC 2045 caps encoded lines at 76 characters.
+    let wrapped: Vec<&str> = encoded.as_bytes()
+        .chunks(76)
+        .map(|chunk| std::str::from_utf8(chunk).unwrap_or_default())
+        .collect();
+
+    format!(
+        "From: {from}\r\n\
+         To: {to}\r\n\
+         Subject: {subject}\r\n\
+         MIME-Version: 1.0\r\n\
+         Content-Type: multipart/mixed; boundary=\"{b}\"\r\n\
+         \r\n\
+         --{b}\r\n\
+         Content-Type: text/plain; charset=UTF-8\r\n\
+         \r\n\
+         Ride distance report attached ({rows} rows).\r\n\
+         \r\n\
+         --{b}\r\n\
+         Content-Type: text/csv; name=\"ride_data_report.csv\"\r\n\
+         Content-Disposition: attachment; filename=\"ride_data_report.csv\"\r\n\
+         Content-Transfer-Encoding: base64\r\n\
+         \r\n\
+         {body}\r\n\
+         --{b}--\r\n",
+        from = settings.sender,
+        to = settings.recipients.join(", "),
+        subject = header_value(subject),
+        b = BOUNDARY,
+        rows = rows,
+        body = wrapped.join("\r\n"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings() -> EmailSettings {
+        EmailSettings { sender: "reports@example.com".to_string(), recipients: vec!["ops@example.com".to_string()] }
+    }
+
+    #[test]
+    fn subject_cannot_inject_headers() {
+        let message = build_message(&settings(), "Ride data 2024-03\r\nBcc: attacker@example.com\r\n\r\nhi", "imei\n1\n");
+        let headers = message.split("\r\n\r\n").next().unwrap();
+        assert!(headers.lines().all(|line| !line.starts_with("Bcc:")));
+        assert!(headers.contains("Subject: Ride data 2024-03Bcc: attacker@example.comhi\r\n"));
+    }
+
+    #[test]
+    fn the_csv_is_attached_in_wrapped_base64() {
+        let csv = format!("imei,total_distance\n{}", "359000000000001,12.5\n".repeat(20));
+        let message = build_message(&settings(), "Ride data 2024-03", &csv);
+        assert!(message.contains("Ride distance report attached (20 rows)."));
+        let attachment = message.split("Content-Transfer-Encoding: base64\r\n\r\n").nth(1).unwrap();
+        let body = attachment.split("\r\n--").next().unwrap();
+        assert!(body.split("\r\n").all(|line| line.len() <= 76));
+        assert_eq!(STANDARD.decode(body.replace("\r\n", "")).unwrap(), csv.as_bytes());
+        assert!(message.ends_with(&format!("--{}--\r\n", BOUNDARY)));
+    }
+
+    #[test]
+    fn non_ascii_subjects_are_encoded() {
+        assert_eq!(header_value("Ride data 2024-03"), "Ride data 2024-03");
+        assert_eq!(header_value("Fahrten – März"), format!("=?UTF-8?B?{}?=", STANDARD.encode("Fahrten – März")));
+    }
+}
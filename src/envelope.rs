@@ -0,0 +1,175 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::LazyLock;
+
+use crate::CustomEvent;
+
+/// Wrapper the invocation arrived in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Envelope {
+    Direct,
+    Sqs,
+    /// A DynamoDB Streams batch: from the ride table, applied incrementally by [`crate::streams`], or
+    /// from the aggregates table, published as lifecycle events by [`crate::lifecycle`].
+    DynamoDbStream,
+    ApiGateway,
+    EventBridge,
+}
+
+impl Envelope {
+    pub fn detect(event: &Value) -> Envelope {
+        let source = event.get("Records")
+            .and_then(|records| records.as_array())
+            .and_then(|records| records.first())
+            .and_then(|record| record.get("eventSource"))
+            .and_then(|source| source.as_str());
+        if source == Some("aws:sqs") {
+            Envelope::Sqs
+        } else if source == Some("aws:dynamodb") {
+            Envelope::DynamoDbStream
+        } else if event.get("requestContext").is_some() && (event.get("httpMethod").is_some() || event.get("rawPath").is_some()) {
+            Envelope::ApiGateway
+        } else if event.get("detail-type").is_some() && event.get("detail").is_some() {
+            Envelope::EventBridge
+        } else {
+            Envelope::Direct
+        }
+    }
+}
+
+/// Extracts the inner event(s) from whatever envelope wraps them; SQS batches yield one event per
+/// record, and DynamoDB Streams batches their records as they are.
+pub fn unwrap(event: Value) -> Result<(Envelope, Vec<Value>), String> {
+    let envelope = Envelope::detect(&event);
+    let events = match envelope {
+        Envelope::Direct => vec![event],
+        Envelope::EventBridge => vec![event["detail"].clone()],
+        Envelope::ApiGateway => {
+            let base64_encoded = event.get("isBase64Encoded").and_then(|v| v.as_bool()).unwrap_or(false);
+            let mut body = parse_body(event.get("body"), base64_encoded)?;
+            if let (Value::Object(fields), Some(params)) = (&mut body, event.get("queryStringParameters").and_then(|v| v.as_object())) {
+                for (name, value) in params {
+                    if let (false, Some(value)) = (fields.contains_key(name), value.as_str()) {
+                        fields.insert(name.clone(), query_value(name, value));
+                    }
+                }
+            }
+            vec![body]
+        }
+        Envelope::Sqs => event["Records"].as_array().into_iter().flatten()
+            .map(|record| parse_body(record.get("body"), false))
+            .collect::<Result<Vec<_>, _>>()?,
+        Envelope::DynamoDbStream => event["Records"].as_array().cloned().unwrap_or_default(),
+    };
+    Ok((envelope, events))
+}
+
+/// JSON types of each event field, from the event's schema.
+static FIELD_TYPES: LazyLock<HashMap<String, Vec<String>>> = LazyLock::new(|| {
+    let schema = serde_json::to_value(schemars::schema_for!(CustomEvent)).unwrap_or_default();
+    let properties = schema.get("properties").and_then(Value::as_object).cloned().unwrap_or_default();
+    properties.into_iter().map(|(name, property)| {
+        let types = match property.get("type") {
+            Some(Value::String(single)) => vec![single.clone()],
+            Some(Value::Array(types)) => types.iter().filter_map(|t| t.as_str().map(str::to_string)).collect(),
+            _ => Vec::new(),
+        };
+        (name, types)
+    }).collect()
+});
+
+/// A query parameter as an event field: typed as a boolean or number only where the field is one,
+/// so `input_ride_month=202403` or `imeis=350000000000001` stay strings; fields in the body win
+/// over parameters.
+fn query_value(name: &str, value: &str) -> Value {
+    let types = FIELD_TYPES.get(name).map(Vec::as_slice).unwrap_or_default();
+    let is = |t: &str| types.iter().any(|field_type| field_type == t);
+    match value {
+        "true" | "false" if is("boolean") => Value::Bool(value == "true"),
+        _ if is("integer") || is("number") => {
+            value.parse::<serde_json::Number>().map(Value::Number).unwrap_or_else(|_| Value::String(value.to_string()))
+        }
+        _ => Value::String(value.to_string()),
+    }
+}
+
+/// The proxy response API Gateway and Function URLs expect, with `body` as JSON text.
+pub fn http_response(status: u16, body: &Value) -> Value {
+    json!({
+        "statusCode": status,
+        "headers": { "content-type": "application/json" },
+        "body": body.to_string(),
+    })
+}
+
+fn parse_body(body: Option<&Value>, base64_encoded: bool) -> Result<Value, String> {
+    let body = match body {
+        None | Some(Value::Null) => return Ok(json!({})),
+        Some(Value::String(body)) => body,
+        Some(other) => return Ok(other.clone()),
+    };
+    let decoded = if base64_encoded {
+        let bytes = STANDARD.decode(body).map_err(|err| format!("invalid base64 body: {}", err))?;
+        String::from_utf8(bytes).map_err(|err| format!("invalid UTF-8 body: {}", err))?
+    } else {
+        body.clone()
+    };
+    if decoded.trim().is_empty() {
+        return Ok(json!({}));
+    }
+    serde_json::from_str(&decoded).map_err(|err| format!("invalid JSON body: {}", err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn api_gateway(body: Value, base64_encoded: bool, params: Value) -> Value {
+        json!({
+            "requestContext": { "requestId": "r" },
+            "httpMethod": "POST",
+            "isBase64Encoded": base64_encoded,
+            "body": body,
+            "queryStringParameters": params,
+        })
+    }
+
+    #[test]
+    fn api_gateway_json_body() {
+        let event = api_gateway(json!(r#"{"imeis": ["350000000000001"], "dry_run": true}"#), false, Value::Null);
+        let (envelope, events) = unwrap(event).unwrap();
+        assert_eq!(envelope, Envelope::ApiGateway);
+        assert_eq!(events, [json!({ "imeis": ["350000000000001"], "dry_run": true })]);
+    }
+
+    #[test]
+    fn api_gateway_base64_body() {
+        let body = STANDARD.encode(r#"{"input_ride_month": "2024-03"}"#);
+        let (_, events) = unwrap(api_gateway(json!(body), true, Value::Null)).unwrap();
+        assert_eq!(events, [json!({ "input_ride_month": "2024-03" })]);
+        assert!(unwrap(api_gateway(json!("not base64!"), true, Value::Null)).unwrap_err().starts_with("invalid base64 body"));
+    }
+
+    #[test]
+    fn query_parameters_are_typed_by_field() {
+        let params = json!({
+            "input_ride_month": "202403",
+            "imeis": "350000000000001",
+            "dry_run": "true",
+            "max_rides_per_imei": "500",
+            "output_unit": "mi",
+            "fan_out": "true",
+        });
+        let (_, events) = unwrap(api_gateway(json!(r#"{"fan_out": false}"#), false, params)).unwrap();
+        assert_eq!(events, [json!({
+            "input_ride_month": "202403",
+            "imeis": "350000000000001",
+            "dry_run": true,
+            "max_rides_per_imei": 500,
+            "output_unit": "mi",
+            "fan_out": false,
+        })]);
+    }
+}
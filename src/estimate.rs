@@ -0,0 +1,127 @@
+//! `estimate: true`: ballpark totals from a sample of rides instead of a full query.
+//! The window is cut into equal time strata; each stratum reads at most `ESTIMATE_SAMPLE_LIMIT`
+//! rides from a random start key, and the distance per second seen there is scaled up to the whole window.
+
+use aws_sdk_dynamodb::types::AttributeValue;
+use aws_sdk_dynamodb::Client;
+use anyhow::Result;
+use chrono::Utc;
+use lambda_runtime::Error;
+use schemars::JsonSchema;
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::{config, metrics, normalize, ride, time, CustomEvent, ErrorOutput, RIDE_PROJECTION};
+
+const STRATA: u32 = 10;
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct EstimateOutput {
+    imei: String,
+    /// `YYYY-MM` month, or the ride window's label when no month was requested.
+    period: String,
+    estimated_distance: f64,
+    /// 95% confidence bounds across strata.
+    lower_bound: f64,
+    upper_bound: f64,
+    rides_sampled: usize,
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct EstimateResponse {
+    estimates: Vec<EstimateOutput>,
+}
+
+pub async fn estimate(client: &Client, payload: &CustomEvent, imeis: &[String]) -> Result<Value, Error> {
+    let (from, to) = match window(payload.input_ride_month.as_deref()) {
+        Ok(window) => window,
+        Err(err) => return Ok(json!(ErrorOutput { error: err })),
+    };
+    let period = payload.input_ride_month.clone().unwrap_or_else(|| ride::window().label());
+    let limit = config::get().estimate_sample_limit;
+
+    let ride_types = payload.ride_types();
+    let mut estimates = Vec::with_capacity(imeis.len());
+    for imei in imeis {
+        let mut scaled = Vec::with_capacity(STRATA as usize);
+        let mut rides_sampled = 0;
+        let stratum_len = (to - from) / STRATA as i64;
+        for stratum in 0..STRATA as i64 {
+            let stratum_from = from + stratum * stratum_len;
+            let stratum_to = stratum_from + stratum_len;
+            let sample = sample_stratum(client, imei, payload.input_ride_month.as_deref(), &ride_types, stratum_from, stratum_to, limit).await?;
+            rides_sampled += sample.rides;
+            scaled.push(sample.distance / sample.seconds as f64 * (to - from) as f64);
+        }
+
+        let mean = scaled.iter().sum::<f64>() / scaled.len() as f64;
+        let variance = scaled.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / (scaled.len() - 1) as f64;
+        let margin = 1.96 * (variance / scaled.len() as f64).sqrt();
+        estimates.push(EstimateOutput {
+            imei: imei.clone(),
+            period: period.clone(),
+            estimated_distance: mean,
+            lower_bound: (mean - margin).max(0.0),
+            upper_bound: mean + margin,
+            rides_sampled,
+        });
+    }
+    Ok(json!(EstimateResponse { estimates }))
+}
+
+/// Epoch seconds `[from, to)` covered by the requested month (IST), or by the ride window up to now.
+fn window(input_ride_month: Option<&str>) -> Result<(i64, i64), String> {
+    if let Some(month) = input_ride_month {
+        return time::month_range(month).map_err(|err| err.to_string());
+    }
+    let window = ride::window();
+    match window.bounds() {
+        Some((from, to)) if window.start.is_some() && from < Utc::now().timestamp() => Ok((from, to.min(Utc::now().timestamp()))),
+        Some(_) if window.start.is_some() => Err("RIDE_WINDOW_START is in the future".to_string()),
+        _ => Err("an estimate without input_ride_month needs RIDE_WINDOW_START".to_string()),
+    }
+}
+
+struct StratumSample {
+    distance: f64,
+    rides: usize,
+    /// Length of the stretch of time the sampled rides cover.
+    seconds: i64,
+}
+
+async fn sample_stratum(client: &Client, imei: &str, input_ride_month: Option<&str>, ride_types: &ride::RideTypes, from: i64, to: i64, limit: i32) -> Result<StratumSample> {
+    let start = fastrand::i64(from..to);
+    let resp = client.query()
+        .table_name(&config::get().ride_table)
+        .key_condition_expression("#imei = :imei AND ride_start BETWEEN :from AND :to")
+        .expression_attribute_names("#imei", "imei")
+        .expression_attribute_names("#source", "source")
+        .expression_attribute_values(":imei", AttributeValue::S(imei.to_string()))
+        .expression_attribute_values(":from", AttributeValue::N(from.to_string()))
+        .expression_attribute_values(":to", AttributeValue::N((to - 1).to_string()))
+        .exclusive_start_key("imei", AttributeValue::S(imei.to_string()))
+        .exclusive_start_key("ride_start", AttributeValue::N(start.to_string()))
+        .projection_expression(RIDE_PROJECTION)
+        .limit(limit)
+        .send()
+        .await
+        .inspect_err(|_| metrics::dynamodb_error("query"))?;
+
+    let truncated = resp.last_evaluated_key().is_some();
+    let mut items = resp.items.unwrap_or_default();
+    normalize::normalize(&mut items);
+    let mut sample = StratumSample { distance: 0.0, rides: items.len(), seconds: to - start };
+    for item in &items {
+        let decision = ride::classify(item, input_ride_month, ride_types);
+        if decision.exclusion.is_none() {
+            sample.distance += decision.distance.unwrap_or(0.0);
+        }
+    }
+    // A full page stops short of the stratum end; only the time up to the last ride read was sampled.
+    if truncated {
+        if let Some(last) = items.last().and_then(|item| ride::classify(item, None, ride_types).ride_start) {
+            sample.seconds = (last as i64 - start).max(1);
+        }
+    }
+    Ok(sample)
+}
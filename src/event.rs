@@ -0,0 +1,74 @@
+use schemars::schema_for;
+use serde_json::Value;
+use tracing::warn;
+
+use crate::CustomEvent;
+
+/// How unknown event fields are treated, chosen per environment with `EVENT_PARSING`
+/// (`strict` or `lenient`, default `lenient`).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ParsingMode {
+    Strict,
+    Lenient,
+}
+
+impl ParsingMode {
+    pub fn from_env() -> ParsingMode {
+        crate::config::get().event_parsing
+    }
+}
+
+/// Deserializes the event, rejecting unknown fields in strict mode and only logging them in lenient mode.
+pub fn parse_event(value: Value, mode: ParsingMode) -> Result<CustomEvent, String> {
+    let mut unknown = Vec::new();
+    let event: CustomEvent = serde_ignored::deserialize(value, |path| unknown.push(path.to_string()))
+        .map_err(|err| format!("invalid event: {}", err))?;
+
+    if unknown.is_empty() {
+        return Ok(event);
+    }
+
+    let known = known_fields();
+    let messages: Vec<String> = unknown.iter().map(|field| match suggest(field, &known) {
+        Some(suggestion) => format!("unknown field `{}`, did you mean `{}`?", field, suggestion),
+        None => format!("unknown field `{}`", field),
+    }).collect();
+
+    match mode {
+        ParsingMode::Strict => Err(messages.join("; ")),
+        ParsingMode::Lenient => {
+            for message in &messages {
+                warn!("Ignoring event field: {}", message);
+            }
+            Ok(event)
+        }
+    }
+}
+
+fn known_fields() -> Vec<String> {
+    schema_for!(CustomEvent).schema.object
+        .map(|object| object.properties.keys().cloned().collect())
+        .unwrap_or_default()
+}
+
+fn suggest<'a>(field: &str, known: &'a [String]) -> Option<&'a str> {
+    known.iter()
+        .map(|candidate| (edit_distance(field, candidate), candidate))
+        .filter(|(distance, _)| *distance <= 3)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, candidate)| candidate.as_str())
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != *cb);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
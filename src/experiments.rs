@@ -0,0 +1,91 @@
+//! Experimental metrics, computed beside the stable ones while a new definition bakes on
+//! production data. `EXPERIMENTS` lists the enabled ones, comma-separated; each is a sum over a
+//! device-month's counted rides, reported in a row's `experimental` map and stored on the aggregate
+//! row under its own `exp.<name>` attribute, so readers of the stable fields see no change. An
+//! experiment that graduates gets a stable field of its own; one that is dropped just stops being
+//! written.
+//!
+//! - `outlier_v2_distance`: the distance without rides whose average speed (distance over
+//!   `ride_stats.ride_duration`) exceeds [`OUTLIER_V2_MAX_KMH`]; rides without a duration count.
+
+use aws_sdk_dynamodb::types::AttributeValue;
+use std::collections::{BTreeMap, HashMap};
+
+use crate::stats;
+
+/// Prefix of experimental attributes and `experimental` keys.
+pub const PREFIX: &str = "exp.";
+
+/// Above this average speed (km/h) a ride is an outlier for `outlier_v2_distance`.
+pub const OUTLIER_V2_MAX_KMH: f64 = 160.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Experiment {
+    OutlierV2Distance,
+}
+
+impl Experiment {
+    pub const ALL: [Experiment; 1] = [Experiment::OutlierV2Distance];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Experiment::OutlierV2Distance => "outlier_v2_distance",
+        }
+    }
+
+    /// The `exp.`-prefixed attribute and key it is written under.
+    pub fn attribute(self) -> String {
+        format!("{}{}", PREFIX, self.name())
+    }
+
+    fn parse(name: &str) -> Option<Experiment> {
+        Experiment::ALL.into_iter().find(|experiment| experiment.name() == name)
+    }
+
+    /// What one counted ride of `distance` km adds to the experiment.
+    fn value(self, item: &HashMap<String, AttributeValue>, distance: f64) -> f64 {
+        match self {
+            Experiment::OutlierV2Distance => match stats::ride_stat(item, "ride_duration").filter(|seconds| *seconds > 0.0) {
+                Some(seconds) if distance / (seconds / 3600.0) > OUTLIER_V2_MAX_KMH => 0.0,
+                _ => distance,
+            },
+        }
+    }
+}
+
+/// Names in `EXPERIMENTS` that are not experiments.
+pub fn unknown(names: &str) -> Vec<String> {
+    names.split(',').map(str::trim).filter(|name| !name.is_empty() && Experiment::parse(name).is_none()).map(str::to_string).collect()
+}
+
+/// The experiments among comma-separated `names`.
+pub fn parse(names: &str) -> Vec<Experiment> {
+    names.split(',').filter_map(|name| Experiment::parse(name.trim())).collect()
+}
+
+/// The experiments `EXPERIMENTS` enables.
+pub fn enabled() -> &'static [Experiment] {
+    &crate::config::get().experiments
+}
+
+/// Adds one counted ride to each enabled experiment's total.
+pub fn record(totals: &mut BTreeMap<String, f64>, item: &HashMap<String, AttributeValue>, distance: f64) {
+    for experiment in enabled() {
+        *totals.entry(experiment.attribute()).or_default() += experiment.value(item, distance);
+    }
+}
+
+/// Sums `other` into `totals`.
+pub fn add(totals: &mut BTreeMap<String, f64>, other: &BTreeMap<String, f64>) {
+    for (attribute, value) in other {
+        *totals.entry(attribute.clone()).or_default() += value;
+    }
+}
+
+/// The experimental attributes of a stored row.
+pub fn of_item(item: &HashMap<String, AttributeValue>) -> BTreeMap<String, f64> {
+    item.iter()
+        .filter(|(name, _)| name.starts_with(PREFIX))
+        .filter_map(|(name, value)| Some((name.clone(), value.as_n().ok()?.parse().ok()?)))
+        .collect()
+}
@@ -0,0 +1,305 @@
+//! `action: "export"`: a month-end snapshot of the stored aggregates for `input_ride_month`, written
+//! to `REPORT_BUCKET` under `exports/<month>/<run_id>/` as JSONL parts of `EXPORT_PART_ROWS` rows
+//! (default 10000), sorted by IMEI, plus a `manifest.json` listing every part with its SHA-256.
+//! `dataset_sha256` hashes the parts concatenated in order, i.e. the whole sorted dataset, before
+//! any `export_compression`; each part's `sha256` is of the object as stored. Objects are written
+//! with `If-None-Match: *`, so a snapshot is never overwritten.
+//!
+//! Separately, an aggregation with an `export` section uploads its own results as CSV or Parquet for
+//! Athena, one object per month under `<prefix>/ride_month=<YYYY-MM>/`. Its `compression` wraps CSV
+//! and sets Parquet's page codec (by default Snappy).
+//!
+//! Both follow the request's [`number_format`](crate::numbers): with `string_fixed2` the decimal
+//! fields are two-decimal strings, and Parquet types those columns as UTF8.
+
+use aws_sdk_s3::primitives::ByteStream;
+use anyhow::{anyhow, Result};
+use lambda_runtime::Error;
+use parquet::basic::Compression;
+use parquet::data_type::{BoolType, ByteArray, ByteArrayType, DataType, DoubleType, Int64Type};
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::{SerializedFileWriter, SerializedRowGroupWriter};
+use parquet::schema::parser::parse_message_type;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tracing::info;
+use utoipa::ToSchema;
+
+use crate::codec::ExportCompression;
+use crate::numbers::{self, NumberFormat};
+use crate::{aggregates, config, kms, retries, s3, upload, CustomEvent, CustomOutput, ErrorOutput};
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct ExportPart {
+    key: String,
+    rows: usize,
+    bytes: usize,
+    sha256: String,
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct ExportManifest {
+    ride_month: String,
+    run_id: String,
+    exported_at: String,
+    rows: usize,
+    dataset_sha256: String,
+    compression: ExportCompression,
+    parts: Vec<ExportPart>,
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct ExportOutput {
+    ride_month: String,
+    rows: usize,
+    parts: usize,
+    dataset_sha256: String,
+    manifest_uri: String,
+    /// Presigned GET URLs of the manifest and of each part, in order.
+    manifest_url: String,
+    part_urls: Vec<String>,
+}
+
+pub async fn export(shared_config: &aws_config::SdkConfig, payload: &CustomEvent, run_id: &str, exported_at: &str) -> Result<Value, Error> {
+    let Some(ride_month) = payload.input_ride_month.clone() else {
+        return Ok(json!(ErrorOutput { error: "input_ride_month is required to export".to_string() }));
+    };
+    let Some(bucket) = config::get().report_bucket.clone() else {
+        return Ok(json!(ErrorOutput { error: "REPORT_BUCKET is not set".to_string() }));
+    };
+    let part_rows = config::get().export_part_rows;
+    let compression = payload.export_compression.unwrap_or(ExportCompression::None);
+    let codec = compression.codec();
+    let encryption = kms::Encryption::for_request(payload);
+    let number_format = payload.number_format.unwrap_or_default();
+
+    let mut rows = aggregates::query_month(&retries::dynamodb(shared_config), &ride_month).await?;
+    rows.sort_by(|a, b| a.imei.cmp(&b.imei));
+
+    let s3_client = aws_sdk_s3::Client::new(shared_config);
+    let prefix = format!("exports/{}/{}", ride_month, run_id);
+    let mut dataset = Sha256::new();
+    let mut parts = Vec::new();
+    for (i, chunk) in rows.chunks(part_rows).enumerate() {
+        let mut body = Vec::new();
+        for row in chunk {
+            let mut row = serde_json::to_value(row)?;
+            number_format.apply(&mut row);
+            serde_json::to_writer(&mut body, &row)?;
+            body.push(b'\n');
+        }
+        dataset.update(&body);
+        let body = codec.compress(body)?;
+        let part = ExportPart {
+            key: format!("{}/part-{:05}.jsonl{}", prefix, i, codec.extension()),
+            rows: chunk.len(),
+            bytes: body.len(),
+            sha256: hex::encode(Sha256::digest(&body)),
+        };
+        put_once(&s3_client, &bucket, &part.key, "application/x-ndjson", body, &encryption).await?;
+        parts.push(part);
+    }
+
+    let manifest = ExportManifest {
+        ride_month: ride_month.clone(),
+        run_id: run_id.to_string(),
+        exported_at: exported_at.to_string(),
+        rows: rows.len(),
+        dataset_sha256: hex::encode(dataset.finalize()),
+        compression,
+        parts,
+    };
+    let manifest_key = format!("{}/manifest.json", prefix);
+    put_once(&s3_client, &bucket, &manifest_key, "application/json", serde_json::to_vec_pretty(&manifest)?, &encryption).await?;
+
+    let expiry = s3::presign_expiry(payload.presign_expiry_secs);
+    let manifest_uri = format!("s3://{}/{}", bucket, manifest_key);
+    let manifest_url = s3::presign(&s3_client, &manifest_uri, expiry).await?;
+    let mut part_urls = Vec::with_capacity(manifest.parts.len());
+    for part in &manifest.parts {
+        part_urls.push(s3::presign(&s3_client, &format!("s3://{}/{}", bucket, part.key), expiry).await?);
+    }
+
+    info!("Exported {} rows for {} in {} parts", manifest.rows, ride_month, manifest.parts.len());
+    Ok(json!(ExportOutput {
+        ride_month,
+        rows: manifest.rows,
+        parts: manifest.parts.len(),
+        dataset_sha256: manifest.dataset_sha256,
+        manifest_uri,
+        manifest_url,
+        part_urls,
+    }))
+}
+
+/// Writes the object unless it exists, in parts when it is larger than [`upload::multipart_threshold`].
+pub async fn put_once(s3: &aws_sdk_s3::Client, bucket: &str, key: &str, content_type: &str, body: Vec<u8>, encryption: &kms::Encryption) -> Result<()> {
+    if body.len() > upload::multipart_threshold() {
+        return upload::put_parts(s3, bucket, key, content_type, body, true, encryption).await;
+    }
+    s3.put_object()
+        .bucket(bucket)
+        .key(key)
+        .content_type(content_type)
+        .set_server_side_encryption(encryption.server_side_encryption())
+        .set_ssekms_key_id(encryption.key_id())
+        .set_ssekms_encryption_context(encryption.context_header())
+        .set_metadata(encryption.metadata())
+        .if_none_match("*")
+        .body(ByteStream::from(body))
+        .send()
+        .await?;
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize, JsonSchema, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ExportFormat {
+    #[default]
+    Csv,
+    Parquet,
+}
+
+/// Where an aggregation uploads its results.
+#[derive(Debug, Clone, Deserialize, JsonSchema, ToSchema)]
+pub struct ResultsExport {
+    pub bucket: String,
+    /// Key prefix, default `results`.
+    pub prefix: Option<String>,
+    #[serde(default)]
+    pub format: ExportFormat,
+    pub compression: Option<ExportCompression>,
+}
+
+/// Columns of an exported object; the month is the `ride_month=` partition, so a row's own
+/// period (a month, day or week start, or a range label) is `period`.
+const COLUMNS: &str = "imei,granularity,period,total_distance,ride_count,total_duration,average_speed,max_speed,total_energy,month_to_date,as_of";
+
+const PARQUET_SCHEMA: &str = "message ride_data_results {
+    OPTIONAL BYTE_ARRAY imei (UTF8);
+    OPTIONAL BYTE_ARRAY granularity (UTF8);
+    OPTIONAL BYTE_ARRAY period (UTF8);
+    OPTIONAL DOUBLE total_distance;
+    OPTIONAL INT64 ride_count;
+    OPTIONAL DOUBLE total_duration;
+    OPTIONAL DOUBLE average_speed;
+    OPTIONAL DOUBLE max_speed;
+    OPTIONAL DOUBLE total_energy;
+    OPTIONAL BOOLEAN month_to_date;
+    OPTIONAL BYTE_ARRAY as_of (UTF8);
+}";
+
+/// Uploads `rows` split by month, returning the `s3://` URI of each object written. Objects are
+/// named after the run and a random suffix, since batched requests share a run_id.
+pub async fn export_results(
+    shared_config: &aws_config::SdkConfig,
+    target: &ResultsExport,
+    rows: &[CustomOutput],
+    run_id: &str,
+    encryption: &kms::Encryption,
+    number_format: NumberFormat,
+) -> Result<Vec<String>> {
+    let mut months: BTreeMap<&str, Vec<&CustomOutput>> = BTreeMap::new();
+    for row in rows {
+        months.entry(row.ride_month.get(..7).unwrap_or(&row.ride_month)).or_default().push(row);
+    }
+
+    let s3 = aws_sdk_s3::Client::new(shared_config);
+    let prefix = target.prefix.as_deref().unwrap_or("results").trim_end_matches('/');
+    let name = format!("{}-{}", run_id, &uuid::Uuid::new_v4().simple().to_string()[..8]);
+    let mut uris = Vec::with_capacity(months.len());
+    for (month, rows) in months {
+        let (extension, content_type, body) = match (target.format, target.compression) {
+            (ExportFormat::Csv, None) => ("csv".to_string(), "text/csv", to_csv(&rows, number_format).into_bytes()),
+            (ExportFormat::Csv, Some(compression)) => {
+                let codec = compression.codec();
+                (format!("csv{}", codec.extension()), "text/csv", codec.compress(to_csv(&rows, number_format).into_bytes())?)
+            }
+            (ExportFormat::Parquet, compression) => {
+                let pages = compression.map_or(Compression::SNAPPY, |compression| compression.codec().parquet());
+                ("parquet".to_string(), "application/vnd.apache.parquet", to_parquet(&rows, pages, number_format)?)
+            }
+        };
+        let key = format!("{}/ride_month={}/{}.{}", prefix, month, name, extension);
+        put_once(&s3, &target.bucket, &key, content_type, body, encryption).await?;
+        uris.push(format!("s3://{}/{}", target.bucket, key));
+    }
+    info!("Exported {} result rows to {} objects", rows.len(), uris.len());
+    Ok(uris)
+}
+
+fn to_csv(rows: &[&CustomOutput], number_format: NumberFormat) -> String {
+    let number = |value: Option<f64>| value.map(|v| number_format.text(v)).unwrap_or_default();
+    let mut csv = format!("{}\n", COLUMNS);
+    for row in rows {
+        csv.push_str(&format!(
+            "{},{},{},{},{},{},{},{},{},{},{}\n",
+            row.imei,
+            row.granularity.as_str(),
+            row.ride_month,
+            number_format.text(row.total_distance),
+            row.ride_count.map(|count| count.to_string()).unwrap_or_default(),
+            number(row.total_duration),
+            number(row.average_speed),
+            number(row.max_speed),
+            number(row.total_energy),
+            row.month_to_date,
+            row.as_of,
+        ));
+    }
+    csv
+}
+
+fn to_parquet(rows: &[&CustomOutput], compression: Compression, number_format: NumberFormat) -> Result<Vec<u8>> {
+    let schema = Arc::new(parse_message_type(&parquet_schema(number_format))?);
+    let properties = Arc::new(WriterProperties::builder().set_compression(compression).build());
+    let mut body = Vec::new();
+    let mut writer = SerializedFileWriter::new(&mut body, schema, properties)?;
+    let mut row_group = writer.next_row_group()?;
+    let text = |value: &str| Some(ByteArray::from(value));
+
+    column::<ByteArrayType>(&mut row_group, rows.iter().map(|row| text(&row.imei)).collect())?;
+    column::<ByteArrayType>(&mut row_group, rows.iter().map(|row| text(row.granularity.as_str())).collect())?;
+    column::<ByteArrayType>(&mut row_group, rows.iter().map(|row| text(&row.ride_month)).collect())?;
+    let decimal = |row_group: &mut SerializedRowGroupWriter<'_, &mut Vec<u8>>, values: Vec<Option<f64>>| match number_format {
+        NumberFormat::Float => column::<DoubleType>(row_group, values),
+        NumberFormat::StringFixed2 => column::<ByteArrayType>(row_group, values.into_iter().map(|value| value.map(|v| ByteArray::from(numbers::fixed2(v).as_str()))).collect()),
+    };
+    decimal(&mut row_group, rows.iter().map(|row| Some(row.total_distance)).collect())?;
+    column::<Int64Type>(&mut row_group, rows.iter().map(|row| row.ride_count.map(|count| count as i64)).collect())?;
+    decimal(&mut row_group, rows.iter().map(|row| row.total_duration).collect())?;
+    decimal(&mut row_group, rows.iter().map(|row| row.average_speed).collect())?;
+    decimal(&mut row_group, rows.iter().map(|row| row.max_speed).collect())?;
+    decimal(&mut row_group, rows.iter().map(|row| row.total_energy).collect())?;
+    column::<BoolType>(&mut row_group, rows.iter().map(|row| Some(row.month_to_date)).collect())?;
+    column::<ByteArrayType>(&mut row_group, rows.iter().map(|row| text(&row.as_of)).collect())?;
+
+    row_group.close()?;
+    writer.close()?;
+    Ok(body)
+}
+
+/// [`PARQUET_SCHEMA`], its DOUBLE columns as UTF8 strings for `string_fixed2`.
+fn parquet_schema(number_format: NumberFormat) -> String {
+    match number_format {
+        NumberFormat::Float => PARQUET_SCHEMA.to_string(),
+        NumberFormat::StringFixed2 => PARQUET_SCHEMA.lines().map(|line| match line.trim().strip_prefix("OPTIONAL DOUBLE ") {
+            Some(name) => format!("    OPTIONAL BYTE_ARRAY {} (UTF8);\n", name.trim_end_matches(';')),
+            None => format!("{}\n", line),
+        }).collect(),
+    }
+}
+
+/// Writes the row group's next column, in [`PARQUET_SCHEMA`] order.
+fn column<T: DataType>(row_group: &mut SerializedRowGroupWriter<'_, &mut Vec<u8>>, values: Vec<Option<T::T>>) -> Result<()> {
+    let mut column = row_group.next_column()?.ok_or_else(|| anyhow!("more columns written than the parquet schema has"))?;
+    let levels: Vec<i16> = values.iter().map(|value| i16::from(value.is_some())).collect();
+    let present: Vec<T::T> = values.into_iter().flatten().collect();
+    column.typed::<T>().write_batch(&present, Some(&levels), None)?;
+    column.close()?;
+    Ok(())
+}
@@ -0,0 +1,132 @@
+//! Per-device failures: a device whose rides could not be read, or whose aggregation panicked, is
+//! reported in the response's `errors` instead of failing the whole run, so callers can retry just
+//! those devices. Unless more than `fail_if_error_rate_above` percent of them failed (default
+//! `FAIL_IF_ERROR_RATE_ABOVE`; unset, never): then the run fails as a whole, so the job or state
+//! machine that sent it retries. With a budget, no device's rows are written until every device
+//! has been read, so a run over it writes nothing.
+
+use aws_sdk_dynamodb::error::{ProvideErrorMetadata, SdkError};
+use aws_sdk_dynamodb::operation::query::QueryError;
+use aws_smithy_types::error::display::DisplayErrorContext;
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::any::Any;
+use std::collections::BTreeSet;
+use utoipa::ToSchema;
+
+use crate::partitions;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, JsonSchema, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    /// Still throttled after the SDK's retries.
+    Throttled,
+    Timeout,
+    /// The request never got a response, e.g. a connection failure.
+    Network,
+    AccessDenied,
+    /// The ride table does not exist.
+    NotFound,
+    /// DynamoDB rejected the query as malformed.
+    Invalid,
+    /// Any other DynamoDB error.
+    Service,
+    /// Aggregating the device's rides panicked, which sending it again will not fix.
+    Panic,
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema, ToSchema)]
+pub struct Failure {
+    pub kind: ErrorKind,
+    /// Whether sending the device again may succeed.
+    pub retryable: bool,
+    pub message: String,
+}
+
+impl Failure {
+    pub fn of_query(err: &SdkError<QueryError>) -> Failure {
+        let (kind, retryable) = classify(err);
+        Failure { kind, retryable, message: DisplayErrorContext(err).to_string() }
+    }
+
+    /// From the payload `catch_unwind` returns.
+    pub fn of_panic(panic: Box<dyn Any + Send>) -> Failure {
+        let message = match (panic.downcast_ref::<&str>(), panic.downcast_ref::<String>()) {
+            (Some(message), _) => message.to_string(),
+            (_, Some(message)) => message.clone(),
+            _ => "panicked".to_string(),
+        };
+        Failure { kind: ErrorKind::Panic, retryable: false, message }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema, ToSchema)]
+pub struct DeviceError {
+    /// The requested IMEI; for an aliased device, `identity` is the one whose query failed.
+    pub imei: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub identity: Option<String>,
+    #[serde(flatten)]
+    pub failure: Failure,
+}
+
+/// The request's error budget, else the deployment's.
+pub fn error_rate_threshold(requested: Option<f64>) -> Option<f64> {
+    requested.or(crate::config::get().fail_if_error_rate_above)
+}
+
+/// Whether `failed` of `devices` is more than `threshold` percent.
+pub fn over_budget(failed: usize, devices: usize, threshold: f64) -> bool {
+    devices > 0 && failed as f64 * 100.0 / devices as f64 > threshold
+}
+
+/// Devices with any failure; an aliased device whose identities failed twice counts once.
+pub fn failed_devices(errors: &[DeviceError]) -> usize {
+    errors.iter().map(|error| error.imei.as_str()).collect::<BTreeSet<_>>().len()
+}
+
+fn classify(err: &SdkError<QueryError>) -> (ErrorKind, bool) {
+    if partitions::is_throttling(err) {
+        return (ErrorKind::Throttled, true);
+    }
+    match err {
+        SdkError::TimeoutError(_) => (ErrorKind::Timeout, true),
+        SdkError::DispatchFailure(_) => (ErrorKind::Network, true),
+        SdkError::ServiceError(_) => match err.code() {
+            Some("ResourceNotFoundException") => (ErrorKind::NotFound, false),
+            Some("AccessDeniedException" | "UnrecognizedClientException") => (ErrorKind::AccessDenied, false),
+            Some("ValidationException") => (ErrorKind::Invalid, false),
+            _ => (ErrorKind::Service, true),
+        },
+        _ => (ErrorKind::Service, true),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(imei: &str, identity: Option<&str>) -> DeviceError {
+        DeviceError {
+            imei: imei.to_string(),
+            identity: identity.map(str::to_string),
+            failure: Failure { kind: ErrorKind::Timeout, retryable: true, message: "timed out".to_string() },
+        }
+    }
+
+    #[test]
+    fn budget_is_exceeded_only_above_the_threshold() {
+        assert!(!over_budget(0, 0, 0.0));
+        assert!(!over_budget(1, 4, 25.0));
+        assert!(over_budget(2, 4, 25.0));
+        assert!(over_budget(1, 4, 0.0));
+        assert!(!over_budget(4, 4, 100.0));
+    }
+
+    #[test]
+    fn aliased_identities_count_as_one_device() {
+        let errors = [error("350000000000001", None), error("350000000000001", Some("350000000000019")), error("350000000000027", None)];
+        assert_eq!(failed_devices(&errors), 2);
+        assert!(!over_budget(failed_devices(&errors), 2, 100.0));
+    }
+}
@@ -0,0 +1,192 @@
+//! SQS fan-out for requests too large for one invocation. The coordinator (`fan_out: true`) splits
+//! the IMEIs into shards of `FANOUT_SHARD_SIZE` (default 25; larger when the Lambda concurrency
+//! quota calls for fewer shards, see [`quotas`]) and queues one copy of the request per shard on
+//! `FANOUT_QUEUE_URL`; the same function consumes them as workers. Each worker writes its rows to
+//! `REPORT_BUCKET` under `fanout/<job_id>/` and records its shard on the job, and whichever worker
+//! completes the set assembles `report.jsonl`. Backfill-priority jobs go on their own queue and
+//! yield to interactive ones; see [`priority`].
+
+use anyhow::{anyhow, Result};
+use aws_sdk_dynamodb::types::{AttributeValue, ReturnValue};
+use aws_sdk_s3::primitives::ByteStream;
+use aws_sdk_sqs::types::SendMessageBatchRequestEntry;
+use chrono::{SecondsFormat, Utc};
+use lambda_runtime::Error;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tracing::info;
+use utoipa::ToSchema;
+
+use crate::priority::Priority;
+use crate::{config, metrics, quotas, retries, CustomOutput, ErrorOutput};
+
+pub const JOBS_TABLE: &str = "ride_data_fanout_jobs";
+
+/// Set by the coordinator on each queued shard.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema, ToSchema)]
+pub struct FanOutJob {
+    pub job_id: String,
+    pub shard: usize,
+}
+
+impl FanOutJob {
+    /// Stored on every row the shard writes, so a redelivered message cannot apply twice.
+    pub fn idempotency_token(&self) -> String {
+        format!("{}#{}", self.job_id, self.shard)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct FanOutOutput {
+    job_id: String,
+    shards: usize,
+    imeis: usize,
+    /// Where the assembled report appears once every shard has completed.
+    report_uri: String,
+}
+
+pub async fn coordinate(shared_config: &aws_config::SdkConfig, request: &Value, imeis: &[String], priority: Priority, run_id: &str) -> Result<Value, Error> {
+    let (Some(queue_url), Some(bucket)) = (priority.queue_url(), config::get().report_bucket.clone()) else {
+        return Ok(json!(ErrorOutput { error: "fan_out needs FANOUT_QUEUE_URL and REPORT_BUCKET".to_string() }));
+    };
+    let mut shard_size = config::get().fanout_shard_size;
+    if let Some(max_shards) = quotas::max_fan_out_shards() {
+        let needed = imeis.len().div_ceil(max_shards);
+        if needed > shard_size {
+            info!("Raising the fan-out shard size to {} to stay within {} shards", needed, max_shards);
+            shard_size = needed;
+        }
+    }
+    let shards: Vec<&[String]> = imeis.chunks(shard_size).collect();
+    let job_id = run_id.to_string();
+
+    retries::dynamodb(shared_config).put_item()
+        .table_name(JOBS_TABLE)
+        .item("job_id", AttributeValue::S(job_id.clone()))
+        .item("total_shards", AttributeValue::N(shards.len().to_string()))
+        .item("status", AttributeValue::S("running".to_string()))
+        .item("priority", AttributeValue::S(priority.as_str().to_string()))
+        .item("created_at", AttributeValue::S(Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true)))
+        .item("request", AttributeValue::S(request.to_string()))
+        .send()
+        .await
+        .inspect_err(|_| metrics::dynamodb_error("put_item"))?;
+
+    let sqs = aws_sdk_sqs::Client::new(shared_config);
+    for (batch_index, batch) in shards.chunks(10).enumerate() {
+        let mut send = sqs.send_message_batch().queue_url(&queue_url);
+        for (i, shard) in batch.iter().enumerate() {
+            let shard_number = batch_index * 10 + i;
+            let body = shard_request(request, shard, &FanOutJob { job_id: job_id.clone(), shard: shard_number });
+            send = send.entries(SendMessageBatchRequestEntry::builder()
+                .id(shard_number.to_string())
+                .message_body(body.to_string())
+                .message_attributes("priority", priority.attribute()?)
+                .build()?);
+        }
+        let resp = send.send().await?;
+        if !resp.failed().is_empty() {
+            return Err(anyhow!("queueing fan-out shards failed: {:?}", resp.failed()).into());
+        }
+    }
+
+    info!("Fan-out job {} queued {} {} shards for {} imeis", job_id, shards.len(), priority.as_str(), imeis.len());
+    Ok(json!(FanOutOutput {
+        report_uri: format!("s3://{}/fanout/{}/report.jsonl", bucket, job_id),
+        job_id,
+        shards: shards.len(),
+        imeis: imeis.len(),
+    }))
+}
+
+/// The original request narrowed to one shard's IMEIs, with anything that selected IMEIs removed.
+fn shard_request(request: &Value, shard: &[String], job: &FanOutJob) -> Value {
+    let mut request = request.clone();
+    if let Some(fields) = request.as_object_mut() {
+        for field in ["fan_out", "cohorts", "cohort_tags", "groups", "imeis_compressed", "imeis_s3_uri", "input_manifest_s3_uri"] {
+            fields.remove(field);
+        }
+        fields.insert("imeis".to_string(), json!(shard.join(",")));
+        fields.insert("fan_out_job".to_string(), json!(job));
+    }
+    request
+}
+
+/// Stores a worker's rows and marks its shard done, assembling the report when it was the last one.
+pub async fn complete_shard(shared_config: &aws_config::SdkConfig, job: &FanOutJob, rows: &[CustomOutput]) -> Result<()> {
+    let bucket = config::get().report_bucket.clone().ok_or_else(|| anyhow!("fan-out workers need REPORT_BUCKET"))?;
+    let s3 = aws_sdk_s3::Client::new(shared_config);
+    let mut body = String::new();
+    for row in rows {
+        body.push_str(&serde_json::to_string(row)?);
+        body.push('\n');
+    }
+    s3.put_object()
+        .bucket(&bucket)
+        .key(format!("fanout/{}/shard-{:05}.jsonl", job.job_id, job.shard))
+        .body(ByteStream::from(body.into_bytes()))
+        .send()
+        .await?;
+
+    // Shards are recorded as a number set, so a redelivered message does not count twice.
+    let resp = retries::dynamodb(shared_config).update_item()
+        .table_name(JOBS_TABLE)
+        .key("job_id", AttributeValue::S(job.job_id.clone()))
+        .update_expression("ADD completed_shards :shard")
+        .expression_attribute_values(":shard", AttributeValue::Ns(vec![job.shard.to_string()]))
+        .return_values(ReturnValue::AllNew)
+        .send()
+        .await
+        .inspect_err(|_| metrics::dynamodb_error("update_item"))?;
+    let job_item = resp.attributes().ok_or_else(|| anyhow!("fan-out job {} has no tracker row", job.job_id))?;
+    let completed = job_item.get("completed_shards").and_then(|v| v.as_ns().ok()).map(|s| s.len()).unwrap_or(0);
+    let total: usize = job_item.get("total_shards").and_then(|v| v.as_n().ok()).and_then(|n| n.parse().ok()).unwrap_or(usize::MAX);
+    if completed < total {
+        return Ok(());
+    }
+
+    let mut report = Vec::new();
+    for shard in 0..total {
+        let key = format!("fanout/{}/shard-{:05}.jsonl", job.job_id, shard);
+        let object = s3.get_object().bucket(&bucket).key(&key).send().await?;
+        report.extend(object.body.collect().await?.into_bytes());
+    }
+    let report_key = format!("fanout/{}/report.jsonl", job.job_id);
+    s3.put_object().bucket(&bucket).key(&report_key).body(ByteStream::from(report)).send().await?;
+
+    retries::dynamodb(shared_config).update_item()
+        .table_name(JOBS_TABLE)
+        .key("job_id", AttributeValue::S(job.job_id.clone()))
+        .update_expression("SET #status = :complete, report_uri = :uri, completed_at = :at")
+        .expression_attribute_names("#status", "status")
+        .expression_attribute_values(":complete", AttributeValue::S("complete".to_string()))
+        .expression_attribute_values(":uri", AttributeValue::S(format!("s3://{}/{}", bucket, report_key)))
+        .expression_attribute_values(":at", AttributeValue::S(Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true)))
+        .send()
+        .await
+        .inspect_err(|_| metrics::dynamodb_error("update_item"))?;
+    info!("Fan-out job {} complete: {} shards assembled", job.job_id, total);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn each_shard_writes_with_its_own_token() {
+        let job = |shard| FanOutJob { job_id: "run-1".to_string(), shard };
+        assert_eq!(job(0).idempotency_token(), "run-1#0");
+        assert_eq!(job(0).idempotency_token(), job(0).idempotency_token());
+        assert_ne!(job(0).idempotency_token(), job(1).idempotency_token());
+    }
+
+    #[test]
+    fn shard_requests_carry_the_job_and_only_their_imeis() {
+        let request = json!({"imeis": "1,2,3", "cohorts": "fleet-a", "fan_out": true, "month": "2024-04"});
+        let job = FanOutJob { job_id: "run-1".to_string(), shard: 1 };
+        let shard = shard_request(&request, &["2".to_string(), "3".to_string()], &job);
+        assert_eq!(shard, json!({"imeis": "2,3", "month": "2024-04", "fan_out_job": {"job_id": "run-1", "shard": 1}}));
+    }
+}
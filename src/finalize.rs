@@ -0,0 +1,39 @@
+use aws_sdk_dynamodb::Client;
+use chrono::SecondsFormat;
+use lambda_runtime::Error;
+use schemars::JsonSchema;
+use serde::Serialize;
+use serde_json::{json, Value};
+use tracing::warn;
+
+use crate::clock::Clock;
+use crate::{aggregates, CustomEvent, ErrorOutput};
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct FinalizeOutput {
+    imei: String,
+    ride_month: String,
+    /// False when no aggregate row exists yet for that month.
+    finalized: bool,
+}
+
+/// `action: "finalize"`: locks the `input_ride_month` row of every IMEI once the books close.
+pub async fn finalize(client: &Client, payload: &CustomEvent, clock: &Clock) -> Result<Value, Error> {
+    let Some(ride_month) = &payload.input_ride_month else {
+        return Ok(json!(ErrorOutput { error: "input_ride_month is required to finalize".to_string() }));
+    };
+    if payload.imeis.is_empty() {
+        return Ok(json!(ErrorOutput { error: "IMEI cannot be empty".to_string() }));
+    }
+
+    let finalized_at = clock.now().to_rfc3339_opts(SecondsFormat::Secs, true);
+    let mut output = Vec::new();
+    for imei in &payload.imeis {
+        let finalized = aggregates::finalize_row(client, imei, ride_month, &finalized_at).await?;
+        if !finalized {
+            warn!("No aggregate row to finalize for imei {} month {}", imei, ride_month);
+        }
+        output.push(FinalizeOutput { imei: imei.to_string(), ride_month: ride_month.clone(), finalized });
+    }
+    Ok(json!(output))
+}
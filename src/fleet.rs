@@ -0,0 +1,223 @@
+//! Fleet rollups, stored in the aggregates table beside the device rows:
+//!
+//! - With `include_by_month`, the response totals the device rows per period.
+//! - With `include_fleet_summary`, a run totals every requested device per period into rows keyed
+//!   `FLEET#<fleet_group_id>` (default `FLEET#all`).
+//! - Every device row an aggregation writes also adds its change (new stats minus what the rollup
+//!   last counted for it) to the `FLEET#*` row of its period with an atomic `ADD`, so that row totals
+//!   every device without being recomputed and re-runs only add their difference. Ride corrections
+//!   add their change the same way; other rows changed outside aggregation runs (imports) are not
+//!   reflected.
+
+use aws_sdk_dynamodb::Client;
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use tracing::error;
+use utoipa::ToSchema;
+
+use crate::aggregates::{self, RowTotals};
+use crate::cost::CapacityMeter;
+use crate::stats::{Metric, RowExplain};
+use crate::{experiments, CustomEvent, CustomOutput};
+
+/// Key of the incrementally maintained rollup over every device.
+pub const ROLLUP_KEY: &str = "FLEET#*";
+
+/// Whether an aggregates-table key is a fleet row rather than a device.
+pub fn is_fleet_key(imei: &str) -> bool {
+    imei.starts_with("FLEET#")
+}
+
+pub fn key(payload: &CustomEvent) -> String {
+    format!("FLEET#{}", payload.fleet_group_id.as_deref().unwrap_or("all"))
+}
+
+/// Sums the device rows per period. With `incomplete` (some device failed, was cut off or was
+/// completed by an earlier run) the rows are marked truncated, so they are reported but not stored.
+pub fn rollup(key: &str, rows: &[CustomOutput], incomplete: bool) -> Vec<CustomOutput> {
+    let mut periods: BTreeMap<&str, CustomOutput> = BTreeMap::new();
+    // Per period: the speed-times-duration of rows with both, and their duration, for a duration-weighted average.
+    let mut timed: BTreeMap<&str, (f64, f64)> = BTreeMap::new();
+    for row in rows {
+        let fleet = periods.entry(&row.ride_month).or_insert_with(|| CustomOutput {
+            imei: key.to_string(),
+            granularity: row.granularity,
+            ride_month: row.ride_month.clone(),
+            total_distance: 0.0,
+            total_duration: None,
+            ride_count: None,
+            average_speed: None,
+            max_speed: None,
+            total_energy: None,
+            distance_histogram: None,
+            month_to_date: false,
+            as_of: row.as_of.clone(),
+            write_skipped: None,
+            breakdowns: BTreeMap::new(),
+            explain: RowExplain::default(),
+            truncated: incomplete,
+            fraud_flags: Vec::new(),
+            experimental: BTreeMap::new(),
+            applied_rides: Default::default(),
+        });
+        fleet.total_distance += row.total_distance;
+        fleet.total_duration = add(fleet.total_duration, row.total_duration);
+        fleet.ride_count = row.ride_count.map(|count| fleet.ride_count.unwrap_or(0) + count).or(fleet.ride_count);
+        fleet.total_energy = add(fleet.total_energy, row.total_energy);
+        fleet.max_speed = match (fleet.max_speed, row.max_speed) {
+            (Some(a), Some(b)) => Some(a.max(b)),
+            (a, b) => a.or(b),
+        };
+        if let Some(histogram) = &row.distance_histogram {
+            let fleet_histogram = fleet.distance_histogram.get_or_insert_with(BTreeMap::new);
+            for (band, rides) in histogram {
+                *fleet_histogram.entry(band.clone()).or_default() += rides;
+            }
+        }
+        fleet.month_to_date |= row.month_to_date;
+        fleet.truncated |= row.truncated;
+        fleet.explain.add(&row.explain);
+        experiments::add(&mut fleet.experimental, &row.experimental);
+        if let (Some(speed), Some(duration)) = (row.average_speed, row.total_duration) {
+            let entry = timed.entry(&row.ride_month).or_default();
+            entry.0 += speed * duration;
+            entry.1 += duration;
+        }
+    }
+    periods.into_iter().map(|(period, mut fleet)| {
+        fleet.average_speed = timed.get(period).filter(|(_, duration)| *duration > 0.0).map(|(weighted, duration)| weighted / duration);
+        fleet
+    }).collect()
+}
+
+#[derive(Debug, Clone, Default, Serialize, JsonSchema, ToSchema)]
+pub struct PeriodTotal {
+    pub total_distance: f64,
+    /// Devices with at least one counted ride in the period.
+    pub devices: usize,
+}
+
+/// Totals per period over the device rows, for `include_by_month`.
+pub fn by_month(rows: &[CustomOutput]) -> BTreeMap<String, PeriodTotal> {
+    let mut periods: BTreeMap<String, PeriodTotal> = BTreeMap::new();
+    for row in rows {
+        let total = periods.entry(row.ride_month.clone()).or_default();
+        total.total_distance += row.total_distance;
+        if row.explain.rides_included > 0 {
+            total.devices += 1;
+        }
+    }
+    periods
+}
+
+/// Attempts at adding a row's change before giving up, each after re-reading its contribution.
+const ROLLUP_ATTEMPTS: usize = 3;
+
+/// Adds a written device row's change to its period's `FLEET#*` row. The change is taken from what
+/// the rollup last counted for the row (its [`aggregates::Contribution`]), recorded in the same
+/// transaction as the `ADD`, so concurrent or repeated runs add each change once. A row stored before
+/// contributions were recorded counts as its `previous` totals. With `written` metrics (an update
+/// setting only those), the rest of the row was left as stored and stays counted as it was. A failure
+/// is logged rather than failing the run, whose device rows are already stored.
+pub async fn maintain(client: &Client, row: &CustomOutput, previous: Option<RowTotals>, written: Option<&[Metric]>, meter: &CapacityMeter) {
+    if is_fleet_key(&row.imei) {
+        return;
+    }
+    for _ in 0..ROLLUP_ATTEMPTS {
+        let result = async {
+            let recorded = aggregates::get_contribution(client, row, meter).await?;
+            let Some(change) = change(recorded.map(|c| c.totals).or(previous), row, written) else {
+                return Ok(true);
+            };
+            aggregates::add_to_rollup(client, ROLLUP_KEY, row, recorded.map(|c| c.version), &change, meter).await
+        };
+        match result.await {
+            Ok(true) => return,
+            Ok(false) => {}
+            Err(err) => {
+                error!("Error updating fleet rollup for imei {} period {}: {:?}", row.imei, row.ride_month, err);
+                return;
+            }
+        }
+    }
+    error!("Fleet rollup for imei {} period {} kept changing; it was not updated", row.imei, row.ride_month);
+}
+
+/// What to add to the rollup for `row`, when it last counted the row as `counted` (None: not at all);
+/// None when it already counts the row as it is. Metrics outside `written`, if given, count as before.
+fn change(counted: Option<RowTotals>, row: &CustomOutput, written: Option<&[Metric]>) -> Option<aggregates::RollupChange> {
+    let mut totals = RowTotals::of_row(row);
+    if let (Some(counted), Some(written)) = (counted, written) {
+        let kept = |metric| !written.contains(&metric);
+        if kept(Metric::Distance) {
+            totals.distance = counted.distance;
+        }
+        if kept(Metric::RideCount) {
+            totals.ride_count = counted.ride_count;
+        }
+        if kept(Metric::Duration) {
+            totals.duration = counted.duration;
+        }
+        if kept(Metric::Energy) {
+            totals.energy = counted.energy;
+        }
+    }
+    let delta = totals.minus(counted.unwrap_or_default());
+    let devices = if counted.is_some() { 0 } else { 1 };
+    if delta == RowTotals::default() && devices == 0 {
+        return None;
+    }
+    Some(aggregates::RollupChange { totals, delta, devices })
+}
+
+fn add(total: Option<f64>, value: Option<f64>) -> Option<f64> {
+    value.map(|value| total.unwrap_or(0.0) + value).or(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The rollup's totals and devices after adding `change`.
+    fn apply(rollup: (RowTotals, i64), change: Option<aggregates::RollupChange>) -> (RowTotals, i64) {
+        let Some(change) = change else {
+            return rollup;
+        };
+        let (totals, delta) = (rollup.0, change.delta);
+        let added = RowTotals {
+            distance: totals.distance + delta.distance,
+            ride_count: totals.ride_count + delta.ride_count,
+            duration: totals.duration + delta.duration,
+            energy: totals.energy + delta.energy,
+        };
+        (added, rollup.1 + change.devices)
+    }
+
+    #[test]
+    fn rerunning_a_month_leaves_the_rollup_unchanged() {
+        let mut row = CustomOutput::fixture("350000000000001", "2024-04", 12.5);
+        row.ride_count = Some(3);
+        let first = apply((RowTotals::default(), 0), change(None, &row, None));
+        assert_eq!(first, (RowTotals::of_row(&row), 1));
+        // A re-run reads the contribution the first one recorded.
+        assert_eq!(change(Some(RowTotals::of_row(&row)), &row, None), None);
+        assert_eq!(apply(first, change(Some(RowTotals::of_row(&row)), &row, None)), first);
+
+        let counted = RowTotals::of_row(&row);
+        row.total_distance = 15.0;
+        let revised = apply(first, change(Some(counted), &row, None));
+        assert_eq!(revised, (RowTotals::of_row(&row), 1));
+    }
+
+    #[test]
+    fn an_update_of_some_metrics_keeps_counting_the_others_as_stored() {
+        let counted = RowTotals { distance: 12.5, ride_count: 3.0, duration: 5400.0, energy: 800.0 };
+        // A run computing only the distance leaves the row's other metrics unset.
+        let row = CustomOutput::fixture("350000000000001", "2024-04", 15.0);
+        let change = change(Some(counted), &row, Some(&[Metric::Distance])).unwrap();
+        assert_eq!(change.delta, RowTotals { distance: 2.5, ..Default::default() });
+        assert_eq!(change.totals, RowTotals { distance: 15.0, ..counted });
+        assert_eq!(change.devices, 0);
+    }
+}
@@ -0,0 +1,84 @@
+//! `fraud_checks`: heuristics run per device-month over the rides that counted. A month is flagged
+//! when at least `FRAUD_DUPLICATE_MIN_RIDES` rides (default 5) share one distance (to 10 m) and make up
+//! `FRAUD_DUPLICATE_SHARE` of the month (default 0.5), or when getting from where one ride ended to
+//! where the next started takes more than `FRAUD_MAX_REPOSITION_KMH` (default 150). Positions are the
+//! optional `start_lat`/`start_lng`/`end_lat`/`end_lng` entries of `ride_stats`.
+
+use aws_sdk_dynamodb::types::AttributeValue;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use utoipa::ToSchema;
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize, JsonSchema, ToSchema)]
+#[serde(rename_all = "snake_case", tag = "kind")]
+pub enum FraudFlag {
+    /// `rides` rides of the month all cover `distance` km.
+    DuplicateDistance { distance: f64, rides: u64 },
+    /// The device covered `km` between the ride ending before `ride_start` and that ride, in `hours`.
+    Teleportation { ride_start: u64, km: f64, hours: f64 },
+}
+
+/// What the heuristics need from one counted ride.
+#[derive(Debug, Clone)]
+pub struct RideFacts {
+    pub ride_start: u64,
+    pub ride_end: Option<u64>,
+    pub distance: f64,
+    pub start: Option<(f64, f64)>,
+    pub end: Option<(f64, f64)>,
+}
+
+impl RideFacts {
+    pub fn new(item: &HashMap<String, AttributeValue>, ride_start: u64, ride_end: Option<u64>, distance: f64) -> RideFacts {
+        let stats = item.get("ride_stats").and_then(|v| v.as_m().ok());
+        let coordinate = |name: &str| stats?.get(name)?.as_s().ok()?.parse::<f64>().ok();
+        let position = |lat: &str, lng: &str| Some((coordinate(lat)?, coordinate(lng)?));
+        RideFacts {
+            ride_start,
+            ride_end,
+            distance,
+            start: position("start_lat", "start_lng"),
+            end: position("end_lat", "end_lng"),
+        }
+    }
+}
+
+/// Flags for one device-month.
+pub fn flags(rides: &mut [RideFacts]) -> Vec<FraudFlag> {
+    let mut flags = Vec::new();
+
+    let mut by_distance: HashMap<i64, u64> = HashMap::new();
+    for ride in rides.iter() {
+        *by_distance.entry((ride.distance * 100.0).round() as i64).or_default() += 1;
+    }
+    let min_rides = crate::config::get().fraud_duplicate_min_rides;
+    let share = crate::config::get().fraud_duplicate_share;
+    let mut duplicates: Vec<_> = by_distance.into_iter()
+        .filter(|(_, count)| *count >= min_rides && *count as f64 >= share * rides.len() as f64)
+        .collect();
+    duplicates.sort();
+    flags.extend(duplicates.into_iter().map(|(distance, rides)| FraudFlag::DuplicateDistance { distance: distance as f64 / 100.0, rides }));
+
+    let max_kmh = crate::config::get().fraud_max_reposition_kmh;
+    rides.sort_by_key(|ride| ride.ride_start);
+    for pair in rides.windows(2) {
+        let (previous, next) = (&pair[0], &pair[1]);
+        let (Some(from), Some(to), Some(ended)) = (previous.end, next.start, previous.ride_end) else {
+            continue;
+        };
+        let km = haversine_km(from, to);
+        let hours = next.ride_start.saturating_sub(ended) as f64 / 3600.0;
+        if km > 1.0 && km > max_kmh * hours {
+            flags.push(FraudFlag::Teleportation { ride_start: next.ride_start, km, hours });
+        }
+    }
+    flags
+}
+
+/// Great-circle distance between two `(lat, lng)` points in degrees.
+fn haversine_km((lat1, lng1): (f64, f64), (lat2, lng2): (f64, f64)) -> f64 {
+    let (dlat, dlng) = ((lat2 - lat1).to_radians(), (lng2 - lng1).to_radians());
+    let a = (dlat / 2.0).sin().powi(2) + lat1.to_radians().cos() * lat2.to_radians().cos() * (dlng / 2.0).sin().powi(2);
+    2.0 * 6371.0 * a.sqrt().asin()
+}
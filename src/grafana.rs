@@ -0,0 +1,107 @@
+//! Grafana JSON datasource contract (`/`, `/search`, `/query`) over the aggregates table.
+//! Each target is an IMEI; datapoints are monthly totals stamped at the start of the month in IST.
+
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::Json;
+use aws_sdk_dynamodb::Client;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+use crate::time::{self, Granularity};
+use crate::aggregates;
+
+#[derive(Debug, Deserialize, Default)]
+pub struct SearchRequest {
+    #[serde(default)]
+    target: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct QueryRequest {
+    range: QueryRange,
+    targets: Vec<QueryTarget>,
+}
+
+#[derive(Debug, Deserialize)]
+struct QueryRange {
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+struct QueryTarget {
+    target: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TimeSeries {
+    target: String,
+    datapoints: Vec<(f64, i64)>,
+}
+
+type HandlerError = (StatusCode, String);
+
+fn internal_error(err: anyhow::Error) -> HandlerError {
+    error!("Error serving grafana request: {:?}", err);
+    (StatusCode::INTERNAL_SERVER_ERROR, err.to_string())
+}
+
+pub async fn test_connection() -> StatusCode {
+    StatusCode::OK
+}
+
+pub async fn search(State(client): State<Client>, body: Option<Json<SearchRequest>>) -> Result<Json<Vec<String>>, HandlerError> {
+    let request = body.map(|Json(body)| body).unwrap_or_default();
+    let imeis = aggregates::scan_imeis(&client, &request.target).await.map_err(internal_error)?;
+    Ok(Json(imeis))
+}
+
+pub async fn query(State(client): State<Client>, Json(request): Json<QueryRequest>) -> Result<Json<Vec<TimeSeries>>, HandlerError> {
+    let from_month = time::month_of(request.range.from).map_err(|err| internal_error(err.into()))?;
+    let to_month = time::month_of(request.range.to).map_err(|err| internal_error(err.into()))?;
+
+    let mut series = Vec::with_capacity(request.targets.len());
+    for target in request.targets {
+        let rows = aggregates::query_periods(&client, &target.target, Granularity::Monthly, &from_month, &to_month)
+            .await
+            .map_err(internal_error)?;
+        series.push(TimeSeries { target: target.target, datapoints: datapoints(&rows) });
+    }
+    Ok(Json(series))
+}
+
+/// `[value, epoch millis]` pairs, one per month with a stored total.
+fn datapoints(rows: &[aggregates::PeriodRow]) -> Vec<(f64, i64)> {
+    rows.iter()
+        .filter_map(|row| Some((row.total_distance, time::month_start(&row.period, 0).ok()?.timestamp_millis())))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn queries_read_the_range_and_targets_grafana_sends() {
+        let request: QueryRequest = serde_json::from_value(json!({
+            "range": {"from": "2024-01-15T00:00:00.000Z", "to": "2024-03-31T23:59:59.999Z", "raw": {"from": "now-90d", "to": "now"}},
+            "targets": [{"target": "359000000000001", "refId": "A", "type": "timeserie"}],
+            "maxDataPoints": 100,
+        })).unwrap();
+        assert_eq!(request.range.from.to_rfc3339(), "2024-01-15T00:00:00+00:00");
+        assert_eq!(request.targets.iter().map(|target| target.target.as_str()).collect::<Vec<_>>(), ["359000000000001"]);
+    }
+
+    #[test]
+    fn datapoints_are_stamped_at_the_local_start_of_the_month() {
+        let row = |period: &str, total_distance| aggregates::PeriodRow { period: period.to_string(), total_distance };
+        let series = TimeSeries { target: "359000000000001".to_string(), datapoints: datapoints(&[row("2024-03", 12.5), row("2024-04", 3.0), row("bad", 1.0)]) };
+        assert_eq!(serde_json::to_value(series).unwrap(), json!({
+            "target": "359000000000001",
+            "datapoints": [[12.5, 1709231400000_i64], [3.0, 1711909800000_i64]],
+        }));
+    }
+}
@@ -0,0 +1,45 @@
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::Json;
+use aws_sdk_dynamodb::Client;
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::{aggregates, audit, config, history, metrics};
+
+#[derive(Debug, Serialize)]
+struct TableCheck {
+    table: &'static str,
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[utoipa::path(get, path = "/healthz", responses((status = 200, description = "The process is up")))]
+pub async fn healthz() -> Json<Value> {
+    Json(json!({"status": "ok"}))
+}
+
+/// Ready once every table the service touches can be described, which also proves
+/// the endpoint is reachable and the credentials are accepted.
+#[utoipa::path(get, path = "/readyz", responses(
+    (status = 200, description = "Every table can be described"),
+    (status = 503, description = "At least one table check failed"),
+))]
+pub async fn readyz(State(client): State<Client>) -> (StatusCode, Json<Value>) {
+    let mut checks = Vec::new();
+    for table in [config::get().ride_table.as_str(), aggregates::table_name(), history::TABLE_NAME, audit::TABLE_NAME] {
+        let check = match client.describe_table().table_name(table).send().await {
+            Ok(_) => TableCheck { table, ok: true, error: None },
+            Err(err) => {
+                metrics::dynamodb_error("describe_table");
+                TableCheck { table, ok: false, error: Some(format!("{}", aws_sdk_dynamodb::error::DisplayErrorContext(&err))) }
+            }
+        };
+        checks.push(check);
+    }
+
+    let ready = checks.iter().all(|c| c.ok);
+    let status = if ready { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    (status, Json(json!({"status": if ready { "ready" } else { "not_ready" }, "checks": checks})))
+}
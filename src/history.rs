@@ -0,0 +1,106 @@
+use aws_sdk_dynamodb::types::{AttributeValue, ReturnConsumedCapacity};
+use aws_sdk_dynamodb::Client;
+use anyhow::Result;
+use serde::Serialize;
+use std::collections::HashMap;
+
+use crate::cost::CapacityMeter;
+use crate::time::Granularity;
+use crate::{aggregates, config, metrics};
+
+pub const TABLE_NAME: &str = "ride_data_monthly_distance_history";
+
+/// Minimum change in km before a restated aggregate is recorded, from `REVISION_THRESHOLD_KM` (default 0.01).
+pub fn threshold_from_env() -> f64 {
+    config::get().revision_threshold_km
+}
+
+#[derive(Debug, Clone)]
+pub struct Revision<'a> {
+    pub imei: &'a str,
+    pub granularity: Granularity,
+    pub ride_month: &'a str,
+    pub previous_value: f64,
+    pub new_value: f64,
+    pub run_id: &'a str,
+    pub reason: &'a str,
+    pub revised_at: &'a str,
+}
+
+/// History rows are keyed by `imei#<aggregate sort key>` with `revised_at` as the sort key, so one
+/// aggregate's restatements read back in chronological order.
+pub async fn record(client: &Client, revision: &Revision<'_>, meter: &CapacityMeter) -> Result<()> {
+    let resp = client.put_item()
+        .table_name(TABLE_NAME)
+        .item("aggregate_key", AttributeValue::S(format!("{}#{}", revision.imei, aggregates::sort_key(revision.granularity, revision.ride_month))))
+        .item("revised_at", AttributeValue::S(format!("{}#{}", revision.revised_at, revision.run_id)))
+        .item("imei", AttributeValue::S(revision.imei.to_string()))
+        .item("ride_month", AttributeValue::S(revision.ride_month.to_string()))
+        .item("previous_value", AttributeValue::N(revision.previous_value.to_string()))
+        .item("new_value", AttributeValue::N(revision.new_value.to_string()))
+        .item("run_id", AttributeValue::S(revision.run_id.to_string()))
+        .item("reason", AttributeValue::S(revision.reason.to_string()))
+        .return_consumed_capacity(ReturnConsumedCapacity::Total)
+        .send()
+        .await
+        .inspect_err(|_| metrics::dynamodb_error("put_item"))?;
+    meter.write(resp.consumed_capacity());
+    Ok(())
+}
+
+/// One recorded restatement of an aggregate.
+#[derive(Debug, Clone, Serialize)]
+pub struct StoredRevision {
+    /// RFC 3339 time the restatement was written.
+    pub revised_at: String,
+    pub run_id: String,
+    pub previous_value: f64,
+    pub new_value: f64,
+    pub reason: String,
+}
+
+/// The last revision written before `before`, and failing that the first one written after it.
+pub async fn around(client: &Client, imei: &str, granularity: Granularity, period: &str, before: &str) -> Result<(Option<StoredRevision>, Option<StoredRevision>)> {
+    let key = format!("{}#{}", imei, aggregates::sort_key(granularity, period));
+    let query = |condition: &str, forward: bool| client.query()
+        .table_name(TABLE_NAME)
+        .key_condition_expression(format!("aggregate_key = :key AND {}", condition))
+        .expression_attribute_values(":key", AttributeValue::S(key.clone()))
+        .expression_attribute_values(":before", AttributeValue::S(before.to_string()))
+        .scan_index_forward(forward)
+        .limit(1)
+        .send();
+
+    let earlier = query("revised_at < :before", false).await.inspect_err(|_| metrics::dynamodb_error("query"))?;
+    if let Some(revision) = earlier.items().first().and_then(parse_revision) {
+        return Ok((Some(revision), None));
+    }
+    let later = query("revised_at >= :before", true).await.inspect_err(|_| metrics::dynamodb_error("query"))?;
+    Ok((None, later.items().first().and_then(parse_revision)))
+}
+
+/// The `limit` latest revisions, newest first.
+pub async fn recent(client: &Client, imei: &str, granularity: Granularity, period: &str, limit: usize) -> Result<Vec<StoredRevision>> {
+    let resp = client.query()
+        .table_name(TABLE_NAME)
+        .key_condition_expression("aggregate_key = :key")
+        .expression_attribute_values(":key", AttributeValue::S(format!("{}#{}", imei, aggregates::sort_key(granularity, period))))
+        .scan_index_forward(false)
+        .limit(limit as i32)
+        .send()
+        .await
+        .inspect_err(|_| metrics::dynamodb_error("query"))?;
+    Ok(resp.items().iter().filter_map(parse_revision).collect())
+}
+
+fn parse_revision(item: &HashMap<String, AttributeValue>) -> Option<StoredRevision> {
+    let text = |name: &str| item.get(name).and_then(|v| v.as_s().ok()).cloned();
+    let number = |name: &str| item.get(name).and_then(|v| v.as_n().ok()).and_then(|n| n.parse().ok());
+    Some(StoredRevision {
+        revised_at: text("revised_at")?.split_once('#')?.0.to_string(),
+        run_id: text("run_id").unwrap_or_default(),
+        previous_value: number("previous_value")?,
+        new_value: number("new_value")?,
+        reason: text("reason").unwrap_or_default(),
+    })
+}
@@ -0,0 +1,97 @@
+use axum::extract::{FromRef, MatchedPath, Request, State};
+use axum::http::StatusCode;
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use aws_config::SdkConfig;
+use aws_sdk_dynamodb::Client;
+use lambda_runtime::Error;
+use serde_json::Value;
+use std::time::Instant;
+use tracing::{error, info, info_span, Instrument};
+use utoipa::OpenApi;
+
+use crate::{config, grafana, health, metrics, retries, AggregationResponse, CustomEvent, ErrorOutput};
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "ride-data", description = "Monthly ride distance aggregation over HTTP."),
+    paths(ride_data, metrics_text, health::healthz, health::readyz),
+    components(schemas(ErrorOutput)),
+)]
+struct ApiDoc;
+
+#[derive(Clone)]
+struct AppState {
+    shared_config: SdkConfig,
+    client: Client,
+}
+
+impl FromRef<AppState> for Client {
+    fn from_ref(state: &AppState) -> Client {
+        state.client.clone()
+    }
+}
+
+/// Long-lived HTTP mode for container deployments, listening on `PORT` (default 8080).
+pub async fn serve(shared_config: SdkConfig) -> Result<(), Error> {
+    let state = AppState { client: retries::dynamodb(&shared_config), shared_config };
+    let app = Router::new()
+        .route("/ride-data", post(ride_data))
+        .route("/grafana", get(grafana::test_connection))
+        .route("/grafana/search", post(grafana::search))
+        .route("/grafana/query", post(grafana::query))
+        .route("/metrics", get(metrics_text))
+        .route("/openapi.json", get(|| async { Json(ApiDoc::openapi()) }))
+        .route("/healthz", get(health::healthz))
+        .route("/readyz", get(health::readyz))
+        .route_layer(middleware::from_fn(track_metrics))
+        .with_state(state);
+
+    let port = config::get().port;
+    let listener = tokio::net::TcpListener::bind(("0.0.0.0", port)).await?;
+    info!("Listening on port {}", port);
+    axum::serve(listener, app).await?;
+    Ok(())
+}
+
+/// Handles one event exactly like the Lambda does; invalid events get a 200 with an `ErrorOutput` body,
+/// and a JSON array is answered with an array of per-request results.
+#[utoipa::path(post, path = "/ride-data", request_body = CustomEvent, responses(
+    (status = 200, description = "Aggregated rows, or an ErrorOutput for an invalid event", body = AggregationResponse),
+    (status = 500, description = "The request failed", body = String),
+))]
+async fn ride_data(State(state): State<AppState>, Json(payload): Json<Value>) -> Result<Json<Value>, (StatusCode, String)> {
+    let run_id = uuid::Uuid::new_v4().to_string();
+    let span = info_span!("request", request_id = %run_id);
+    let result = crate::handle_event(&state.shared_config, payload, &run_id).instrument(span.clone()).await;
+    if let Err(err) = &result {
+        error!(parent: &span, "Error handling request: {:?}", err);
+    }
+    drop(span);
+    crate::sentry::flush().await;
+    #[cfg(feature = "datadog")]
+    crate::datadog::flush().await;
+    result
+        .map(Json)
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
+}
+
+#[utoipa::path(get, path = "/metrics", responses((status = 200, description = "Prometheus text exposition", body = String)))]
+async fn metrics_text() -> String {
+    metrics::render()
+}
+
+async fn track_metrics(request: Request, next: Next) -> Response {
+    let path = request.extensions().get::<MatchedPath>()
+        .map(|p| p.as_str().to_string())
+        .unwrap_or_else(|| "unmatched".to_string());
+    let started = Instant::now();
+
+    let response = next.run(request).await.into_response();
+
+    metrics::HTTP_LATENCY.with_label_values(&[&path]).observe(started.elapsed().as_secs_f64());
+    metrics::HTTP_REQUESTS.with_label_values(&[&path, response.status().as_str()]).inc();
+    response
+}
@@ -0,0 +1,281 @@
+//! IMEI lists too large for the event payload: `imeis_compressed` carries a base64-encoded gzip of
+//! the list and `imeis_s3_uri` points at an object holding it (optionally gzipped). Entries are
+//! separated by commas or newlines.
+//!
+//! `imeis` itself is a JSON array or a comma-separated string; every IMEI must be 15 digits. To
+//! aggregate, an entry may instead be a pattern, 8 to 14 digits and a `*` (`8663070523*`), for every
+//! device in the devices table whose IMEI starts with those digits: a `begins_with` query on its
+//! `DEVICES_TAC_INDEX` (default `tac-imei-index`), keyed by the IMEI's first 8 digits (`tac`, the
+//! type allocation code) and `imei`. A pattern matching more than `IMEI_PATTERN_LIMIT` devices
+//! (default 10000) is rejected.
+
+use anyhow::Result;
+use aws_sdk_dynamodb::types::AttributeValue;
+use base64::Engine;
+use flate2::read::GzDecoder;
+use schemars::JsonSchema;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::io::Read;
+use tracing::{info, warn};
+use utoipa::ToSchema;
+
+use crate::{cohorts, config, kms, metrics, retries, s3, CustomEvent};
+
+/// Digits of the type allocation code, the shortest pattern prefix.
+const TAC_DIGITS: usize = 8;
+
+pub fn tac_index() -> String {
+    config::get().devices_tac_index.clone()
+}
+
+pub fn pattern_limit() -> usize {
+    config::get().imei_pattern_limit
+}
+
+pub fn is_pattern(entry: &str) -> bool {
+    entry.ends_with('*')
+}
+
+/// IMEIs from `imeis`, `imeis_compressed` and `imeis_s3_uri`, in that order. An undecodable
+/// input is reported as `Ok(Err(..))` so it can be rejected like any other invalid event.
+pub async fn requested(shared_config: &aws_config::SdkConfig, payload: &CustomEvent) -> Result<Result<Vec<String>, String>> {
+    let mut imeis = Vec::with_capacity(payload.imeis.len());
+    for entry in &payload.imeis {
+        match entry.strip_suffix('*') {
+            Some(prefix) => match expand(&retries::dynamodb(shared_config), prefix).await? {
+                Ok(matched) => imeis.extend(matched),
+                Err(err) => return Ok(Err(err)),
+            },
+            None => imeis.push(entry.clone()),
+        }
+    }
+    if let Some(compressed) = &payload.imeis_compressed {
+        let bytes = match base64::engine::general_purpose::STANDARD.decode(compressed.trim()) {
+            Ok(bytes) => bytes,
+            Err(err) => return Ok(Err(format!("imeis_compressed is not base64: {}", err))),
+        };
+        match decode(&bytes) {
+            Ok(text) => imeis.extend(split(&text)),
+            Err(err) => return Ok(Err(format!("imeis_compressed is not gzipped text: {}", err))),
+        }
+    }
+    if let Some(uri) = &payload.imeis_s3_uri {
+        if s3::parse_uri(uri).is_none() {
+            return Ok(Err(format!("imeis_s3_uri {:?} is not an s3://bucket/key URI", uri)));
+        }
+        let bytes = match s3::get_object(&aws_sdk_s3::Client::new(shared_config), uri, &kms::Encryption::for_request(payload)).await? {
+            Ok(bytes) => bytes,
+            Err(err) => return Ok(Err(err)),
+        };
+        match decode(&bytes) {
+            Ok(text) => imeis.extend(split(&text)),
+            Err(err) => return Ok(Err(format!("imeis_s3_uri does not hold a text IMEI list: {}", err))),
+        }
+    }
+    Ok(Ok(imeis))
+}
+
+/// The devices whose IMEI starts with `prefix`, in IMEI order.
+async fn expand(client: &aws_sdk_dynamodb::Client, prefix: &str) -> Result<Result<Vec<String>, String>> {
+    let limit = pattern_limit();
+    let mut items = client.query()
+        .table_name(cohorts::DEVICES_TABLE)
+        .index_name(tac_index())
+        .key_condition_expression("#tac = :tac AND begins_with(#imei, :prefix)")
+        .projection_expression("#imei")
+        .expression_attribute_names("#tac", "tac")
+        .expression_attribute_names("#imei", "imei")
+        .expression_attribute_values(":tac", AttributeValue::S(prefix[..TAC_DIGITS].to_string()))
+        .expression_attribute_values(":prefix", AttributeValue::S(prefix.to_string()))
+        .into_paginator()
+        .items()
+        .send();
+    let mut imeis = Vec::new();
+    while let Some(item) = items.next().await {
+        let item = item.inspect_err(|_| metrics::dynamodb_error("query"))?;
+        imeis.extend(item.get("imei").and_then(|v| v.as_s().ok()).cloned());
+        if imeis.len() > limit {
+            return Ok(Err(format!("IMEI pattern {}* matches more than IMEI_PATTERN_LIMIT ({}) devices", prefix, limit)));
+        }
+    }
+    match imeis.len() {
+        0 => warn!("IMEI pattern {}* matches no devices", prefix),
+        matched => info!("Expanded IMEI pattern {}* to {} devices", prefix, matched),
+    }
+    Ok(Ok(imeis))
+}
+
+/// Gunzips when the bytes carry the gzip magic number, then reads them as UTF-8.
+fn decode(bytes: &[u8]) -> std::io::Result<String> {
+    let mut text = String::new();
+    if bytes.starts_with(&[0x1f, 0x8b]) {
+        GzDecoder::new(bytes).read_to_string(&mut text)?;
+    } else {
+        text = String::from_utf8(bytes.to_vec()).map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+    }
+    Ok(text)
+}
+
+fn split(list: &str) -> Vec<String> {
+    list.split([',', '\n']).map(str::trim).filter(|imei| !imei.is_empty()).map(str::to_string).collect()
+}
+
+/// `imeis` as sent: a JSON array, or the older comma-separated string.
+#[derive(Deserialize, JsonSchema, ToSchema)]
+#[serde(untagged)]
+pub enum ImeiList {
+    List(Vec<String>),
+    Commas(String),
+}
+
+/// Either form of [`ImeiList`], as sent: [`tokenize`] and [`dedupe`] clean it up and report what they changed.
+pub fn deserialize_list<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    Ok(match ImeiList::deserialize(deserializer)? {
+        ImeiList::List(imeis) => imeis,
+        ImeiList::Commas(imeis) => imeis.split(',').map(str::to_string).collect(),
+    })
+}
+
+/// What [`tokenize`] changed in `imeis`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Cleanup {
+    /// Entries with leading or trailing whitespace.
+    pub trimmed: usize,
+    /// Entries that were empty once trimmed, and were dropped.
+    pub dropped_empty: usize,
+}
+
+/// Trims each entry and drops the empty ones, so `"a, b,,c "` is `[a, b, c]` rather than five
+/// lookups. Entries with anything but digits left in them are for [`validate`] to reject.
+pub fn tokenize(imeis: Vec<String>) -> (Vec<String>, Cleanup) {
+    let mut cleanup = Cleanup::default();
+    let imeis = imeis.into_iter().filter_map(|imei| {
+        let trimmed = imei.trim();
+        if trimmed.is_empty() {
+            cleanup.dropped_empty += 1;
+            return None;
+        }
+        if trimmed.len() != imei.len() {
+            cleanup.trimmed += 1;
+        }
+        Some(trimmed.to_string())
+    }).collect();
+    (imeis, cleanup)
+}
+
+/// Drops repeated IMEIs, keeping the first of each in order, and adds how many times each was
+/// repeated to `repeats`, so a device's rides are never queried and summed twice.
+pub fn dedupe(imeis: &mut Vec<String>, repeats: &mut BTreeMap<String, usize>) {
+    let mut seen = HashSet::with_capacity(imeis.len());
+    imeis.retain(|imei| {
+        let first = seen.insert(imei.clone());
+        if !first {
+            *repeats.entry(imei.clone()).or_default() += 1;
+        }
+        first
+    });
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema, ToSchema)]
+pub struct InvalidImei {
+    imei: String,
+    reason: String,
+}
+
+/// Returned instead of aggregating when any requested IMEI is malformed.
+#[derive(Debug, Clone, Serialize, JsonSchema, ToSchema)]
+pub struct InvalidImeisOutput {
+    pub error: String,
+    invalid_imeis: Vec<InvalidImei>,
+}
+
+/// Rejects IMEIs that are not 15 digits, and patterns that are not 8 to 14 digits and a `*`,
+/// listing each offender.
+pub fn validate(imeis: &[String]) -> Result<(), InvalidImeisOutput> {
+    let invalid_imeis: Vec<InvalidImei> = imeis.iter().filter_map(|imei| {
+        let reason = if let Some(prefix) = imei.strip_suffix('*') {
+            if prefix.chars().all(|c| c.is_ascii_digit()) && (TAC_DIGITS..15).contains(&prefix.len()) {
+                return None;
+            }
+            format!("not a pattern: needs {} to 14 digits before the *", TAC_DIGITS)
+        } else if let Some(invalid) = imei.chars().find(|c| !c.is_ascii_digit()) {
+            format!("not numeric: contains {:?}", invalid)
+        } else if imei.len() != 15 {
+            "not 15 digits".to_string()
+        } else {
+            return None;
+        };
+        Some(InvalidImei { imei: imei.clone(), reason })
+    }).collect();
+    if invalid_imeis.is_empty() {
+        return Ok(());
+    }
+    Err(InvalidImeisOutput { error: format!("{} of {} IMEIs are invalid", invalid_imeis.len(), imeis.len()), invalid_imeis })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Deserialize)]
+    struct Event {
+        #[serde(deserialize_with = "deserialize_list")]
+        imeis: Vec<String>,
+    }
+
+    const A: &str = "356938035643809";
+    const B: &str = "490154203237518";
+
+    fn parse(imeis: serde_json::Value) -> Vec<String> {
+        serde_json::from_value::<Event>(serde_json::json!({ "imeis": imeis })).unwrap().imeis
+    }
+
+    #[test]
+    fn deserialize_keeps_entries_as_sent() {
+        assert_eq!(parse(serde_json::json!([A, B, A])), [A, B, A]);
+        assert_eq!(parse(serde_json::json!(format!(" {}, ,{},{} ", A, A, B))), [format!(" {}", A), " ".to_string(), A.to_string(), format!("{} ", B)]);
+    }
+
+    #[test]
+    fn tokenize_trims_and_drops_empty_entries() {
+        let (imeis, cleanup) = tokenize(parse(serde_json::json!(format!("{}, {},,{} ,", A, B, A))));
+        assert_eq!(imeis, [A, B, A]);
+        assert_eq!(cleanup, Cleanup { trimmed: 2, dropped_empty: 2 });
+    }
+
+    #[test]
+    fn validate_rejects_invalid_characters() {
+        let (imeis, _) = tokenize(vec![format!(" {} ", A), "35693803564380x".to_string()]);
+        let rejection = validate(&imeis).unwrap_err();
+        assert_eq!(rejection.invalid_imeis.len(), 1);
+        assert_eq!(rejection.invalid_imeis[0].reason, "not numeric: contains 'x'");
+    }
+
+    #[test]
+    fn validate_accepts_prefix_patterns() {
+        assert!(validate(&["8663070523*".to_string(), "86630705*".to_string()]).is_ok());
+        let rejection = validate(&["8663070*".to_string(), "86630705x*".to_string(), format!("{}*", A)]).unwrap_err();
+        assert_eq!(rejection.invalid_imeis.len(), 3);
+    }
+
+    #[test]
+    fn dedupe_keeps_first_occurrences_and_counts_repeats() {
+        let mut imeis = parse(serde_json::json!([B, A, B, A, B]));
+        let mut repeats = BTreeMap::new();
+        dedupe(&mut imeis, &mut repeats);
+        assert_eq!(imeis, [B, A]);
+        assert_eq!(repeats, BTreeMap::from([(A.to_string(), 1), (B.to_string(), 2)]));
+    }
+
+    #[test]
+    fn dedupe_adds_repeats_across_sources() {
+        let mut repeats = BTreeMap::new();
+        let mut inline = vec![A.to_string(), A.to_string()];
+        dedupe(&mut inline, &mut repeats);
+        let mut combined: Vec<String> = inline.into_iter().chain([A.to_string(), B.to_string()]).collect();
+        dedupe(&mut combined, &mut repeats);
+        assert_eq!(combined, [A, B]);
+        assert_eq!(repeats, BTreeMap::from([(A.to_string(), 2)]));
+    }
+}
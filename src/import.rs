@@ -0,0 +1,161 @@
+//! `action: "import"`: loads externally computed aggregate rows (e.g. from the legacy pipeline) from
+//! `import_s3_uri`, a JSONL or CSV (`imei,ride_month,total_distance[,granularity[,as_of]]`) object.
+//! Every line is validated before anything is written; `on_conflict` decides what happens to rows
+//! already stored, and finalized rows are only replaced with `force`.
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Datelike, NaiveDate, SecondsFormat, Weekday};
+use lambda_runtime::Error;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::io::AsyncBufReadExt;
+use tracing::info;
+use utoipa::ToSchema;
+
+use crate::clock::Clock;
+use crate::cost::CapacityMeter;
+use crate::time::{self, Granularity};
+use crate::{aggregates, kms, retries, s3, CustomEvent, ErrorOutput};
+
+/// Invalid lines reported before giving up on listing them.
+const MAX_REPORTED_ERRORS: usize = 20;
+
+/// What to do when an imported row already exists.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize, JsonSchema, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictPolicy {
+    /// Keep the stored row.
+    #[default]
+    Skip,
+    /// Replace it with the imported one.
+    Overwrite,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ImportRow {
+    pub imei: String,
+    pub ride_month: String,
+    pub total_distance: f64,
+    #[serde(default)]
+    pub granularity: Granularity,
+    /// RFC 3339; defaults to the import time.
+    pub as_of: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct ImportOutput {
+    rows: usize,
+    imported: usize,
+    /// Rows already stored and kept under `on_conflict: skip`.
+    skipped_existing: usize,
+    /// Rows kept because the stored copy is finalized.
+    skipped_finalized: usize,
+}
+
+pub async fn import(shared_config: &aws_config::SdkConfig, payload: &CustomEvent, clock: &Clock) -> Result<Value, Error> {
+    let uri = payload.import_s3_uri.as_deref().unwrap_or_default();
+    let Some((bucket, key)) = s3::parse_uri(uri) else {
+        return Ok(json!(ErrorOutput { error: format!("import_s3_uri {:?} is not an s3://bucket/key URI", uri) }));
+    };
+    let encryption = kms::Encryption::for_request(payload);
+    let rows = match read_rows(&aws_sdk_s3::Client::new(shared_config), bucket, key, &encryption).await? {
+        Ok(rows) => rows,
+        Err(errors) => return Ok(json!(ErrorOutput { error: format!("import rejected: {}", errors.join("; ")) })),
+    };
+
+    let client = retries::dynamodb(shared_config);
+    let policy = payload.on_conflict.unwrap_or_default();
+    let force = payload.force.unwrap_or(false);
+    let imported_at = clock.now().to_rfc3339_opts(SecondsFormat::Secs, true);
+    let meter = CapacityMeter::default();
+    let mut output = ImportOutput { rows: rows.len(), imported: 0, skipped_existing: 0, skipped_finalized: 0 };
+    for row in &rows {
+        let as_of = row.as_of.as_deref().unwrap_or(&imported_at);
+        match aggregates::import_row(&client, row, as_of, uri, policy == ConflictPolicy::Overwrite, force, &meter).await? {
+            None => output.imported += 1,
+            Some(aggregates::SkipReason::Finalized) => output.skipped_finalized += 1,
+            Some(_) => output.skipped_existing += 1,
+        }
+    }
+    info!("Imported {} of {} rows from {}", output.imported, output.rows, uri);
+    Ok(json!(output))
+}
+
+/// Parses and validates every line; invalid lines are reported together as `Ok(Err(..))`.
+async fn read_rows(client: &aws_sdk_s3::Client, bucket: &str, key: &str, encryption: &kms::Encryption) -> Result<Result<Vec<ImportRow>, Vec<String>>> {
+    let resp = client.get_object().bucket(bucket).key(key).send().await
+        .map_err(|err| anyhow!("reading import s3://{}/{}: {}", bucket, key, err))?;
+    if let Err(err) = encryption.check(&format!("s3://{}/{}", bucket, key), resp.server_side_encryption(), resp.metadata()) {
+        return Ok(Err(vec![err]));
+    }
+    let mut lines = tokio::io::BufReader::new(resp.body.into_async_read()).lines();
+
+    let mut rows = Vec::new();
+    let mut errors = Vec::new();
+    let mut line_number = 0;
+    while let Some(line) = lines.next_line().await? {
+        line_number += 1;
+        let line = line.trim();
+        if line.is_empty() || (line_number == 1 && line.starts_with("imei")) {
+            continue;
+        }
+        match parse_row(line).and_then(validate) {
+            Ok(row) => rows.push(row),
+            Err(err) if errors.len() < MAX_REPORTED_ERRORS => errors.push(format!("line {}: {}", line_number, err)),
+            Err(_) => {}
+        }
+    }
+    if errors.is_empty() {
+        Ok(Ok(rows))
+    } else {
+        Ok(Err(errors))
+    }
+}
+
+fn parse_row(line: &str) -> Result<ImportRow, String> {
+    if line.starts_with('{') {
+        return serde_json::from_str(line).map_err(|err| err.to_string());
+    }
+    let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+    let [imei, ride_month, total_distance, rest @ ..] = fields.as_slice() else {
+        return Err("expected imei,ride_month,total_distance[,granularity[,as_of]]".to_string());
+    };
+    if rest.len() > 2 {
+        return Err(format!("expected at most 5 columns, got {}", fields.len()));
+    }
+    Ok(ImportRow {
+        imei: imei.to_string(),
+        ride_month: ride_month.to_string(),
+        total_distance: total_distance.parse().map_err(|_| format!("total_distance {:?} is not a number", total_distance))?,
+        granularity: match rest.first().filter(|g| !g.is_empty()) {
+            Some(granularity) => Granularity::parse(granularity).ok_or_else(|| format!("unknown granularity {:?}", granularity))?,
+            None => Granularity::Monthly,
+        },
+        as_of: rest.get(1).filter(|a| !a.is_empty()).map(|a| a.to_string()),
+    })
+}
+
+fn validate(row: ImportRow) -> Result<ImportRow, String> {
+    if row.imei.is_empty() || !row.imei.chars().all(|c| c.is_ascii_digit()) {
+        return Err(format!("imei {:?} is not numeric", row.imei));
+    }
+    if !row.total_distance.is_finite() || row.total_distance < 0.0 {
+        return Err(format!("total_distance {} is not a non-negative number", row.total_distance));
+    }
+    let month = row.ride_month.get(..7).unwrap_or_default();
+    let valid_period = time::month_start(month, 0).is_ok() && match row.granularity {
+        Granularity::Monthly => row.ride_month.len() == 7,
+        Granularity::Daily => NaiveDate::parse_from_str(&row.ride_month, "%Y-%m-%d").is_ok(),
+        Granularity::Weekly => NaiveDate::parse_from_str(&row.ride_month, "%Y-%m-%d").is_ok_and(|day| day.weekday() == Weekday::Mon),
+        Granularity::Range => false,
+    };
+    if !valid_period {
+        return Err(format!("ride_month {:?} is not a {} period", row.ride_month, row.granularity.as_str()));
+    }
+    if let Some(as_of) = &row.as_of {
+        DateTime::parse_from_rfc3339(as_of).map_err(|_| format!("as_of {:?} is not RFC 3339", as_of))?;
+    }
+    Ok(row)
+}
@@ -0,0 +1,66 @@
+//! With `EXPORT_KMS_KEY_ID` set, exported objects are written with SSE-KMS under that key and an
+//! encryption context naming the request's `tenant_id` and `fleet_group_id` (those it has), so key
+//! policies can confine each tenant's grants and CloudTrail records whose objects were decrypted.
+//! S3 never returns the context on reads, so it is also stored as the `kms-encryption-context`
+//! metadata, and an SSE-KMS object carrying it is only read by requests with the same context.
+
+use aws_sdk_s3::types::ServerSideEncryption;
+use base64::Engine;
+use std::collections::{BTreeMap, HashMap};
+
+use crate::CustomEvent;
+
+const CONTEXT_METADATA: &str = "kms-encryption-context";
+
+/// How one request's exports are encrypted, and which objects it may read.
+#[derive(Debug, Clone, Default)]
+pub struct Encryption {
+    key_id: Option<String>,
+    context: BTreeMap<String, String>,
+}
+
+impl Encryption {
+    pub fn for_request(payload: &CustomEvent) -> Encryption {
+        let context = [("tenant_id", &payload.tenant_id), ("fleet_group_id", &payload.fleet_group_id)]
+            .into_iter()
+            .filter_map(|(name, value)| Some((name.to_string(), value.clone().filter(|value| !value.is_empty())?)))
+            .collect();
+        Encryption { key_id: crate::config::get().export_kms_key_id.clone(), context }
+    }
+
+    pub fn server_side_encryption(&self) -> Option<ServerSideEncryption> {
+        self.key_id.as_ref().map(|_| ServerSideEncryption::AwsKms)
+    }
+
+    pub fn key_id(&self) -> Option<String> {
+        self.key_id.clone()
+    }
+
+    /// The `x-amz-server-side-encryption-context` header: the context as base64 JSON.
+    pub fn context_header(&self) -> Option<String> {
+        self.stored_context().map(|json| base64::engine::general_purpose::STANDARD.encode(json))
+    }
+
+    pub fn metadata(&self) -> Option<HashMap<String, String>> {
+        self.stored_context().map(|json| HashMap::from([(CONTEXT_METADATA.to_string(), json)]))
+    }
+
+    fn stored_context(&self) -> Option<String> {
+        self.key_id.as_ref().filter(|_| !self.context.is_empty())?;
+        serde_json::to_string(&self.context).ok()
+    }
+
+    /// Whether an object read back, by its encryption and user metadata, may be used by this request.
+    pub fn check(&self, uri: &str, encryption: Option<&ServerSideEncryption>, metadata: Option<&HashMap<String, String>>) -> Result<(), String> {
+        if encryption != Some(&ServerSideEncryption::AwsKms) {
+            return Ok(());
+        }
+        let Some(stored) = metadata.and_then(|metadata| metadata.get(CONTEXT_METADATA)) else {
+            return Ok(());
+        };
+        match serde_json::from_str::<BTreeMap<String, String>>(stored) {
+            Ok(context) if context == self.context => Ok(()),
+            _ => Err(format!("{} is encrypted for {}, not for this request's {}", uri, stored, serde_json::to_string(&self.context).unwrap_or_default())),
+        }
+    }
+}
@@ -0,0 +1,61 @@
+//! `legacy_compat`: reproduces the old Python aggregator for rides starting in a UTC date range, so
+//! parallel-run diffs against its output only show real behaviour changes. Inside the range:
+//! - rides are bucketed by their UTC month instead of IST;
+//! - `deleted` / `tombstone` flags are ignored;
+//! - overlapping rides all count;
+//! - each ride's distance is rounded to 2 decimals before summing, and the month total again after,
+//!   half to even like Python's `round`.
+//!
+//! Legacy totals are for comparison only, so requests with `legacy_compat` never write.
+
+use aws_sdk_dynamodb::types::AttributeValue;
+use chrono::NaiveDate;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use std::collections::HashMap;
+use utoipa::ToSchema;
+
+use crate::ride::{self, RideDecision, RideTypes};
+use crate::time::{self, Granularity};
+
+#[derive(Debug, Clone, Copy, Deserialize, JsonSchema, ToSchema)]
+pub struct LegacyCompat {
+    /// First UTC day (`YYYY-MM-DD`) whose rides are handled the legacy way.
+    #[schemars(with = "String")]
+    #[schema(value_type = String)]
+    pub from: NaiveDate,
+    /// Last UTC day, inclusive.
+    #[schemars(with = "String")]
+    #[schema(value_type = String)]
+    pub to: NaiveDate,
+}
+
+impl LegacyCompat {
+    pub fn validate(&self, granularity: Granularity) -> Result<(), String> {
+        if self.from > self.to {
+            return Err("legacy_compat.from must not be after legacy_compat.to".to_string());
+        }
+        if granularity != Granularity::Monthly {
+            return Err("legacy_compat only supports monthly granularity".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn covers(&self, ride_start: u64) -> bool {
+        i64::try_from(ride_start).ok()
+            .and_then(|secs| time::instant(secs).ok())
+            .is_some_and(|start| (self.from..=self.to).contains(&start.date_naive()))
+    }
+}
+
+/// Re-decides a ride the way the legacy pipeline did.
+pub fn reclassify(decision: &mut RideDecision, item: &HashMap<String, AttributeValue>, input_ride_month: Option<&str>, ride_types: &RideTypes) {
+    decision.ride_month = decision.ride_start
+        .and_then(|start| time::instant(i64::try_from(start).ok()?).ok())
+        .map(|start| start.format("%Y-%m").to_string());
+    decision.exclusion = ride::eligibility(decision, item, input_ride_month, ride_types);
+}
+
+pub fn round(km: f64) -> f64 {
+    (km * 100.0).round_ties_even() / 100.0
+}
@@ -0,0 +1,1868 @@
+//! The ride aggregator; `src/main.rs` only starts [`run`].
+
+// `config::echo` is one `json!` larger than the default limit expands.
+#![recursion_limit = "256"]
+
+use aws_config::{meta::region::RegionProviderChain};
+use aws_sdk_dynamodb::config::Region;
+use aws_sdk_dynamodb::{types::{AttributeValue, ReturnConsumedCapacity}, Client};
+use lambda_runtime::{service_fn, LambdaEvent, Error};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use aws_sdk_dynamodb::error::SdkError;
+use aws_sdk_dynamodb::operation::query::builders::QueryFluentBuilder;
+use aws_sdk_dynamodb::operation::query::QueryError;
+use anyhow::Result;
+use chrono::{DateTime, NaiveDate, SecondsFormat, Utc};
+use futures::stream::{self, StreamExt, TryStreamExt};
+use futures::FutureExt;
+use store::RideStore;
+use tracing::{debug, error, info, info_span, warn, Instrument};
+use utoipa::ToSchema;
+
+mod aggregates;
+mod aliases;
+mod anomalies;
+#[cfg(feature = "alloc-stats")]
+mod alloc;
+mod as_of;
+mod audit;
+mod backfill;
+mod batch;
+mod billing;
+mod cache;
+mod capacity;
+#[cfg(feature = "chaos")]
+mod chaos;
+mod checkpoint;
+mod cli;
+mod clock;
+mod codec;
+mod cohorts;
+mod compare;
+mod config;
+mod counts;
+mod corrections;
+mod cost;
+mod date_index;
+#[cfg(feature = "datadog")]
+mod datadog;
+mod decommission;
+mod destination;
+mod diagnose;
+mod drift;
+mod email;
+mod envelope;
+mod estimate;
+mod event;
+mod experiments;
+mod export;
+mod failures;
+mod fanout;
+mod finalize;
+mod fleet;
+mod fraud;
+mod grafana;
+mod health;
+mod history;
+mod imeis;
+mod import;
+mod http;
+mod kms;
+mod legacy;
+mod lifecycle;
+mod maintenance;
+mod logging;
+mod manifest;
+mod metrics;
+mod migrate;
+mod normalize;
+mod numbers;
+mod pagination;
+mod partitions;
+mod planner;
+mod preflight;
+mod priority;
+mod quotas;
+mod read;
+mod reconcile;
+mod regulatory;
+mod replay;
+mod report;
+mod retention;
+mod retries;
+mod ride;
+mod s3;
+mod scan;
+mod schema;
+mod selftest;
+mod sentry;
+mod shadow;
+mod signatures;
+mod soak;
+mod startup;
+mod stats;
+mod store;
+mod streams;
+#[cfg(test)]
+mod testing;
+mod thresholds;
+mod time;
+mod trace;
+mod units;
+mod upload;
+mod verify;
+mod warnings;
+mod webhook;
+mod years;
+
+const RIDE_PROJECTION: &str = "ride_start, ride_end, ride_start_local, ride_stats, ride_type, firmware_version, distance_unit, #source, deleted, tombstone, signature";
+/// [`RIDE_PROJECTION`] without `ride_stats`, whose entries are projected per metric.
+const RIDE_ATTRIBUTES: &str = "ride_start, ride_end, ride_start_local, ride_type, firmware_version, distance_unit, #source, deleted, tombstone, signature";
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Default, JsonSchema, ToSchema)]
+#[serde(rename_all = "snake_case")]
+enum Action {
+    #[default]
+    Aggregate,
+    Describe,
+    Finalize,
+    Reconcile,
+    DebugTrace,
+    RideCorrected,
+    RideVoided,
+    Replay,
+    Export,
+    AggregateAsOf,
+    DeviceDecommissioned,
+    Import,
+    RegulatoryReport,
+    EnforceRetention,
+    Read,
+    Diagnose,
+    Selftest,
+    CompareMonths,
+    MigrateLegacyRows,
+}
+
+#[derive(Debug, Clone, Deserialize, Default, JsonSchema, ToSchema)]
+struct CustomEvent {
+    #[serde(default)]
+    action: Action,
+    /// IMEIs to aggregate, as a JSON array or a comma-separated string. An entry of 8 to 14 digits
+    /// and a `*` stands for every device in the devices table whose IMEI starts with those digits.
+    #[serde(default, deserialize_with = "imeis::deserialize_list")]
+    #[schemars(with = "imeis::ImeiList")]
+    #[schema(value_type = imeis::ImeiList)]
+    imeis: Vec<String>,
+    /// Base64-encoded gzip of a comma- or newline-separated IMEI list, for lists too large for `imeis`.
+    imeis_compressed: Option<String>,
+    /// `s3://bucket/key` of an object (optionally gzipped) holding a comma- or newline-separated IMEI list.
+    imeis_s3_uri: Option<String>,
+    /// `s3://bucket/key` of a JSONL or CSV manifest of (imei, months) work items to aggregate in shards.
+    input_manifest_s3_uri: Option<String>,
+    /// Restrict aggregation to one `YYYY-MM` month (IST), or `current_month`, `previous_month` or
+    /// `last_<n>_months` (the n months ending with the current one, as a date range).
+    input_ride_month: Option<String>,
+    /// With `end_date`: only count rides starting on these `YYYY-MM-DD` days (IST), inclusive. Periods
+    /// the range only partly covers are reported but not stored.
+    #[schemars(with = "Option<String>")]
+    #[schema(value_type = Option<String>)]
+    start_date: Option<NaiveDate>,
+    #[schemars(with = "Option<String>")]
+    #[schema(value_type = Option<String>)]
+    end_date: Option<NaiveDate>,
+    /// Email the result as CSV to the configured recipients.
+    email_report: Option<bool>,
+    /// Upload the results to S3 as CSV or Parquet, partitioned by month.
+    export: Option<export::ResultsExport>,
+    /// How `action: "export"` compresses its parts (default `none`).
+    export_compression: Option<codec::ExportCompression>,
+    /// Lifetime of the presigned URLs returned for exported objects, in seconds (default
+    /// `PRESIGN_EXPIRY_SECS`, else an hour; at most a week).
+    presign_expiry_secs: Option<u64>,
+    /// Post a run summary to the configured webhook.
+    post_summary: Option<bool>,
+    /// Leave stored rows of closed months alone if they were written after the month ended.
+    preserve_final_months: Option<bool>,
+    /// Overwrite rows even if they have been finalized.
+    force: Option<bool>,
+    /// Replace stored rows (default true); false only inserts rows for periods with none stored.
+    overwrite: Option<bool>,
+    /// Why this run restates existing aggregates; recorded in the revision history.
+    reason: Option<String>,
+    /// For `reconcile` without `imeis`: how many stored IMEIs to sample (default 50).
+    sample_size: Option<usize>,
+    /// Named IMEI lists to report cohort-level monthly totals for; members are aggregated too.
+    cohorts: Option<HashMap<String, Vec<String>>>,
+    /// Device tags whose tagged devices (from the devices table) form one cohort each.
+    cohort_tags: Option<Vec<String>>,
+    /// Named IMEI lists whose rows are also returned nested per group, in `groups`; members are aggregated too,
+    /// and may be given by an old IMEI of an aliased device. Not with `fan_out`.
+    groups: Option<BTreeMap<String, Vec<String>>>,
+    /// Also total every requested device per period into fleet rows, returned in `fleet_summary` and stored.
+    include_fleet_summary: Option<bool>,
+    /// Names the fleet rows' key, `FLEET#<fleet_group_id>` (default `all`).
+    fleet_group_id: Option<String>,
+    /// Also return `by_month`: each period's total distance and device count over all requested devices.
+    include_by_month: Option<bool>,
+    /// Extra dimensions to split each month's distance and ride counts by.
+    breakdowns: Option<Vec<stats::Breakdown>>,
+    /// For `ride_corrected` / `ride_voided`: the ride whose distance changed or that was voided.
+    correction: Option<corrections::RideCorrection>,
+    /// Price each device-month with this tenant's rate card.
+    tenant_id: Option<String>,
+    /// Return sampled, extrapolated totals with confidence bounds instead of aggregating and writing.
+    estimate: Option<bool>,
+    /// Stop reading an IMEI's rides after this many items; its rows are marked truncated and not written.
+    max_rides_per_imei: Option<usize>,
+    /// Stop reading rides once this many items have been read across all IMEIs.
+    max_total_items: Option<usize>,
+    /// What to compute and read per device-month (default `distance`, `ride_count`, `duration`, `speed`);
+    /// `["ride_count"]` alone only counts rides.
+    metrics: Option<Vec<stats::Metric>>,
+    /// Count the rides the request would read and return projected capacity, duration and cost instead of running it.
+    preflight: Option<bool>,
+    /// Required for anything that writes to the tables when `ENVIRONMENT=prod`.
+    allow_prod_write: Option<bool>,
+    /// Pretend the current time is this RFC 3339 instant, for month-to-date decisions and `as_of` stamps.
+    fixed_now: Option<String>,
+    /// Emit zero-distance rows for months in the requested range where a device had no rides.
+    fill_gaps: Option<bool>,
+    /// Log verbosity for this request only; `debug` logs every ride decision.
+    log_level: Option<logging::LogLevel>,
+    /// Split the IMEIs into shards queued on SQS for worker invocations instead of aggregating here.
+    fan_out: Option<bool>,
+    /// Set on queued shards: the fan-out job this worker invocation belongs to.
+    fan_out_job: Option<fanout::FanOutJob>,
+    /// With `fan_out`: `interactive` (default) or `backfill`, whose shards yield to interactive ones.
+    priority: Option<priority::Priority>,
+    /// Set on queued backfill work: how many times it was re-queued behind interactive shards.
+    deferrals: Option<u32>,
+    /// Aggregate the IMEIs `BACKFILL_CHUNK_SIZE` at a time, each invocation queueing a continuation for
+    /// the rest; `fan_out` is ignored, as is this with `dry_run`.
+    backfill: Option<bool>,
+    /// Set on backfill continuations: the job and chunk this invocation runs.
+    backfill_job: Option<backfill::BackfillJob>,
+    /// Length of the periods to total rides over (default `monthly`); `range` gives one total per
+    /// IMEI for `start_date`..`end_date`.
+    granularity: Option<time::Granularity>,
+    /// Unit of the distances and speeds in the response (default `km`); rows are stored, exported
+    /// and charged in km whatever it is.
+    output_unit: Option<units::OutputUnit>,
+    /// How decimal stats are written in the response and exports: `float` (default) or
+    /// `string_fixed2`, two-decimal strings.
+    number_format: Option<numbers::NumberFormat>,
+    /// After writing, read back this many written rows and fail the run if any differs from its computed total.
+    verify_writes: Option<usize>,
+    /// With `action: "replay"`: the `aggregation_runs` run_id whose requests to re-execute.
+    replay_run_id: Option<String>,
+    /// Resume a failed run: skip the devices that run already completed for the same IMEIs and period.
+    resume_run_id: Option<String>,
+    /// Aggregate without writing rows or history, recording the run, or sending reports; `fan_out` is ignored.
+    /// The response's `write_plan` shows what the writes would have done.
+    dry_run: Option<bool>,
+    /// Check each ride's device signature, excluding or flagging rides that fail.
+    verify_signatures: Option<signatures::Mode>,
+    /// Run the fraud heuristics and report `fraud_flags` per device-month.
+    fraud_checks: Option<bool>,
+    /// Also count each device's rides outside the ride window, per year, as `skipped_years`.
+    report_skipped_years: Option<bool>,
+    /// With `action: "aggregate_as_of"`: the RFC 3339 instant to reconstruct the aggregate at.
+    as_of: Option<String>,
+    /// With `action: "device_decommissioned"`: also write the device's stored rows to S3.
+    export_history: Option<bool>,
+    /// With `action: "import"`: `s3://bucket/key` of the JSONL or CSV rows to load.
+    import_s3_uri: Option<String>,
+    /// With `action: "import"`: what to do with rows that are already stored (default `skip`).
+    on_conflict: Option<import::ConflictPolicy>,
+    /// Reproduce the old Python aggregator's rounding, timezone and filters for rides in this UTC
+    /// date range, for parallel-run diffs; implies `dry_run`.
+    legacy_compat: Option<legacy::LegacyCompat>,
+    /// `ride_type`s to count instead of just `trip`, filtered in the ride query so other rides are not
+    /// read at all: they are missing from `explain`, as are rides whose stored `ride_type` differs
+    /// in case or whitespace. Cannot be combined with `ride_types_include`.
+    ride_types: Option<Vec<String>>,
+    /// `ride_type`s to count instead of just `trip`; other rides are still read and explained.
+    ride_types_include: Option<Vec<String>>,
+    /// `ride_type`s never to count, even if included.
+    ride_types_exclude: Option<Vec<String>>,
+    /// With `action: "selftest"`: first write the fixture rides to the ride table.
+    seed_fixture: Option<bool>,
+    /// With `action: "compare_months"`: the `YYYY-MM` month to compare `input_ride_month` with
+    /// (default the month before).
+    compare_to_month: Option<String>,
+    /// Fail the run, rather than return partial results, if more than this percentage of devices
+    /// fail (default `FAIL_IF_ERROR_RATE_ABOVE`); rows are then written only once every device is read.
+    fail_if_error_rate_above: Option<f64>,
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema, ToSchema)]
+struct CustomOutput {
+    imei:String,
+    granularity: time::Granularity,
+    /// `YYYY-MM` month, the `YYYY-MM-DD` first day of a daily or weekly period, or
+    /// `YYYY-MM-DD..YYYY-MM-DD` for a whole-range total.
+    ride_month: String,
+    total_distance: f64,
+    /// Seconds the counted rides lasted, from `ride_stats.ride_duration`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total_duration: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ride_count: Option<u64>,
+    /// km/h over the counted rides that recorded a duration.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    average_speed: Option<f64>,
+    /// Highest `ride_stats.max_speed` (km/h) of the counted rides.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_speed: Option<f64>,
+    /// Watt-hours, with the `energy` metric.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total_energy: Option<f64>,
+    /// Rides per distance band (km), with the `histogram` metric.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    distance_histogram: Option<BTreeMap<String, u64>>,
+    /// The period was still in progress when aggregated, so the total is partial.
+    month_to_date: bool,
+    /// When the total was computed (RFC 3339).
+    as_of: String,
+    /// Why the stored row was left untouched, if it was.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    write_skipped: Option<aggregates::SkipReason>,
+    /// Distance and rides per value of each requested breakdown dimension.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    breakdowns: stats::Breakdowns,
+    /// Ride counts behind the total.
+    explain: stats::RowExplain,
+    /// The IMEI's rides were cut off at a query limit, so the total is partial.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    truncated: bool,
+    /// Fraud heuristics that fired for the device-month, with `fraud_checks`.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    fraud_flags: Vec<fraud::FraudFlag>,
+    /// Enabled `EXPERIMENTS`, by `exp.` attribute; distances in km whatever the `output_unit`.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    experimental: BTreeMap<String, f64>,
+    /// `ride_start` of each counted ride; a monthly row stores them as `applied_rides`, so stream
+    /// records of these rides are not added to it again.
+    #[serde(skip)]
+    applied_rides: BTreeSet<u64>,
+}
+
+#[cfg(test)]
+impl CustomOutput {
+    /// A complete monthly row, for tests.
+    fn fixture(imei: &str, ride_month: &str, total_distance: f64) -> CustomOutput {
+        CustomOutput {
+            imei: imei.to_string(),
+            granularity: time::Granularity::Monthly,
+            ride_month: ride_month.to_string(),
+            total_distance,
+            total_duration: None,
+            ride_count: None,
+            average_speed: None,
+            max_speed: None,
+            total_energy: None,
+            distance_histogram: None,
+            month_to_date: false,
+            as_of: "2024-04-01T00:00:00Z".to_string(),
+            write_skipped: None,
+            breakdowns: BTreeMap::new(),
+            explain: stats::RowExplain::default(),
+            truncated: false,
+            fraud_flags: Vec::new(),
+            experimental: BTreeMap::new(),
+            applied_rides: BTreeSet::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema, ToSchema)]
+struct AggregationResponse {
+    /// Ordered by IMEI, then period.
+    results: Vec<CustomOutput>,
+    /// IMEIs whose rides were cut off, or not read at all, because of `max_rides_per_imei` / `max_total_items`.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    truncated_imeis: Vec<String>,
+    /// IMEIs whose ride queries were throttled or slow, hinting at hot partitions in the ride table.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    hot_partitions: Vec<partitions::HotPartition>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    cohorts: Vec<cohorts::CohortOutput>,
+    /// With `groups`: each group's rows from `results`.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    groups: BTreeMap<String, Vec<CustomOutput>>,
+    /// With `include_fleet_summary`: every requested device totalled per period, keyed `FLEET#<fleet_group_id>`.
+    /// Marked truncated, and not stored, unless every device was read in full by this run.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    fleet_summary: Vec<CustomOutput>,
+    /// With `include_by_month`: period -> totals over the rows in `results`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    by_month: Option<BTreeMap<String, fleet::PeriodTotal>>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    charges: Vec<billing::ChargeOutput>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    warnings: Vec<warnings::Warning>,
+    /// How many ride attribute values were trimmed, case-folded or (`ride_stats.*`) rewritten as plain decimals before filtering, per attribute.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    normalized_values: BTreeMap<String, u64>,
+    /// Earlier IMEIs whose rides were merged into their device's current IMEI, old to new.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    imei_aliases: BTreeMap<String, String>,
+    /// Ride items that would have counted but could not be read, per error; they are left out of the totals.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    skipped_items: ride::SkippedItems,
+    /// Rides starting in the future or implausibly long, by `ANOMALY_ACTION` excluded or only flagged.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    anomalies: Vec<anomalies::Anomaly>,
+    /// Monthly distance thresholds (`ride_data_distance_thresholds`) this run's rows newly reached.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    distance_alerts: Vec<thresholds::DistanceAlert>,
+    /// Devices this run took past their service interval, announced to `MAINTENANCE_EVENT_BUS`.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    maintenance_due: Vec<maintenance::MaintenanceDue>,
+    /// With `report_skipped_years`: device -> year -> rides starting outside the ride window, which are never counted.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    skipped_years: years::SkippedYears,
+    /// IMEIs whose totals were reused from the cache (`AGGREGATE_CACHE`) because none of their rides changed.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    cached_imeis: Vec<String>,
+    /// Devices an earlier attempt of the run already completed; their rows are not in `results`.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    resumed_imeis: Vec<String>,
+    /// With `dry_run`: what writing each row would have done to the stored one, and how its stats differ.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    write_plan: Vec<aggregates::PlannedWrite>,
+    /// How the period was chosen when the request named none.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    default_ride_month: Option<time::DefaultedMonth>,
+    /// Devices whose rides could not be read; they have no rows in `results` and were not written.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    errors: Vec<failures::DeviceError>,
+    /// `s3://` URIs of the objects `export` wrote.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    exported: Vec<String>,
+    /// Presigned GET URLs of the `exported` objects, in the same order.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    exported_urls: Vec<String>,
+    /// With `backfill`: the job's progress after this chunk.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    backfill: Option<backfill::BackfillProgress>,
+    /// Unit of the distances and speeds in `results`, `fleet_summary`, `by_month` and `cohorts`.
+    output_unit: units::OutputUnit,
+    /// Estimated AWS cost of producing this response.
+    cost: cost::RunCost,
+    diagnostics: Diagnostics,
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema, ToSchema)]
+struct Diagnostics {
+    /// DynamoDB retries per operation, e.g. `Query` or `BatchWriteItem`.
+    retries: BTreeMap<String, retries::OperationRetries>,
+    runtime: startup::RuntimeInfo,
+    /// How the rides were read.
+    query_plan: planner::QueryPlan,
+    /// Capacity mode of each table batch-written to, which sets how the writes are paced.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    capacity_modes: BTreeMap<String, capacity::CapacityMode>,
+    /// Heap allocations while handling the request, with the `alloc-stats` feature.
+    #[cfg(feature = "alloc-stats")]
+    memory: alloc::MemoryStats,
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema, ToSchema)]
+struct ErrorOutput {
+    error: String,
+}
+
+/// Starts the Lambda runtime, or the mode `RIDE_DATA_MODE` or the command line asks for.
+pub async fn run() -> Result<(), Error> {
+    startup::process_started();
+    logging::init();
+    sentry::install_panic_hook();
+    if let Err(err) = config::validate() {
+        error!("{}", err);
+        return Err("invalid configuration".into());
+    }
+    debug!("Resolved config: {}", config::echo());
+    startup::init_done();
+
+    if cli::requested() {
+        return cli::run().await;
+    }
+    quotas::load(&load_aws_config().await).await;
+    match config::get().mode {
+        config::Mode::Http => return http::serve(load_aws_config().await).await,
+        config::Mode::Soak => return soak::run(load_aws_config().await).await,
+        config::Mode::Lambda => {}
+    }
+
+    destination::prewarm(&retries::dynamodb(&load_aws_config().await)).await;
+    let func = service_fn(get_ride_data);
+    lambda_runtime::run(func).await?;
+    Ok(())
+}
+
+async fn load_aws_config() -> aws_config::SdkConfig {
+    let region_provider = RegionProviderChain::first_try(Region::new(config::get().region.clone())).or_default_provider();
+    aws_config::from_env().region(region_provider).load().await
+}
+
+async fn get_ride_data(e: LambdaEvent<Value>) -> Result<Value, Error> {
+    let span = info_span!("request", request_id = %e.context.request_id);
+    let result = handle_invocation(e).instrument(span.clone()).await;
+    if let Err(err) = &result {
+        error!(parent: &span, "Error handling invocation: {:?}", err);
+    }
+    drop(span);
+    sentry::flush().await;
+    #[cfg(feature = "datadog")]
+    datadog::flush().await;
+    result
+}
+
+async fn handle_invocation(e: LambdaEvent<Value>) -> Result<Value, Error> {
+    let over_http = envelope::Envelope::detect(&e.payload) == envelope::Envelope::ApiGateway;
+    let (envelope, events) = match envelope::unwrap(e.payload) {
+        Ok(unwrapped) => unwrapped,
+        Err(err) => {
+            warn!("Rejected event: {}", err);
+            let rejection = json!(ErrorOutput { error: err });
+            return Ok(if over_http { envelope::http_response(400, &rejection) } else { rejection });
+        }
+    };
+    let shared_config = load_aws_config().await;
+    let run_id = e.context.request_id;
+
+    if over_http {
+        let event = events.into_iter().next().unwrap_or_default();
+        return Ok(match handle_event(&shared_config, event, &run_id).await {
+            Ok(response) if response.get("error").is_some() => envelope::http_response(400, &response),
+            Ok(response) => envelope::http_response(200, &response),
+            // Nearly every failure that gets this far is DynamoDB (or S3) giving up.
+            Err(err) => {
+                error!("Error handling HTTP request: {:?}", err);
+                envelope::http_response(502, &json!(ErrorOutput { error: err.to_string() }))
+            }
+        });
+    }
+
+    if envelope == envelope::Envelope::DynamoDbStream {
+        return handle_stream(&shared_config, events, &run_id).await;
+    }
+    if envelope != envelope::Envelope::Sqs {
+        if let Some(event) = events.into_iter().next() {
+            return handle_event(&shared_config, event, &run_id).await;
+        }
+        return Ok(json!(ErrorOutput { error: "empty event".to_string() }));
+    }
+
+    let mut responses = Vec::with_capacity(events.len());
+    let mut scheduler = priority::Scheduler::default();
+    for event in events {
+        if let Some(deferred) = scheduler.defer(&shared_config, &event).await? {
+            responses.push(deferred);
+            continue;
+        }
+        responses.push(handle_event(&shared_config, event, &run_id).await?);
+    }
+    Ok(json!(responses))
+}
+
+/// A batch of DynamoDB stream records: the aggregates table's own changes are published, ride changes applied.
+async fn handle_stream(shared_config: &aws_config::SdkConfig, records: Vec<Value>, run_id: &str) -> Result<Value, Error> {
+    if lifecycle::is_aggregates_stream(&records) {
+        return lifecycle::publish(shared_config, records).await;
+    }
+    streams::apply(shared_config, records, run_id).await
+}
+
+/// Handles one request, then mirrors it to the [`shadow`] function if it is sampled.
+async fn handle_shadowed(shared_config: &aws_config::SdkConfig, request: Value, run_id: &str) -> Result<Value, Error> {
+    if shadow::function_name().is_none() {
+        return handle_request(shared_config, request, run_id).await;
+    }
+    let response = handle_request(shared_config, request.clone(), run_id).await?;
+    shadow::mirror(shared_config, &request, &response).await;
+    Ok(response)
+}
+
+/// Handles one unwrapped event. An array is a batch of independent requests: each is
+/// processed in turn and a failure is reported in its own slot without affecting the others.
+async fn handle_event(shared_config: &aws_config::SdkConfig, event: Value, run_id: &str) -> Result<Value, Error> {
+    let Value::Array(requests) = event else {
+        return handle_shadowed(shared_config, event, run_id).await;
+    };
+
+    let mut results = Vec::with_capacity(requests.len());
+    for request in requests {
+        let result = match handle_shadowed(shared_config, request, run_id).await {
+            Ok(result) => result,
+            Err(err) => {
+                error!("Error processing batch request: {:?}", err);
+                json!(ErrorOutput { error: err.to_string() })
+            }
+        };
+        results.push(result);
+    }
+    Ok(json!(results))
+}
+
+/// Taken as a request arrives, before it is parsed.
+struct Arrival {
+    started: Instant,
+    started_at: String,
+    runtime: startup::RuntimeInfo,
+    #[cfg(feature = "alloc-stats")]
+    memory: alloc::Tracker,
+}
+
+impl Arrival {
+    fn now() -> Arrival {
+        Arrival {
+            #[cfg(feature = "alloc-stats")]
+            memory: alloc::Tracker::start(),
+            started: Instant::now(),
+            runtime: startup::runtime_info(),
+            started_at: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+        }
+    }
+}
+
+/// How the request's own IMEI list was cleaned up, reported as warnings once the run's IMEIs are resolved.
+struct ImeiCleanup {
+    cleanup: imeis::Cleanup,
+    repeats: BTreeMap<String, usize>,
+}
+
+/// Checks what every action shares, then hands the request to its action's handler.
+async fn handle_request(shared_config: &aws_config::SdkConfig, event: Value, run_id: &str) -> Result<Value, Error> {
+    let arrival = Arrival::now();
+    let request = event.clone();
+    let mut payload = match event::parse_event(event, event::ParsingMode::from_env()) {
+        Ok(payload) => payload,
+        Err(err) => {
+            warn!("Rejected event: {}", err);
+            return Ok(json!(ErrorOutput { error: err }));
+        }
+    };
+    let (requested_imeis, imei_cleanup) = imeis::tokenize(std::mem::take(&mut payload.imeis));
+    payload.imeis = requested_imeis;
+    let mut imei_repeats = BTreeMap::new();
+    imeis::dedupe(&mut payload.imeis, &mut imei_repeats);
+
+    if let Err(rejection) = imeis::validate(&payload.imeis) {
+        warn!("Rejected event: {}", rejection.error);
+        return Ok(json!(rejection));
+    }
+    if let Some(deferrals) = payload.deferrals {
+        info!("Running backfill work deferred {} times behind interactive shards", deferrals);
+    }
+    if payload.action != Action::Aggregate && payload.imeis.iter().any(|imei| imeis::is_pattern(imei)) {
+        return Ok(json!(ErrorOutput { error: "IMEI patterns are only supported when aggregating".to_string() }));
+    }
+
+    if writes_tables(&payload) && config::get().environment == config::Environment::Prod && payload.allow_prod_write != Some(true) {
+        warn!("Refused {:?} against prod tables without allow_prod_write", payload.action);
+        return Ok(json!(ErrorOutput { error: "writes to prod tables need allow_prod_write: true".to_string() }));
+    }
+
+    if payload.fail_if_error_rate_above.is_some_and(|percent| !(0.0..=100.0).contains(&percent)) {
+        return Ok(json!(ErrorOutput { error: "fail_if_error_rate_above must be a percentage from 0 to 100".to_string() }));
+    }
+    if payload.groups.is_some() && payload.fan_out.unwrap_or(false) {
+        return Ok(json!(ErrorOutput { error: "groups cannot be combined with fan_out: shard results are not nested".to_string() }));
+    }
+    if payload.presign_expiry_secs.is_some_and(|secs| secs == 0 || secs > s3::MAX_PRESIGN_EXPIRY_SECS) {
+        return Ok(json!(ErrorOutput { error: format!("presign_expiry_secs must be between 1 and {}", s3::MAX_PRESIGN_EXPIRY_SECS) }));
+    }
+
+    let _level = logging::override_level(payload.log_level);
+    let clock = match clock::Clock::resolve(payload.fixed_now.as_deref()) {
+        Ok(clock) => clock,
+        Err(err) => return Ok(json!(ErrorOutput { error: err })),
+    };
+    if let Some(value) = payload.input_ride_month.clone() {
+        match time::resolve_month_keyword(&value, clock.now()) {
+            Ok(None) => {
+                if let Err(err) = time::parse_month(&value) {
+                    warn!("Rejected event: {}", err);
+                    return Ok(json!(ErrorOutput { error: err.to_string() }));
+                }
+            }
+            Ok(Some(time::ResolvedMonth::Month(month))) => {
+                info!("Resolved input_ride_month {} to {}", value, month);
+                payload.input_ride_month = Some(month);
+            }
+            Ok(Some(time::ResolvedMonth::Range(range))) => {
+                if payload.start_date.is_some() || payload.end_date.is_some() {
+                    return Ok(json!(ErrorOutput { error: format!("input_ride_month {} cannot be combined with start_date / end_date", value) }));
+                }
+                info!("Resolved input_ride_month {} to {}", value, range.label());
+                (payload.input_ride_month, payload.start_date, payload.end_date) = (None, Some(range.start), Some(range.end));
+            }
+            Err(err) => return Ok(json!(ErrorOutput { error: err.to_string() })),
+        }
+    }
+
+    match payload.action {
+        Action::Describe => return Ok(schema::describe()),
+        Action::Finalize => return finalize::finalize(&retries::dynamodb(shared_config), &payload, &clock).await,
+        Action::Reconcile => return reconcile::reconcile(shared_config, &payload, run_id).await,
+        Action::DebugTrace => return trace::debug_trace(&retries::dynamodb(shared_config), &payload).await,
+        Action::RideCorrected | Action::RideVoided => {
+            return corrections::compensate_ride(&retries::dynamodb(shared_config), &payload, run_id, &clock).await;
+        }
+        Action::Replay => return replay::replay(shared_config, &payload, run_id).await,
+        Action::Export => return export::export(shared_config, &payload, run_id, &arrival.started_at).await,
+        Action::AggregateAsOf => return as_of::aggregate_as_of(&retries::dynamodb(shared_config), &payload).await,
+        Action::DeviceDecommissioned => return decommission::decommission(shared_config, &payload, run_id, &clock).await,
+        Action::Import => return import::import(shared_config, &payload, &clock).await,
+        Action::RegulatoryReport => return regulatory::regulatory_report(shared_config, &payload, run_id, &clock).await,
+        Action::EnforceRetention => return retention::enforce_retention(shared_config, &payload, run_id, &clock).await,
+        Action::Read => return read::read(shared_config, &payload).await,
+        Action::Diagnose => return diagnose::diagnose(shared_config, &payload, run_id, &clock).await,
+        Action::Selftest => return selftest::selftest(shared_config, &payload, run_id, &clock).await,
+        Action::CompareMonths => return compare::compare_months(shared_config, &payload).await,
+        Action::MigrateLegacyRows => return migrate::migrate_legacy_rows(shared_config).await,
+        Action::Aggregate => {}
+    }
+    let cleanup = ImeiCleanup { cleanup: imei_cleanup, repeats: imei_repeats };
+    handle_aggregate(shared_config, payload, &request, cleanup, run_id, &clock, arrival).await
+}
+
+/// Aggregates the requested IMEIs' rides, or hands them to the manifest, fan-out, estimate, preflight or count paths.
+async fn handle_aggregate(
+    shared_config: &aws_config::SdkConfig,
+    mut payload: CustomEvent,
+    request: &Value,
+    cleanup: ImeiCleanup,
+    run_id: &str,
+    clock: &clock::Clock,
+    arrival: Arrival,
+) -> Result<Value, Error> {
+    let no_period = payload.input_ride_month.is_none() && payload.start_date.is_none() && payload.end_date.is_none();
+    let default_ride_month = match no_period && payload.input_manifest_s3_uri.is_none() && payload.legacy_compat.is_none() {
+        true => {
+            let default = time::DefaultMonth::from_env();
+            payload.input_ride_month = default.month(clock.now())?;
+            Some(time::DefaultedMonth { default, input_ride_month: payload.input_ride_month.clone() })
+        }
+        false => None,
+    };
+    if let Some(compat) = &payload.legacy_compat {
+        if let Err(err) = compat.validate(payload.granularity.unwrap_or_default()) {
+            return Ok(json!(ErrorOutput { error: err }));
+        }
+    }
+    if let Err(err) = payload.check_date_range() {
+        return Ok(json!(ErrorOutput { error: err }));
+    }
+    if payload.ride_types.is_some() && payload.ride_types_include.is_some() {
+        return Ok(json!(ErrorOutput { error: "ride_types cannot be combined with ride_types_include".to_string() }));
+    }
+    if payload.input_manifest_s3_uri.is_some() {
+        return manifest::run(shared_config, &payload, run_id, clock, &cost::CapacityMeter::default()).await;
+    }
+
+    let retry_stats = Arc::new(retries::RetryStats::default());
+    let client = retries::client(shared_config, &retry_stats);
+    let cohorts = cohorts::resolve(&client, &payload).await?;
+    let (mut imeis, request_warnings) = match resolve_imeis(shared_config, &payload, &cohorts, cleanup).await? {
+        Ok(resolved) => resolved,
+        Err(rejection) => return Ok(rejection),
+    };
+
+    if payload.estimate.unwrap_or(false) {
+        return estimate::estimate(&client, &payload, &imeis).await;
+    }
+    if payload.preflight.unwrap_or(false) {
+        return preflight::preflight(&client, &payload, &imeis).await;
+    }
+    if payload.counts_only() {
+        return counts::ride_counts(&client, &payload, &imeis).await;
+    }
+    let dry_run = is_dry_run(&payload);
+    let backfill = match (&payload.backfill_job, payload.backfill.unwrap_or(false)) {
+        _ if dry_run => None,
+        (Some(job), _) => Some(job.clone()),
+        (None, true) => Some(backfill::start(shared_config, imeis.len(), run_id).await?),
+        (None, false) => None,
+    };
+    let remaining = match backfill {
+        Some(_) => imeis.split_off(backfill::chunk_size().min(imeis.len())),
+        None => Vec::new(),
+    };
+    if payload.fan_out.unwrap_or(false) && !dry_run && backfill.is_none() {
+        return fanout::coordinate(shared_config, request, &imeis, payload.priority.unwrap_or_default(), run_id).await;
+    }
+
+    // Every write verifies the table too; checking here fails the run before any ride is read.
+    if !dry_run {
+        destination::verify(&client).await?;
+    }
+    let keys = match payload.verify_signatures {
+        Some(_) => Some(signatures::HmacKeys::load(shared_config, &imeis).await?),
+        None => None,
+    };
+    let meter = cost::CapacityMeter::default();
+    let authenticator = keys.as_ref().map(|keys| keys as &dyn signatures::RideAuthenticator);
+    let result = aggregate_ride_data(&client, &payload, &imeis, run_id, clock, authenticator, &meter).await;
+    let period = payload.input_ride_month.clone()
+        .or_else(|| payload.date_range().map(time::DateRange::label))
+        .unwrap_or_else(|| ride::window().label());
+
+    if payload.post_summary.unwrap_or(false) && !dry_run {
+        post_summary(&period, imeis.len(), &result).await;
+    }
+    let Aggregation {
+        rows: mut output,
+        truncated_imeis,
+        hot_partitions,
+        mut warnings,
+        normalized_values,
+        imei_aliases,
+        skipped_items,
+        anomalies,
+        skipped_years,
+        cached_imeis,
+        resumed_imeis,
+        mut write_plan,
+        errors,
+        items_read,
+        ride_queries,
+        query_latency,
+        query_plan,
+    } = result?;
+
+    let mut fleet_summary = Vec::new();
+    if payload.include_fleet_summary.unwrap_or(false) {
+        let incomplete = !errors.is_empty() || !resumed_imeis.is_empty();
+        fleet_summary = fleet::rollup(&fleet::key(&payload), &output, incomplete);
+        write_plan.extend(write_rows(&client, &payload, &mut fleet_summary, run_id, &meter, &mut warnings).await?);
+    }
+    let mut distance_alerts = Vec::new();
+    if !dry_run {
+        match thresholds::check(shared_config, &client, output.iter().chain(&fleet_summary), run_id).await {
+            Ok(alerts) => distance_alerts = alerts,
+            Err(err) => error!("Error checking distance thresholds: {:?}", err),
+        }
+    }
+    let mut maintenance_due = Vec::new();
+    if !dry_run {
+        match maintenance::check(shared_config, &client, &output).await {
+            Ok(due) => maintenance_due = due,
+            Err(err) => error!("Error checking maintenance schedules: {:?}", err),
+        }
+    }
+    metrics::emit_invocation(&metrics::Invocation {
+        imeis: imeis.len(),
+        granularity: payload.granularity.unwrap_or_default(),
+        items_read,
+        rides_aggregated: output.iter().map(|row| row.explain.rides_included).sum(),
+        ride_queries,
+        query_latency,
+        write_failures: output.iter().chain(&fleet_summary)
+            .filter(|row| row.write_skipped == Some(aggregates::SkipReason::Unprocessed))
+            .count(),
+    });
+
+    if payload.email_report.unwrap_or(false) && !dry_run {
+        email_report(shared_config, &period, &output).await;
+    }
+
+    let exported = match payload.export.as_ref().filter(|_| !dry_run) {
+        Some(target) => export::export_results(shared_config, target, &output, run_id, &kms::Encryption::for_request(&payload), payload.number_format.unwrap_or_default()).await?,
+        None => Vec::new(),
+    };
+    let expiry = s3::presign_expiry(payload.presign_expiry_secs);
+    let s3_client = aws_sdk_s3::Client::new(shared_config);
+    let mut exported_urls = Vec::with_capacity(exported.len());
+    for uri in &exported {
+        exported_urls.push(s3::presign(&s3_client, uri, expiry).await?);
+    }
+
+    let charges = match &payload.tenant_id {
+        Some(tenant_id) => {
+            let card = billing::load_rate_card(&client, tenant_id).await?;
+            output.iter().map(|row| billing::charge(&card, row)).collect()
+        }
+        None => Vec::new(),
+    };
+
+    if !anomalies.is_empty() && !dry_run {
+        if let Err(err) = anomalies::publish(shared_config, &anomalies, run_id).await {
+            error!("Error publishing ride anomalies: {:?}", err);
+        }
+    }
+
+    if let Some(job) = payload.fan_out_job.as_ref().filter(|_| !dry_run) {
+        fanout::complete_shard(shared_config, job, &output).await?;
+    }
+    let backfill = match &backfill {
+        Some(job) => Some(backfill::advance(shared_config, request, &payload, job, imeis.len(), errors.len(), &remaining).await?),
+        None => None,
+    };
+
+    let cost = meter.run_cost(arrival.started.elapsed());
+    if !dry_run {
+        let config = config::echo();
+        let run = audit::RunRecord { run_id, started_at: &arrival.started_at, request, config: &config, rows: output.len(), cost, runtime: &arrival.runtime };
+        if let Err(err) = audit::record(&client, &run).await {
+            error!("Error recording aggregation run: {:?}", err);
+        }
+    }
+
+    let output_unit = payload.output_unit.unwrap_or_default();
+    for row in output.iter_mut().chain(&mut fleet_summary) {
+        units::convert(row, output_unit);
+    }
+    let response = AggregationResponse {
+        cohorts: cohorts::rollup(&cohorts, &imei_aliases, &output),
+        groups: payload.groups.as_ref().map(|groups| cohorts::nest(groups, &imei_aliases, &output)).unwrap_or_default(),
+        fleet_summary,
+        by_month: payload.include_by_month.unwrap_or(false).then(|| fleet::by_month(&output)),
+        charges,
+        cost,
+        results: output,
+        truncated_imeis,
+        hot_partitions,
+        warnings: request_warnings.into_iter().chain(warnings).collect(),
+        normalized_values,
+        imei_aliases,
+        skipped_items,
+        anomalies,
+        distance_alerts,
+        maintenance_due,
+        skipped_years,
+        cached_imeis,
+        resumed_imeis,
+        write_plan,
+        default_ride_month,
+        errors,
+        exported,
+        exported_urls,
+        backfill,
+        output_unit,
+        diagnostics: Diagnostics {
+            retries: retry_stats.snapshot(),
+            runtime: arrival.runtime,
+            query_plan,
+            capacity_modes: capacity::snapshot(),
+            #[cfg(feature = "alloc-stats")]
+            memory: arrival.memory.stats(),
+        },
+    };
+    respond(&s3_client, &payload, response, run_id, expiry).await
+}
+
+/// The request's IMEIs with its cohorts' and groups' members, each once, or the rejection of an invalid list.
+async fn resolve_imeis(
+    shared_config: &aws_config::SdkConfig,
+    payload: &CustomEvent,
+    cohorts: &BTreeMap<String, Vec<String>>,
+    cleanup: ImeiCleanup,
+) -> Result<Result<(Vec<String>, Vec<warnings::Warning>), Value>, Error> {
+    let ImeiCleanup { cleanup, mut repeats } = cleanup;
+    let mut imeis: Vec<String> = Vec::new();
+    let mut requested = match imeis::requested(shared_config, payload).await? {
+        Ok(requested) => requested,
+        Err(err) => {
+            warn!("Rejected event: {}", err);
+            return Ok(Err(json!(ErrorOutput { error: err })));
+        }
+    };
+    imeis::dedupe(&mut requested, &mut repeats);
+    let mut request_warnings = Vec::new();
+    if cleanup != imeis::Cleanup::default() {
+        let imeis::Cleanup { trimmed, dropped_empty } = cleanup;
+        warnings::push(&mut request_warnings, warnings::Warning::ImeiListCleaned { trimmed, dropped_empty });
+    }
+    for (imei, repeats) in repeats {
+        warnings::push(&mut request_warnings, warnings::Warning::DuplicateImei { imei, occurrences: repeats + 1 });
+    }
+    let group_members = payload.groups.iter().flat_map(|groups| groups.values().flatten().cloned());
+    for imei in requested.into_iter().chain(cohorts.values().flatten().cloned()).chain(group_members) {
+        if !imeis.contains(&imei) {
+            imeis.push(imei);
+        }
+    }
+    if let Err(rejection) = imeis::validate(&imeis) {
+        warn!("Rejected event: {}", rejection.error);
+        return Ok(Err(json!(rejection)));
+    }
+    if imeis.is_empty() {
+        warn!("Imei cannot be empty");
+        return Ok(Err(json!(ErrorOutput { error: "IMEI cannot be empty".to_string() })));
+    }
+    Ok(Ok((imeis, request_warnings)))
+}
+
+/// Posts the run's summary to the webhook; a failure is only logged.
+async fn post_summary(period: &str, devices: usize, result: &Result<Aggregation, Error>) {
+    let Some(settings) = webhook::WebhookSettings::from_env() else {
+        warn!("post_summary requested but SUMMARY_WEBHOOK_URL is not set");
+        return;
+    };
+    let summary = webhook::RunSummary {
+        period: period.to_string(),
+        devices,
+        total_km: result.as_ref().map(|aggregation| aggregation.rows.iter().map(|r| r.total_distance).sum()).unwrap_or(0.0),
+        failures: match result {
+            Ok(aggregation) => aggregation.errors.iter().map(|err| format!("{}: {:?}", err.imei, err.failure.kind)).collect(),
+            Err(err) => vec![err.to_string()],
+        },
+    };
+    if let Err(err) = webhook::post_summary(&settings, &summary).await {
+        error!("Error posting run summary: {:?}", err);
+    }
+}
+
+/// Emails the rows as a CSV report; a failure is only logged.
+async fn email_report(shared_config: &aws_config::SdkConfig, period: &str, output: &[CustomOutput]) {
+    let Some(settings) = email::EmailSettings::from_env() else {
+        warn!("email_report requested but REPORT_EMAIL_SENDER/REPORT_EMAIL_RECIPIENTS are not set");
+        return;
+    };
+    let ses_client = aws_sdk_sesv2::Client::new(shared_config);
+    let subject = format!("Ride distance report {}", period);
+    if let Err(err) = email::send_report(&ses_client, &settings, &subject, &report::to_csv(output)).await {
+        error!("Error emailing report: {:?}", err);
+    }
+}
+
+/// The response in the requested number format, spilled to S3 when it has too many rows to return inline.
+async fn respond(
+    s3_client: &aws_sdk_s3::Client,
+    payload: &CustomEvent,
+    response: AggregationResponse,
+    run_id: &str,
+    expiry: Duration,
+) -> Result<Value, Error> {
+    let rows = response.results.len() + response.fleet_summary.len();
+    let number_format = payload.number_format.unwrap_or_default();
+    if let Some(bucket) = upload::spill_bucket(rows) {
+        let name = format!("{}-{}", run_id, &uuid::Uuid::new_v4().simple().to_string()[..8]);
+        let encryption = kms::Encryption::for_request(payload);
+        let spilled = match number_format {
+            numbers::NumberFormat::Float => upload::spill(s3_client, &bucket, &name, rows, response, expiry, &encryption).await?,
+            _ => {
+                let mut response = json!(response);
+                number_format.apply(&mut response);
+                upload::spill(s3_client, &bucket, &name, rows, response, expiry, &encryption).await?
+            }
+        };
+        info!("Wrote the {}-row response to S3", rows);
+        return Ok(json!(spilled));
+    }
+    let mut response = json!(response);
+    number_format.apply(&mut response);
+    Ok(response)
+}
+
+/// Whether handling the event writes to DynamoDB (reconcile, export and regulatory reports only write to S3).
+fn writes_tables(payload: &CustomEvent) -> bool {
+    match payload.action {
+        Action::Aggregate => {
+            !payload.estimate.unwrap_or(false) && !payload.preflight.unwrap_or(false) && !payload.counts_only() && !is_dry_run(payload)
+                && payload.granularity != Some(time::Granularity::Range)
+        }
+        Action::Finalize | Action::RideCorrected | Action::RideVoided | Action::DeviceDecommissioned | Action::Import
+            | Action::MigrateLegacyRows => true,
+        Action::EnforceRetention => !is_dry_run(payload),
+        Action::Selftest => payload.seed_fixture.unwrap_or(false),
+        // Each replayed request passes the guard itself.
+        Action::Describe | Action::Reconcile | Action::DebugTrace | Action::Replay | Action::Export | Action::AggregateAsOf
+            | Action::RegulatoryReport | Action::Read | Action::Diagnose | Action::CompareMonths => false,
+    }
+}
+
+impl CustomEvent {
+    fn ride_types(&self) -> ride::RideTypes {
+        let include = self.ride_types.as_deref().or(self.ride_types_include.as_deref());
+        ride::RideTypes::new(include, self.ride_types_exclude.as_deref())
+    }
+
+    /// The `ride_type`s to filter the ride query on, with `ride_types`; empty reads every ride.
+    fn ride_type_filter(&self) -> Vec<String> {
+        match self.ride_types {
+            Some(_) => self.ride_types().counted().into_iter().map(str::to_string).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    fn counts_only(&self) -> bool {
+        self.metrics.as_deref() == Some(&[stats::Metric::RideCount])
+    }
+
+    fn date_range(&self) -> Option<time::DateRange> {
+        self.start_date.zip(self.end_date).map(|(start, end)| time::DateRange { start, end })
+    }
+
+    fn check_date_range(&self) -> Result<(), String> {
+        if self.start_date.is_some() != self.end_date.is_some() {
+            return Err("start_date and end_date must be given together".to_string());
+        }
+        let Some(range) = self.date_range() else {
+            return match self.granularity {
+                Some(time::Granularity::Range) => Err("granularity range needs start_date and end_date".to_string()),
+                _ => Ok(()),
+            };
+        };
+        if range.start > range.end {
+            return Err("start_date must not be after end_date".to_string());
+        }
+        if self.input_ride_month.is_some() || self.input_manifest_s3_uri.is_some() {
+            return Err("start_date / end_date cannot be combined with input_ride_month or input_manifest_s3_uri".to_string());
+        }
+        range.bounds().map(|_| ()).map_err(|err| err.to_string())
+    }
+}
+
+fn is_dry_run(payload: &CustomEvent) -> bool {
+    payload.dry_run.unwrap_or(false) || payload.legacy_compat.is_some()
+}
+
+/// How many IMEIs to query at once, from `IMEI_CONCURRENCY` (default 8), within the read quota.
+fn imei_concurrency_from_env() -> usize {
+    let configured = config::get().imei_concurrency;
+    quotas::max_query_concurrency().map_or(configured, |max| configured.min(max))
+}
+
+/// Rows produced by one aggregation, plus the IMEIs that could not be read in full.
+struct Aggregation {
+    rows: Vec<CustomOutput>,
+    truncated_imeis: Vec<String>,
+    hot_partitions: Vec<partitions::HotPartition>,
+    warnings: Vec<warnings::Warning>,
+    normalized_values: BTreeMap<String, u64>,
+    imei_aliases: BTreeMap<String, String>,
+    skipped_items: ride::SkippedItems,
+    anomalies: Vec<anomalies::Anomaly>,
+    skipped_years: years::SkippedYears,
+    cached_imeis: Vec<String>,
+    resumed_imeis: Vec<String>,
+    write_plan: Vec<aggregates::PlannedWrite>,
+    errors: Vec<failures::DeviceError>,
+    items_read: usize,
+    ride_queries: usize,
+    query_latency: Duration,
+    query_plan: planner::QueryPlan,
+}
+
+async fn aggregate_ride_data(
+    store: &impl RideStore,
+    payload: &CustomEvent,
+    imeis: &[String],
+    run_id: &str,
+    clock: &clock::Clock,
+    authenticator: Option<&dyn signatures::RideAuthenticator>,
+    meter: &cost::CapacityMeter,
+) -> Result<Aggregation, Error> {
+    let breakdowns = payload.breakdowns.clone().unwrap_or_default();
+    let granularity = payload.granularity.unwrap_or_default();
+    let date_range = payload.date_range();
+    let metrics = payload.metrics.clone().unwrap_or_else(|| stats::DEFAULT_METRICS.to_vec());
+
+    let mut truncated_imeis = Vec::new();
+    let mut hot_partitions = Vec::new();
+    let hot_latency = partitions::latency_threshold_from_env();
+    let items_read = AtomicUsize::new(0);
+    let mut warnings = Vec::new();
+    let mut normalized_values: BTreeMap<String, u64> = BTreeMap::new();
+    let mut skipped_items = ride::SkippedItems::new();
+    let mut anomalies = Vec::new();
+    let mut cached_imeis = Vec::new();
+    let mut write_plan = Vec::new();
+    let mut errors = Vec::new();
+    let (mut ride_queries, mut query_latency) = (0, Duration::ZERO);
+    let now = clock.now();
+
+    let current_month = time::month_of(now)?;
+    let current_period = match date_range {
+        Some(range) if granularity == time::Granularity::Range => {
+            range.bounds().ok().filter(|(from, to)| (*from..*to).contains(&now.timestamp())).map(|_| range.label())
+        }
+        _ => Some(granularity.period_of(now)?),
+    };
+    let as_of = now.to_rfc3339_opts(SecondsFormat::Secs, true);
+    // Without a requested period or a window start, each device's gaps are filled from its first ride.
+    let window = ride::window();
+    let fill_gaps = payload.fill_gaps.unwrap_or(false) && granularity == time::Granularity::Monthly;
+    let (gap_months, gaps_from_first_ride): (Vec<String>, bool) = match (&payload.input_ride_month, date_range) {
+        _ if !fill_gaps => (Vec::new(), false),
+        (Some(month), _) => (window.touches(month).then(|| month.clone()).into_iter().collect(), false),
+        (None, Some(range)) => (range.months()?.into_iter().map(|(month, _)| month).filter(|month| window.touches(month)).collect(), false),
+        (None, None) => match window.months_until(&current_month)? {
+            Some(months) => (months, false),
+            None => (Vec::new(), true),
+        },
+    };
+
+    // Rides are read per IMEI a device has reported and totalled under its current one.
+    let client = store.dynamodb();
+    let mut devices = match client {
+        Some(client) => aliases::resolve(client, imeis).await?,
+        None => imeis.iter().collect::<BTreeSet<_>>().into_iter()
+            .map(|imei| aliases::Device { imei: imei.clone(), identities: vec![imei.clone()] })
+            .collect(),
+    };
+    let device_count = devices.len();
+    let budget = failures::error_rate_threshold(payload.fail_if_error_rate_above);
+    let checkpoint = (!is_dry_run(payload) && client.is_some()).then(|| checkpoint::Checkpoint::new(run_id, payload, imeis));
+    let mut resumed_imeis = Vec::new();
+    if let (Some(checkpoint), Some(client)) = (&checkpoint, client) {
+        let completed = checkpoint.completed(client, meter).await?;
+        devices.retain(|device| match completed.contains(&device.imei) {
+            true => {
+                resumed_imeis.push(device.imei.clone());
+                false
+            }
+            false => true,
+        });
+        if !resumed_imeis.is_empty() {
+            info!("Resuming run: skipping {} devices already completed", resumed_imeis.len());
+        }
+    }
+    let mut imei_aliases = BTreeMap::new();
+    for device in &devices {
+        for identity in device.identities.iter().filter(|identity| **identity != device.imei) {
+            imei_aliases.insert(identity.clone(), device.imei.clone());
+        }
+    }
+    // The max_total_items budget is spent in IMEI order, so it is only exact one device at a time.
+    let concurrency = match payload.max_total_items {
+        Some(_) => 1,
+        None => imei_concurrency_from_env(),
+    };
+    let year_probes: Vec<(String, String)> = match payload.report_skipped_years {
+        Some(true) => devices.iter().flat_map(|device| device.identities.iter().map(|identity| (device.imei.clone(), identity.clone()))).collect(),
+        _ => Vec::new(),
+    };
+    let ride_types = payload.ride_types();
+    let type_filter = payload.ride_type_filter();
+    let identities: Vec<String> = devices.iter().flat_map(|device| device.identities.clone()).collect();
+    let projection = ride_projection(&metrics, payload.fraud_checks.unwrap_or(false));
+    let (query_plan, month_rides) = match client {
+        Some(client) => {
+            let mut query_plan = planner::plan(client, payload, identities.len()).await;
+            let month_rides = planner::read(client, payload, &mut query_plan, &identities, &projection, meter).await;
+            (query_plan, month_rides)
+        }
+        None => (planner::QueryPlan::per_imei("no DynamoDB store"), None),
+    };
+    let (items_read, breakdowns, ride_types, type_filter, metrics, month_rides) = (&items_read, &breakdowns, &ride_types, &type_filter, &metrics, &month_rides);
+    let mut devices_read = stream::iter(devices)
+        .map(|device| async move {
+            let mut reads: Vec<(String, Option<usize>, Option<ImeiStats>)> = Vec::with_capacity(device.identities.len());
+            for imei in &device.identities {
+                let remaining = payload.max_total_items.map(|max| max.saturating_sub(items_read.load(Ordering::SeqCst)));
+                let limit = match (payload.max_rides_per_imei, remaining) {
+                    (Some(max), Some(remaining)) => Some(max.min(remaining)),
+                    (max, remaining) => max.or(remaining),
+                };
+                if remaining == Some(0) {
+                    reads.push((imei.clone(), limit, None));
+                    continue;
+                }
+                let query = StatsQuery {
+                    input_ride_month: payload.input_ride_month.as_deref(),
+                    date_range,
+                    granularity,
+                    breakdowns,
+                    max_rides: limit,
+                    now,
+                    signatures: authenticator.zip(payload.verify_signatures),
+                    fraud_checks: payload.fraud_checks.unwrap_or(false),
+                    legacy_compat: payload.legacy_compat,
+                    ride_types,
+                    type_filter,
+                    metrics,
+                };
+                let span = info_span!("imei", imei = %imei, month = payload.input_ride_month.as_deref());
+                // A panic on one IMEI's rides fails that device rather than the whole invocation.
+                let started = Instant::now();
+                let read = AssertUnwindSafe(async {
+                    match (month_rides, client) {
+                        (Some(month_rides), _) => monthly_stats(month_rides, imei, &query, meter).await,
+                        (None, Some(client)) => cache::imei_stats(client, imei, &query, meter, !is_dry_run(payload)).await,
+                        (None, None) => monthly_stats(store, imei, &query, meter).await,
+                    }
+                });
+                let imei_stats = match read.catch_unwind().instrument(span).await {
+                    Ok(imei_stats) => imei_stats?,
+                    Err(panic) => {
+                        let failure = failures::Failure::of_panic(panic);
+                        error!(imei = %imei, "Aggregating imei panicked: {}", failure.message);
+                        ImeiStats::failed(failure, started.elapsed())
+                    }
+                };
+                items_read.fetch_add(imei_stats.items_read, Ordering::SeqCst);
+                reads.push((imei.clone(), limit, Some(imei_stats)));
+            }
+            Ok::<_, Error>((device.imei, reads))
+        })
+        .buffered(concurrency);
+
+    // Each device's rows are written as soon as its rides are read, so a run failing later keeps them;
+    // with an error budget they are held until the run is known to be within it.
+    let writes = DeviceWrites { store, payload, checkpoint: checkpoint.as_ref(), run_id, now, meter };
+    let mut held = Vec::new();
+    let mut output: Vec<CustomOutput> = Vec::new();
+    while let Some((device, reads)) = devices_read.try_next().await? {
+        let mut month_stats: BTreeMap<String, stats::MonthStats> = BTreeMap::new();
+        let (mut truncated, mut complete, mut failed) = (false, true, false);
+        for (imei, limit, imei_stats) in reads {
+            let Some(imei_stats) = imei_stats else {
+                warnings::push(&mut warnings, warnings::Warning::TruncatedResults { imei, items_read: 0 });
+                (truncated, complete) = (true, false);
+                continue;
+            };
+            ride_queries += 1;
+            query_latency += imei_stats.query_latency;
+            let throttled = imei_stats.failure.as_ref().is_some_and(|failure| failure.kind == failures::ErrorKind::Throttled);
+            if throttled || imei_stats.query_latency > hot_latency {
+                let hot = partitions::HotPartition {
+                    imei: imei.clone(),
+                    latency_ms: imei_stats.query_latency.as_millis() as u64,
+                    throttled,
+                };
+                partitions::emit_metric(&hot);
+                hot_partitions.push(hot);
+            }
+            if let Some(failure) = imei_stats.failure {
+                errors.push(failures::DeviceError { identity: (imei != device).then_some(imei), imei: device.clone(), failure });
+                failed = true;
+                continue;
+            }
+            if imei_stats.cached {
+                cached_imeis.push(imei.clone());
+            }
+            if limit.is_some_and(|limit| imei_stats.items_read >= limit) {
+                warnings::push(&mut warnings, warnings::Warning::TruncatedResults { imei, items_read: imei_stats.items_read });
+                (truncated, complete) = (true, false);
+            }
+            warnings.extend(imei_stats.warnings);
+            for (attribute, count) in imei_stats.normalized {
+                *normalized_values.entry(attribute).or_default() += count;
+            }
+            for (error, count) in imei_stats.skipped {
+                *skipped_items.entry(error).or_default() += count;
+            }
+            anomalies.extend(imei_stats.anomalies);
+            for (ride_month, stats) in imei_stats.months {
+                month_stats.entry(ride_month).or_default().merge(stats);
+            }
+        }
+        // A device missing some of its rides gets no rows at all, rather than partial ones.
+        if failed {
+            continue;
+        }
+        if truncated {
+            truncated_imeis.push(device.clone());
+        }
+        // Only devices whose rides were read in full are known to have had nothing in a month.
+        if complete {
+            let from_first_ride = match month_stats.keys().next().filter(|_| gaps_from_first_ride) {
+                Some(first) => time::months_between(first, &current_month)?.into_iter().filter(|month| window.touches(month)).collect(),
+                None => Vec::new(),
+            };
+            for month in gap_months.iter().chain(&from_first_ride).filter(|month| **month <= current_month) {
+                month_stats.entry(month.clone()).or_default();
+            }
+        }
+
+        let mut rows: Vec<CustomOutput> = month_stats.into_iter().map(|(ride_month, month_stats)| {
+            CustomOutput {
+                month_to_date: current_period.as_ref() == Some(&ride_month),
+                truncated,
+                imei: device.clone(),
+                granularity,
+                ride_month,
+                total_distance: month_stats.distance,
+                total_duration: metrics.contains(&stats::Metric::Duration).then_some(month_stats.duration),
+                ride_count: metrics.contains(&stats::Metric::RideCount).then_some(month_stats.explain.rides_included),
+                average_speed: month_stats.average_speed().filter(|_| metrics.contains(&stats::Metric::Speed)),
+                max_speed: month_stats.max_speed,
+                total_energy: metrics.contains(&stats::Metric::Energy).then_some(month_stats.energy),
+                distance_histogram: metrics.contains(&stats::Metric::Histogram).then_some(month_stats.histogram),
+                as_of: as_of.clone(),
+                write_skipped: None,
+                breakdowns: month_stats.breakdowns,
+                explain: month_stats.explain,
+                fraud_flags: month_stats.fraud_flags,
+                experimental: month_stats.experimental,
+                applied_rides: month_stats.ride_starts,
+            }
+        }).collect();
+        if budget.is_some() {
+            held.push((device, rows, complete));
+            continue;
+        }
+        write_plan.extend(writes.write(&device, &mut rows, complete, &mut warnings).await?);
+        output.extend(rows);
+    }
+    if let Some(threshold) = budget {
+        let failed = failures::failed_devices(&errors);
+        if failures::over_budget(failed, device_count, threshold) {
+            let first: BTreeSet<&str> = errors.iter().map(|err| err.imei.as_str()).take(10).collect();
+            error!("{} of {} devices failed, above the {}% error budget; nothing was written", failed, device_count, threshold);
+            return Err(format!("{} of {} devices failed, above the {}% error budget; nothing was written (first: {})",
+                failed, device_count, threshold, first.into_iter().collect::<Vec<_>>().join(", ")).into());
+        }
+        for (device, mut rows, complete) in held {
+            write_plan.extend(writes.write(&device, &mut rows, complete, &mut warnings).await?);
+            output.extend(rows);
+        }
+    }
+
+    if let (Some(sample), Some(client)) = (payload.verify_writes, client) {
+        verify::verify_writes(client, &output, sample, meter).await?;
+    }
+
+    // Part of the response contract, so downstream checks can diff responses.
+    output.sort_by(|a, b| (&a.imei, &a.ride_month).cmp(&(&b.imei, &b.ride_month)));
+    write_plan.sort_by(|a, b| (&a.imei, &a.ride_month).cmp(&(&b.imei, &b.ride_month)));
+    truncated_imeis.sort();
+    for row in output.iter() {
+        info!(imei = %row.imei, month = %row.ride_month, total_distance = row.total_distance, "Aggregated period");
+    }
+    let skipped_years = match client {
+        Some(client) => years::skipped_years(client, year_probes, meter).await?,
+        None => years::SkippedYears::new(),
+    };
+
+    Ok(Aggregation {
+        rows: output,
+        truncated_imeis,
+        hot_partitions,
+        warnings,
+        normalized_values,
+        imei_aliases,
+        skipped_items,
+        anomalies,
+        skipped_years,
+        cached_imeis,
+        resumed_imeis,
+        write_plan,
+        errors,
+        items_read: items_read.load(Ordering::SeqCst),
+        ride_queries,
+        query_latency,
+        query_plan,
+    })
+}
+
+/// Writes a read device's rows, then checkpoints it if they were all its rows and all landed.
+struct DeviceWrites<'a, S> {
+    store: &'a S,
+    payload: &'a CustomEvent,
+    checkpoint: Option<&'a checkpoint::Checkpoint>,
+    run_id: &'a str,
+    now: DateTime<Utc>,
+    meter: &'a cost::CapacityMeter,
+}
+
+impl<S: RideStore> DeviceWrites<'_, S> {
+    async fn write(&self, device: &str, rows: &mut [CustomOutput], complete: bool, warnings: &mut Vec<warnings::Warning>) -> Result<Vec<aggregates::PlannedWrite>, Error> {
+        let plan = write_rows(self.store, self.payload, rows, self.run_id, self.meter, warnings).await?;
+        let unprocessed = rows.iter().any(|row| row.write_skipped == Some(aggregates::SkipReason::Unprocessed));
+        if let (Some(checkpoint), Some(client)) = (self.checkpoint.filter(|_| complete && !unprocessed), self.store.dynamodb()) {
+            checkpoint.record(client, device, self.now, self.meter).await?;
+        }
+        Ok(plan)
+    }
+}
+
+/// Puts one device's rows that may be written, recording why the others were not and the revisions made.
+/// A dry run writes nothing and returns what it would have done instead.
+async fn write_rows(
+    store: &impl RideStore,
+    payload: &CustomEvent,
+    output: &mut [CustomOutput],
+    run_id: &str,
+    meter: &cost::CapacityMeter,
+    warnings: &mut Vec<warnings::Warning>,
+) -> Result<Vec<aggregates::PlannedWrite>, Error> {
+    // A run scoped to a month reads only its rides, so a week across either end of it is partial too.
+    let date_range = payload.date_range().or_else(|| payload.input_ride_month.as_deref().and_then(time::DateRange::month));
+    let revision_threshold = history::threshold_from_env();
+    let token = payload.fan_out_job.as_ref().map(fanout::FanOutJob::idempotency_token);
+    let updated_at = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+    let options = aggregates::WriteOptions {
+        preserve_final: payload.preserve_final_months.unwrap_or(false),
+        force: payload.force.unwrap_or(false),
+        overwrite: payload.overwrite.unwrap_or(true),
+        token: token.as_deref(),
+        invocation_id: run_id,
+        updated_at: &updated_at,
+        metrics: payload.metrics.as_deref().unwrap_or(&stats::DEFAULT_METRICS),
+    };
+    // An update sets only the computed metrics, so the rollup keeps counting the others as stored.
+    let written = (aggregates::write_mode() == aggregates::WriteMode::Update).then_some(options.metrics);
+    for row in output.iter_mut() {
+        if row.truncated {
+            row.write_skipped = Some(aggregates::SkipReason::Truncated);
+        } else if date_range.is_some_and(|range| !range.covers(row.granularity, &row.ride_month)) {
+            row.write_skipped = Some(aggregates::SkipReason::PartialPeriod);
+        }
+    }
+    let pending: Vec<usize> = (0..output.len()).filter(|&i| output[i].write_skipped.is_none()).collect();
+    let rows: Vec<&CustomOutput> = pending.iter().map(|&i| &output[i]).collect();
+    if is_dry_run(payload) {
+        let outcomes = match store.dynamodb() {
+            Some(client) => aggregates::plan_rows(client, &rows, &options, meter).await?,
+            None => vec![aggregates::PutOutcome::default(); rows.len()],
+        };
+        let mut outcomes = pending.into_iter().zip(outcomes).collect::<HashMap<_, _>>();
+        let plan = output.iter_mut().enumerate().map(|(i, row)| {
+            let outcome = outcomes.remove(&i).unwrap_or(aggregates::PutOutcome { skipped: row.write_skipped, ..Default::default() });
+            row.write_skipped = Some(aggregates::SkipReason::DryRun);
+            aggregates::PlannedWrite::new(row, &outcome)
+        }).collect();
+        return Ok(plan);
+    }
+    let outcomes = store.put_rows(&rows, &options, meter).await?;
+    for (i, outcome) in pending.into_iter().zip(outcomes) {
+        let row = &mut output[i];
+        row.write_skipped = outcome.skipped;
+        match row.write_skipped {
+            Some(aggregates::SkipReason::Unprocessed) => warnings::push(warnings, warnings::Warning::UnprocessedWrite {
+                imei: row.imei.clone(),
+                ride_month: row.ride_month.clone(),
+            }),
+            Some(reason) => info!("Kept stored row for imei {} month {}: {:?}", row.imei, row.ride_month, reason),
+            None => {}
+        }
+
+        // Rollups and revisions are kept beside the rows, in DynamoDB.
+        let Some(client) = store.dynamodb() else {
+            continue;
+        };
+        if row.write_skipped.is_none() {
+            fleet::maintain(client, row, outcome.previous, written, meter).await;
+        }
+        if let Some(previous) = outcome.previous_distance {
+            if (row.total_distance - previous).abs() > revision_threshold {
+                let revision = history::Revision {
+                    imei: &row.imei,
+                    granularity: row.granularity,
+                    ride_month: &row.ride_month,
+                    previous_value: previous,
+                    new_value: row.total_distance,
+                    run_id,
+                    reason: payload.reason.as_deref().unwrap_or("recompute"),
+                    revised_at: &row.as_of,
+                };
+                history::record(client, &revision, meter).await?;
+            }
+        }
+    }
+    Ok(Vec::new())
+}
+
+/// One IMEI's monthly totals and how many ride items were read to produce them.
+struct ImeiStats {
+    months: HashMap<String, stats::MonthStats>,
+    items_read: usize,
+    query_latency: Duration,
+    /// Why the ride query failed, after the SDK's retries; `months` is then empty.
+    failure: Option<failures::Failure>,
+    warnings: Vec<warnings::Warning>,
+    /// Ride attribute values changed by [`normalize::normalize`], per attribute.
+    normalized: BTreeMap<String, u64>,
+    skipped: ride::SkippedItems,
+    anomalies: Vec<anomalies::Anomaly>,
+    /// Reused from [`cache`] instead of read; `items_read` is then 0.
+    cached: bool,
+}
+
+impl ImeiStats {
+    fn failed(failure: failures::Failure, query_latency: Duration) -> ImeiStats {
+        ImeiStats {
+            months: HashMap::new(),
+            items_read: 0,
+            query_latency,
+            failure: Some(failure),
+            warnings: Vec::new(),
+            normalized: BTreeMap::new(),
+            skipped: ride::SkippedItems::new(),
+            anomalies: Vec::new(),
+            cached: false,
+        }
+    }
+}
+
+/// What to total one IMEI's rides over.
+#[derive(Clone, Copy)]
+struct StatsQuery<'a> {
+    /// Only count rides in this `YYYY-MM` month.
+    input_ride_month: Option<&'a str>,
+    /// Only count rides starting on these days.
+    date_range: Option<time::DateRange>,
+    granularity: time::Granularity,
+    breakdowns: &'a [stats::Breakdown],
+    max_rides: Option<usize>,
+    /// Rides starting more than [`ride::clock_skew_tolerance`] after this are anomalies.
+    now: DateTime<Utc>,
+    signatures: Option<(&'a dyn signatures::RideAuthenticator, signatures::Mode)>,
+    fraud_checks: bool,
+    legacy_compat: Option<legacy::LegacyCompat>,
+    ride_types: &'a ride::RideTypes,
+    /// `ride_type`s the query itself filters on; empty reads every ride.
+    type_filter: &'a [String],
+    /// Which reducers run and which `ride_stats` entries are read.
+    metrics: &'a [stats::Metric],
+}
+
+/// Trip totals per period (IST) for one IMEI, optionally restricted to one month.
+async fn monthly_stats(store: &impl RideStore, imei: &str, query: &StatsQuery<'_>, meter: &cost::CapacityMeter) -> Result<ImeiStats, Error> {
+    let ride_starts = ride_starts(query.input_ride_month, query.date_range, query.legacy_compat.is_some())?;
+    let started = Instant::now();
+    let projection = ride_projection(query.metrics, query.fraud_checks);
+    let mut items = match store.query_rides(imei, ride_starts, query.type_filter, query.max_rides, &projection, meter).await {
+        Ok(items) => items,
+        Err(err) => {
+            metrics::dynamodb_error("query");
+            let failure = failures::Failure::of_query(&err);
+            warn!("Ride query for imei {} failed ({:?}): {:?}", imei, failure.kind, err);
+            return Ok(ImeiStats::failed(failure, started.elapsed()));
+        }
+    };
+    let query_latency = started.elapsed();
+    metrics::RIDES_PROCESSED.inc_by(items.len() as u64);
+
+    let normalized = normalize::normalize(&mut items);
+    let (months, warnings, skipped, anomalies) = aggregate_rides(imei, &items, query);
+    info!(
+        items = items.len(),
+        periods = months.len(),
+        query_ms = query_latency.as_millis() as u64,
+        elapsed_ms = started.elapsed().as_millis() as u64,
+        "Aggregated imei"
+    );
+    Ok(ImeiStats { months, items_read: items.len(), query_latency, failure: None, warnings, normalized, skipped, anomalies, cached: false })
+}
+
+/// Buckets one IMEI's ride items into per-period totals, with the warnings raised and the items skipped on the way.
+/// Pure: the items are read, and the totals written, by the caller.
+fn aggregate_rides(
+    imei: &str,
+    items: &[pagination::Item],
+    query: &StatsQuery<'_>,
+) -> (HashMap<String, stats::MonthStats>, Vec<warnings::Warning>, ride::SkippedItems, Vec<anomalies::Anomaly>) {
+    let StatsQuery { input_ride_month, date_range, granularity, breakdowns, max_rides: _, now, signatures, fraud_checks, legacy_compat, ride_types, type_filter: _, metrics } = *query;
+    let range_bounds = date_range.and_then(|range| range.bounds().ok());
+    let rules = anomalies::Rules::from_env();
+    let mut anomalies = Vec::new();
+    let mut month_stats: HashMap<String, stats::MonthStats> = HashMap::new();
+    let mut warnings = Vec::new();
+    let mut included = Vec::new();
+    let mut missing_stats: BTreeMap<String, u64> = BTreeMap::new();
+    let mut skipped = ride::SkippedItems::new();
+    let mut drift = drift::Tracker::default();
+    for item in items.iter() {
+        let mut decision = ride::classify(item, input_ride_month, ride_types);
+        // Like rides of other months, rides outside the date range are not in scope at all.
+        if range_bounds.is_some_and(|(from, to)| !decision.ride_start.is_some_and(|start| (from..to).contains(&(start as i64)))) {
+            continue;
+        }
+        if let Some(ride_start) = decision.ride_start {
+            drift.record(item, ride_start);
+        }
+        let legacy = decision.ride_start.is_some_and(|start| legacy_compat.is_some_and(|compat| compat.covers(start)));
+        if legacy {
+            legacy::reclassify(&mut decision, item, input_ride_month, ride_types);
+        }
+        let mut unverified = false;
+        if let (None, Some((authenticator, mode))) = (decision.exclusion, signatures) {
+            if authenticator.verify(imei, item) == Some(false) {
+                warnings::push(&mut warnings, warnings::Warning::InvalidSignature { imei: imei.to_string(), ride_start: decision.ride_start.unwrap_or(0) });
+                match mode {
+                    signatures::Mode::Exclude => decision.exclusion = Some(ride::Exclusion::InvalidSignature),
+                    signatures::Mode::Flag => unverified = true,
+                }
+            }
+        }
+        if let Some(ride_start) = decision.ride_start.filter(|start| rules.is_future(*start, now.timestamp())) {
+            warnings::push(&mut warnings, warnings::Warning::ClockSkew { imei: imei.to_string(), ride_start, now: now.timestamp() });
+        }
+        if let Some(reason) = rules.check(&decision, now.timestamp()).filter(|_| decision.exclusion.is_none()) {
+            let excluded = rules.action == anomalies::Action::Exclude;
+            anomalies.push(anomalies::Anomaly {
+                imei: imei.to_string(),
+                ride_start: decision.ride_start.unwrap_or(0),
+                reason,
+                distance: decision.distance,
+                excluded,
+            });
+            if excluded {
+                decision.exclusion = Some(reason.exclusion());
+            }
+        }
+        debug!(imei, ride_start = ?decision.ride_start, distance = ?decision.distance, exclusion = ?decision.exclusion, "Classified ride");
+        if decision.skipped() {
+            for error in &decision.parse_errors {
+                *skipped.entry(*error).or_default() += 1;
+            }
+        }
+        let period = match granularity {
+            time::Granularity::Monthly => decision.ride_month,
+            time::Granularity::Range => date_range.map(time::DateRange::label),
+            _ => decision.ride_start.and_then(|start| granularity.period_of_epoch(start as i64).ok()),
+        };
+        let Some(ride_month) = period else {
+            continue;
+        };
+        match (decision.exclusion, decision.distance) {
+            (None, Some(distance)) if legacy => included.push((ride_month, decision.ride_start.unwrap_or(0), item, legacy::round(distance), unverified, true)),
+            (None, Some(distance)) => included.push((ride_month, decision.ride_start.unwrap_or(0), item, distance, unverified, false)),
+            (Some(exclusion), _) if exclusion.in_scope() => {
+                match exclusion {
+                    ride::Exclusion::MissingStats => *missing_stats.entry(ride_month.clone()).or_default() += 1,
+                    ride::Exclusion::Outlier => warnings::push(&mut warnings, warnings::Warning::OutlierDropped {
+                        imei: imei.to_string(),
+                        ride_start: decision.ride_start.unwrap_or(0),
+                        distance: decision.distance.unwrap_or(0.0),
+                    }),
+                    _ => {}
+                }
+                month_stats.entry(ride_month).or_default().record_exclusion(exclusion)
+            }
+            _ => {}
+        }
+    }
+    for (ride_month, rides) in missing_stats {
+        warnings::push(&mut warnings, warnings::Warning::MissingStats { imei: imei.to_string(), ride_month, rides });
+    }
+    if let Some(warning) = drift.warning(imei) {
+        warnings::push(&mut warnings, warning);
+    }
+
+    // The legacy pipeline never deduplicated, so its rides take no part in overlap checks.
+    let spans: Vec<_> = included.iter()
+        .map(|(_, start, item, _, _, legacy)| (*start, ride::ride_end(item).filter(|_| !legacy)))
+        .collect();
+    let overlapped: HashSet<usize> = ride::overlapped(&spans).into_iter().collect();
+    let mut fraud_facts: HashMap<String, Vec<fraud::RideFacts>> = HashMap::new();
+    for (i, (ride_month, ride_start, item, distance, unverified, legacy)) in included.into_iter().enumerate() {
+        if fraud_checks && !overlapped.contains(&i) {
+            fraud_facts.entry(ride_month.clone()).or_default().push(fraud::RideFacts::new(item, ride_start, ride::ride_end(item), distance));
+        }
+        let stats = month_stats.entry(ride_month).or_default();
+        if overlapped.contains(&i) {
+            warnings::push(&mut warnings, warnings::Warning::DuplicateRide { imei: imei.to_string(), ride_start });
+            stats.record_exclusion(ride::Exclusion::Overlapping);
+        } else {
+            stats.add_ride(item, distance, breakdowns, metrics);
+            stats.ride_starts.insert(ride_start);
+            if unverified {
+                stats.explain.unverified_rides += 1;
+            }
+            if legacy {
+                stats.explain.legacy_rides += 1;
+            }
+        }
+    }
+    for stats in month_stats.values_mut().filter(|stats| stats.explain.legacy_rides > 0) {
+        stats.distance = legacy::round(stats.distance);
+    }
+    for (ride_month, mut facts) in fraud_facts {
+        if let Some(stats) = month_stats.get_mut(&ride_month) {
+            stats.fraud_flags = fraud::flags(&mut facts);
+        }
+    }
+    month_stats.retain(|_, stats| stats.explain.rides_included > 0);
+    (month_stats, warnings, skipped, anomalies)
+}
+
+/// Epoch seconds `[from, to)` of the rides worth reading for `input_ride_month` or `date_range`, or
+/// for the ride window; None when neither restricts them.
+fn ride_starts(input_ride_month: Option<&str>, date_range: Option<time::DateRange>, legacy_compat: bool) -> Result<Option<(i64, i64)>, time::TimeError> {
+    let bounds = match (input_ride_month, date_range) {
+        (Some(month), _) => Some(time::month_range(month)?),
+        (None, Some(range)) => Some(range.bounds()?),
+        (None, None) => ride::window().bounds(),
+    };
+    // Legacy rides are bucketed by UTC month, which ends 5:30 later than the IST one.
+    Ok(bounds.map(|(from, to)| (from, if legacy_compat { to.saturating_add(86_400) } else { to })))
+}
+
+/// The ride table query for an IMEI, only rides starting within `ride_starts` if given.
+fn ride_query(client: &Client, imei: &str, ride_starts: Option<(i64, i64)>) -> QueryFluentBuilder {
+    let condition = match ride_starts {
+        Some(_) => "#imei = :imei AND ride_start BETWEEN :from AND :to",
+        None => "#imei = :imei",
+    };
+    let request = client
+        .query()
+        .table_name(&config::get().ride_table)
+        .key_condition_expression(condition)
+        .expression_attribute_names("#imei", "imei")
+        .expression_attribute_values(":imei", AttributeValue::S(imei.to_string()));
+    match ride_starts {
+        Some((from, to)) => request
+            .expression_attribute_values(":from", AttributeValue::N(from.to_string()))
+            .expression_attribute_values(":to", AttributeValue::N((to - 1).to_string())),
+        None => request,
+    }
+}
+
+/// Ride attributes to read for `metrics`. Fraud checks read the ride positions, so they need all of
+/// `ride_stats`; otherwise a ride whose `ride_stats` lacks `ride_distance` reads as having none.
+fn ride_projection(metrics: &[stats::Metric], fraud_checks: bool) -> String {
+    match fraud_checks {
+        true => RIDE_PROJECTION.to_string(),
+        false => format!("{}, {}", RIDE_ATTRIBUTES, stats::ride_stats_projection(metrics)),
+    }
+}
+
+/// Reads an IMEI's rides, only those starting within `ride_starts` (epoch seconds, `[from, to)`) if
+/// given, and only those of a `ride_types` type if any.
+async fn query_ride_new(
+    client: &Client,
+    imei: &str,
+    ride_starts: Option<(i64, i64)>,
+    ride_types: &[String],
+    limit: Option<usize>,
+    projection: &str,
+    meter: &cost::CapacityMeter,
+) -> Result<Vec<pagination::Item>, SdkError<QueryError>> {
+    #[cfg(feature = "chaos")]
+    chaos::before_query(imei).await?;
+
+    let mut request = ride_query(client, imei, ride_starts).expression_attribute_names("#source", "source");
+    if !ride_types.is_empty() {
+        let placeholders: Vec<String> = (0..ride_types.len()).map(|i| format!(":type{}", i)).collect();
+        request = request
+            .filter_expression(format!("#ride_type IN ({})", placeholders.join(", ")))
+            .expression_attribute_names("#ride_type", "ride_type");
+        for (placeholder, ride_type) in placeholders.iter().zip(ride_types) {
+            request = request.expression_attribute_values(placeholder, AttributeValue::S(ride_type.clone()));
+        }
+    }
+    let stream = request
+        .projection_expression(projection)
+        .set_limit(limit.map(|limit| limit as i32))
+        .return_consumed_capacity(ReturnConsumedCapacity::Total)
+        .into_paginator()
+        .send();
+    let items = pagination::collect(&mut pagination::QueryPages { stream, meter }, limit).await?;
+
+    #[cfg(feature = "chaos")]
+    let items = {
+        let mut items = items;
+        chaos::corrupt(&mut items);
+        items
+    };
+    Ok(items)
+}
+
+
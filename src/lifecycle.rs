@@ -0,0 +1,168 @@
+//! Lifecycle events for aggregate rows, from the aggregates table's DynamoDB Stream (with
+//! `NEW_AND_OLD_IMAGES`): every row written by any path (aggregation, streams, corrections,
+//! imports, finalization) or removed becomes one EventBridge event on `LIFECYCLE_EVENT_BUS`, with
+//! source `ride-data` and detail-type
+//!
+//! - `aggregate.created`: an `INSERT`;
+//! - `aggregate.finalized`: a `MODIFY` that sets `finalized`;
+//! - `aggregate.updated`: any other `MODIFY`;
+//! - `aggregate.deleted`: a `REMOVE`.
+//!
+//! The detail carries the row's `imei`, `granularity` and `period`, and its `before` and `after`
+//! images as plain JSON (null where there is none), so a consumer can keep its own projection.
+//! Events are sent in record order, ten per `PutEvents`; as with the ride stream, the mapping needs
+//! `ReportBatchItemFailures`, and on the first entry EventBridge rejects the rest of the batch is
+//! reported failed and redelivered.
+
+use aws_sdk_eventbridge::types::PutEventsRequestEntry;
+use lambda_runtime::Error;
+use serde_json::{json, Map, Value};
+use tracing::{error, info, warn};
+
+use crate::{aggregates, config};
+
+pub const SOURCE: &str = "ride-data";
+const ENTRIES_PER_CALL: usize = 10;
+
+pub fn event_bus() -> Option<String> {
+    config::get().lifecycle_event_bus.clone()
+}
+
+/// Whether a stream batch comes from the aggregates table rather than the ride table.
+pub fn is_aggregates_stream(records: &[Value]) -> bool {
+    let table = format!(":table/{}/stream/", aggregates::table_name());
+    records.first()
+        .and_then(|record| record["eventSourceARN"].as_str())
+        .is_some_and(|arn| arn.contains(&table))
+}
+
+/// A DynamoDB JSON attribute value as plain JSON.
+fn plain(value: &Value) -> Value {
+    let Some((kind, value)) = value.as_object().and_then(|value| value.iter().next()) else {
+        return Value::Null;
+    };
+    let number = |n: &Value| n.as_str().and_then(|n| n.parse::<f64>().ok()).map_or_else(|| n.clone(), |n| json!(n));
+    match kind.as_str() {
+        "N" => number(value),
+        "NS" => Value::Array(value.as_array().into_iter().flatten().map(number).collect()),
+        "NULL" => Value::Null,
+        "L" => Value::Array(value.as_array().into_iter().flatten().map(plain).collect()),
+        "M" => image(value),
+        _ => value.clone(),
+    }
+}
+
+fn image(image: &Value) -> Value {
+    match image.as_object() {
+        Some(attributes) => Value::Object(attributes.iter().map(|(name, value)| (name.clone(), plain(value))).collect::<Map<_, _>>()),
+        None => Value::Null,
+    }
+}
+
+/// The lifecycle event of one stream record: its detail-type and detail.
+pub fn event(record: &Value) -> Option<(&'static str, Value)> {
+    let (before, after) = (image(&record["dynamodb"]["OldImage"]), image(&record["dynamodb"]["NewImage"]));
+    let finalized = |image: &Value| image["finalized"] == json!(true);
+    let detail_type = match record["eventName"].as_str()? {
+        "INSERT" => "aggregate.created",
+        "MODIFY" if finalized(&after) && !finalized(&before) => "aggregate.finalized",
+        "MODIFY" => "aggregate.updated",
+        "REMOVE" => "aggregate.deleted",
+        _ => return None,
+    };
+    let keys = &record["dynamodb"]["Keys"];
+    let period = keys["period"]["S"].as_str()?;
+    let (granularity, period) = aggregates::parse_sort_key(period).map_or(("monthly", period), |(granularity, period)| (granularity.as_str(), period));
+    Some((detail_type, json!({
+        "imei": keys["imei"]["S"].as_str()?,
+        "granularity": granularity,
+        "period": period,
+        "before": before,
+        "after": after,
+        "event_id": record["eventID"],
+        "sequence_number": record["dynamodb"]["SequenceNumber"],
+    })))
+}
+
+/// Publishes a batch of aggregates-table stream records, reporting the ones to redeliver.
+pub async fn publish(shared_config: &aws_config::SdkConfig, records: Vec<Value>) -> Result<Value, Error> {
+    let Some(bus) = event_bus() else {
+        warn!("Ignoring {} aggregates stream records: LIFECYCLE_EVENT_BUS is not set", records.len());
+        return Ok(json!({ "batchItemFailures": [] }));
+    };
+    let client = aws_sdk_eventbridge::Client::new(shared_config);
+    let sequence = |record: &Value| record["dynamodb"]["SequenceNumber"].as_str().map(str::to_string);
+
+    let mut failed_from = None;
+    let mut published = 0;
+    for (chunk_index, chunk) in records.chunks(ENTRIES_PER_CALL).enumerate() {
+        let events: Vec<(usize, &'static str, Value)> = chunk.iter().enumerate()
+            .filter_map(|(i, record)| event(record).map(|(detail_type, detail)| (chunk_index * ENTRIES_PER_CALL + i, detail_type, detail)))
+            .collect();
+        if events.is_empty() {
+            continue;
+        }
+        let entries = events.iter().map(|(_, detail_type, detail)| PutEventsRequestEntry::builder()
+            .event_bus_name(&bus)
+            .source(SOURCE)
+            .detail_type(*detail_type)
+            .detail(detail.to_string())
+            .build()).collect();
+        match client.put_events().set_entries(Some(entries)).send().await {
+            Ok(resp) => {
+                let rejected = resp.entries().iter().position(|entry| entry.error_code().is_some());
+                if let Some(position) = rejected {
+                    let entry = &resp.entries()[position];
+                    error!("EventBridge rejected a lifecycle event: {:?} {:?}", entry.error_code(), entry.error_message());
+                    published += position;
+                    failed_from = Some(events[position].0);
+                    break;
+                }
+                published += events.len();
+            }
+            Err(err) => {
+                error!("Error publishing lifecycle events: {}", aws_sdk_eventbridge::error::DisplayErrorContext(&err));
+                failed_from = Some(events[0].0);
+                break;
+            }
+        }
+    }
+
+    let failures: Vec<Value> = failed_from
+        .map(|from| records[from..].iter().filter_map(sequence).map(|sequence| json!({ "itemIdentifier": sequence })).collect())
+        .unwrap_or_default();
+    info!(records = records.len(), published, failed = failures.len(), "Published lifecycle events");
+    Ok(json!({ "batchItemFailures": failures }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(name: &str, old: Value, new: Value) -> Value {
+        json!({
+            "eventID": "1",
+            "eventName": name,
+            "dynamodb": {
+                "Keys": {"imei": {"S": "356938035643809"}, "period": {"S": "period#monthly#2024-06"}},
+                "OldImage": old,
+                "NewImage": new,
+                "SequenceNumber": "100",
+            },
+        })
+    }
+
+    #[test]
+    fn names_events_by_what_changed() {
+        let open = json!({"total_distance": {"N": "12.5"}, "finalized": {"BOOL": false}});
+        let closed = json!({"total_distance": {"N": "12.5"}, "finalized": {"BOOL": true}});
+        let (detail_type, detail) = event(&record("INSERT", Value::Null, open.clone())).unwrap();
+        assert_eq!(detail_type, "aggregate.created");
+        assert_eq!(detail["before"], Value::Null);
+        assert_eq!(detail["after"], json!({"total_distance": 12.5, "finalized": false}));
+        assert_eq!((detail["granularity"].as_str(), detail["period"].as_str()), (Some("monthly"), Some("2024-06")));
+        assert_eq!(event(&record("MODIFY", open.clone(), closed.clone())).unwrap().0, "aggregate.finalized");
+        assert_eq!(event(&record("MODIFY", closed.clone(), closed.clone())).unwrap().0, "aggregate.updated");
+        assert_eq!(event(&record("REMOVE", closed, Value::Null)).unwrap().0, "aggregate.deleted");
+    }
+}
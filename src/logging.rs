@@ -0,0 +1,79 @@
+use schemars::JsonSchema;
+use serde::Deserialize;
+use std::sync::OnceLock;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::{fmt, reload, Registry};
+use utoipa::ToSchema;
+
+static FILTER: OnceLock<reload::Handle<LevelFilter, Registry>> = OnceLock::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, JsonSchema, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum LogLevel {
+    Debug,
+    Info,
+    Warn,
+}
+
+impl From<LogLevel> for LevelFilter {
+    fn from(level: LogLevel) -> LevelFilter {
+        match level {
+            LogLevel::Debug => LevelFilter::DEBUG,
+            LogLevel::Info => LevelFilter::INFO,
+            LogLevel::Warn => LevelFilter::WARN,
+        }
+    }
+}
+
+/// Level from `LOG_LEVEL` (default `info`).
+fn base_level() -> LevelFilter {
+    crate::config::get().log_level
+}
+
+/// Installs the global subscriber with a filter that requests can swap out while they run. Events
+/// are JSON objects carrying the fields of the spans they happened in (`request_id`, `imei`, `month`),
+/// or plain lines with `LOG_FORMAT=text`. In command-line mode they go to stderr, leaving stdout
+/// to the output.
+pub fn init() {
+    let (filter, handle) = reload::Layer::new(base_level());
+    let json = !crate::config::get().log_text;
+    let writer = || -> BoxMakeWriter {
+        match crate::cli::requested() {
+            true => BoxMakeWriter::new(std::io::stderr),
+            false => BoxMakeWriter::new(std::io::stdout),
+        }
+    };
+    let registry = tracing_subscriber::registry()
+        .with(filter)
+        .with(json.then(|| fmt::layer().json().flatten_event(true).with_current_span(false).with_target(false).with_writer(writer())))
+        .with((!json).then(|| fmt::layer().with_ansi(false).with_target(false).with_writer(writer())))
+        .with(crate::sentry::layer());
+    #[cfg(feature = "datadog")]
+    let registry = registry.with(crate::datadog::layer());
+    registry.init();
+    let _ = FILTER.set(handle);
+}
+
+/// Restores the `LOG_LEVEL` filter when dropped.
+pub struct LevelOverride;
+
+impl Drop for LevelOverride {
+    fn drop(&mut self) {
+        set(base_level());
+    }
+}
+
+/// Switches the filter to `level` until the returned guard is dropped. The filter is process-wide,
+/// so in HTTP mode it also applies to requests running at the same time.
+pub fn override_level(level: Option<LogLevel>) -> Option<LevelOverride> {
+    set(level?.into());
+    Some(LevelOverride)
+}
+
+fn set(level: LevelFilter) {
+    if let Some(handle) = FILTER.get() {
+        let _ = handle.modify(|filter| *filter = level);
+    }
+}
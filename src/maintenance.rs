@@ -0,0 +1,156 @@
+//! Usage-based maintenance: a device in the devices table with `last_service_km`, its lifetime
+//! distance (the sum of its stored monthly rows) when it was last serviced, is due again once it has
+//! ridden its `service_interval_km` since (else `SERVICE_INTERVAL_KM`; with neither, never). After
+//! an aggregation run writes a device's monthly rows, its lifetime distance is re-summed and, when
+//! due, a `device.maintenance_due` event sent to `MAINTENANCE_EVENT_BUS` (unset, nothing is checked).
+//!
+//! The device item's `maintenance_due_notified_km` records the `last_service_km` an event was sent
+//! for, set by a conditional write, so each service interval is announced once; recording a service
+//! (a new `last_service_km`) re-arms it.
+
+use anyhow::Result;
+use aws_sdk_dynamodb::operation::update_item::UpdateItemError;
+use aws_sdk_dynamodb::types::AttributeValue;
+use aws_sdk_dynamodb::Client;
+use aws_sdk_eventbridge::types::PutEventsRequestEntry;
+use futures::stream::{self, StreamExt, TryStreamExt};
+use schemars::JsonSchema;
+use serde::Serialize;
+use serde_json::json;
+use std::collections::{BTreeSet, HashMap};
+use tracing::info;
+use utoipa::ToSchema;
+
+use crate::cost::CapacityMeter;
+use crate::{aggregates, batch, cohorts, config, imei_concurrency_from_env, lifecycle, metrics, time, CustomOutput};
+
+pub const DETAIL_TYPE: &str = "device.maintenance_due";
+
+pub fn event_bus() -> Option<String> {
+    config::get().maintenance_event_bus.clone()
+}
+
+pub fn default_interval_km() -> Option<f64> {
+    config::get().service_interval_km
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, JsonSchema, ToSchema)]
+pub struct MaintenanceDue {
+    imei: String,
+    last_service_km: f64,
+    service_interval_km: f64,
+    /// Lifetime distance, summed over the device's stored monthly rows.
+    lifetime_km: f64,
+    since_service_km: f64,
+}
+
+struct Schedule {
+    last_service_km: f64,
+    interval_km: f64,
+    notified_for_km: Option<f64>,
+}
+
+fn schedule(item: &HashMap<String, AttributeValue>, default_interval_km: Option<f64>) -> Option<Schedule> {
+    let number = |name: &str| item.get(name).and_then(|v| v.as_n().ok()).and_then(|n| n.parse::<f64>().ok());
+    Some(Schedule {
+        last_service_km: number("last_service_km")?,
+        interval_km: number("service_interval_km").or(default_interval_km).filter(|km| *km > 0.0)?,
+        notified_for_km: number("maintenance_due_notified_km"),
+    })
+}
+
+/// Records that the event for this service was sent, unless a concurrent run already did.
+async fn mark_notified(client: &Client, imei: &str, last_service_km: f64) -> Result<bool> {
+    let result = client.update_item()
+        .table_name(cohorts::DEVICES_TABLE)
+        .key("imei", AttributeValue::S(imei.to_string()))
+        .update_expression("SET maintenance_due_notified_km = :last")
+        .condition_expression("last_service_km = :last AND (attribute_not_exists(maintenance_due_notified_km) OR maintenance_due_notified_km <> :last)")
+        .expression_attribute_values(":last", AttributeValue::N(last_service_km.to_string()))
+        .send()
+        .await;
+    match result {
+        Ok(_) => Ok(true),
+        Err(err) => match err.as_service_error() {
+            Some(UpdateItemError::ConditionalCheckFailedException(_)) => Ok(false),
+            _ => {
+                metrics::dynamodb_error("update_item");
+                Err(err.into())
+            }
+        },
+    }
+}
+
+/// Checks the devices whose monthly rows the run wrote, sending an event for each newly due.
+pub async fn check(shared_config: &aws_config::SdkConfig, client: &Client, rows: &[CustomOutput]) -> Result<Vec<MaintenanceDue>> {
+    let Some(bus) = event_bus() else {
+        return Ok(Vec::new());
+    };
+    let imeis: BTreeSet<&str> = rows.iter()
+        .filter(|row| row.granularity == time::Granularity::Monthly && row.write_skipped.is_none())
+        .map(|row| row.imei.as_str())
+        .collect();
+    if imeis.is_empty() {
+        return Ok(Vec::new());
+    }
+    let keys = imeis.iter().map(|imei| HashMap::from([("imei".to_string(), AttributeValue::S(imei.to_string()))])).collect();
+    let default_interval = default_interval_km();
+    let schedules: Vec<(String, Schedule)> = batch::get(client, cohorts::DEVICES_TABLE, keys, &CapacityMeter::default()).await?
+        .iter()
+        .filter_map(|item| Some((item.get("imei")?.as_s().ok()?.clone(), schedule(item, default_interval)?)))
+        .filter(|(_, schedule)| schedule.notified_for_km != Some(schedule.last_service_km))
+        .collect();
+
+    let due: Vec<MaintenanceDue> = stream::iter(schedules)
+        .map(|(imei, schedule)| async move {
+            let lifetime_km: f64 = aggregates::query_device(client, &imei).await?.iter()
+                .filter(|row| row.granularity == time::Granularity::Monthly)
+                .map(|row| row.total_distance)
+                .sum();
+            let since_service_km = lifetime_km - schedule.last_service_km;
+            anyhow::Ok((since_service_km >= schedule.interval_km).then_some(MaintenanceDue {
+                imei,
+                last_service_km: schedule.last_service_km,
+                service_interval_km: schedule.interval_km,
+                lifetime_km,
+                since_service_km,
+            }))
+        })
+        .buffered(imei_concurrency_from_env())
+        .try_filter_map(|due| async move { Ok(due) })
+        .try_collect()
+        .await?;
+
+    let events = aws_sdk_eventbridge::Client::new(shared_config);
+    let mut sent = Vec::new();
+    for device in due {
+        if !mark_notified(client, &device.imei, device.last_service_km).await? {
+            continue;
+        }
+        let entry = PutEventsRequestEntry::builder()
+            .event_bus_name(&bus)
+            .source(lifecycle::SOURCE)
+            .detail_type(DETAIL_TYPE)
+            .detail(json!(device).to_string())
+            .build();
+        let resp = events.put_events().entries(entry).send().await;
+        let failure = match &resp {
+            Ok(resp) => resp.entries().first().and_then(|entry| entry.error_code()).map(str::to_string),
+            Err(err) => Some(aws_sdk_eventbridge::error::DisplayErrorContext(err).to_string()),
+        };
+        if let Some(failure) = failure {
+            // Let the next run send it again.
+            client.update_item()
+                .table_name(cohorts::DEVICES_TABLE)
+                .key("imei", AttributeValue::S(device.imei.clone()))
+                .update_expression("REMOVE maintenance_due_notified_km")
+                .send()
+                .await
+                .inspect_err(|_| metrics::dynamodb_error("update_item"))?;
+            anyhow::bail!("error sending the maintenance event for {}: {}", device.imei, failure);
+        }
+        sent.push(device);
+    }
+    info!("Sent {} maintenance due events", sent.len());
+    Ok(sent)
+}
@@ -0,0 +1,149 @@
+//! Manifest-driven batch runs: `input_manifest_s3_uri` names a JSONL (`{"imei": .., "months": [..]}`)
+//! or CSV (`imei,months` with `;`-separated months) list of work items. Items are grouped by month,
+//! aggregated in shards of `MANIFEST_SHARD_SIZE` IMEIs (default 100), and every row produced is
+//! written as JSONL next to the input manifest.
+
+use anyhow::{anyhow, Result};
+use aws_sdk_s3::primitives::ByteStream;
+use lambda_runtime::Error;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use tokio::io::AsyncBufReadExt;
+use tracing::{error, info};
+
+use crate::clock::Clock;
+use crate::cost::CapacityMeter;
+use crate::signatures::{HmacKeys, RideAuthenticator};
+use crate::{aggregate_ride_data, config, kms, retries, s3, CustomEvent, ErrorOutput};
+
+#[derive(Debug, Clone, Deserialize)]
+struct WorkItem {
+    imei: String,
+    /// Empty means every month in the aggregated window.
+    #[serde(default)]
+    months: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct ShardFailure {
+    ride_month: Option<String>,
+    imeis: Vec<String>,
+    error: String,
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct ManifestOutput {
+    work_items: usize,
+    shards: usize,
+    rows: usize,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    failed_shards: Vec<ShardFailure>,
+    results_uri: String,
+}
+
+pub async fn run(shared_config: &aws_config::SdkConfig, payload: &CustomEvent, run_id: &str, clock: &Clock, meter: &CapacityMeter) -> Result<Value, Error> {
+    let uri = payload.input_manifest_s3_uri.as_deref().unwrap_or_default();
+    let Some((bucket, key)) = s3::parse_uri(uri) else {
+        return Ok(json!(ErrorOutput { error: format!("input_manifest_s3_uri {:?} is not an s3://bucket/key URI", uri) }));
+    };
+    let s3_client = aws_sdk_s3::Client::new(shared_config);
+    let items = match read_items(&s3_client, bucket, key, &kms::Encryption::for_request(payload)).await? {
+        Ok(items) => items,
+        Err(err) => return Ok(json!(ErrorOutput { error: err })),
+    };
+
+    let mut by_month: BTreeMap<Option<String>, Vec<String>> = BTreeMap::new();
+    for item in &items {
+        if item.months.is_empty() {
+            by_month.entry(None).or_default().push(item.imei.clone());
+        }
+        for month in &item.months {
+            by_month.entry(Some(month.clone())).or_default().push(item.imei.clone());
+        }
+    }
+
+    let shard_size = config::get().manifest_shard_size;
+    let client = retries::dynamodb(shared_config);
+    let mut results = String::new();
+    let mut shards = 0;
+    let mut rows = 0;
+    let mut failed_shards = Vec::new();
+    for (ride_month, imeis) in by_month {
+        let shard_payload = CustomEvent { input_ride_month: ride_month.clone(), ..payload.clone() };
+        for shard in imeis.chunks(shard_size) {
+            shards += 1;
+            let keys = match payload.verify_signatures {
+                Some(_) => Some(HmacKeys::load(shared_config, shard).await?),
+                None => None,
+            };
+            let authenticator = keys.as_ref().map(|keys| keys as &dyn RideAuthenticator);
+            match aggregate_ride_data(&client, &shard_payload, shard, run_id, clock, authenticator, meter).await {
+                Ok(aggregation) => {
+                    rows += aggregation.rows.len();
+                    for row in &aggregation.rows {
+                        results.push_str(&serde_json::to_string(row)?);
+                        results.push('\n');
+                    }
+                }
+                Err(err) => {
+                    error!("Manifest shard for {:?} failed: {:?}", ride_month, err);
+                    failed_shards.push(ShardFailure { ride_month: ride_month.clone(), imeis: shard.to_vec(), error: err.to_string() });
+                }
+            }
+        }
+    }
+
+    let results_key = format!("{}.{}.results.jsonl", key, run_id);
+    s3_client.put_object()
+        .bucket(bucket)
+        .key(&results_key)
+        .content_type("application/x-ndjson")
+        .body(ByteStream::from(results.into_bytes()))
+        .send()
+        .await?;
+
+    info!("Manifest {} done: {} items, {} shards, {} failed", uri, items.len(), shards, failed_shards.len());
+    Ok(json!(ManifestOutput {
+        work_items: items.len(),
+        shards,
+        rows,
+        failed_shards,
+        results_uri: format!("s3://{}/{}", bucket, results_key),
+    }))
+}
+
+/// Streams the manifest line by line; a malformed line is reported as `Ok(Err(..))`.
+async fn read_items(client: &aws_sdk_s3::Client, bucket: &str, key: &str, encryption: &kms::Encryption) -> Result<Result<Vec<WorkItem>, String>> {
+    let resp = client.get_object().bucket(bucket).key(key).send().await
+        .map_err(|err| anyhow!("reading manifest s3://{}/{}: {}", bucket, key, err))?;
+    if let Err(err) = encryption.check(&format!("s3://{}/{}", bucket, key), resp.server_side_encryption(), resp.metadata()) {
+        return Ok(Err(err));
+    }
+    let mut lines = tokio::io::BufReader::new(resp.body.into_async_read()).lines();
+
+    let mut items = Vec::new();
+    let mut line_number = 0;
+    while let Some(line) = lines.next_line().await? {
+        line_number += 1;
+        let line = line.trim();
+        if line.is_empty() || (line_number == 1 && line.starts_with("imei")) {
+            continue;
+        }
+        let item = if line.starts_with('{') {
+            match serde_json::from_str(line) {
+                Ok(item) => item,
+                Err(err) => return Ok(Err(format!("manifest line {}: {}", line_number, err))),
+            }
+        } else {
+            let (imei, months) = line.split_once(',').unwrap_or((line, ""));
+            WorkItem {
+                imei: imei.trim().to_string(),
+                months: months.split(';').map(str::trim).filter(|m| !m.is_empty()).map(str::to_string).collect(),
+            }
+        };
+        items.push(item);
+    }
+    Ok(Ok(items))
+}
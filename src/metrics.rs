@@ -0,0 +1,139 @@
+use prometheus::{
+    register_histogram_vec, register_int_counter, register_int_counter_vec, Encoder, HistogramVec, IntCounter,
+    IntCounterVec, TextEncoder,
+};
+use chrono::Utc;
+use serde_json::{json, Value};
+use std::sync::LazyLock;
+use std::time::Duration;
+
+use crate::time::Granularity;
+
+pub static HTTP_REQUESTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!("ride_data_http_requests_total", "HTTP requests served", &["path", "status"]).unwrap()
+});
+
+pub static HTTP_LATENCY: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!("ride_data_http_request_duration_seconds", "HTTP request latency", &["path"]).unwrap()
+});
+
+pub static DYNAMODB_ERRORS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!("ride_data_dynamodb_errors_total", "Failed DynamoDB operations", &["operation"]).unwrap()
+});
+
+pub static RIDES_PROCESSED: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!("ride_data_rides_processed_total", "Ride items read from ride_data").unwrap()
+});
+
+pub static WARNINGS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!("ride_data_warnings_total", "Warnings raised while aggregating", &["code"]).unwrap()
+});
+
+pub static NORMALIZED_VALUES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!("ride_data_normalized_values_total", "Ride attribute values trimmed, case-folded or rewritten as plain decimals", &["attribute"]).unwrap()
+});
+
+pub static SKIPPED_YEAR_RIDES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!("ride_data_skipped_year_rides_total", "Rides found outside the ride window, by year", &["year"]).unwrap()
+});
+
+pub static SHADOW_RUNS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!("ride_data_shadow_runs_total", "Requests mirrored to the shadow function, by outcome", &["outcome"]).unwrap()
+});
+
+pub static SHADOW_DIVERGED_ROWS: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!("ride_data_shadow_diverged_rows_total", "Result rows that differed, or were missing on one side, in shadow runs").unwrap()
+});
+
+pub fn dynamodb_error(operation: &str) {
+    DYNAMODB_ERRORS.with_label_values(&[operation]).inc();
+}
+
+/// One aggregation invocation's figures, for [`emit_invocation`].
+pub struct Invocation {
+    pub imeis: usize,
+    pub granularity: Granularity,
+    pub items_read: usize,
+    pub rides_aggregated: u64,
+    pub ride_queries: usize,
+    /// Summed over the ride queries.
+    pub query_latency: Duration,
+    /// Rows DynamoDB left unprocessed after the retries.
+    pub write_failures: usize,
+}
+
+/// Prints the invocation in CloudWatch embedded metric format under the `RideData` namespace, by
+/// granularity and IMEI-count band (`1`, `2-10`, `11-100`, `101-1000`, `1001+`) so the dimension
+/// stays low-cardinality. `ItemsSkipped` are ride items read but not counted; `DynamoDBLatency` is
+/// the mean ride query latency.
+pub fn emit_invocation(invocation: &Invocation) {
+    let band = match invocation.imeis {
+        0..=1 => "1",
+        2..=10 => "2-10",
+        11..=100 => "11-100",
+        101..=1000 => "101-1000",
+        _ => "1001+",
+    };
+    let latency_ms = match invocation.ride_queries {
+        0 => 0.0,
+        queries => invocation.query_latency.as_secs_f64() * 1000.0 / queries as f64,
+    };
+    emf(json!({
+        "_aws": {
+            "Timestamp": Utc::now().timestamp_millis(),
+            "CloudWatchMetrics": [{
+                "Namespace": "RideData",
+                "Dimensions": [["ImeiCount", "Granularity"]],
+                "Metrics": [
+                    { "Name": "ItemsRead", "Unit": "Count" },
+                    { "Name": "ItemsSkipped", "Unit": "Count" },
+                    { "Name": "RidesAggregated", "Unit": "Count" },
+                    { "Name": "DynamoDBLatency", "Unit": "Milliseconds" },
+                    { "Name": "WriteFailures", "Unit": "Count" },
+                ],
+            }],
+        },
+        "ImeiCount": band,
+        "Granularity": invocation.granularity.as_str(),
+        "ItemsRead": invocation.items_read,
+        "ItemsSkipped": (invocation.items_read as u64).saturating_sub(invocation.rides_aggregated),
+        "RidesAggregated": invocation.rides_aggregated,
+        "DynamoDBLatency": latency_ms,
+        "WriteFailures": invocation.write_failures,
+    }));
+}
+
+/// Prints an embedded-metric line to stdout for the Lambda log agent; not in command-line mode,
+/// where stdout is the output.
+pub fn emf(line: Value) {
+    if !crate::cli::requested() {
+        println!("{}", line);
+    }
+}
+
+/// Everything registered so far in the Prometheus text exposition format.
+pub fn render() -> String {
+    let mut buffer = Vec::new();
+    TextEncoder::new().encode(&prometheus::gather(), &mut buffer).unwrap_or_default();
+    String::from_utf8(buffer).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_counters_and_histograms_in_the_text_format() {
+        dynamodb_error("render_test");
+        HTTP_REQUESTS.with_label_values(&["/render_test", "200"]).inc();
+        HTTP_LATENCY.with_label_values(&["/render_test"]).observe(0.2);
+
+        let text = render();
+        assert!(text.contains("# TYPE ride_data_dynamodb_errors_total counter"));
+        assert!(text.contains(r#"ride_data_dynamodb_errors_total{operation="render_test"} 1"#));
+        assert!(text.contains(r#"ride_data_http_requests_total{path="/render_test",status="200"} 1"#));
+        assert!(text.contains(r#"ride_data_http_request_duration_seconds_bucket{path="/render_test",le="0.1"} 0"#));
+        assert!(text.contains(r#"ride_data_http_request_duration_seconds_bucket{path="/render_test",le="0.25"} 1"#));
+        assert!(text.contains(r#"ride_data_http_request_duration_seconds_count{path="/render_test"} 1"#));
+    }
+}
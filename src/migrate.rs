@@ -0,0 +1,52 @@
+//! `action: "migrate_legacy_rows"`: copies the baseline table's rows (`LEGACY_AGGREGATES_TABLE`,
+//! keyed by `imei` and `date`) into the aggregates table as monthly rows keyed by `period`. A row the
+//! aggregates table already has is kept, as it was written since, so the copy can be re-run after a
+//! timeout until it reports nothing copied. Until then monthly reads fall back to the baseline table.
+
+use lambda_runtime::Error;
+use schemars::JsonSchema;
+use serde::Serialize;
+use serde_json::{json, Value};
+use tracing::{info, warn};
+
+use crate::cost::CapacityMeter;
+use crate::{aggregates, config, metrics, retries, ErrorOutput};
+
+#[derive(Debug, Clone, Default, Serialize, JsonSchema)]
+pub struct MigrateOutput {
+    legacy_table: String,
+    scanned: usize,
+    copied: usize,
+    /// Rows the aggregates table already had.
+    kept: usize,
+    /// Baseline items without a `date`, left where they are.
+    unreadable: usize,
+}
+
+pub async fn migrate_legacy_rows(shared_config: &aws_config::SdkConfig) -> Result<Value, Error> {
+    let Some(legacy_table) = &config::get().legacy_aggregates_table else {
+        return Ok(json!(ErrorOutput { error: "LEGACY_AGGREGATES_TABLE must name the baseline table to migrate".to_string() }));
+    };
+    let client = retries::dynamodb(shared_config);
+    let meter = CapacityMeter::default();
+    let mut output = MigrateOutput { legacy_table: legacy_table.clone(), ..Default::default() };
+    let mut pages = client.scan().table_name(legacy_table).into_paginator().send();
+    while let Some(page) = pages.next().await {
+        let page = page.inspect_err(|_| metrics::dynamodb_error("scan"))?;
+        for item in page.items.unwrap_or_default() {
+            output.scanned += 1;
+            let Some(item) = aggregates::from_legacy(item) else {
+                warn!("Baseline item without a date in {}", legacy_table);
+                output.unreadable += 1;
+                continue;
+            };
+            if aggregates::insert_legacy(&client, item, &meter).await? {
+                output.copied += 1;
+            } else {
+                output.kept += 1;
+            }
+        }
+    }
+    info!(scanned = output.scanned, copied = output.copied, kept = output.kept, "Migrated baseline rows from {}", legacy_table);
+    Ok(json!(output))
+}
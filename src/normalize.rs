@@ -0,0 +1,174 @@
+//! Cleans up string attributes that writers store inconsistently (`Trip`, `TRIP `) before rides are
+//! classified, so they compare equal to the canonical values.
+//!
+//! Numeric `ride_stats` strings that do not parse, like the `12,5` of some legacy rows, are
+//! rewritten as plain decimals: grouping (spaces, `'`, the separator that is not the decimal one)
+//! is dropped and Arabic-Indic, Devanagari and full-width digits become ASCII. `DECIMAL_SEPARATOR`
+//! says which of `.` and `,` is the decimal one: `dot`, `comma`, or `auto` (the default), where
+//! the last of the two in a value is, or a lone one is. A lone one followed by exactly three digits
+//! (`1,234`) could be either, so `auto` leaves that value to be counted as malformed. Values that
+//! still do not parse are left.
+
+use aws_sdk_dynamodb::types::AttributeValue;
+use std::collections::BTreeMap;
+
+use crate::metrics;
+use crate::pagination::Item;
+
+/// Ride attributes that are trimmed, and whether they are also case-folded.
+const ATTRIBUTES: [(&str, bool); 6] = [
+    ("ride_type", true),
+    ("source", true),
+    ("deleted", true),
+    ("tombstone", true),
+    ("firmware_version", false),
+    ("distance_unit", true),
+];
+
+/// `ride_stats` entries stored as numeric strings.
+const NUMERIC_STATS: [&str; 4] = ["ride_distance", "ride_duration", "max_speed", "energy_wh"];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DecimalSeparator {
+    Auto,
+    Comma,
+    Dot,
+}
+
+impl DecimalSeparator {
+    pub fn from_env() -> DecimalSeparator {
+        crate::config::get().decimal_separator
+    }
+}
+
+/// Normalizes the items in place, returning how many values of each attribute were changed.
+/// `ride_stats` entries are counted as `ride_stats.<entry>`.
+pub fn normalize(items: &mut [Item]) -> BTreeMap<String, u64> {
+    let separator = DecimalSeparator::from_env();
+    let mut changed: BTreeMap<String, u64> = BTreeMap::new();
+    for item in items.iter_mut() {
+        if let Some(AttributeValue::M(stats)) = item.get_mut("ride_stats") {
+            for name in NUMERIC_STATS {
+                let Some(AttributeValue::S(value)) = stats.get_mut(name) else {
+                    continue;
+                };
+                if let Some(fixed) = fix_numeral(value, separator) {
+                    *value = fixed;
+                    *changed.entry(format!("ride_stats.{}", name)).or_default() += 1;
+                }
+            }
+        }
+        for (name, fold) in ATTRIBUTES {
+            let Some(AttributeValue::S(value)) = item.get_mut(name) else {
+                continue;
+            };
+            let trimmed = value.trim();
+            let normalized = if fold { trimmed.to_lowercase() } else { trimmed.to_string() };
+            if normalized != *value {
+                *value = normalized;
+                *changed.entry(name.to_string()).or_default() += 1;
+            }
+        }
+    }
+    for (name, count) in &changed {
+        metrics::NORMALIZED_VALUES.with_label_values(&[name]).inc_by(*count);
+    }
+    changed
+}
+
+/// `value` as a plain decimal (`12,5` as `12.5`), or None when it parses already or cannot be fixed.
+fn fix_numeral(value: &str, separator: DecimalSeparator) -> Option<String> {
+    if value.parse::<f64>().is_ok() {
+        return None;
+    }
+    // `.` and `,` are left to be told apart below; the Arabic separators are unambiguous.
+    let mut chars = Vec::new();
+    for c in value.trim().chars() {
+        match c {
+            ' ' | '\'' | '\u{00A0}' | '\u{2009}' | '\u{202F}' | '\u{066C}' => {}
+            '\u{066B}' => chars.push('D'),
+            '.' | ',' | '-' | '+' => chars.push(c),
+            _ => chars.push(ascii_digit(c)?),
+        }
+    }
+    let decimal = if chars.contains(&'D') {
+        Some('D')
+    } else {
+        match separator {
+            DecimalSeparator::Comma => Some(','),
+            DecimalSeparator::Dot => Some('.'),
+            DecimalSeparator::Auto => {
+                let separators: Vec<usize> = (0..chars.len()).filter(|&i| matches!(chars[i], '.' | ',')).collect();
+                if let [only] = separators[..] {
+                    if chars[only + 1..].len() == 3 && chars[only + 1..].iter().all(char::is_ascii_digit) {
+                        return None;
+                    }
+                }
+                chars.iter().rev().find(|c| matches!(c, '.' | ',')).copied().filter(|last| {
+                    let other = if *last == '.' { ',' } else { '.' };
+                    chars.contains(&other) || chars.iter().filter(|c| *c == last).count() == 1
+                })
+            }
+        }
+    };
+    let fixed: String = chars.into_iter()
+        .filter_map(|c| match c {
+            c if Some(c) == decimal => Some('.'),
+            '.' | ',' | 'D' => None,
+            c => Some(c),
+        })
+        .collect();
+    fixed.parse::<f64>().is_ok().then_some(fixed)
+}
+
+fn ascii_digit(c: char) -> Option<char> {
+    let zero = match c {
+        '0'..='9' => '0',
+        '\u{0660}'..='\u{0669}' => '\u{0660}',
+        '\u{06F0}'..='\u{06F9}' => '\u{06F0}',
+        '\u{0966}'..='\u{096F}' => '\u{0966}',
+        '\u{FF10}'..='\u{FF19}' => '\u{FF10}',
+        _ => return None,
+    };
+    char::from_digit(c as u32 - zero as u32, 10)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fixes_decimal_commas_and_grouping() {
+        let auto = DecimalSeparator::Auto;
+        assert_eq!(fix_numeral("12,5", auto).as_deref(), Some("12.5"));
+        assert_eq!(fix_numeral("1.234,5", auto).as_deref(), Some("1234.5"));
+        assert_eq!(fix_numeral("1,234.5", auto).as_deref(), Some("1234.5"));
+        assert_eq!(fix_numeral("1,234,567", auto).as_deref(), Some("1234567"));
+        assert_eq!(fix_numeral("1 234,5", auto).as_deref(), Some("1234.5"));
+        assert_eq!(fix_numeral("1 234", auto).as_deref(), Some("1234"));
+        assert_eq!(fix_numeral("12.5", auto), None);
+        assert_eq!(fix_numeral("twelve", auto), None);
+    }
+
+    #[test]
+    fn leaves_a_lone_separator_before_three_digits_unless_configured() {
+        assert_eq!(fix_numeral("1,234", DecimalSeparator::Auto), None);
+        assert_eq!(fix_numeral("-1,234", DecimalSeparator::Auto), None);
+        assert_eq!(fix_numeral("1,2345", DecimalSeparator::Auto).as_deref(), Some("1.2345"));
+        assert_eq!(fix_numeral("1,234", DecimalSeparator::Comma).as_deref(), Some("1.234"));
+        assert_eq!(fix_numeral("1,234", DecimalSeparator::Dot).as_deref(), Some("1234"));
+    }
+
+    #[test]
+    fn honours_the_configured_separator() {
+        assert_eq!(fix_numeral("1,5", DecimalSeparator::Dot).as_deref(), Some("15"));
+        assert_eq!(fix_numeral("1.234,5", DecimalSeparator::Comma).as_deref(), Some("1234.5"));
+        assert_eq!(fix_numeral("1,234.5", DecimalSeparator::Comma).as_deref(), Some("1.2345"));
+    }
+
+    #[test]
+    fn reads_other_digits() {
+        assert_eq!(fix_numeral("\u{0661}\u{0662}\u{066B}\u{0665}", DecimalSeparator::Auto).as_deref(), Some("12.5"));
+        assert_eq!(fix_numeral("\u{FF11}\u{FF12},5", DecimalSeparator::Auto).as_deref(), Some("12.5"));
+    }
+}
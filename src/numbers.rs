@@ -0,0 +1,80 @@
+//! `number_format`: how distances, durations, speeds and energy are written in responses and
+//! exports. `float` (the default) writes them as the numbers computed; `string_fixed2` as strings
+//! with exactly two decimals, rounded half away from zero (`"12.30"`), for consumers that must not
+//! parse floats. Counts stay integers. Parquet exports then type those columns as UTF8.
+
+use rust_decimal::prelude::FromPrimitive;
+use rust_decimal::{Decimal, RoundingStrategy};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use utoipa::ToSchema;
+
+/// Keys whose numeric values are formatted, wherever they appear.
+pub const FIELDS: [&str; 7] = ["total_distance", "distance", "distance_delta", "total_duration", "average_speed", "max_speed", "total_energy"];
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize, JsonSchema, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum NumberFormat {
+    #[default]
+    Float,
+    StringFixed2,
+}
+
+impl NumberFormat {
+    /// `value` as this format writes it in text (CSV).
+    pub fn text(self, value: f64) -> String {
+        match self {
+            NumberFormat::Float => value.to_string(),
+            NumberFormat::StringFixed2 => fixed2(value),
+        }
+    }
+
+    /// Rewrites the [`FIELDS`] numbers throughout `value`.
+    pub fn apply(self, value: &mut Value) {
+        if self == NumberFormat::Float {
+            return;
+        }
+        match value {
+            Value::Object(object) => {
+                for (key, value) in object.iter_mut() {
+                    match value.as_f64() {
+                        Some(number) if FIELDS.contains(&key.as_str()) => *value = Value::String(fixed2(number)),
+                        _ => self.apply(value),
+                    }
+                }
+            }
+            Value::Array(values) => values.iter_mut().for_each(|value| self.apply(value)),
+            _ => {}
+        }
+    }
+}
+
+/// Two decimals, rounded half away from zero on the decimal value (so 2.675 is `"2.68"`).
+pub fn fixed2(value: f64) -> String {
+    match Decimal::from_f64(value) {
+        Some(decimal) => format!("{:.2}", decimal.round_dp_with_strategy(2, RoundingStrategy::MidpointAwayFromZero)),
+        None => value.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn formats_fixed2() {
+        assert_eq!(fixed2(12.3), "12.30");
+        assert_eq!(fixed2(2.675), "2.68");
+        assert_eq!(fixed2(-0.005), "-0.01");
+        assert_eq!(fixed2(7.0), "7.00");
+    }
+
+    #[test]
+    fn rewrites_only_the_decimal_fields() {
+        let mut value = json!({"results": [{"total_distance": 1.5, "ride_count": 3, "breakdowns": {"trip": {"distance": 0.125, "rides": 1}}}]});
+        NumberFormat::StringFixed2.apply(&mut value);
+        assert_eq!(value, json!({"results": [{"total_distance": "1.50", "ride_count": 3, "breakdowns": {"trip": {"distance": "0.13", "rides": 1}}}]}));
+    }
+}
@@ -0,0 +1,126 @@
+//! Draining multi-page DynamoDB queries. Results come through the [`Pages`] trait so aggregation can
+//! be checked against fake multi-page responses.
+
+use aws_sdk_dynamodb::config::http::HttpResponse;
+use aws_sdk_dynamodb::error::SdkError;
+use aws_sdk_dynamodb::operation::query::{QueryError, QueryOutput};
+use aws_sdk_dynamodb::types::AttributeValue;
+use aws_smithy_async::future::pagination_stream::PaginationStream;
+use std::collections::HashMap;
+
+use crate::cost::CapacityMeter;
+
+pub type Item = HashMap<String, AttributeValue>;
+
+/// A source of result pages, exhausted when it returns `None`.
+pub trait Pages {
+    type Error;
+
+    async fn next_page(&mut self) -> Option<Result<Vec<Item>, Self::Error>>;
+}
+
+/// Pages of a `Query` paginator, metering each page's consumed capacity.
+pub struct QueryPages<'a> {
+    pub stream: PaginationStream<Result<QueryOutput, SdkError<QueryError, HttpResponse>>>,
+    pub meter: &'a CapacityMeter,
+}
+
+impl Pages for QueryPages<'_> {
+    type Error = SdkError<QueryError>;
+
+    async fn next_page(&mut self) -> Option<Result<Vec<Item>, Self::Error>> {
+        let page = self.stream.next().await?;
+        Some(page.map(|page| {
+            self.meter.read(page.consumed_capacity());
+            page.items.unwrap_or_default()
+        }))
+    }
+}
+
+/// Every item of every page, stopping once `limit` items have been read.
+pub async fn collect<P: Pages>(pages: &mut P, limit: Option<usize>) -> Result<Vec<Item>, P::Error> {
+    let mut items = Vec::new();
+    while let Some(page) = pages.next_page().await {
+        items.extend(page?);
+        if let Some(limit) = limit.filter(|limit| items.len() >= *limit) {
+            items.truncate(limit);
+            break;
+        }
+    }
+    Ok(items)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{TimeZone, Utc};
+    use std::collections::VecDeque;
+
+    use crate::ride::RideTypes;
+    use crate::{aggregate_rides, time, StatsQuery};
+
+    struct FakePages(VecDeque<Vec<Item>>);
+
+    impl Pages for FakePages {
+        type Error = String;
+
+        async fn next_page(&mut self) -> Option<Result<Vec<Item>, String>> {
+            self.0.pop_front().map(Ok)
+        }
+    }
+
+    fn trip(ride_start: i64, distance: f64) -> Item {
+        HashMap::from([
+            ("imei".to_string(), AttributeValue::S("123".to_string())),
+            ("ride_start".to_string(), AttributeValue::N(ride_start.to_string())),
+            ("ride_type".to_string(), AttributeValue::S("trip".to_string())),
+            ("ride_stats".to_string(), AttributeValue::M(HashMap::from([
+                ("ride_distance".to_string(), AttributeValue::S(distance.to_string())),
+            ]))),
+        ])
+    }
+
+    /// Three pages of two rides each, an hour apart from 2024-03-01 12:00 UTC.
+    fn pages() -> FakePages {
+        let start = Utc.with_ymd_and_hms(2024, 3, 1, 12, 0, 0).unwrap().timestamp();
+        let rides: Vec<Item> = (0..6).map(|i| trip(start + i * 3600, 1.5)).collect();
+        FakePages(rides.chunks(2).map(<[Item]>::to_vec).collect())
+    }
+
+    #[tokio::test]
+    async fn collect_reads_every_page() {
+        let items = collect(&mut pages(), None).await.unwrap();
+        assert_eq!(items.len(), 6);
+    }
+
+    #[tokio::test]
+    async fn collect_stops_at_limit_across_pages() {
+        let mut pages = pages();
+        let items = collect(&mut pages, Some(3)).await.unwrap();
+        assert_eq!(items.len(), 3);
+        assert_eq!(pages.0.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn aggregate_rides_totals_rides_from_later_pages() {
+        let items = collect(&mut pages(), None).await.unwrap();
+        let query = StatsQuery {
+            input_ride_month: None,
+            date_range: None,
+            granularity: time::Granularity::Monthly,
+            breakdowns: &[],
+            max_rides: None,
+            now: Utc.with_ymd_and_hms(2024, 4, 1, 0, 0, 0).unwrap(),
+            signatures: None,
+            fraud_checks: false,
+            legacy_compat: None,
+            ride_types: &RideTypes::default(),
+            type_filter: &[],
+            metrics: &[],
+        };
+        let (months, ..) = aggregate_rides("123", &items, &query);
+        let march = &months["2024-03"];
+        assert_eq!(march.explain.rides_included, 6);
+        assert!((march.distance - 9.0).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,55 @@
+//! Hot-partition hints: IMEIs whose ride queries are throttled or unusually slow.
+//! Each one is also logged as a CloudWatch embedded-metric line so it can be alarmed on.
+
+use aws_sdk_dynamodb::error::{ProvideErrorMetadata, SdkError};
+use aws_sdk_dynamodb::operation::query::QueryError;
+use chrono::Utc;
+use schemars::JsonSchema;
+use serde::Serialize;
+use serde_json::json;
+use std::time::Duration;
+use utoipa::ToSchema;
+
+use crate::{config, metrics};
+
+#[derive(Debug, Clone, Serialize, JsonSchema, ToSchema)]
+pub struct HotPartition {
+    pub imei: String,
+    pub latency_ms: u64,
+    /// The query was still throttled after the SDK's retries, so the IMEI was not aggregated.
+    pub throttled: bool,
+}
+
+/// Query latency above which an IMEI is reported as hot, from `HOT_PARTITION_LATENCY_MS` (default 2000).
+pub fn latency_threshold_from_env() -> Duration {
+    config::get().hot_partition_latency
+}
+
+pub fn is_throttling(err: &SdkError<QueryError>) -> bool {
+    match err.as_service_error() {
+        Some(QueryError::ProvisionedThroughputExceededException(_)) | Some(QueryError::RequestLimitExceeded(_)) => true,
+        Some(other) => other.code() == Some("ThrottlingException"),
+        None => false,
+    }
+}
+
+/// Prints the partition in CloudWatch embedded metric format under the `RideData` namespace.
+pub fn emit_metric(hot: &HotPartition) {
+    let line = json!({
+        "_aws": {
+            "Timestamp": Utc::now().timestamp_millis(),
+            "CloudWatchMetrics": [{
+                "Namespace": "RideData",
+                "Dimensions": [["imei"]],
+                "Metrics": [
+                    { "Name": "HotPartitionLatency", "Unit": "Milliseconds" },
+                    { "Name": "HotPartitionThrottles", "Unit": "Count" },
+                ],
+            }],
+        },
+        "imei": hot.imei,
+        "HotPartitionLatency": hot.latency_ms,
+        "HotPartitionThrottles": u8::from(hot.throttled),
+    });
+    metrics::emf(line);
+}
@@ -0,0 +1,183 @@
+//! How an aggregation reads rides: a query per IMEI, a query per day through the
+//! [`date_index`], or a parallel [`scan`] of the ride table. The last two read every device's
+//! rides of the month, so they only apply to a monthly `input_ride_month` request without
+//! `max_rides_per_imei`, `max_total_items` or `legacy_compat`; then each strategy is costed in read
+//! capacity units and the cheapest picked. `QUERY_STRATEGY` (`auto`, `per_imei`, `date_index` or
+//! `scan`) forces one where it applies. The plan is logged and reported as `diagnostics.query_plan`.
+//!
+//! Costs follow from `DescribeTable`, read once per process (DynamoDB refreshes its counts about
+//! every six hours): a device's month of rides is the ride table's average item size times its
+//! items per device (from the `devices` item count) per month kept (`RIDE_RETENTION_MONTHS` and
+//! the current one; 12 when unset). A query or scan page costs 0.5 RCU per 4 KB, at least 0.5.
+
+use aws_sdk_dynamodb::types::IndexStatus;
+use aws_sdk_dynamodb::Client;
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::sync::Mutex;
+use tracing::{info, warn};
+use utoipa::ToSchema;
+
+use crate::cost::CapacityMeter;
+use crate::store::MonthRides;
+use crate::{cohorts, config, date_index, metrics, retention, scan, time, CustomEvent};
+
+/// RCU of an eventually consistent read of 4 KB, and the least a query page costs.
+const RCU_PER_4KB: f64 = 0.5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, JsonSchema, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Strategy {
+    PerImei,
+    DateIndex,
+    Scan,
+}
+
+impl Strategy {
+    fn from_env() -> Option<Strategy> {
+        crate::config::get().query_strategy
+    }
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema, ToSchema)]
+pub struct Estimate {
+    pub strategy: Strategy,
+    pub read_capacity_units: f64,
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema, ToSchema)]
+pub struct QueryPlan {
+    pub strategy: Strategy,
+    /// Why the strategy was picked without costing the others, or why the picked one was abandoned.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    /// Estimated cost of each strategy that applies.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub estimates: Vec<Estimate>,
+}
+
+impl QueryPlan {
+    pub fn per_imei(reason: &str) -> QueryPlan {
+        QueryPlan { strategy: Strategy::PerImei, reason: Some(reason.to_string()), estimates: Vec::new() }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct TableStats {
+    ride_items: i64,
+    ride_bytes: i64,
+    date_index: bool,
+    devices: i64,
+}
+
+static STATS: Mutex<Option<TableStats>> = Mutex::new(None);
+
+/// The plan for reading the rides of `identities` IMEIs.
+pub async fn plan(client: &Client, payload: &CustomEvent, identities: usize) -> QueryPlan {
+    let forced = Strategy::from_env();
+    let Some(month) = payload.input_ride_month.as_deref() else {
+        return QueryPlan::per_imei("no input_ride_month");
+    };
+    if payload.granularity.is_some_and(|granularity| granularity != time::Granularity::Monthly) {
+        return QueryPlan::per_imei("not monthly");
+    }
+    if payload.max_rides_per_imei.is_some() || payload.max_total_items.is_some() {
+        return QueryPlan::per_imei("rides read are capped");
+    }
+    if payload.legacy_compat.is_some() {
+        return QueryPlan::per_imei("legacy_compat reads past the month");
+    }
+    if forced == Some(Strategy::PerImei) {
+        return QueryPlan::per_imei("QUERY_STRATEGY=per_imei");
+    }
+    let Ok((from, to)) = time::month_range(month) else {
+        return QueryPlan::per_imei("invalid input_ride_month");
+    };
+    let Some(stats) = stats(client).await.filter(|stats| stats.ride_items > 0) else {
+        return QueryPlan::per_imei("no ride table statistics");
+    };
+
+    let months_kept = retention::retention_months().map_or(12, |months| months + 1) as f64;
+    let devices = stats.devices.max(identities as i64) as f64;
+    let device_month_bytes = stats.ride_bytes as f64 / devices / months_kept;
+    let device_month_rcu = device_month_bytes / 4096.0 * RCU_PER_4KB;
+    let days = date_index::utc_days(from, to).len() as f64;
+    let mut estimates = vec![Estimate { strategy: Strategy::PerImei, read_capacity_units: identities as f64 * device_month_rcu.max(RCU_PER_4KB) }];
+    if stats.date_index {
+        let read_capacity_units = (days * RCU_PER_4KB).max(devices * device_month_rcu);
+        estimates.push(Estimate { strategy: Strategy::DateIndex, read_capacity_units });
+    }
+    estimates.push(Estimate { strategy: Strategy::Scan, read_capacity_units: stats.ride_bytes as f64 / 4096.0 * RCU_PER_4KB });
+
+    let (strategy, reason) = match forced.filter(|forced| estimates.iter().any(|estimate| estimate.strategy == *forced)) {
+        Some(forced) => (forced, Some("QUERY_STRATEGY".to_string())),
+        None => {
+            let cheapest = estimates.iter().min_by(|a, b| a.read_capacity_units.total_cmp(&b.read_capacity_units));
+            (cheapest.map_or(Strategy::PerImei, |estimate| estimate.strategy), None)
+        }
+    };
+    QueryPlan { strategy, reason, estimates }
+}
+
+/// Reads the month's rides up front if the plan reads them for every device, falling back to
+/// per-IMEI queries (and saying so in the plan) if that fails.
+pub async fn read<'a>(
+    client: &'a Client,
+    payload: &CustomEvent,
+    plan: &mut QueryPlan,
+    identities: &[String],
+    projection: &str,
+    meter: &CapacityMeter,
+) -> Option<MonthRides<'a>> {
+    info!(?plan, "Query plan");
+    let ride_starts = time::month_range(payload.input_ride_month.as_deref()?).ok()?;
+    let ride_types = payload.ride_type_filter();
+    let read = match plan.strategy {
+        Strategy::PerImei => return None,
+        Strategy::DateIndex => date_index::read(client, ride_starts, &ride_types, projection, meter).await.map_err(anyhow::Error::from),
+        Strategy::Scan => scan::read(client, ride_starts, &ride_types, projection, meter).await.map_err(anyhow::Error::from),
+    };
+    match read {
+        Ok(items) => Some(MonthRides::new(client, items, identities)),
+        Err(err) => {
+            metrics::dynamodb_error(if plan.strategy == Strategy::Scan { "scan" } else { "query" });
+            warn!("Error reading the month's rides ({:?}), querying per IMEI: {:?}", plan.strategy, err);
+            plan.reason = Some(format!("reading with {:?} failed", plan.strategy));
+            plan.strategy = Strategy::PerImei;
+            None
+        }
+    }
+}
+
+/// The ride table's size and index, and how many devices there are; None if the table cannot be described.
+async fn stats(client: &Client) -> Option<TableStats> {
+    if let Some(stats) = *STATS.lock().unwrap() {
+        return Some(stats);
+    }
+    let ride_table = match client.describe_table().table_name(&config::get().ride_table).send().await {
+        Ok(resp) => resp.table?,
+        Err(err) => {
+            metrics::dynamodb_error("describe_table");
+            warn!("Error describing the ride table: {}", aws_sdk_dynamodb::error::DisplayErrorContext(&err));
+            return None;
+        }
+    };
+    let devices = match client.describe_table().table_name(cohorts::DEVICES_TABLE).send().await {
+        Ok(resp) => resp.table().and_then(|table| table.item_count()).unwrap_or_default(),
+        Err(err) => {
+            metrics::dynamodb_error("describe_table");
+            warn!("Error describing {}: {}", cohorts::DEVICES_TABLE, aws_sdk_dynamodb::error::DisplayErrorContext(&err));
+            0
+        }
+    };
+    let stats = TableStats {
+        ride_items: ride_table.item_count().unwrap_or_default(),
+        ride_bytes: ride_table.table_size_bytes().unwrap_or_default(),
+        date_index: ride_table.global_secondary_indexes().iter().any(|index| {
+            index.index_name() == Some(date_index::INDEX_NAME) && index.index_status() == Some(&IndexStatus::Active)
+        }),
+        devices,
+    };
+    *STATS.lock().unwrap() = Some(stats);
+    Some(stats)
+}
@@ -0,0 +1,87 @@
+//! `preflight: true`: counts the rides a request would read with `Select=COUNT` queries and projects
+//! its capacity, duration and cost, without aggregating or writing anything.
+
+use aws_sdk_dynamodb::types::{ReturnConsumedCapacity, Select};
+use aws_sdk_dynamodb::Client;
+use lambda_runtime::Error;
+use schemars::JsonSchema;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::time::Duration;
+
+use crate::cost::{CapacityMeter, RunCost};
+use crate::{aliases, config, imei_concurrency_from_env, metrics, ride_query, ride_starts, CustomEvent};
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct PreflightImei {
+    imei: String,
+    /// Ride items its queries would read, after `max_rides_per_imei`.
+    rides: u64,
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct PreflightOutput {
+    imeis: Vec<PreflightImei>,
+    total_rides: u64,
+    /// IMEIs that `max_rides_per_imei` or `max_total_items` would cut off.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    truncated_imeis: Vec<String>,
+    /// At `PREFLIGHT_RIDES_PER_SECOND` per query (default 2000), `IMEI_CONCURRENCY` queries at a time.
+    estimated_seconds: f64,
+    /// The counting queries read what the real queries would, so their capacity is the projection.
+    estimated_cost: RunCost,
+}
+
+pub async fn preflight(client: &Client, payload: &CustomEvent, imeis: &[String]) -> Result<Value, Error> {
+    let ride_starts = ride_starts(payload.input_ride_month.as_deref(), payload.date_range(), payload.legacy_compat.is_some())?;
+    let meter = CapacityMeter::default();
+
+    let mut output = Vec::with_capacity(imeis.len());
+    let mut truncated_imeis = Vec::new();
+    let mut total_rides = 0;
+    let mut budget = payload.max_total_items.map(|max| max as u64);
+    for device in aliases::resolve(client, imeis).await? {
+        let mut rides = 0;
+        for identity in &device.identities {
+            let counted = count(client, identity, ride_starts, &meter).await?;
+            rides += payload.max_rides_per_imei.map_or(counted, |max| counted.min(max as u64));
+            if payload.max_rides_per_imei.is_some_and(|max| counted > max as u64) && !truncated_imeis.contains(&device.imei) {
+                truncated_imeis.push(device.imei.clone());
+            }
+        }
+        if let Some(remaining) = budget.as_mut() {
+            if rides > *remaining && !truncated_imeis.contains(&device.imei) {
+                truncated_imeis.push(device.imei.clone());
+            }
+            rides = rides.min(*remaining);
+            *remaining -= rides;
+        }
+        total_rides += rides;
+        output.push(PreflightImei { imei: device.imei, rides });
+    }
+
+    let rides_per_second = config::get().preflight_rides_per_second;
+    let estimated_seconds = total_rides as f64 / rides_per_second / imei_concurrency_from_env() as f64;
+    Ok(json!(PreflightOutput {
+        imeis: output,
+        total_rides,
+        truncated_imeis,
+        estimated_seconds,
+        estimated_cost: meter.run_cost(Duration::from_secs_f64(estimated_seconds)),
+    }))
+}
+
+async fn count(client: &Client, imei: &str, ride_starts: Option<(i64, i64)>, meter: &CapacityMeter) -> Result<u64, Error> {
+    let mut pages = ride_query(client, imei, ride_starts)
+        .select(Select::Count)
+        .return_consumed_capacity(ReturnConsumedCapacity::Total)
+        .into_paginator()
+        .send();
+    let mut rides = 0;
+    while let Some(page) = pages.next().await {
+        let page = page.inspect_err(|_| metrics::dynamodb_error("query"))?;
+        meter.read(page.consumed_capacity());
+        rides += page.count() as u64;
+    }
+    Ok(rides)
+}
@@ -0,0 +1,135 @@
+//! Run priorities for queued work. A fan-out request's `priority` is `interactive` (the default)
+//! or `backfill`; backfill shards are queued on `FANOUT_BACKFILL_QUEUE_URL` when it is set, else
+//! alongside interactive ones on `FANOUT_QUEUE_URL`, and every shard carries a `priority` message
+//! attribute. Backfill continuations (`backfill_job`) are backfill work too.
+//!
+//! Only work on a queue of its own yields: with backfill shards on the interactive queue, priority
+//! just labels them.
+//!
+//! A worker handed backfill work first checks `FANOUT_QUEUE_URL`: while interactive shards are
+//! waiting there, it re-queues the backfill work `FANOUT_BACKFILL_DEFER_SECS` later (default 60)
+//! and returns, leaving the concurrency to the interactive shards. Each message is deferred at most
+//! `FANOUT_MAX_DEFERRALS` times (default 5), so a steady interactive load delays a backfill but
+//! never starves it. A cap on the backfill queue's event source mapping (`MaximumConcurrency`)
+//! bounds what backfills take the rest of the time.
+
+use anyhow::Result;
+use aws_sdk_sqs::types::{MessageAttributeValue, QueueAttributeName};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tracing::{info, warn};
+use utoipa::ToSchema;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize, JsonSchema, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Priority {
+    #[default]
+    Interactive,
+    Backfill,
+}
+
+impl Priority {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Priority::Interactive => "interactive",
+            Priority::Backfill => "backfill",
+        }
+    }
+
+    /// The queue this priority's fan-out shards go on.
+    pub fn queue_url(self) -> Option<String> {
+        match self {
+            Priority::Backfill => backfill_queue_url().or_else(interactive_queue_url),
+            Priority::Interactive => interactive_queue_url(),
+        }
+    }
+
+    pub fn attribute(self) -> Result<MessageAttributeValue> {
+        Ok(MessageAttributeValue::builder().data_type("String").string_value(self.as_str()).build()?)
+    }
+}
+
+fn interactive_queue_url() -> Option<String> {
+    crate::config::get().fanout_queue_url.clone()
+}
+
+fn backfill_queue_url() -> Option<String> {
+    crate::config::get().fanout_backfill_queue_url.clone()
+}
+
+pub fn defer_secs() -> i32 {
+    crate::config::get().fanout_backfill_defer_secs
+}
+
+pub fn max_deferrals() -> u32 {
+    crate::config::get().fanout_max_deferrals
+}
+
+/// Where a queued request would be re-queued if it is backfill work.
+fn backfill_queue(request: &Value) -> Option<String> {
+    if request.get("backfill_job").is_some_and(|job| !job.is_null()) {
+        return crate::config::get().backfill_queue_url.clone().filter(|url| Some(url) != interactive_queue_url().as_ref());
+    }
+    let shard = request.get("fan_out_job").is_some_and(|job| !job.is_null());
+    let priority: Priority = request.get("priority").and_then(|priority| serde_json::from_value(priority.clone()).ok()).unwrap_or_default();
+    (shard && priority == Priority::Backfill).then(backfill_queue_url).flatten()
+}
+
+/// Whether interactive shards are waiting on `FANOUT_QUEUE_URL`; errors count as not.
+async fn interactive_waiting(sqs: &aws_sdk_sqs::Client) -> bool {
+    let Some(url) = interactive_queue_url() else {
+        return false;
+    };
+    match sqs.get_queue_attributes().queue_url(url).attribute_names(QueueAttributeName::ApproximateNumberOfMessages).send().await {
+        Ok(resp) => resp.attributes()
+            .and_then(|attributes| attributes.get(&QueueAttributeName::ApproximateNumberOfMessages))
+            .and_then(|n| n.parse::<u64>().ok())
+            .is_some_and(|waiting| waiting > 0),
+        Err(err) => {
+            warn!("Error reading the interactive queue depth: {}", aws_sdk_sqs::error::DisplayErrorContext(&err));
+            false
+        }
+    }
+}
+
+/// Checks the interactive queue once per batch of queued requests.
+#[derive(Default)]
+pub struct Scheduler {
+    interactive_waiting: Option<bool>,
+}
+
+impl Scheduler {
+    /// Re-queues `request` for later if it is backfill work and interactive work is waiting,
+    /// returning the response to record for it; None means run it now.
+    pub async fn defer(&mut self, shared_config: &aws_config::SdkConfig, request: &Value) -> Result<Option<Value>> {
+        let Some(queue_url) = backfill_queue(request) else {
+            return Ok(None);
+        };
+        let deferrals = request.get("deferrals").and_then(Value::as_u64).unwrap_or(0) as u32;
+        if deferrals >= max_deferrals() {
+            return Ok(None);
+        }
+        let sqs = aws_sdk_sqs::Client::new(shared_config);
+        let waiting = match self.interactive_waiting {
+            Some(waiting) => waiting,
+            None => *self.interactive_waiting.insert(interactive_waiting(&sqs).await),
+        };
+        if !waiting {
+            return Ok(None);
+        }
+        let mut deferred = request.clone();
+        if let Some(fields) = deferred.as_object_mut() {
+            fields.insert("deferrals".to_string(), json!(deferrals + 1));
+        }
+        sqs.send_message()
+            .queue_url(&queue_url)
+            .message_body(deferred.to_string())
+            .delay_seconds(defer_secs())
+            .message_attributes("priority", Priority::Backfill.attribute()?)
+            .send()
+            .await?;
+        info!("Deferred backfill work {} s behind waiting interactive shards ({} of {} deferrals)", defer_secs(), deferrals + 1, max_deferrals());
+        Ok(Some(json!({ "deferred": true, "deferrals": deferrals + 1 })))
+    }
+}
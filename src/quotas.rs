@@ -0,0 +1,88 @@
+//! Account quotas, read once at startup rather than assumed: DynamoDB `DescribeLimits` and Lambda
+//! `GetAccountSettings`. They cap settings whose defaults suit a production account but break in
+//! small sandbox accounts (whose Lambda concurrency can be as low as 10):
+//!
+//! - fan-out queues at most half the unreserved Lambda concurrency in shards, making them larger
+//!   than `FANOUT_SHARD_SIZE` when needed, so the workers leave room for everything else;
+//! - `IMEI_CONCURRENCY` is capped so the ride queries in flight, at one 1 MB page (128 RCU) each,
+//!   stay within the table read capacity quota.
+//!
+//! A quota that cannot be read (e.g. for lack of `dynamodb:DescribeLimits` or
+//! `lambda:GetAccountSettings`) caps nothing; `SERVICE_QUOTAS=false` skips reading them.
+
+use serde::Serialize;
+use std::sync::OnceLock;
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// RCU of one eventually consistent 1 MB query page.
+const RCU_PER_PAGE: i64 = 128;
+
+static QUOTAS: OnceLock<Quotas> = OnceLock::new();
+
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct Quotas {
+    pub lambda_concurrency: Option<i32>,
+    pub lambda_unreserved_concurrency: Option<i32>,
+    pub table_max_read_capacity_units: Option<i64>,
+    pub account_max_read_capacity_units: Option<i64>,
+}
+
+/// Reads the quotas, within a couple of seconds, for [`get`].
+pub async fn load(shared_config: &aws_config::SdkConfig) {
+    if !crate::config::get().service_quotas {
+        return;
+    }
+    let dynamodb = async {
+        match aws_sdk_dynamodb::Client::new(shared_config).describe_limits().send().await {
+            Ok(limits) => (limits.table_max_read_capacity_units(), limits.account_max_read_capacity_units()),
+            Err(err) => {
+                warn!("Error reading DynamoDB limits: {:?}", err);
+                (None, None)
+            }
+        }
+    };
+    let lambda = async {
+        match aws_sdk_lambda::Client::new(shared_config).get_account_settings().send().await {
+            Ok(settings) => settings.account_limit().map(|limit| (Some(limit.concurrent_executions()), limit.unreserved_concurrent_executions())).unwrap_or_default(),
+            Err(err) => {
+                warn!("Error reading Lambda account settings: {:?}", err);
+                (None, None)
+            }
+        }
+    };
+    let Ok(((table_max, account_max), (concurrency, unreserved))) = tokio::time::timeout(Duration::from_secs(2), futures::future::join(dynamodb, lambda)).await else {
+        warn!("Timed out reading service quotas");
+        return;
+    };
+    let quotas = Quotas {
+        lambda_concurrency: concurrency,
+        lambda_unreserved_concurrency: unreserved,
+        table_max_read_capacity_units: table_max,
+        account_max_read_capacity_units: account_max,
+    };
+    info!(?quotas, "Read service quotas");
+    let _ = QUOTAS.set(quotas);
+}
+
+/// The quotas [`load`] read; all unknown before then or if it could not.
+pub fn get() -> Quotas {
+    QUOTAS.get().copied().unwrap_or_default()
+}
+
+/// Shards a fan-out may queue at most.
+pub fn max_fan_out_shards() -> Option<usize> {
+    let quotas = get();
+    let concurrency = quotas.lambda_unreserved_concurrency.or(quotas.lambda_concurrency)?;
+    Some((concurrency / 2).max(1) as usize)
+}
+
+/// Ride queries that may be in flight at once.
+pub fn max_query_concurrency() -> Option<usize> {
+    let quotas = get();
+    let capacity = match (quotas.table_max_read_capacity_units, quotas.account_max_read_capacity_units) {
+        (Some(table), Some(account)) => table.min(account),
+        (table, account) => table.or(account)?,
+    };
+    Some((capacity / RCU_PER_PAGE).max(1) as usize)
+}
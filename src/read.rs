@@ -0,0 +1,72 @@
+//! `action: "read"`: the rows already stored for `imeis`, in the shape aggregations return them, so
+//! callers need not know the aggregates table's schema. The period is `input_ride_month` or the
+//! months (for daily and weekly rows, the days) of `start_date`..`end_date`, read with one key
+//! condition query per IMEI. Only what rows store is filled in: no breakdowns, fraud flags, or
+//! `explain` counts besides `voided_rides`.
+
+use futures::stream::{self, StreamExt, TryStreamExt};
+use lambda_runtime::Error;
+use schemars::JsonSchema;
+use serde::Serialize;
+use serde_json::{json, Value};
+use tracing::info;
+
+use crate::time::Granularity;
+use crate::{aggregates, imei_concurrency_from_env, retries, units, CustomEvent, CustomOutput, ErrorOutput};
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct ReadOutput {
+    /// Ordered by IMEI, then period.
+    results: Vec<CustomOutput>,
+    /// Requested IMEIs with no stored rows in the period.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    imeis_without_rows: Vec<String>,
+    output_unit: units::OutputUnit,
+}
+
+pub async fn read(shared_config: &aws_config::SdkConfig, payload: &CustomEvent) -> Result<Value, Error> {
+    if payload.imeis.is_empty() {
+        return Ok(json!(ErrorOutput { error: "IMEI cannot be empty".to_string() }));
+    }
+    if let Err(err) = payload.check_date_range() {
+        return Ok(json!(ErrorOutput { error: err }));
+    }
+    let granularity = payload.granularity.unwrap_or_default();
+    let (from, to) = match (granularity, &payload.input_ride_month, payload.date_range()) {
+        (Granularity::Range, _, _) => return Ok(json!(ErrorOutput { error: "range totals are never stored, so cannot be read".to_string() })),
+        (Granularity::Monthly, Some(month), _) => (month.clone(), month.clone()),
+        (_, Some(month), _) => (format!("{}-01", month), format!("{}-31", month)),
+        (Granularity::Monthly, None, Some(range)) => (range.start.format("%Y-%m").to_string(), range.end.format("%Y-%m").to_string()),
+        (_, None, Some(range)) => (range.start.to_string(), range.end.to_string()),
+        (_, None, None) => return Ok(json!(ErrorOutput { error: "input_ride_month or start_date / end_date is required to read rows".to_string() })),
+    };
+
+    let client = retries::dynamodb(shared_config);
+    let (client, from, to) = (&client, &from, &to);
+    let per_imei: Vec<(String, Vec<CustomOutput>)> = stream::iter(payload.imeis.clone())
+        .map(|imei| async move {
+            let rows = aggregates::query_outputs(client, &imei, granularity, from, to).await?;
+            Ok::<_, anyhow::Error>((imei, rows))
+        })
+        .buffered(imei_concurrency_from_env())
+        .try_collect()
+        .await?;
+
+    let output_unit = payload.output_unit.unwrap_or_default();
+    let mut results = Vec::new();
+    let mut imeis_without_rows = Vec::new();
+    for (imei, rows) in per_imei {
+        if rows.is_empty() {
+            imeis_without_rows.push(imei);
+        }
+        results.extend(rows);
+    }
+    results.sort_by(|a, b| (&a.imei, &a.ride_month).cmp(&(&b.imei, &b.ride_month)));
+    for row in &mut results {
+        units::convert(row, output_unit);
+    }
+    info!("Read {} stored rows for {} imeis, {}..{}", results.len(), payload.imeis.len(), from, to);
+    let mut output = json!(ReadOutput { results, imeis_without_rows, output_unit });
+    payload.number_format.unwrap_or_default().apply(&mut output);
+    Ok(output)
+}
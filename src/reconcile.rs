@@ -0,0 +1,122 @@
+use aws_sdk_s3::primitives::ByteStream;
+use chrono::Utc;
+use lambda_runtime::Error;
+use schemars::JsonSchema;
+use serde::Serialize;
+use serde_json::{json, Value};
+use tracing::info;
+
+use crate::cost::CapacityMeter;
+use crate::ride::RideTypes;
+use crate::stats::Metric;
+use crate::time::Granularity;
+use crate::{aggregates, billing, config, monthly_stats, retries, CustomEvent, ErrorOutput, StatsQuery};
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct Discrepancy {
+    imei: String,
+    raw_distance: f64,
+    stored_distance: Option<f64>,
+    billed_distance: Option<f64>,
+    issue: String,
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct ReconcileOutput {
+    ride_month: String,
+    checked: usize,
+    discrepancies: Vec<Discrepancy>,
+    report_uri: String,
+}
+
+/// `action: "reconcile"`: re-sums raw rides for the requested (or a sampled set of) IMEIs and compares
+/// them with the stored aggregates and the billed amounts, writing the full report to `REPORT_BUCKET`.
+pub async fn reconcile(shared_config: &aws_config::SdkConfig, payload: &CustomEvent, run_id: &str) -> Result<Value, Error> {
+    let Some(ride_month) = payload.input_ride_month.clone() else {
+        return Ok(json!(ErrorOutput { error: "input_ride_month is required to reconcile".to_string() }));
+    };
+    let Some(bucket) = config::get().report_bucket.clone() else {
+        return Ok(json!(ErrorOutput { error: "REPORT_BUCKET is not set".to_string() }));
+    };
+    let tolerance = config::get().reconcile_tolerance_km;
+    let client = retries::dynamodb(shared_config);
+
+    let imeis: Vec<String> = if payload.imeis.is_empty() {
+        let mut stored = aggregates::query_imeis_for_month(&client, &ride_month).await?;
+        fastrand::shuffle(&mut stored);
+        stored.truncate(payload.sample_size.unwrap_or(50));
+        stored
+    } else {
+        payload.imeis.clone()
+    };
+
+    let mut csv = String::from("imei,ride_month,raw_distance,stored_distance,billed_distance,issue\n");
+    let mut discrepancies = Vec::new();
+    let query = StatsQuery {
+        input_ride_month: Some(&ride_month),
+        date_range: None,
+        granularity: Granularity::Monthly,
+        breakdowns: &[],
+        max_rides: None,
+        now: Utc::now(),
+        signatures: None,
+        fraud_checks: false,
+        legacy_compat: None,
+        ride_types: &RideTypes::default(),
+        type_filter: &[],
+        metrics: &[Metric::Distance],
+    };
+    for imei in &imeis {
+        let raw = monthly_stats(&client, imei, &query, &CapacityMeter::default()).await?;
+        if let Some(failure) = raw.failure {
+            return Err(format!("Error querying rides of imei {}: {}", imei, failure.message).into());
+        }
+        let raw_distance = raw.months.get(&ride_month).map(|stats| stats.distance).unwrap_or(0.0);
+        let stored_distance = aggregates::get_distance(&client, imei, Granularity::Monthly, &ride_month, &CapacityMeter::default()).await?;
+        let billed_distance = billing::billed_distance(&client, imei, &ride_month).await?;
+
+        let mut issues = Vec::new();
+        match stored_distance {
+            Some(stored) if (stored - raw_distance).abs() > tolerance => issues.push("stored_mismatch"),
+            None if raw_distance > 0.0 => issues.push("stored_missing"),
+            _ => {}
+        }
+        match billed_distance {
+            Some(billed) if (billed - raw_distance).abs() > tolerance => issues.push("billed_mismatch"),
+            None if raw_distance > 0.0 => issues.push("billed_missing"),
+            _ => {}
+        }
+        let issue = if issues.is_empty() { "ok".to_string() } else { issues.join("|") };
+
+        csv.push_str(&format!(
+            "{},{},{},{},{},{}\n",
+            imei,
+            ride_month,
+            raw_distance,
+            stored_distance.map(|d| d.to_string()).unwrap_or_default(),
+            billed_distance.map(|d| d.to_string()).unwrap_or_default(),
+            issue,
+        ));
+        if !issues.is_empty() {
+            discrepancies.push(Discrepancy { imei: imei.clone(), raw_distance, stored_distance, billed_distance, issue });
+        }
+    }
+
+    let key = format!("reconciliation/{}/{}.csv", ride_month, run_id);
+    aws_sdk_s3::Client::new(shared_config)
+        .put_object()
+        .bucket(&bucket)
+        .key(&key)
+        .content_type("text/csv")
+        .body(ByteStream::from(csv.into_bytes()))
+        .send()
+        .await?;
+
+    info!("Reconciled {} imeis for {}: {} discrepancies", imeis.len(), ride_month, discrepancies.len());
+    Ok(json!(ReconcileOutput {
+        ride_month,
+        checked: imeis.len(),
+        discrepancies,
+        report_uri: format!("s3://{}/{}", bucket, key),
+    }))
+}
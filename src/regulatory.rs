@@ -0,0 +1,116 @@
+//! `action: "regulatory_report"`: the transport authority's monthly distance return for
+//! `input_ride_month`, one CSV line per registration state and vehicle class, assembled from the
+//! stored monthly rows and each device's `registration_state` / `vehicle_class` in the devices
+//! table. Written to `REPORT_BUCKET` under `regulatory/<month>/`; devices without metadata are
+//! reported under `UNKNOWN`.
+
+use aws_sdk_dynamodb::types::AttributeValue;
+use chrono::SecondsFormat;
+use lambda_runtime::Error;
+use schemars::JsonSchema;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+use tracing::{info, warn};
+
+use crate::clock::Clock;
+use crate::cost::CapacityMeter;
+use crate::{aggregates, batch, cohorts, config, export, kms, retries, s3, CustomEvent, ErrorOutput};
+
+/// The authority's fixed column layout; distances in km to two decimals.
+const COLUMNS: &str = "report_month,state,vehicle_class,vehicles,total_distance_km";
+const UNKNOWN: &str = "UNKNOWN";
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct RegulatoryReportOutput {
+    ride_month: String,
+    report_uri: String,
+    /// Presigned GET URL of the report.
+    report_url: String,
+    generated_at: String,
+    /// Report lines, one per state and vehicle class.
+    lines: usize,
+    vehicles: usize,
+    total_distance: f64,
+    /// Vehicles reported under `UNKNOWN` for lack of device metadata.
+    unknown_vehicles: usize,
+    /// The month was still in progress for some rows, so their distances are partial.
+    month_to_date: bool,
+}
+
+#[derive(Default)]
+struct Line {
+    vehicles: usize,
+    distance: f64,
+}
+
+pub async fn regulatory_report(shared_config: &aws_config::SdkConfig, payload: &CustomEvent, run_id: &str, clock: &Clock) -> Result<Value, Error> {
+    let Some(ride_month) = payload.input_ride_month.clone() else {
+        return Ok(json!(ErrorOutput { error: "input_ride_month is required for a regulatory report".to_string() }));
+    };
+    let Some(bucket) = config::get().report_bucket.clone() else {
+        return Ok(json!(ErrorOutput { error: "REPORT_BUCKET is not set".to_string() }));
+    };
+
+    let client = retries::dynamodb(shared_config);
+    let rows = aggregates::query_month(&client, &ride_month).await?;
+    let keys = rows.iter()
+        .map(|row| HashMap::from([("imei".to_string(), AttributeValue::S(row.imei.clone()))]))
+        .collect();
+    let devices: HashMap<String, (String, String)> = batch::get(&client, cohorts::DEVICES_TABLE, keys, &CapacityMeter::default()).await?
+        .iter()
+        .filter_map(|item| {
+            let text = |name: &str| item.get(name).and_then(|v| v.as_s().ok()).map(|v| v.trim().to_uppercase()).filter(|v| !v.is_empty());
+            let imei = item.get("imei")?.as_s().ok()?.clone();
+            Some((imei, (text("registration_state").unwrap_or_else(|| UNKNOWN.to_string()), text("vehicle_class").unwrap_or_else(|| UNKNOWN.to_string()))))
+        })
+        .collect();
+
+    let mut lines: BTreeMap<(String, String), Line> = BTreeMap::new();
+    let mut unknown_vehicles = 0;
+    for row in &rows {
+        let (state, class) = devices.get(&row.imei).cloned().unwrap_or_else(|| (UNKNOWN.to_string(), UNKNOWN.to_string()));
+        if state == UNKNOWN {
+            unknown_vehicles += 1;
+        }
+        let line = lines.entry((state, class)).or_default();
+        line.vehicles += 1;
+        line.distance += row.total_distance;
+    }
+    if unknown_vehicles > 0 {
+        warn!("{} vehicles have no registration_state for the {} regulatory report", unknown_vehicles, ride_month);
+    }
+
+    let mut csv = format!("{}\n", COLUMNS);
+    for ((state, class), line) in &lines {
+        csv.push_str(&format!("{},{},{},{},{:.2}\n", ride_month, field(state), field(class), line.vehicles, line.distance));
+    }
+    let generated_at = clock.now().to_rfc3339_opts(SecondsFormat::Secs, true);
+    let key = format!("regulatory/{}/distance-by-state-{}.csv", ride_month, run_id);
+    let s3_client = aws_sdk_s3::Client::new(shared_config);
+    export::put_once(&s3_client, &bucket, &key, "text/csv", csv.into_bytes(), &kms::Encryption::for_request(payload)).await?;
+
+    let report_uri = format!("s3://{}/{}", bucket, key);
+    let report_url = s3::presign(&s3_client, &report_uri, s3::presign_expiry(payload.presign_expiry_secs)).await?;
+
+    info!("Wrote regulatory report for {} with {} lines", ride_month, lines.len());
+    Ok(json!(RegulatoryReportOutput {
+        ride_month,
+        report_uri,
+        report_url,
+        generated_at,
+        lines: lines.len(),
+        vehicles: rows.len(),
+        total_distance: rows.iter().map(|row| row.total_distance).sum(),
+        unknown_vehicles,
+        month_to_date: rows.iter().any(|row| row.month_to_date),
+    }))
+}
+
+/// Device metadata is free text; a value with a comma or quote is quoted.
+fn field(value: &str) -> String {
+    match value.contains([',', '"']) {
+        true => format!("\"{}\"", value.replace('"', "\"\"")),
+        false => value.to_string(),
+    }
+}
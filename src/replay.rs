@@ -0,0 +1,60 @@
+//! `action: "replay"`: re-executes the requests recorded in `aggregation_runs` under `replay_run_id`,
+//! pinned to the time they originally ran. With `dry_run` nothing is written or sent.
+
+use lambda_runtime::Error;
+use schemars::JsonSchema;
+use serde::Serialize;
+use serde_json::{json, Value};
+use tracing::{info, warn};
+
+use crate::{audit, config, handle_request, retries, CustomEvent, ErrorOutput};
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct ReplayOutput {
+    replayed_run_id: String,
+    dry_run: bool,
+    /// Config keys whose current value differs from the recorded run's; the replay uses the current ones.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    config_drift: Vec<String>,
+    /// One response per recorded request, in the order they originally started.
+    responses: Vec<Value>,
+}
+
+pub async fn replay(shared_config: &aws_config::SdkConfig, payload: &CustomEvent, run_id: &str) -> Result<Value, Error> {
+    let Some(replayed_run_id) = &payload.replay_run_id else {
+        return Ok(json!(ErrorOutput { error: "replay needs replay_run_id".to_string() }));
+    };
+    let dry_run = payload.dry_run.unwrap_or(false);
+    let recorded = audit::load(&retries::dynamodb(shared_config), replayed_run_id).await?;
+    if recorded.is_empty() {
+        return Ok(json!(ErrorOutput { error: format!("no recorded run {:?} in {}", replayed_run_id, audit::TABLE_NAME) }));
+    }
+
+    let current = config::echo();
+    let mut config_drift = Vec::new();
+    let mut responses = Vec::with_capacity(recorded.len());
+    for run in recorded {
+        if let (Some(Value::Object(then)), Value::Object(now)) = (&run.config, &current) {
+            for (key, value) in then {
+                if now.get(key) != Some(value) && !config_drift.contains(key) {
+                    warn!("Config {} changed since run {}: was {}, now {:?}", key, replayed_run_id, value, now.get(key));
+                    config_drift.push(key.clone());
+                }
+            }
+        }
+
+        let mut request = run.request;
+        if let Value::Object(fields) = &mut request {
+            fields.entry("fixed_now").or_insert_with(|| Value::String(run.started_at.clone()));
+            // The replay's own opt-in governs prod writes, not the one recorded with the original request.
+            fields.insert("allow_prod_write".to_string(), json!(payload.allow_prod_write));
+            if dry_run {
+                fields.insert("dry_run".to_string(), Value::Bool(true));
+            }
+        }
+        info!("Replaying request of run {} started at {}", replayed_run_id, run.started_at);
+        responses.push(Box::pin(handle_request(shared_config, request, run_id)).await?);
+    }
+
+    Ok(json!(ReplayOutput { replayed_run_id: replayed_run_id.clone(), dry_run, config_drift, responses }))
+}
@@ -0,0 +1,9 @@
+use crate::CustomOutput;
+
+pub fn to_csv(rows: &[CustomOutput]) -> String {
+    let mut csv = String::from("imei,ride_month,total_distance\n");
+    for row in rows {
+        csv.push_str(&format!("{},{},{}\n", row.imei, row.ride_month, row.total_distance));
+    }
+    csv
+}
@@ -0,0 +1,399 @@
+//! `action: "enforce_retention"`: deletes the raw rides of `input_ride_month` once the month is
+//! older than the `RIDE_RETENTION_MONTHS` months kept before the current one, every monthly row
+//! stored for it is finalized, and an `export` snapshot of it is in `REPORT_BUCKET`. Each device's
+//! rides are deleted in `BatchWriteItem` chunks; with `dry_run` they are only counted. The monthly
+//! rows are kept, and only devices with a row for the month are covered.
+//!
+//! Before anything is deleted, every device's rides are archived to `ARCHIVE_BUCKET` in the
+//! `GLACIER_IR` storage class, as gzipped JSONL of the items in DynamoDB JSON (as stream images
+//! are), under `retention/<month>/<run_id>/`, with a `manifest.json` listing each part and its
+//! SHA-256. A failure before the manifest is written deletes nothing.
+
+use aws_sdk_dynamodb::types::AttributeValue;
+use aws_sdk_s3::primitives::ByteStream;
+use aws_sdk_s3::types::StorageClass;
+use base64::Engine;
+use chrono::{Months, SecondsFormat};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use lambda_runtime::Error;
+use schemars::JsonSchema;
+use serde::Serialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::io::Write;
+use tracing::{info, warn};
+
+use crate::clock::Clock;
+use crate::config::{self, Config};
+use crate::cost::CapacityMeter;
+use crate::pagination::Item;
+use crate::{aggregates, batch, is_dry_run, retries, ride_query, time, CustomEvent, ErrorOutput};
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct RetentionOutput {
+    ride_month: String,
+    dry_run: bool,
+    /// The snapshot whose existence allowed the deletion.
+    export_manifest_uri: String,
+    /// Where the deleted rides were archived; absent with `dry_run`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    archive_manifest_uri: Option<String>,
+    devices: usize,
+    /// Ride items deleted, or that would be with `dry_run`.
+    rides_deleted: usize,
+    /// Per device, the ride items deleted.
+    deleted_by_imei: BTreeMap<String, usize>,
+    /// Ride items DynamoDB still left unprocessed after the retries; running again deletes them.
+    rides_remaining: usize,
+}
+
+/// Months before the current one whose rides are kept, from `RIDE_RETENTION_MONTHS`.
+pub fn retention_months() -> Option<u32> {
+    config::get().ride_retention_months
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct ArchivePart {
+    imei: String,
+    key: String,
+    rides: usize,
+    bytes: usize,
+    sha256: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct ArchiveManifest {
+    ride_month: String,
+    run_id: String,
+    archived_at: String,
+    rides: usize,
+    parts: Vec<ArchivePart>,
+}
+
+#[derive(Debug, Clone)]
+struct Settings {
+    retention_months: Option<u32>,
+    report_bucket: Option<String>,
+    archive_bucket: Option<String>,
+}
+
+impl Settings {
+    fn from_config(config: &Config) -> Settings {
+        Settings {
+            retention_months: config.ride_retention_months,
+            report_bucket: config.report_bucket.clone(),
+            archive_bucket: config.archive_bucket.clone(),
+        }
+    }
+}
+
+pub async fn enforce_retention(shared_config: &aws_config::SdkConfig, payload: &CustomEvent, run_id: &str, clock: &Clock) -> Result<Value, Error> {
+    enforce(shared_config, payload, run_id, clock, &Settings::from_config(config::get())).await
+}
+
+async fn enforce(shared_config: &aws_config::SdkConfig, payload: &CustomEvent, run_id: &str, clock: &Clock, settings: &Settings) -> Result<Value, Error> {
+    let Some(ride_month) = payload.input_ride_month.clone() else {
+        return Ok(json!(ErrorOutput { error: "input_ride_month is required to enforce retention".to_string() }));
+    };
+    let Some(months) = settings.retention_months else {
+        return Ok(json!(ErrorOutput { error: "RIDE_RETENTION_MONTHS is not set".to_string() }));
+    };
+    let Some(bucket) = settings.report_bucket.clone() else {
+        return Ok(json!(ErrorOutput { error: "REPORT_BUCKET is not set".to_string() }));
+    };
+    let Some(archive_bucket) = settings.archive_bucket.clone() else {
+        return Ok(json!(ErrorOutput { error: "ARCHIVE_BUCKET is not set".to_string() }));
+    };
+    let ride_starts = match time::month_range(&ride_month) {
+        Ok(range) => range,
+        Err(err) => return Ok(json!(ErrorOutput { error: err.to_string() })),
+    };
+    let current = time::month_of(clock.now())?;
+    let oldest_kept = time::month_start(&current, 0)?
+        .checked_sub_months(Months::new(months))
+        .map(|start| start.format("%Y-%m").to_string())
+        .ok_or(time::TimeError::InvalidMonth(current))?;
+    if ride_month >= oldest_kept {
+        return Ok(json!(ErrorOutput {
+            error: format!("{} is within the {}-month retention period; the oldest month kept is {}", ride_month, months, oldest_kept),
+        }));
+    }
+
+    let client = retries::dynamodb(shared_config);
+    let rows = aggregates::query_month(&client, &ride_month).await?;
+    if rows.is_empty() {
+        return Ok(json!(ErrorOutput { error: format!("no monthly rows are stored for {}", ride_month) }));
+    }
+    let open = rows.iter().filter(|row| !row.finalized).count();
+    if open > 0 {
+        return Ok(json!(ErrorOutput { error: format!("{} of {} rows for {} are not finalized", open, rows.len(), ride_month) }));
+    }
+    let s3 = aws_sdk_s3::Client::new(shared_config);
+    let Some(manifest_key) = export_manifest(&s3, &bucket, &ride_month).await? else {
+        return Ok(json!(ErrorOutput { error: format!("no export of {} found in {}", ride_month, bucket) }));
+    };
+
+    let dry_run = is_dry_run(payload);
+    let meter = CapacityMeter::default();
+    let prefix = format!("retention/{}/{}", ride_month, run_id);
+    let mut keys_by_imei: Vec<(String, Vec<Item>)> = Vec::with_capacity(rows.len());
+    let mut parts = Vec::new();
+    for row in &rows {
+        let items: Vec<Item> = ride_query(&client, &row.imei, Some(ride_starts))
+            .into_paginator()
+            .items()
+            .send()
+            .collect::<Result<Vec<_>, _>>()
+            .await?;
+        if !dry_run && !items.is_empty() {
+            let body = archive_body(&items)?;
+            let part = ArchivePart {
+                imei: row.imei.clone(),
+                key: format!("{}/{}.jsonl.gz", prefix, row.imei),
+                rides: items.len(),
+                bytes: body.len(),
+                sha256: hex::encode(Sha256::digest(&body)),
+            };
+            put_archive(&s3, &archive_bucket, &part.key, "application/gzip", body).await?;
+            parts.push(part);
+        }
+        let keys = items.into_iter()
+            .map(|item| item.into_iter().filter(|(name, _)| name == "imei" || name == "ride_start").collect())
+            .collect();
+        keys_by_imei.push((row.imei.clone(), keys));
+    }
+    let archive_manifest_uri = match dry_run {
+        true => None,
+        false => {
+            let manifest = ArchiveManifest {
+                ride_month: ride_month.clone(),
+                run_id: run_id.to_string(),
+                archived_at: clock.now().to_rfc3339_opts(SecondsFormat::Secs, true),
+                rides: parts.iter().map(|part| part.rides).sum(),
+                parts,
+            };
+            let key = format!("{}/manifest.json", prefix);
+            put_archive(&s3, &archive_bucket, &key, "application/json", serde_json::to_vec_pretty(&manifest)?).await?;
+            info!("Archived {} rides for {} in {} parts", manifest.rides, ride_month, manifest.parts.len());
+            Some(format!("s3://{}/{}", archive_bucket, key))
+        }
+    };
+
+    let mut deleted_by_imei = BTreeMap::new();
+    let mut remaining = 0;
+    for (imei, keys) in keys_by_imei {
+        let found = keys.len();
+        let unprocessed = match dry_run || keys.is_empty() {
+            true => 0,
+            false => batch::delete(&client, &config::get().ride_table, keys, &meter).await?.len(),
+        };
+        if unprocessed > 0 {
+            warn!("{} rides of imei {} month {} left undeleted", unprocessed, imei, ride_month);
+        }
+        remaining += unprocessed;
+        deleted_by_imei.insert(imei, found - unprocessed);
+    }
+
+    let rides_deleted = deleted_by_imei.values().sum();
+    info!(dry_run, rides_deleted, rides_remaining = remaining, "Enforced retention for {}", ride_month);
+    Ok(json!(RetentionOutput {
+        ride_month,
+        dry_run,
+        export_manifest_uri: format!("s3://{}/{}", bucket, manifest_key),
+        archive_manifest_uri,
+        devices: rows.len(),
+        rides_deleted,
+        deleted_by_imei,
+        rides_remaining: remaining,
+    }))
+}
+
+/// The key of an `export` manifest for the month, if any run wrote one.
+async fn export_manifest(s3: &aws_sdk_s3::Client, bucket: &str, ride_month: &str) -> anyhow::Result<Option<String>> {
+    let mut pages = s3.list_objects_v2().bucket(bucket).prefix(format!("exports/{}/", ride_month)).into_paginator().send();
+    while let Some(page) = pages.next().await {
+        if let Some(key) = page?.contents().iter().filter_map(|object| object.key()).find(|key| key.ends_with("/manifest.json")) {
+            return Ok(Some(key.to_string()));
+        }
+    }
+    Ok(None)
+}
+
+/// Gzipped JSONL, one item per line in DynamoDB JSON.
+fn archive_body(items: &[Item]) -> anyhow::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    for item in items {
+        let line: serde_json::Map<String, Value> = item.iter().map(|(name, value)| (name.clone(), dynamodb_json(value))).collect();
+        serde_json::to_writer(&mut encoder, &line)?;
+        encoder.write_all(b"\n")?;
+    }
+    Ok(encoder.finish()?)
+}
+
+pub fn dynamodb_json(value: &AttributeValue) -> Value {
+    match value {
+        AttributeValue::S(s) => json!({ "S": s }),
+        AttributeValue::N(n) => json!({ "N": n }),
+        AttributeValue::Bool(b) => json!({ "BOOL": b }),
+        AttributeValue::Null(_) => json!({ "NULL": true }),
+        AttributeValue::Ss(values) => json!({ "SS": values }),
+        AttributeValue::Ns(values) => json!({ "NS": values }),
+        AttributeValue::B(blob) => json!({ "B": base64::engine::general_purpose::STANDARD.encode(blob.as_ref()) }),
+        AttributeValue::Bs(blobs) => json!({ "BS": blobs.iter().map(|blob| base64::engine::general_purpose::STANDARD.encode(blob.as_ref())).collect::<Vec<_>>() }),
+        AttributeValue::L(values) => json!({ "L": values.iter().map(dynamodb_json).collect::<Vec<_>>() }),
+        AttributeValue::M(map) => json!({ "M": map.iter().map(|(name, value)| (name.clone(), dynamodb_json(value))).collect::<serde_json::Map<_, _>>() }),
+        _ => Value::Null,
+    }
+}
+
+/// Archive objects are never overwritten, so a re-run under the same run_id fails instead.
+async fn put_archive(s3: &aws_sdk_s3::Client, bucket: &str, key: &str, content_type: &str, body: Vec<u8>) -> anyhow::Result<()> {
+    s3.put_object()
+        .bucket(bucket)
+        .key(key)
+        .content_type(content_type)
+        .storage_class(StorageClass::GlacierIr)
+        .if_none_match("*")
+        .body(ByteStream::from(body))
+        .send()
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, Fake, Request};
+    use crate::time::Granularity;
+    use std::collections::HashMap;
+
+    const MONTH: &str = "2023-01";
+
+    fn settings() -> Settings {
+        Settings { retention_months: Some(12), report_bucket: Some("reports".to_string()), archive_bucket: Some("archive".to_string()) }
+    }
+
+    fn clock() -> Clock {
+        Clock::resolve(Some("2024-06-01T00:00:00Z")).unwrap()
+    }
+
+    fn payload(dry_run: bool) -> CustomEvent {
+        CustomEvent { input_ride_month: Some(MONTH.to_string()), dry_run: Some(dry_run), ..Default::default() }
+    }
+
+    fn row(imei: &str, finalized: bool) -> Value {
+        json!({
+            "imei": {"S": imei},
+            "period": {"S": aggregates::sort_key(Granularity::Monthly, MONTH)},
+            "total_distance": {"N": "4.5"},
+            "finalized": {"BOOL": finalized},
+        })
+    }
+
+    fn ride(imei: &str, ride_start: u64) -> Value {
+        json!({"imei": {"S": imei}, "ride_start": {"N": ride_start.to_string()}, "ride_type": {"S": "trip"}})
+    }
+
+    /// Serves `rows` for the month and each device's `rides`, with an export of the month in the
+    /// report bucket if `exported`.
+    fn fake(rows: Vec<Value>, exported: bool) -> Fake {
+        failing_uploads(rows, exported, None)
+    }
+
+    /// As [`fake`], with archive uploads whose key contains `failing` denied.
+    fn failing_uploads(rows: Vec<Value>, exported: bool, failing: Option<&'static str>) -> Fake {
+        let rides = HashMap::from([("111", vec![ride("111", 1673000000), ride("111", 1673100000)]), ("222", vec![ride("222", 1673200000)])]);
+        Fake::new(move |request: &Request| {
+            if request.is("Query") {
+                let body = request.json();
+                let items = match body.get("IndexName") {
+                    Some(_) => rows.clone(),
+                    None => rides.get(body["ExpressionAttributeValues"][":imei"]["S"].as_str().unwrap()).cloned().unwrap_or_default(),
+                };
+                return testing::ok(json!({"Items": items, "Count": items.len()}));
+            }
+            if request.operation.is_some() {
+                return testing::ok(json!({}));
+            }
+            if request.uri.contains("list-type=2") {
+                let contents = match exported {
+                    true => format!("<Contents><Key>exports/{}/run/manifest.json</Key></Contents>", MONTH),
+                    false => String::new(),
+                };
+                return (200, format!("<ListBucketResult><Name>reports</Name><IsTruncated>false</IsTruncated>{}</ListBucketResult>", contents));
+            }
+            match failing.filter(|failing| request.uri.contains(failing)) {
+                Some(_) => (403, "<Error><Code>AccessDenied</Code><Message>Access Denied</Message></Error>".to_string()),
+                None => (200, String::new()),
+            }
+        })
+    }
+
+    fn uploads(fake: &Fake) -> Vec<String> {
+        fake.requests().into_iter().filter(|request| request.method == "PUT").map(|request| request.uri).collect()
+    }
+
+    fn deleted(fake: &Fake) -> Vec<String> {
+        fake.sent("BatchWriteItem").iter()
+            .flat_map(|body| body["RequestItems"].as_object().unwrap().values().flat_map(|requests| requests.as_array().unwrap().clone()).collect::<Vec<_>>())
+            .map(|request| request["DeleteRequest"]["Key"]["imei"]["S"].as_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn a_month_with_an_open_row_is_kept() {
+        let fake = fake(vec![row("111", true), row("222", false)], true);
+        let output = enforce(&fake.sdk_config(), &payload(false), "run", &clock(), &settings()).await.unwrap();
+        assert_eq!(output["error"], "1 of 2 rows for 2023-01 are not finalized");
+        assert!(uploads(&fake).is_empty());
+        assert!(deleted(&fake).is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_month_without_an_export_is_kept() {
+        let fake = fake(vec![row("111", true)], false);
+        let output = enforce(&fake.sdk_config(), &payload(false), "run", &clock(), &settings()).await.unwrap();
+        assert_eq!(output["error"], "no export of 2023-01 found in reports");
+        assert!(uploads(&fake).is_empty());
+        assert!(deleted(&fake).is_empty());
+    }
+
+    #[tokio::test]
+    async fn rides_of_a_device_without_a_row_are_kept() {
+        let fake = fake(vec![row("111", true)], true);
+        let output = enforce(&fake.sdk_config(), &payload(false), "run", &clock(), &settings()).await.unwrap();
+        assert_eq!(output["deleted_by_imei"], json!({"111": 2}));
+        assert_eq!(deleted(&fake), ["111", "111"]);
+        let uploads = uploads(&fake);
+        assert_eq!(uploads.len(), 2);
+        assert!(uploads[0].contains("retention/2023-01/run/111.jsonl.gz") && uploads[1].contains("retention/2023-01/run/manifest.json"), "{:?}", uploads);
+    }
+
+    #[tokio::test]
+    async fn a_dry_run_only_counts_the_rides() {
+        let fake = fake(vec![row("111", true), row("222", true)], true);
+        let output = enforce(&fake.sdk_config(), &payload(true), "run", &clock(), &settings()).await.unwrap();
+        assert_eq!(output["rides_deleted"], 3);
+        assert!(output.get("archive_manifest_uri").is_none());
+        assert!(uploads(&fake).is_empty());
+        assert!(deleted(&fake).is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_failed_archive_upload_deletes_nothing() {
+        let fake = failing_uploads(vec![row("111", true), row("222", true)], true, Some("222.jsonl.gz"));
+        assert!(enforce(&fake.sdk_config(), &payload(false), "run", &clock(), &settings()).await.is_err());
+        assert!(!uploads(&fake).iter().any(|uri| uri.contains("manifest.json")));
+        assert!(deleted(&fake).is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_failed_manifest_write_deletes_nothing() {
+        let fake = failing_uploads(vec![row("111", true), row("222", true)], true, Some("manifest.json"));
+        assert!(enforce(&fake.sdk_config(), &payload(false), "run", &clock(), &settings()).await.is_err());
+        assert_eq!(uploads(&fake).len(), 3);
+        assert!(deleted(&fake).is_empty());
+    }
+}
@@ -0,0 +1,133 @@
+//! Per-operation retry statistics for the response's `diagnostics`, gathered by an interceptor on the
+//! request's DynamoDB client. Backoff is the time between one attempt ending and the next starting.
+//! `BatchWriteItem` / `BatchGetItem` calls re-sent for unprocessed items are counted as separate calls.
+//!
+//! Every DynamoDB client retries throttling (`ProvisionedThroughputExceededException` and the like)
+//! and transient errors up to `DYNAMODB_MAX_ATTEMPTS` attempts (default 5), with jittered
+//! exponential backoff from `DYNAMODB_BASE_DELAY_MS` (default 50); other errors fail at once.
+
+use aws_sdk_dynamodb::config::retry::RetryConfig;
+use aws_sdk_dynamodb::config::interceptors::{BeforeTransmitInterceptorContextRef, FinalizerInterceptorContextRef};
+use aws_sdk_dynamodb::config::{ConfigBag, Intercept, RuntimeComponents};
+use aws_sdk_dynamodb::Client;
+use aws_smithy_runtime_api::box_error::BoxError;
+use aws_smithy_runtime_api::client::orchestrator::Metadata;
+use aws_smithy_types::config_bag::{Storable, StoreReplace};
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use utoipa::ToSchema;
+
+#[derive(Debug, Clone, Default, Serialize, JsonSchema, ToSchema)]
+pub struct OperationRetries {
+    pub calls: u64,
+    pub attempts: u64,
+    /// Calls that needed more than one attempt.
+    pub retried_calls: u64,
+    pub max_attempts: u64,
+    pub backoff_ms: u64,
+    /// Calls that finally succeeded, and that failed after their last attempt.
+    pub succeeded: u64,
+    pub failed: u64,
+}
+
+/// Retry statistics per DynamoDB operation name.
+#[derive(Debug, Default)]
+pub struct RetryStats {
+    operations: Mutex<BTreeMap<String, OperationRetries>>,
+}
+
+impl RetryStats {
+    pub fn snapshot(&self) -> BTreeMap<String, OperationRetries> {
+        self.operations.lock().unwrap().clone()
+    }
+}
+
+pub fn max_attempts() -> u32 {
+    crate::config::get().dynamodb_max_attempts
+}
+
+pub fn base_delay() -> Duration {
+    crate::config::get().dynamodb_base_delay
+}
+
+fn builder(shared_config: &aws_config::SdkConfig) -> aws_sdk_dynamodb::config::Builder {
+    aws_sdk_dynamodb::config::Builder::from(shared_config)
+        .retry_config(RetryConfig::standard().with_max_attempts(max_attempts()).with_initial_backoff(base_delay()))
+}
+
+/// A DynamoDB client with the configured retries.
+pub fn dynamodb(shared_config: &aws_config::SdkConfig) -> Client {
+    Client::from_conf(builder(shared_config).build())
+}
+
+/// A DynamoDB client with the configured retries whose calls are recorded in `stats`.
+pub fn client(shared_config: &aws_config::SdkConfig, stats: &Arc<RetryStats>) -> Client {
+    let config = builder(shared_config)
+        .interceptor(RetryInterceptor { stats: Arc::clone(stats) })
+        .build();
+    Client::from_conf(config)
+}
+
+/// One call's attempts so far, kept in the call's config bag.
+#[derive(Debug, Clone, Default)]
+struct CallState {
+    attempts: u64,
+    attempt_ended: Option<Instant>,
+    backoff: Duration,
+}
+
+impl Storable for CallState {
+    type Storer = StoreReplace<Self>;
+}
+
+#[derive(Debug)]
+struct RetryInterceptor {
+    stats: Arc<RetryStats>,
+}
+
+impl Intercept for RetryInterceptor {
+    fn name(&self) -> &'static str {
+        "RetryStats"
+    }
+
+    fn read_before_attempt(&self, _: &BeforeTransmitInterceptorContextRef<'_>, _: &RuntimeComponents, cfg: &mut ConfigBag) -> Result<(), BoxError> {
+        let mut state = cfg.load::<CallState>().cloned().unwrap_or_default();
+        state.attempts += 1;
+        if let Some(ended) = state.attempt_ended {
+            state.backoff += ended.elapsed();
+        }
+        cfg.interceptor_state().store_put(state);
+        Ok(())
+    }
+
+    fn read_after_attempt(&self, _: &FinalizerInterceptorContextRef<'_>, _: &RuntimeComponents, cfg: &mut ConfigBag) -> Result<(), BoxError> {
+        let mut state = cfg.load::<CallState>().cloned().unwrap_or_default();
+        state.attempt_ended = Some(Instant::now());
+        cfg.interceptor_state().store_put(state);
+        Ok(())
+    }
+
+    fn read_after_execution(&self, context: &FinalizerInterceptorContextRef<'_>, _: &RuntimeComponents, cfg: &mut ConfigBag) -> Result<(), BoxError> {
+        let state = cfg.load::<CallState>().cloned().unwrap_or_default();
+        let operation = cfg.load::<Metadata>().map(|metadata| metadata.name().to_string()).unwrap_or_else(|| "unknown".to_string());
+        let succeeded = matches!(context.output_or_error(), Some(Ok(_)));
+
+        let mut operations = self.stats.operations.lock().unwrap();
+        let stats = operations.entry(operation).or_default();
+        stats.calls += 1;
+        stats.attempts += state.attempts;
+        stats.max_attempts = stats.max_attempts.max(state.attempts);
+        if state.attempts > 1 {
+            stats.retried_calls += 1;
+        }
+        stats.backoff_ms += state.backoff.as_millis() as u64;
+        match succeeded {
+            true => stats.succeeded += 1,
+            false => stats.failed += 1,
+        }
+        Ok(())
+    }
+}
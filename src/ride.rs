@@ -0,0 +1,361 @@
+use aws_sdk_dynamodb::types::AttributeValue;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use chrono::NaiveDate;
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+use utoipa::ToSchema;
+
+use crate::{config, time, units};
+
+/// Why a ride did not count towards a monthly total.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Exclusion {
+    /// Soft-deleted (`deleted = true`) or overwritten by a tombstone.
+    Voided,
+    /// Its `ride_type` is not one being counted (by default only `trip`).
+    NotTrip,
+    /// Starts outside `RIDE_WINDOW_START` / `RIDE_WINDOW_END`.
+    OutsideWindow,
+    OtherMonth,
+    MissingRideStart,
+    MissingStats,
+    InvalidDistance,
+    /// Negative or non-finite distance.
+    Outlier,
+    /// Its device signature did not verify and `verify_signatures` is `exclude`.
+    InvalidSignature,
+    /// Overlaps a longer ride of the same device (firmware double-logging).
+    Overlapping,
+    /// Starts further in the future than the clock-skew tolerance allows.
+    ClockSkew,
+    /// Longer than `MAX_RIDE_DISTANCE_KM`.
+    ImplausibleDistance,
+}
+
+impl Exclusion {
+    /// Whether the ride still belongs to a month being aggregated, so it should be explained on that month's row.
+    pub fn in_scope(self) -> bool {
+        !matches!(self, Exclusion::OutsideWindow | Exclusion::OtherMonth | Exclusion::MissingRideStart)
+    }
+}
+
+/// Which `ride_type`s count towards totals: those in `include` (default just `trip`) and not in `exclude`.
+#[derive(Debug, Clone)]
+pub struct RideTypes {
+    include: Vec<String>,
+    exclude: Vec<String>,
+}
+
+impl Default for RideTypes {
+    fn default() -> RideTypes {
+        RideTypes { include: vec!["trip".to_string()], exclude: Vec::new() }
+    }
+}
+
+impl RideTypes {
+    /// An empty or missing `include` list keeps the default. Names are compared the way
+    /// [`normalize`](crate::normalize) leaves stored `ride_type`s: trimmed and lowercased.
+    pub fn new(include: Option<&[String]>, exclude: Option<&[String]>) -> RideTypes {
+        let fold = |types: &[String]| types.iter().map(|t| t.trim().to_lowercase()).collect();
+        let mut types = RideTypes::default();
+        if let Some(include) = include.filter(|include| !include.is_empty()) {
+            types.include = fold(include);
+        }
+        types.exclude = fold(exclude.unwrap_or_default());
+        types
+    }
+
+    /// Every `ride_type` that counts.
+    pub fn counted(&self) -> Vec<&str> {
+        self.include.iter().filter(|t| !self.exclude.contains(t)).map(String::as_str).collect()
+    }
+
+    pub fn counts(&self, ride_type: &str) -> bool {
+        self.include.iter().any(|t| t == ride_type) && !self.exclude.iter().any(|t| t == ride_type)
+    }
+}
+
+/// Why a ride item could not be read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Serialize, JsonSchema, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ParseError {
+    MissingRideStart,
+    MissingStats,
+    /// `ride_stats.ride_distance` is missing or not a number, or its `distance_unit` is unknown.
+    InvalidDistance,
+}
+
+/// Items that would have counted but could not be read, per error.
+pub type SkippedItems = BTreeMap<ParseError, u64>;
+
+/// The attributes every counted ride needs, parsed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RideItem {
+    pub ride_start: u64,
+    pub ride_type: String,
+    pub distance: f64,
+}
+
+impl TryFrom<&HashMap<String, AttributeValue>> for RideItem {
+    /// Everything wrong with the item, not just the first problem.
+    type Error = Vec<ParseError>;
+
+    fn try_from(item: &HashMap<String, AttributeValue>) -> Result<RideItem, Vec<ParseError>> {
+        let mut errors = Vec::new();
+        let ride_start = start_of(item);
+        if ride_start.is_none() {
+            errors.push(ParseError::MissingRideStart);
+        }
+        let distance = distance_of(item);
+        if item.get("ride_stats").and_then(|v| v.as_m().ok()).is_none() {
+            errors.push(ParseError::MissingStats);
+        } else if distance.is_none() {
+            errors.push(ParseError::InvalidDistance);
+        }
+        match (ride_start, distance) {
+            (Some(ride_start), Some(distance)) => Ok(RideItem { ride_start, ride_type: type_of(item), distance }),
+            _ => Err(errors),
+        }
+    }
+}
+
+fn start_of(item: &HashMap<String, AttributeValue>) -> Option<u64> {
+    item.get("ride_start").and_then(|v| v.as_n().ok()).and_then(|s| s.parse::<u64>().ok())
+}
+
+fn type_of(item: &HashMap<String, AttributeValue>) -> String {
+    item.get("ride_type").and_then(|v| v.as_s().ok()).cloned().unwrap_or_else(|| "NA".to_string())
+}
+
+/// The ride's distance in km, whatever unit it was stored in.
+fn distance_of(item: &HashMap<String, AttributeValue>) -> Option<f64> {
+    item.get("ride_stats").and_then(|v| v.as_m().ok())
+        .and_then(|stats| stats.get("ride_distance"))
+        .and_then(|v| v.as_s().ok())
+        .and_then(|d| units::to_km(d, units::unit_of(item)?))
+}
+
+/// What the aggregation makes of one raw ride item.
+#[derive(Debug, Clone)]
+pub struct RideDecision {
+    pub ride_start: Option<u64>,
+    pub ride_type: String,
+    pub ride_month: Option<String>,
+    pub distance: Option<f64>,
+    pub exclusion: Option<Exclusion>,
+    /// Why the item did not parse as a [`RideItem`], if it didn't.
+    pub parse_errors: Vec<ParseError>,
+}
+
+impl RideDecision {
+    /// The item would have counted had it parsed.
+    pub fn skipped(&self) -> bool {
+        matches!(self.exclusion, Some(Exclusion::MissingRideStart | Exclusion::MissingStats | Exclusion::InvalidDistance))
+    }
+}
+
+pub fn classify(item: &HashMap<String, AttributeValue>, input_ride_month: Option<&str>, ride_types: &RideTypes) -> RideDecision {
+    let (ride_start, ride_type, distance, parse_errors) = match RideItem::try_from(item) {
+        Ok(ride) => (Some(ride.ride_start), ride.ride_type, Some(ride.distance), Vec::new()),
+        Err(errors) => (start_of(item), type_of(item), distance_of(item), errors),
+    };
+    let ride_month = ride_start.and_then(ride_month);
+
+    let mut decision = RideDecision { ride_start, ride_type, ride_month, distance, exclusion: None, parse_errors };
+    decision.exclusion = exclusion(&decision, item, input_ride_month, ride_types);
+    decision
+}
+
+fn exclusion(decision: &RideDecision, item: &HashMap<String, AttributeValue>, input_ride_month: Option<&str>, ride_types: &RideTypes) -> Option<Exclusion> {
+    if is_voided(item, &decision.ride_type) {
+        return Some(Exclusion::Voided);
+    }
+    eligibility(decision, item, input_ride_month, ride_types)
+}
+
+/// Why a ride that has not been voided does not count, if it doesn't.
+pub fn eligibility(decision: &RideDecision, item: &HashMap<String, AttributeValue>, input_ride_month: Option<&str>, ride_types: &RideTypes) -> Option<Exclusion> {
+    if !ride_types.counts(&decision.ride_type) {
+        return Some(Exclusion::NotTrip);
+    }
+    let Some(ride_month) = &decision.ride_month else {
+        return Some(Exclusion::MissingRideStart);
+    };
+    if decision.ride_start.is_some_and(|start| !window().contains(start)) {
+        return Some(Exclusion::OutsideWindow);
+    }
+    if input_ride_month.is_some_and(|month| month != ride_month) {
+        return Some(Exclusion::OtherMonth);
+    }
+    if item.get("ride_stats").and_then(|v| v.as_m().ok()).is_none() {
+        return Some(Exclusion::MissingStats);
+    }
+    match decision.distance {
+        None => Some(Exclusion::InvalidDistance),
+        Some(distance) if !distance.is_finite() || distance < 0.0 => Some(Exclusion::Outlier),
+        Some(_) => None,
+    }
+}
+
+fn is_voided(item: &HashMap<String, AttributeValue>, ride_type: &str) -> bool {
+    let flag = |name: &str| match item.get(name) {
+        Some(AttributeValue::Bool(flag)) => *flag,
+        Some(AttributeValue::S(flag)) => flag == "true",
+        _ => false,
+    };
+    ride_type == "tombstone" || flag("deleted") || flag("tombstone")
+}
+
+/// Rides that overlap in time with a longer one (by duration), given each included ride's
+/// `(ride_start, ride_end)`. Rides without a `ride_end` never overlap.
+pub fn overlapped(spans: &[(u64, Option<u64>)]) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..spans.len()).filter(|&i| spans[i].1.is_some()).collect();
+    order.sort_by_key(|&i| spans[i].0);
+
+    let duration = |i: usize| spans[i].1.unwrap_or(spans[i].0).saturating_sub(spans[i].0);
+    let mut dropped = Vec::new();
+    let mut kept: Option<usize> = None;
+    for i in order {
+        match kept {
+            Some(k) if spans[i].0 < spans[k].1.unwrap_or(0) => {
+                if duration(i) > duration(k) {
+                    dropped.push(k);
+                    kept = Some(i);
+                } else {
+                    dropped.push(i);
+                }
+            }
+            _ => kept = Some(i),
+        }
+    }
+    dropped
+}
+
+/// `ride_end` (epoch seconds) of a ride, when the firmware recorded one.
+pub fn ride_end(item: &HashMap<String, AttributeValue>) -> Option<u64> {
+    item.get("ride_end").and_then(|v| v.as_n().ok()).and_then(|e| e.parse().ok())
+}
+
+/// `YYYY-MM` month (IST) a ride starting at `ride_start` (epoch seconds) is bucketed into.
+pub fn ride_month(ride_start: u64) -> Option<String> {
+    time::month_of_epoch(i64::try_from(ride_start).ok()?).ok()
+}
+
+/// Local days (inclusive) rides must start on to count at all, from `RIDE_WINDOW_START` /
+/// `RIDE_WINDOW_END` (`YYYY-MM-DD`); either end may be open, and by default both are.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RideWindow {
+    pub start: Option<NaiveDate>,
+    pub end: Option<NaiveDate>,
+}
+
+/// How far after the current time a ride may start and still count, from `CLOCK_SKEW_TOLERANCE_SECS`
+/// (default 300); device clocks drift.
+pub fn clock_skew_tolerance() -> Duration {
+    config::get().clock_skew_tolerance
+}
+
+/// The configured [`RideWindow`].
+pub fn window() -> RideWindow {
+    config::get().ride_window
+}
+
+impl RideWindow {
+    /// Epoch seconds `[from, to)` the window covers, or None when it is unrestricted.
+    pub fn bounds(self) -> Option<(i64, i64)> {
+        if self == RideWindow::default() {
+            return None;
+        }
+        let from = self.start.and_then(|start| time::midnight(start).ok()).unwrap_or(0);
+        let to = self.end.and_then(|end| time::midnight(end.succ_opt()?).ok()).unwrap_or(i64::MAX);
+        Some((from, to))
+    }
+
+    pub fn contains(self, ride_start: u64) -> bool {
+        let (from, to) = self.bounds().unwrap_or((0, i64::MAX));
+        i64::try_from(ride_start).is_ok_and(|start| (from..to).contains(&start))
+    }
+
+    /// Whether any part of `YYYY-MM` month lies in the window.
+    pub fn touches(self, month: &str) -> bool {
+        let (from, to) = self.bounds().unwrap_or((0, i64::MAX));
+        time::month_range(month).is_ok_and(|(month_from, month_to)| month_from < to && from < month_to)
+    }
+
+    /// Every month from the window's start to its end or `last`, whichever is earlier; None without a start.
+    pub fn months_until(self, last: &str) -> Result<Option<Vec<String>>, time::TimeError> {
+        let Some(start) = self.start else {
+            return Ok(None);
+        };
+        let end = self.end.map(|end| end.format("%Y-%m").to_string()).filter(|end| end.as_str() < last);
+        time::months_between(&start.format("%Y-%m").to_string(), end.as_deref().unwrap_or(last)).map(Some)
+    }
+
+    /// `YYYY-MM-DD..YYYY-MM-DD` with either side empty when open, or `all` when unrestricted.
+    pub fn label(self) -> String {
+        match (self.start, self.end) {
+            (None, None) => "all".to_string(),
+            (start, end) => format!("{}..{}", start.map(|d| d.to_string()).unwrap_or_default(), end.map(|d| d.to_string()).unwrap_or_default()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{TimeZone, Utc};
+
+    /// 2024-12-31 23:59:59 and 2025-01-01 00:00:00 IST.
+    fn new_year() -> (u64, u64) {
+        let midnight = Utc.with_ymd_and_hms(2024, 12, 31, 18, 30, 0).unwrap().timestamp() as u64;
+        (midnight - 1, midnight)
+    }
+
+    fn date(date: &str) -> Option<NaiveDate> {
+        Some(NaiveDate::parse_from_str(date, "%Y-%m-%d").unwrap())
+    }
+
+    #[test]
+    fn rides_are_bucketed_by_local_month_across_the_new_year() {
+        let (last_second, first_second) = new_year();
+        assert_eq!(ride_month(last_second).as_deref(), Some("2024-12"));
+        assert_eq!(ride_month(first_second).as_deref(), Some("2025-01"));
+    }
+
+    #[test]
+    fn unrestricted_window_contains_every_ride() {
+        let window = RideWindow::default();
+        assert_eq!(window.bounds(), None);
+        let (last_second, first_second) = new_year();
+        assert!(window.contains(0) && window.contains(last_second) && window.contains(first_second));
+        assert!(window.touches("2019-06") && window.touches("2031-01"));
+        assert_eq!(window.label(), "all");
+    }
+
+    #[test]
+    fn window_ends_at_local_midnight() {
+        let (last_second, first_second) = new_year();
+        let starting = RideWindow { start: date("2025-01-01"), end: None };
+        assert!(!starting.contains(last_second));
+        assert!(starting.contains(first_second));
+        assert!(!starting.touches("2024-12"));
+        assert!(starting.touches("2025-01"));
+
+        let ending = RideWindow { start: None, end: date("2024-12-31") };
+        assert!(ending.contains(last_second));
+        assert!(!ending.contains(first_second));
+        assert!(ending.touches("2024-12"));
+        assert!(!ending.touches("2025-01"));
+    }
+
+    #[test]
+    fn window_months_run_from_start_to_end_or_last() {
+        let window = RideWindow { start: date("2024-11-15"), end: date("2025-01-10") };
+        assert_eq!(window.months_until("2025-06").unwrap().unwrap(), ["2024-11", "2024-12", "2025-01"]);
+        assert_eq!(window.months_until("2024-12").unwrap().unwrap(), ["2024-11", "2024-12"]);
+        assert_eq!(RideWindow { start: None, end: date("2025-01-10") }.months_until("2025-06").unwrap(), None);
+        assert_eq!(window.label(), "2024-11-15..2025-01-10");
+    }
+}
@@ -0,0 +1,42 @@
+use anyhow::{anyhow, Result};
+use aws_sdk_s3::presigning::PresigningConfig;
+use aws_sdk_s3::Client;
+use std::time::Duration;
+
+use crate::kms::Encryption;
+
+/// The longest S3 lets a presigned URL live.
+pub const MAX_PRESIGN_EXPIRY_SECS: u64 = 7 * 86_400;
+
+/// Splits `s3://bucket/key` into its bucket and key.
+pub fn parse_uri(uri: &str) -> Option<(&str, &str)> {
+    let (bucket, key) = uri.strip_prefix("s3://")?.split_once('/')?;
+    if bucket.is_empty() || key.is_empty() {
+        return None;
+    }
+    Some((bucket, key))
+}
+
+/// How long presigned URLs last: `requested` seconds, else `PRESIGN_EXPIRY_SECS`, else an hour. A URL
+/// also stops working when the credentials that signed it expire, which for a Lambda role is sooner.
+pub fn presign_expiry(requested: Option<u64>) -> Duration {
+    let secs = requested.or(crate::config::get().presign_expiry_secs).unwrap_or(3600);
+    Duration::from_secs(secs.min(MAX_PRESIGN_EXPIRY_SECS))
+}
+
+/// A time-limited GET URL for `s3://bucket/key`, needing no AWS credentials to fetch.
+pub async fn presign(client: &Client, uri: &str, expiry: Duration) -> Result<String> {
+    let (bucket, key) = parse_uri(uri).ok_or_else(|| anyhow!("{:?} is not an s3://bucket/key URI", uri))?;
+    let request = client.get_object().bucket(bucket).key(key).presigned(PresigningConfig::expires_in(expiry)?).await?;
+    Ok(request.uri().to_string())
+}
+
+/// The object's bytes, or `Ok(Err(..))` when [`Encryption::check`] rejects it.
+pub async fn get_object(client: &Client, uri: &str, encryption: &Encryption) -> Result<Result<Vec<u8>, String>> {
+    let (bucket, key) = parse_uri(uri).ok_or_else(|| anyhow!("{:?} is not an s3://bucket/key URI", uri))?;
+    let resp = client.get_object().bucket(bucket).key(key).send().await?;
+    if let Err(err) = encryption.check(uri, resp.server_side_encryption(), resp.metadata()) {
+        return Ok(Err(err));
+    }
+    Ok(Ok(resp.body.collect().await?.into_bytes().to_vec()))
+}
@@ -0,0 +1,60 @@
+//! Every device's rides of a month, read with a parallel `Scan` of the whole ride table in
+//! `IMEI_CONCURRENCY` segments. [`planner`](crate::planner) decides when that is cheaper.
+
+use aws_sdk_dynamodb::error::SdkError;
+use aws_sdk_dynamodb::operation::scan::ScanError;
+use aws_sdk_dynamodb::types::ReturnConsumedCapacity;
+use aws_sdk_dynamodb::Client;
+use futures::stream::{self, StreamExt, TryStreamExt};
+
+use crate::cost::CapacityMeter;
+use crate::pagination::Item;
+use crate::{config, date_index, imei_concurrency_from_env};
+
+/// Every ride starting within `ride_starts` (epoch seconds, `[from, to)`), of a `ride_types` type if any.
+pub async fn read(
+    client: &Client,
+    ride_starts: (i64, i64),
+    ride_types: &[String],
+    projection: &str,
+    meter: &CapacityMeter,
+) -> Result<Vec<Item>, SdkError<ScanError>> {
+    let segments = imei_concurrency_from_env() as i32;
+    let segments: Vec<Vec<Item>> = stream::iter(0..segments)
+        .map(|segment| async move { segment_rides(client, segment, segments, ride_starts, ride_types, projection, meter).await })
+        .buffer_unordered(segments as usize)
+        .try_collect()
+        .await?;
+    Ok(segments.into_iter().flatten().collect())
+}
+
+async fn segment_rides(
+    client: &Client,
+    segment: i32,
+    total_segments: i32,
+    (from, to): (i64, i64),
+    ride_types: &[String],
+    projection: &str,
+    meter: &CapacityMeter,
+) -> Result<Vec<Item>, SdkError<ScanError>> {
+    let filter = date_index::RideFilter::new(from, to, ride_types);
+    let mut pages = client
+        .scan()
+        .table_name(&config::get().ride_table)
+        .segment(segment)
+        .total_segments(total_segments)
+        .filter_expression(filter.expression)
+        .set_expression_attribute_names(Some(filter.names))
+        .set_expression_attribute_values(Some(filter.values))
+        .projection_expression(format!("#imei, {}", projection))
+        .return_consumed_capacity(ReturnConsumedCapacity::Total)
+        .into_paginator()
+        .send();
+    let mut items = Vec::new();
+    while let Some(page) = pages.next().await {
+        let page = page?;
+        meter.read(page.consumed_capacity());
+        items.extend(page.items.unwrap_or_default());
+    }
+    Ok(items)
+}
@@ -0,0 +1,60 @@
+use schemars::schema_for;
+use serde_json::{json, Value};
+
+use crate::as_of::AsOfOutput;
+use crate::compare::CompareOutput;
+use crate::corrections::CorrectionOutput;
+use crate::counts::RideCountResponse;
+use crate::decommission::DecommissionOutput;
+use crate::diagnose::DiagnoseOutput;
+use crate::estimate::EstimateResponse;
+use crate::export::{ExportManifest, ExportOutput};
+use crate::fanout::FanOutOutput;
+use crate::finalize::FinalizeOutput;
+use crate::imeis::InvalidImeisOutput;
+use crate::import::ImportOutput;
+use crate::manifest::ManifestOutput;
+use crate::migrate::MigrateOutput;
+use crate::preflight::PreflightOutput;
+use crate::reconcile::ReconcileOutput;
+use crate::regulatory::RegulatoryReportOutput;
+use crate::read::ReadOutput;
+use crate::retention::RetentionOutput;
+use crate::selftest::SelftestOutput;
+use crate::replay::ReplayOutput;
+use crate::trace::TraceOutput;
+use crate::upload::SpilledResponse;
+use crate::{AggregationResponse, CustomEvent, ErrorOutput};
+
+/// JSON Schemas for the accepted event and the produced responses, returned by `action: "describe"`.
+pub fn describe() -> Value {
+    json!({
+        "event": schema_for!(CustomEvent),
+        "response": schema_for!(AggregationResponse),
+        "aggregate_as_of_response": schema_for!(AsOfOutput),
+        "device_decommissioned_response": schema_for!(Vec<DecommissionOutput>),
+        "estimate_response": schema_for!(EstimateResponse),
+        "export_response": schema_for!(ExportOutput),
+        "export_manifest": schema_for!(ExportManifest),
+        "fan_out_response": schema_for!(FanOutOutput),
+        "finalize_response": schema_for!(Vec<FinalizeOutput>),
+        "import_response": schema_for!(ImportOutput),
+        "manifest_response": schema_for!(ManifestOutput),
+        "preflight_response": schema_for!(PreflightOutput),
+        "reconcile_response": schema_for!(ReconcileOutput),
+        "regulatory_report_response": schema_for!(RegulatoryReportOutput),
+        "retention_response": schema_for!(RetentionOutput),
+        "read_response": schema_for!(ReadOutput),
+        "spilled_response": schema_for!(SpilledResponse),
+        "ride_count_response": schema_for!(RideCountResponse),
+        "replay_response": schema_for!(ReplayOutput),
+        "debug_trace_response": schema_for!(TraceOutput),
+        "diagnose_response": schema_for!(DiagnoseOutput),
+        "selftest_response": schema_for!(SelftestOutput),
+        "compare_months_response": schema_for!(CompareOutput),
+        "migrate_legacy_rows_response": schema_for!(MigrateOutput),
+        "ride_corrected_response": schema_for!(CorrectionOutput),
+        "invalid_imeis_response": schema_for!(InvalidImeisOutput),
+        "error": schema_for!(ErrorOutput),
+    })
+}
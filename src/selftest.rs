@@ -0,0 +1,178 @@
+//! `action: "selftest"`: the post-deploy gate. Aggregates a small fixture of two devices' rides in
+//! `SELFTEST_MONTH` (default `2024-01`, which must be inside the ride window) as a dry run, through
+//! the same path as any request, and checks every total against the exact values the fixture was
+//! built for. The fixture covers decimal summing, a metre-unit ride, a voided ride, a non-trip ride,
+//! a ride without `ride_stats`, and rides a minute either side of the month's end in the reporting
+//! timezone. `seed_fixture: true` first (re)writes the fixture rides, which is how an environment
+//! is seeded; it is a write, so on prod tables it also needs `allow_prod_write`.
+//!
+//! A pass returns every check; a failure is an error naming the failed checks, so the pipeline's
+//! invoke fails.
+
+use aws_sdk_dynamodb::types::AttributeValue;
+use aws_sdk_dynamodb::Client;
+use lambda_runtime::Error;
+use schemars::JsonSchema;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use tracing::{error, info};
+
+use crate::clock::Clock;
+use crate::cost::CapacityMeter;
+use crate::{aggregate_ride_data, config, metrics, retries, time, CustomEvent, ErrorOutput};
+
+/// Valid IMEIs in the 99 reporting-body range, where no real device is allocated.
+pub const FIXTURE_IMEIS: [&str; 2] = ["990000000000010", "990000000000028"];
+
+pub fn month() -> String {
+    config::get().selftest_month.clone()
+}
+
+/// When a fixture ride starts, relative to the month.
+#[derive(Debug, Clone, Copy)]
+enum At {
+    AfterStart(i64),
+    BeforeEnd(i64),
+    AfterEnd(i64),
+}
+
+struct FixtureRide {
+    imei: &'static str,
+    at: At,
+    ride_type: &'static str,
+    /// `ride_stats.ride_distance`; None leaves out `ride_stats`.
+    distance: Option<&'static str>,
+    unit: Option<&'static str>,
+    deleted: bool,
+}
+
+const fn ride(imei: &'static str, at: At, ride_type: &'static str, distance: Option<&'static str>) -> FixtureRide {
+    FixtureRide { imei, at, ride_type, distance, unit: None, deleted: false }
+}
+
+const DAY: i64 = 86_400;
+
+const FIXTURE: [FixtureRide; 10] = [
+    ride(FIXTURE_IMEIS[0], At::AfterStart(3_600), "trip", Some("12.345")),
+    ride(FIXTURE_IMEIS[0], At::AfterStart(2 * DAY), "trip", Some("7.5")),
+    ride(FIXTURE_IMEIS[0], At::AfterStart(5 * DAY), "trip", Some("0.155")),
+    FixtureRide { unit: Some("m"), ..ride(FIXTURE_IMEIS[0], At::AfterStart(6 * DAY), "trip", Some("1500")) },
+    FixtureRide { deleted: true, ..ride(FIXTURE_IMEIS[0], At::AfterStart(7 * DAY), "trip", Some("100")) },
+    ride(FIXTURE_IMEIS[0], At::AfterStart(8 * DAY), "charging", Some("3")),
+    ride(FIXTURE_IMEIS[1], At::AfterStart(DAY), "trip", Some("3.25")),
+    ride(FIXTURE_IMEIS[1], At::AfterStart(10 * DAY), "trip", None),
+    ride(FIXTURE_IMEIS[1], At::BeforeEnd(60), "trip", Some("5.75")),
+    ride(FIXTURE_IMEIS[1], At::AfterEnd(60), "trip", Some("50")),
+];
+
+/// What each fixture device must total: distance, rides counted, voided, other types, parse failures.
+const EXPECTED: [(&str, f64, u64, u64, u64, u64); 2] = [
+    (FIXTURE_IMEIS[0], 21.5, 4, 1, 1, 0),
+    (FIXTURE_IMEIS[1], 9.0, 2, 0, 0, 1),
+];
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct Check {
+    imei: String,
+    field: String,
+    expected: f64,
+    actual: Option<f64>,
+    passed: bool,
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct SelftestOutput {
+    ride_month: String,
+    seeded: bool,
+    passed: bool,
+    checks: Vec<Check>,
+}
+
+fn ride_start(month_start: i64, next_month: i64, at: At) -> i64 {
+    match at {
+        At::AfterStart(secs) => month_start + secs,
+        At::BeforeEnd(secs) => next_month - secs,
+        At::AfterEnd(secs) => next_month + secs,
+    }
+}
+
+async fn seed(client: &Client, month: &str) -> anyhow::Result<()> {
+    let (month_start, next_month) = time::month_range(month)?;
+    for fixture in &FIXTURE {
+        let mut item = HashMap::from([
+            ("imei".to_string(), AttributeValue::S(fixture.imei.to_string())),
+            ("ride_start".to_string(), AttributeValue::N(ride_start(month_start, next_month, fixture.at).to_string())),
+            ("ride_type".to_string(), AttributeValue::S(fixture.ride_type.to_string())),
+            ("source".to_string(), AttributeValue::S("selftest".to_string())),
+        ]);
+        if let Some(distance) = fixture.distance {
+            item.insert("ride_stats".to_string(), AttributeValue::M(HashMap::from([("ride_distance".to_string(), AttributeValue::S(distance.to_string()))])));
+        }
+        if let Some(unit) = fixture.unit {
+            item.insert("distance_unit".to_string(), AttributeValue::S(unit.to_string()));
+        }
+        if fixture.deleted {
+            item.insert("deleted".to_string(), AttributeValue::Bool(true));
+        }
+        client.put_item()
+            .table_name(&config::get().ride_table)
+            .set_item(Some(item))
+            .send()
+            .await
+            .inspect_err(|_| metrics::dynamodb_error("put_item"))?;
+    }
+    Ok(())
+}
+
+pub async fn selftest(shared_config: &aws_config::SdkConfig, payload: &CustomEvent, run_id: &str, clock: &Clock) -> Result<Value, Error> {
+    let month = month();
+    if time::month_range(&month).is_err() {
+        return Ok(json!(ErrorOutput { error: format!("SELFTEST_MONTH {:?} is not a YYYY-MM month", month) }));
+    }
+    let client = retries::dynamodb(shared_config);
+    let seeded = payload.seed_fixture.unwrap_or(false);
+    if seeded {
+        seed(&client, &month).await?;
+        info!("Seeded {} fixture rides for {}", FIXTURE.len(), month);
+    }
+
+    let imeis: Vec<String> = FIXTURE_IMEIS.iter().map(|imei| imei.to_string()).collect();
+    let fixture_payload = CustomEvent {
+        imeis: imeis.clone(),
+        input_ride_month: Some(month.clone()),
+        dry_run: Some(true),
+        ..Default::default()
+    };
+    let aggregation = aggregate_ride_data(&client, &fixture_payload, &imeis, run_id, clock, None, &CapacityMeter::default()).await?;
+
+    let mut checks = Vec::new();
+    for (imei, distance, rides, voided, other_types, parse_failures) in EXPECTED {
+        let row = aggregation.rows.iter().find(|row| row.imei == imei && row.ride_month == month);
+        let mut check = |field: &str, expected: f64, actual: Option<f64>| checks.push(Check {
+            imei: imei.to_string(),
+            field: field.to_string(),
+            expected,
+            actual,
+            passed: actual == Some(expected),
+        });
+        check("total_distance", distance, row.map(|row| row.total_distance));
+        check("explain.rides_included", rides as f64, row.map(|row| row.explain.rides_included as f64));
+        check("explain.voided_rides", voided as f64, row.map(|row| row.explain.voided_rides as f64));
+        check("explain.excluded_by_type", other_types as f64, row.map(|row| row.explain.excluded_by_type as f64));
+        check("explain.parse_failures", parse_failures as f64, row.map(|row| row.explain.parse_failures as f64));
+    }
+    for error in &aggregation.errors {
+        error!("Selftest device failed: {:?}", error);
+    }
+    let failed: Vec<&Check> = checks.iter().filter(|check| !check.passed).collect();
+    if !failed.is_empty() || !aggregation.errors.is_empty() {
+        let summary: Vec<String> = failed.iter()
+            .map(|check| format!("{} {}: expected {}, got {:?}", check.imei, check.field, check.expected, check.actual))
+            .collect();
+        return Err(format!("selftest failed for {}: {} of {} checks failed, {} devices errored; {}",
+            month, failed.len(), checks.len(), aggregation.errors.len(), summary.join("; ")).into());
+    }
+    info!("Selftest passed: {} checks for {}", checks.len(), month);
+    Ok(json!(SelftestOutput { ride_month: month, seeded, passed: true, checks }))
+}
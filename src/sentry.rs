@@ -0,0 +1,238 @@
+//! Error reports to Sentry when `SENTRY_DSN` is set: every `error!` event, with the fields of the
+//! spans it happened in (`request_id`, `month`, ...) as tags, and every panic. IMEIs never leave:
+//! `imei` and `imeis` fields are dropped, and any 15-digit run in a message is replaced.
+//!
+//! Events are queued and sent by [`flush`] after each invocation, while the Lambda is still
+//! running; a panic is sent at once from the panic hook, in case the process does not survive it.
+//! `SENTRY_ENVIRONMENT` (default `ENVIRONMENT`) names the environment.
+
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+const TIMEOUT: Duration = Duration::from_secs(2);
+const REDACTED_FIELDS: [&str; 2] = ["imei", "imeis"];
+
+/// Where events for the DSN's project go, and the key they are sent with.
+#[derive(Debug, Clone)]
+pub struct Dsn {
+    envelope_url: String,
+    public_key: String,
+}
+
+impl Dsn {
+    /// `https://<public_key>@<host>/<project_id>`.
+    pub fn parse(dsn: &str) -> Option<Dsn> {
+        let (scheme, rest) = dsn.split_once("://")?;
+        let (public_key, rest) = rest.split_once('@')?;
+        let (host, project) = rest.rsplit_once('/')?;
+        let public_key = public_key.split(':').next()?;
+        if public_key.is_empty() || host.is_empty() || project.is_empty() || !project.chars().all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+        Some(Dsn { envelope_url: format!("{}://{}/api/{}/envelope/", scheme, host, project), public_key: public_key.to_string() })
+    }
+}
+
+fn dsn() -> Option<&'static Dsn> {
+    crate::config::get().sentry_dsn.as_ref()
+}
+
+pub fn enabled() -> bool {
+    dsn().is_some()
+}
+
+static QUEUED: Mutex<Vec<Value>> = Mutex::new(Vec::new());
+
+/// `text` with every run of exactly 15 digits, which could be an IMEI, replaced.
+pub fn redact(text: &str) -> String {
+    let mut redacted = String::with_capacity(text.len());
+    let mut digits = String::new();
+    for c in text.chars().chain(std::iter::once('\0')) {
+        if c.is_ascii_digit() {
+            digits.push(c);
+            continue;
+        }
+        match digits.len() {
+            15 => redacted.push_str("[imei]"),
+            _ => redacted.push_str(&digits),
+        }
+        digits.clear();
+        if c != '\0' {
+            redacted.push(c);
+        }
+    }
+    redacted
+}
+
+fn event(level: &str, logger: &str, message: &str, tags: BTreeMap<String, String>, extra: Map<String, Value>) -> Value {
+    json!({
+        "event_id": uuid::Uuid::new_v4().simple().to_string(),
+        "timestamp": SystemTime::now().duration_since(UNIX_EPOCH).map_or(0.0, |since| since.as_secs_f64()),
+        "platform": "native",
+        "level": level,
+        "logger": logger,
+        "message": {"formatted": redact(message)},
+        "release": format!("{}@{}+{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"), env!("BUILD_COMMIT")),
+        "environment": crate::config::get().sentry_environment,
+        "server_name": crate::config::get().function_name,
+        "tags": tags,
+        "extra": extra,
+    })
+}
+
+async fn send(dsn: &Dsn, events: Vec<Value>) -> anyhow::Result<()> {
+    let client = reqwest::Client::new();
+    let auth = format!("Sentry sentry_version=7, sentry_key={}, sentry_client={}/{}", dsn.public_key, env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+    for event in events {
+        let envelope = format!("{}\n{}\n{}\n", json!({"event_id": event["event_id"]}), json!({"type": "event"}), event);
+        client.post(&dsn.envelope_url)
+            .timeout(TIMEOUT)
+            .header("X-Sentry-Auth", &auth)
+            .header("Content-Type", "application/x-sentry-envelope")
+            .body(envelope)
+            .send()
+            .await?
+            .error_for_status()?;
+    }
+    Ok(())
+}
+
+/// Sends the events queued since the last call.
+pub async fn flush() {
+    let Some(dsn) = dsn() else {
+        return;
+    };
+    let events = std::mem::take(&mut *QUEUED.lock().unwrap());
+    if events.is_empty() {
+        return;
+    }
+    if let Err(err) = send(dsn, events).await {
+        // Not error!, which would only queue another event.
+        tracing::warn!("Error sending events to Sentry: {:?}", err);
+    }
+}
+
+/// Reports panics, then runs the hook installed before.
+pub fn install_panic_hook() {
+    let Some(dsn) = dsn() else {
+        return;
+    };
+    let previous = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        let message = match (info.payload().downcast_ref::<&str>(), info.payload().downcast_ref::<String>()) {
+            (Some(message), _) => message.to_string(),
+            (_, Some(message)) => message.clone(),
+            _ => "panic".to_string(),
+        };
+        let mut event = event("fatal", "panic", &message, BTreeMap::new(), Map::new());
+        event["exception"] = json!({"values": [{
+            "type": "panic",
+            "value": redact(&message),
+            "mechanism": {"type": "panic", "handled": false},
+        }]});
+        if let Some(location) = info.location() {
+            event["extra"]["location"] = json!(format!("{}:{}", location.file(), location.line()));
+        }
+        // The panicking thread may be a runtime worker, so the event is sent from a runtime of its own.
+        let dsn = dsn.clone();
+        let sent = std::thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+            runtime.block_on(send(&dsn, vec![event]))
+        }).join();
+        if !matches!(sent, Ok(Ok(()))) {
+            eprintln!("Error sending the panic to Sentry");
+        }
+        previous(info);
+    }));
+}
+
+/// Fields of an event or span, without the redacted ones.
+struct Fields<'a>(&'a mut BTreeMap<String, String>);
+
+impl Visit for Fields<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if !REDACTED_FIELDS.contains(&field.name()) {
+            self.0.insert(field.name().to_string(), redact(value));
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if !REDACTED_FIELDS.contains(&field.name()) {
+            self.0.insert(field.name().to_string(), redact(&format!("{:?}", value)));
+        }
+    }
+}
+
+struct SpanFields(BTreeMap<String, String>);
+
+/// Queues `error!` events for [`flush`]; a no-op without `SENTRY_DSN`.
+pub struct ErrorLayer {
+    enabled: bool,
+}
+
+pub fn layer() -> ErrorLayer {
+    ErrorLayer { enabled: enabled() }
+}
+
+impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for ErrorLayer {
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id).filter(|_| self.enabled) {
+            let mut fields = BTreeMap::new();
+            attrs.record(&mut Fields(&mut fields));
+            span.extensions_mut().insert(SpanFields(fields));
+        }
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(SpanFields(fields)) = span.extensions_mut().get_mut::<SpanFields>() {
+                values.record(&mut Fields(fields));
+            }
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        if !self.enabled || *event.metadata().level() != Level::ERROR {
+            return;
+        }
+        let mut tags = BTreeMap::new();
+        for span in ctx.event_scope(event).into_iter().flat_map(|scope| scope.from_root()) {
+            if let Some(SpanFields(fields)) = span.extensions().get::<SpanFields>() {
+                tags.extend(fields.clone());
+            }
+        }
+        let mut fields = BTreeMap::new();
+        event.record(&mut Fields(&mut fields));
+        let message = fields.remove("message").unwrap_or_default();
+        let extra = fields.into_iter().map(|(name, value)| (name, Value::String(value))).collect();
+        QUEUED.lock().unwrap().push(self::event("error", event.metadata().target(), &message, tags, extra));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redacts_imeis_only() {
+        assert_eq!(redact("imei 356938035643809 failed"), "imei [imei] failed");
+        assert_eq!(redact("356938035643809,356938035643817"), "[imei],[imei]");
+        assert_eq!(redact("ride 1717200000 of 2024-06"), "ride 1717200000 of 2024-06");
+        assert_eq!(redact("3569380356438091"), "3569380356438091");
+    }
+
+    #[test]
+    fn parses_dsns() {
+        let dsn = Dsn::parse("https://abc123@o42.ingest.sentry.io/4501").unwrap();
+        assert_eq!(dsn.envelope_url, "https://o42.ingest.sentry.io/api/4501/envelope/");
+        assert_eq!(dsn.public_key, "abc123");
+        assert!(Dsn::parse("https://o42.ingest.sentry.io/4501").is_none());
+    }
+}
@@ -0,0 +1,199 @@
+//! Shadow runs, the pre-release gate: with `SHADOW_FUNCTION_NAME` set, `SHADOW_SAMPLE_PERCENT` of
+//! aggregation requests (default 10) are re-sent, as `dry_run`, to that function (a staging
+//! deployment of the candidate build, on the staging tables), and its results compared with the
+//! ones just returned. Rows are matched by IMEI and period; a row diverges when its distance differs
+//! by more than `SHADOW_TOLERANCE_KM` (default 0.01) or its ride count differs.
+//!
+//! The comparison is logged and counted (`ride_data_shadow_runs_total` by outcome,
+//! `ride_data_shadow_diverged_rows_total`, and `Shadow*` embedded metrics); the caller's response is
+//! never changed. The shadow call runs after the request, within `SHADOW_TIMEOUT_SECS` (default 30),
+//! so sampled requests take that much longer. Responses spilled to S3 are not compared.
+
+use aws_sdk_lambda::primitives::Blob;
+use chrono::Utc;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::time::Duration;
+use tracing::{info, warn};
+
+use crate::{config, metrics};
+
+pub fn function_name() -> Option<String> {
+    config::get().shadow_function_name.clone()
+}
+
+pub fn sample_percent() -> f64 {
+    config::get().shadow_sample_percent
+}
+
+pub fn tolerance_km() -> f64 {
+    config::get().shadow_tolerance_km
+}
+
+fn timeout() -> Duration {
+    config::get().shadow_timeout
+}
+
+/// How the shadow's results differed from production's.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Divergence {
+    pub rows_compared: usize,
+    /// Production rows the shadow did not return.
+    pub missing_in_shadow: usize,
+    /// Shadow rows production did not return.
+    pub extra_in_shadow: usize,
+    pub diverged_rows: usize,
+    pub max_distance_diff_km: f64,
+}
+
+impl Divergence {
+    pub fn diverged(&self) -> bool {
+        self.missing_in_shadow > 0 || self.extra_in_shadow > 0 || self.diverged_rows > 0
+    }
+}
+
+/// Mirrors `request` to the shadow function if it is sampled, and reports how `response` compares.
+pub async fn mirror(shared_config: &aws_config::SdkConfig, request: &Value, response: &Value) {
+    let Some(function) = function_name() else {
+        return;
+    };
+    let aggregate = request.get("action").and_then(Value::as_str).is_none_or(|action| action == "aggregate");
+    if !aggregate || response.get("error").is_some() || fastrand::f64() * 100.0 >= sample_percent() {
+        return;
+    }
+    if response.get("results").is_none() {
+        // Spilled to S3, or not an aggregation response.
+        metrics::SHADOW_RUNS.with_label_values(&["skipped"]).inc();
+        return;
+    }
+    let mut shadow_request = request.clone();
+    if let Some(request) = shadow_request.as_object_mut() {
+        request.insert("dry_run".to_string(), json!(true));
+    }
+    let shadow = match tokio::time::timeout(timeout(), invoke(shared_config, &function, &shadow_request)).await {
+        Ok(Ok(shadow)) => shadow,
+        Ok(Err(err)) => {
+            metrics::SHADOW_RUNS.with_label_values(&["error"]).inc();
+            warn!("Error running the shadow request on {}: {:?}", function, err);
+            return;
+        }
+        Err(_) => {
+            metrics::SHADOW_RUNS.with_label_values(&["timeout"]).inc();
+            warn!("Shadow request on {} timed out", function);
+            return;
+        }
+    };
+    let divergence = compare(response, &shadow, tolerance_km());
+    report(&function, &divergence);
+}
+
+async fn invoke(shared_config: &aws_config::SdkConfig, function: &str, request: &Value) -> anyhow::Result<Value> {
+    let output = aws_sdk_lambda::Client::new(shared_config)
+        .invoke()
+        .function_name(function)
+        .payload(Blob::new(serde_json::to_vec(request)?))
+        .send()
+        .await?;
+    let payload: Value = serde_json::from_slice(output.payload().map(Blob::as_ref).unwrap_or_default())?;
+    if let Some(error) = output.function_error() {
+        anyhow::bail!("{}: {}", error, payload);
+    }
+    if let Some(error) = payload.get("error") {
+        anyhow::bail!("rejected: {}", error);
+    }
+    Ok(payload)
+}
+
+/// A number as either format writes it.
+fn number(value: Option<&Value>) -> Option<f64> {
+    let value = value?;
+    value.as_f64().or_else(|| value.as_str()?.parse().ok())
+}
+
+fn rows(response: &Value) -> BTreeMap<(String, String, String), &Value> {
+    let rows = response.get("results").and_then(Value::as_array).map(Vec::as_slice).unwrap_or_default();
+    rows.iter().filter_map(|row| {
+        let field = |name: &str| row.get(name).and_then(Value::as_str).map(str::to_string);
+        Some(((field("imei")?, field("granularity")?, field("ride_month")?), row))
+    }).collect()
+}
+
+/// Compares the `results` rows of two aggregation responses.
+pub fn compare(production: &Value, shadow: &Value, tolerance_km: f64) -> Divergence {
+    let (production, shadow) = (rows(production), rows(shadow));
+    let mut divergence = Divergence { extra_in_shadow: shadow.keys().filter(|key| !production.contains_key(*key)).count(), ..Default::default() };
+    for (key, row) in &production {
+        let Some(shadow_row) = shadow.get(key) else {
+            divergence.missing_in_shadow += 1;
+            continue;
+        };
+        divergence.rows_compared += 1;
+        let diff = match (number(row.get("total_distance")), number(shadow_row.get("total_distance"))) {
+            (Some(a), Some(b)) => (a - b).abs(),
+            (None, None) => 0.0,
+            _ => f64::INFINITY,
+        };
+        divergence.max_distance_diff_km = divergence.max_distance_diff_km.max(diff);
+        if diff > tolerance_km || row.get("ride_count") != shadow_row.get("ride_count") {
+            divergence.diverged_rows += 1;
+        }
+    }
+    divergence
+}
+
+fn report(function: &str, divergence: &Divergence) {
+    let outcome = if divergence.diverged() { "diverged" } else { "matched" };
+    metrics::SHADOW_RUNS.with_label_values(&[outcome]).inc();
+    metrics::SHADOW_DIVERGED_ROWS.inc_by((divergence.diverged_rows + divergence.missing_in_shadow + divergence.extra_in_shadow) as u64);
+    if divergence.diverged() {
+        warn!(?divergence, "Shadow run on {} diverged", function);
+    } else {
+        info!(?divergence, "Shadow run on {} matched", function);
+    }
+    metrics::emf(json!({
+        "_aws": {
+            "Timestamp": Utc::now().timestamp_millis(),
+            "CloudWatchMetrics": [{
+                "Namespace": "RideData",
+                "Dimensions": [["ShadowFunction"]],
+                "Metrics": [
+                    { "Name": "ShadowRowsCompared", "Unit": "Count" },
+                    { "Name": "ShadowDivergedRows", "Unit": "Count" },
+                    { "Name": "ShadowMissingRows", "Unit": "Count" },
+                    { "Name": "ShadowExtraRows", "Unit": "Count" },
+                    { "Name": "ShadowMaxDistanceDiff", "Unit": "None" },
+                ],
+            }],
+        },
+        "ShadowFunction": function,
+        "ShadowRowsCompared": divergence.rows_compared,
+        "ShadowDivergedRows": divergence.diverged_rows,
+        "ShadowMissingRows": divergence.missing_in_shadow,
+        "ShadowExtraRows": divergence.extra_in_shadow,
+        // EMF takes no infinities; a distance on one side only counts as a diverged row anyway.
+        "ShadowMaxDistanceDiff": if divergence.max_distance_diff_km.is_finite() { divergence.max_distance_diff_km } else { 0.0 },
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compares_rows_by_imei_and_period() {
+        let row = |imei: &str, month: &str, distance: Value, rides: u64| {
+            json!({"imei": imei, "granularity": "monthly", "ride_month": month, "total_distance": distance, "ride_count": rides})
+        };
+        let production = json!({"results": [row("1", "2024-05", json!(10.0), 3), row("1", "2024-06", json!(4.0), 1), row("2", "2024-06", json!(7.5), 2)]});
+        let shadow = json!({"results": [row("1", "2024-05", json!("10.00"), 3), row("1", "2024-06", json!(4.5), 1), row("3", "2024-06", json!(1.0), 1)]});
+        assert_eq!(compare(&production, &shadow, 0.01), Divergence {
+            rows_compared: 2,
+            missing_in_shadow: 1,
+            extra_in_shadow: 1,
+            diverged_rows: 1,
+            max_distance_diff_km: 0.5,
+        });
+        assert!(!compare(&production, &production, 0.01).diverged());
+    }
+}
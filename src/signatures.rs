@@ -0,0 +1,86 @@
+//! Device signatures on rides: with `verify_signatures`, each ride's `signature` attribute must be
+//! the hex HMAC-SHA256 of `imei:ride_start:ride_end:ride_distance` under the device's key. Keys are
+//! Secrets Manager secrets named `RIDE_SIGNING_KEY_PREFIX` + IMEI (default `ride-data/device-keys/`)
+//! holding the hex key, fetched once per IMEI batch.
+
+use aws_sdk_dynamodb::types::AttributeValue;
+use anyhow::Result;
+use hmac::{Hmac, Mac};
+use schemars::JsonSchema;
+use serde::Deserialize;
+use sha2::Sha256;
+use std::collections::HashMap;
+use tracing::warn;
+use utoipa::ToSchema;
+
+/// What happens to a ride whose signature does not verify.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, JsonSchema, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Mode {
+    /// Leave it out of the totals.
+    Exclude,
+    /// Count it, but report it in `unverified_rides` and the warnings.
+    Flag,
+}
+
+/// Checks a ride against its device's credentials.
+pub trait RideAuthenticator: Send + Sync {
+    /// `None` when there is nothing to check the device's rides against.
+    fn verify(&self, imei: &str, item: &HashMap<String, AttributeValue>) -> Option<bool>;
+}
+
+/// Per-device HMAC keys.
+#[derive(Debug, Clone, Default)]
+pub struct HmacKeys(HashMap<String, Vec<u8>>);
+
+/// `BatchGetSecretValue` takes at most 20 secret ids.
+const BATCH_SIZE: usize = 20;
+
+impl HmacKeys {
+    pub async fn load(shared_config: &aws_config::SdkConfig, imeis: &[String]) -> Result<HmacKeys> {
+        let prefix = &crate::config::get().ride_signing_key_prefix;
+        let client = aws_sdk_secretsmanager::Client::new(shared_config);
+        let mut keys = HashMap::new();
+        for batch in imeis.chunks(BATCH_SIZE) {
+            let resp = client.batch_get_secret_value()
+                .set_secret_id_list(Some(batch.iter().map(|imei| format!("{}{}", prefix, imei)).collect()))
+                .send()
+                .await?;
+            for secret in resp.secret_values() {
+                let (Some(name), Some(key)) = (secret.name(), secret.secret_string()) else {
+                    continue;
+                };
+                let imei = name.strip_prefix(prefix.as_str()).unwrap_or(name);
+                match hex::decode(key.trim()) {
+                    Ok(key) => {
+                        keys.insert(imei.to_string(), key);
+                    }
+                    Err(err) => warn!("Signing key for imei {} is not hex: {}", imei, err),
+                }
+            }
+            for error in resp.errors() {
+                warn!("No signing key {:?}: {:?}", error.secret_id(), error.error_code());
+            }
+        }
+        Ok(HmacKeys(keys))
+    }
+}
+
+impl RideAuthenticator for HmacKeys {
+    fn verify(&self, imei: &str, item: &HashMap<String, AttributeValue>) -> Option<bool> {
+        let key = self.0.get(imei)?;
+        let number = |name: &str| item.get(name).and_then(|v| v.as_n().ok()).map(String::as_str).unwrap_or_default();
+        let distance = item.get("ride_stats").and_then(|v| v.as_m().ok())
+            .and_then(|stats| stats.get("ride_distance"))
+            .and_then(|v| v.as_s().ok())
+            .map(String::as_str)
+            .unwrap_or_default();
+        let Some(signature) = item.get("signature").and_then(|v| v.as_s().ok()).and_then(|s| hex::decode(s).ok()) else {
+            return Some(false);
+        };
+
+        let mut mac = Hmac::<Sha256>::new_from_slice(key).ok()?;
+        mac.update(format!("{}:{}:{}:{}", imei, number("ride_start"), number("ride_end"), distance).as_bytes());
+        Some(mac.verify_slice(&signature).is_ok())
+    }
+}
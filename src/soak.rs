@@ -0,0 +1,136 @@
+//! Soak mode (`RIDE_DATA_MODE=soak`) for capacity and memory-leak testing before month-end runs: the
+//! container sends itself synthetic aggregation requests at `SOAK_REQUESTS_PER_MINUTE` (default 6),
+//! each for 1–3 IMEIs drawn from `SOAK_IMEIS` and one of the last `SOAK_MONTHS` months (default 3),
+//! until `SOAK_DURATION_SECS` has passed (default: until stopped). Requests go through the full
+//! event path and write, so soak mode refuses to start with `ENVIRONMENT=prod`.
+
+use aws_config::SdkConfig;
+use chrono::{Months, Utc};
+use lambda_runtime::Error;
+use serde_json::{json, Value};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{error, info, info_span, warn, Instrument};
+
+use crate::config::{self, Config, Environment};
+use crate::time;
+
+const SUMMARY_EVERY: u64 = 60;
+
+#[derive(Debug, Clone)]
+struct Settings {
+    imeis: Vec<String>,
+    interval: Duration,
+    months: u32,
+    duration: Option<Duration>,
+}
+
+impl Settings {
+    fn from_config(config: &Config) -> Settings {
+        Settings {
+            imeis: config.soak_imeis.clone(),
+            interval: Duration::from_secs_f64(60.0 / config.soak_requests_per_minute),
+            months: config.soak_months,
+            duration: config.soak_duration,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    sent: AtomicU64,
+    failed: AtomicU64,
+    rejected: AtomicU64,
+    in_flight: AtomicU64,
+}
+
+/// Checked by `config::validate` when `RIDE_DATA_MODE=soak`.
+pub fn validate(problems: &mut Vec<String>, config: &Config) {
+    if config.environment == Environment::Prod {
+        problems.push("RIDE_DATA_MODE=soak cannot run with ENVIRONMENT=prod".to_string());
+    }
+    if config.soak_imeis.is_empty() {
+        problems.push("RIDE_DATA_MODE=soak needs SOAK_IMEIS".to_string());
+    }
+}
+
+/// Runs until `SOAK_DURATION_SECS` passes or the process is interrupted, then waits for requests in flight.
+pub async fn run(shared_config: SdkConfig) -> Result<(), Error> {
+    let settings = Settings::from_config(config::get());
+    warn!(imeis = settings.imeis.len(), interval_ms = settings.interval.as_millis() as u64, "Soak mode started");
+
+    let started = Instant::now();
+    let counters = Arc::new(Counters::default());
+    let mut ticks = tokio::time::interval(settings.interval);
+    let mut requests = tokio::task::JoinSet::new();
+    loop {
+        tokio::select! {
+            _ = ticks.tick() => {}
+            _ = tokio::signal::ctrl_c() => break,
+        }
+        if settings.duration.is_some_and(|duration| started.elapsed() >= duration) {
+            break;
+        }
+        while requests.try_join_next().is_some() {}
+
+        // The first summary is the baseline memory before any request.
+        if counters.sent.fetch_add(1, Ordering::Relaxed) % SUMMARY_EVERY == 0 {
+            summary(&counters, started);
+        }
+        let event = synthetic_event(&settings);
+        let run_id = uuid::Uuid::new_v4().to_string();
+        let span = info_span!("request", request_id = %run_id);
+        let (shared_config, counters) = (shared_config.clone(), Arc::clone(&counters));
+        requests.spawn(async move { send(&shared_config, event, &run_id, &counters).await }.instrument(span));
+    }
+
+    while requests.join_next().await.is_some() {}
+    summary(&counters, started);
+    Ok(())
+}
+
+fn synthetic_event(settings: &Settings) -> Value {
+    let mut imeis = settings.imeis.clone();
+    fastrand::shuffle(&mut imeis);
+    imeis.truncate(fastrand::usize(1..=3.min(imeis.len())));
+    let today = Utc::now().with_timezone(&time::offset()).date_naive();
+    let month = today - Months::new(fastrand::u32(0..settings.months.max(1)));
+    json!({ "imeis": imeis, "input_ride_month": month.format("%Y-%m").to_string() })
+}
+
+async fn send(shared_config: &SdkConfig, event: Value, run_id: &str, counters: &Counters) {
+    counters.in_flight.fetch_add(1, Ordering::Relaxed);
+    let started = Instant::now();
+    match crate::handle_event(shared_config, event, run_id).await {
+        Ok(response) if response.get("error").is_some() => {
+            counters.rejected.fetch_add(1, Ordering::Relaxed);
+            warn!(elapsed_ms = started.elapsed().as_millis() as u64, "Soak request rejected: {}", response["error"]);
+        }
+        Ok(_) => info!(elapsed_ms = started.elapsed().as_millis() as u64, "Soak request done"),
+        Err(err) => {
+            counters.failed.fetch_add(1, Ordering::Relaxed);
+            error!(elapsed_ms = started.elapsed().as_millis() as u64, "Soak request failed: {:?}", err);
+        }
+    }
+    counters.in_flight.fetch_sub(1, Ordering::Relaxed);
+}
+
+fn summary(counters: &Counters, started: Instant) {
+    info!(
+        sent = counters.sent.load(Ordering::Relaxed),
+        failed = counters.failed.load(Ordering::Relaxed),
+        rejected = counters.rejected.load(Ordering::Relaxed),
+        in_flight = counters.in_flight.load(Ordering::Relaxed),
+        rss_kb = resident_kb(),
+        elapsed_secs = started.elapsed().as_secs(),
+        "Soak progress",
+    );
+}
+
+/// Resident set size from `/proc/self/statm`, for spotting leaks over a long soak; None off Linux.
+fn resident_kb() -> Option<u64> {
+    let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    Some(pages * 4)
+}
@@ -0,0 +1,55 @@
+//! Build and cold-start metadata reported in each response's `diagnostics.runtime` and in the audit
+//! log, so cold-start regressions after dependency changes show up in production data.
+
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+use utoipa::ToSchema;
+
+static PROCESS_STARTED: OnceLock<Instant> = OnceLock::new();
+static INIT: OnceLock<Duration> = OnceLock::new();
+static COLD: AtomicBool = AtomicBool::new(true);
+
+#[derive(Debug, Clone, Serialize, JsonSchema, ToSchema)]
+pub struct RuntimeInfo {
+    pub version: &'static str,
+    /// Commit the binary was built from, or `unknown`.
+    pub build_commit: &'static str,
+    pub target_arch: &'static str,
+    pub target_os: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub binary_bytes: Option<u64>,
+    /// From process start until the runtime was ready for its first event.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub init_ms: Option<u64>,
+    /// The first request this process handled.
+    pub cold_start: bool,
+}
+
+/// Called first thing in `main`.
+pub fn process_started() {
+    PROCESS_STARTED.get_or_init(Instant::now);
+}
+
+/// Called once configuration and logging are ready, just before serving events.
+pub fn init_done() {
+    if let Some(started) = PROCESS_STARTED.get() {
+        INIT.get_or_init(|| started.elapsed());
+    }
+}
+
+/// Metadata for the request being handled; only the first call in a process reports `cold_start`.
+pub fn runtime_info() -> RuntimeInfo {
+    static BINARY_BYTES: OnceLock<Option<u64>> = OnceLock::new();
+    RuntimeInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        build_commit: env!("BUILD_COMMIT"),
+        target_arch: std::env::consts::ARCH,
+        target_os: std::env::consts::OS,
+        binary_bytes: *BINARY_BYTES.get_or_init(|| std::fs::metadata(std::env::current_exe().ok()?).ok().map(|m| m.len())),
+        init_ms: INIT.get().map(|init| init.as_millis() as u64),
+        cold_start: COLD.swap(false, Ordering::Relaxed),
+    }
+}
@@ -0,0 +1,247 @@
+use aws_sdk_dynamodb::types::AttributeValue;
+use rust_decimal::prelude::{FromPrimitive, ToPrimitive};
+use rust_decimal::Decimal;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use utoipa::ToSchema;
+
+use crate::experiments;
+use crate::fraud::FraudFlag;
+use crate::ride::Exclusion;
+
+/// Optional dimension to split each month's totals by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Serialize, JsonSchema, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Breakdown {
+    Firmware,
+    /// Sync channel the ride arrived through (BLE, cellular, manual upload).
+    Source,
+    /// The ride's `ride_type`, for splitting totals over several included types.
+    RideType,
+}
+
+impl Breakdown {
+    /// Ride attribute holding the dimension value.
+    pub fn attribute(self) -> &'static str {
+        match self {
+            Breakdown::Firmware => "firmware_version",
+            Breakdown::Source => "source",
+            Breakdown::RideType => "ride_type",
+        }
+    }
+}
+
+/// What to report per device-month.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, JsonSchema, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Metric {
+    /// Always reported.
+    Distance,
+    /// Alone, answered by counting rides in DynamoDB without reading them.
+    RideCount,
+    Duration,
+    /// Average and max speed.
+    Speed,
+    /// Total `ride_stats.energy_wh`.
+    Energy,
+    /// Rides per distance band.
+    Histogram,
+}
+
+/// Reported when a request names no `metrics`; energy and the histogram are opt-in.
+pub const DEFAULT_METRICS: [Metric; 4] = [Metric::Distance, Metric::RideCount, Metric::Duration, Metric::Speed];
+
+/// Upper bounds (km, exclusive) of the histogram's distance bands.
+const DISTANCE_BANDS: [(f64, &str); 5] = [(1.0, "0-1"), (5.0, "1-5"), (10.0, "5-10"), (25.0, "10-25"), (f64::INFINITY, "25+")];
+
+/// `ride_stats` entries the metrics read, as projection paths.
+pub fn ride_stats_projection(metrics: &[Metric]) -> String {
+    let mut paths = vec!["ride_stats.ride_distance"];
+    if metrics.contains(&Metric::Duration) || metrics.contains(&Metric::Speed) || !experiments::enabled().is_empty() {
+        paths.push("ride_stats.ride_duration");
+    }
+    if metrics.contains(&Metric::Speed) {
+        paths.push("ride_stats.max_speed");
+    }
+    if metrics.contains(&Metric::Energy) {
+        paths.push("ride_stats.energy_wh");
+    }
+    paths.join(", ")
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize, JsonSchema, ToSchema)]
+pub struct DimensionStats {
+    pub total_distance: f64,
+    pub rides: u64,
+}
+
+pub type Breakdowns = BTreeMap<Breakdown, BTreeMap<String, DimensionStats>>;
+
+/// How many raw rides went into (or were kept out of) one device-month total.
+#[derive(Debug, Clone, Default, Deserialize, Serialize, JsonSchema, ToSchema)]
+pub struct RowExplain {
+    pub rides_included: u64,
+    pub excluded_by_type: u64,
+    /// Rides whose `ride_stats` or distance could not be read.
+    pub parse_failures: u64,
+    pub voided_rides: u64,
+    /// Rides dropped because they overlap a longer ride of the same device.
+    pub overlapping_rides: u64,
+    /// Rides dropped for a negative or non-finite distance.
+    pub outlier_rides: u64,
+    /// Rides whose device signature did not verify, whether excluded or only flagged.
+    pub unverified_rides: u64,
+    /// Rides counted the legacy pipeline's way, with `legacy_compat`.
+    #[serde(skip_serializing_if = "is_zero")]
+    pub legacy_rides: u64,
+    /// Rides dropped for starting beyond the clock-skew tolerance.
+    #[serde(skip_serializing_if = "is_zero")]
+    pub future_rides: u64,
+    /// Rides dropped for a distance above `MAX_RIDE_DISTANCE_KM`.
+    #[serde(skip_serializing_if = "is_zero")]
+    pub implausible_rides: u64,
+}
+
+impl RowExplain {
+    pub fn add(&mut self, other: &RowExplain) {
+        self.rides_included += other.rides_included;
+        self.excluded_by_type += other.excluded_by_type;
+        self.parse_failures += other.parse_failures;
+        self.voided_rides += other.voided_rides;
+        self.overlapping_rides += other.overlapping_rides;
+        self.outlier_rides += other.outlier_rides;
+        self.unverified_rides += other.unverified_rides;
+        self.legacy_rides += other.legacy_rides;
+        self.future_rides += other.future_rides;
+        self.implausible_rides += other.implausible_rides;
+    }
+}
+
+fn is_zero(count: &u64) -> bool {
+    *count == 0
+}
+
+/// Running totals for one device-month.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct MonthStats {
+    /// km, summed in decimal so long months do not drift.
+    pub distance: f64,
+    km: Decimal,
+    /// Seconds of `ride_stats.ride_duration` over the rides that recorded one.
+    pub duration: f64,
+    /// Distance of the rides that recorded a duration, which the average speed is taken over.
+    pub timed_distance: f64,
+    /// Highest `ride_stats.max_speed` (km/h).
+    pub max_speed: Option<f64>,
+    /// Watt-hours of `ride_stats.energy_wh`.
+    pub energy: f64,
+    /// Rides per distance band, with [`Metric::Histogram`].
+    pub histogram: BTreeMap<String, u64>,
+    pub breakdowns: Breakdowns,
+    pub explain: RowExplain,
+    pub fraud_flags: Vec<FraudFlag>,
+    /// Totals of the enabled [`experiments`], by `exp.` attribute.
+    #[serde(default)]
+    pub experimental: BTreeMap<String, f64>,
+    /// `ride_start` of each counted ride.
+    #[serde(default)]
+    pub ride_starts: BTreeSet<u64>,
+}
+
+impl MonthStats {
+    pub fn record_exclusion(&mut self, exclusion: Exclusion) {
+        match exclusion {
+            Exclusion::Voided => self.explain.voided_rides += 1,
+            Exclusion::NotTrip => self.explain.excluded_by_type += 1,
+            Exclusion::MissingStats | Exclusion::InvalidDistance => self.explain.parse_failures += 1,
+            Exclusion::Overlapping => self.explain.overlapping_rides += 1,
+            Exclusion::Outlier => self.explain.outlier_rides += 1,
+            Exclusion::InvalidSignature => self.explain.unverified_rides += 1,
+            Exclusion::ClockSkew => self.explain.future_rides += 1,
+            Exclusion::ImplausibleDistance => self.explain.implausible_rides += 1,
+            _ => {}
+        }
+    }
+
+    /// Folds in the same period's totals from another source, e.g. a device's earlier IMEI.
+    pub fn merge(&mut self, other: MonthStats) {
+        self.add_distance(Decimal::from_f64(other.distance).unwrap_or_default());
+        self.duration += other.duration;
+        self.timed_distance += other.timed_distance;
+        self.max_speed = max_speed(self.max_speed, other.max_speed);
+        self.energy += other.energy;
+        for (band, rides) in other.histogram {
+            *self.histogram.entry(band).or_default() += rides;
+        }
+        for (breakdown, values) in other.breakdowns {
+            for (value, stats) in values {
+                let merged = self.breakdowns.entry(breakdown).or_default().entry(value).or_default();
+                merged.total_distance += stats.total_distance;
+                merged.rides += stats.rides;
+            }
+        }
+        self.explain.add(&other.explain);
+        self.fraud_flags.extend(other.fraud_flags);
+        experiments::add(&mut self.experimental, &other.experimental);
+        self.ride_starts.extend(other.ride_starts);
+    }
+
+    pub fn add_ride(&mut self, item: &HashMap<String, AttributeValue>, distance: f64, breakdowns: &[Breakdown], metrics: &[Metric]) {
+        self.add_distance(Decimal::from_f64(distance).unwrap_or_default());
+        self.explain.rides_included += 1;
+        experiments::record(&mut self.experimental, item, distance);
+        if metrics.contains(&Metric::Duration) || metrics.contains(&Metric::Speed) {
+            if let Some(duration) = ride_stat(item, "ride_duration") {
+                self.duration += duration;
+                self.timed_distance += distance;
+            }
+        }
+        if metrics.contains(&Metric::Speed) {
+            self.max_speed = max_speed(self.max_speed, ride_stat(item, "max_speed"));
+        }
+        if metrics.contains(&Metric::Energy) {
+            self.energy += ride_stat(item, "energy_wh").unwrap_or(0.0);
+        }
+        if metrics.contains(&Metric::Histogram) {
+            if let Some((_, band)) = DISTANCE_BANDS.iter().find(|(below, _)| distance < *below) {
+                *self.histogram.entry(band.to_string()).or_default() += 1;
+            }
+        }
+        for breakdown in breakdowns {
+            let value = item.get(breakdown.attribute())
+                .and_then(|v| v.as_s().ok())
+                .cloned()
+                .unwrap_or_else(|| "unknown".to_string());
+            let stats = self.breakdowns.entry(*breakdown).or_default().entry(value).or_default();
+            stats.total_distance += distance;
+            stats.rides += 1;
+        }
+    }
+
+    fn add_distance(&mut self, km: Decimal) {
+        self.km += km;
+        self.distance = self.km.to_f64().unwrap_or(self.distance);
+    }
+
+    /// km/h over the rides that recorded a duration.
+    pub fn average_speed(&self) -> Option<f64> {
+        (self.duration > 0.0).then(|| self.timed_distance / (self.duration / 3600.0))
+    }
+}
+
+/// A non-negative number from a ride's `ride_stats`, stored as a string like `ride_distance` or as a number.
+pub fn ride_stat(item: &HashMap<String, AttributeValue>, name: &str) -> Option<f64> {
+    let value = match item.get("ride_stats")?.as_m().ok()?.get(name)? {
+        AttributeValue::S(value) | AttributeValue::N(value) => value.parse::<f64>().ok()?,
+        _ => return None,
+    };
+    (value.is_finite() && value >= 0.0).then_some(value)
+}
+
+fn max_speed(a: Option<f64>, b: Option<f64>) -> Option<f64> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.max(b)),
+        (a, b) => a.or(b),
+    }
+}
@@ -0,0 +1,307 @@
+//! Where rides are read from and aggregate rows written to: DynamoDB in the Lambda, an in-memory
+//! store in the tests, so the aggregation itself runs without AWS. The side tables a run also uses
+//! (aliases, checkpoints, caches, rollups, revisions) are only used through a store backed by
+//! DynamoDB.
+
+use aws_sdk_dynamodb::error::SdkError;
+use aws_sdk_dynamodb::operation::query::QueryError;
+use aws_sdk_dynamodb::Client;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+
+use crate::aggregates::{self, PutOutcome, WriteOptions};
+use crate::cost::CapacityMeter;
+use crate::pagination::Item;
+use crate::CustomOutput;
+
+pub trait RideStore: Sync {
+    /// An IMEI's rides, only those starting within `ride_starts` (epoch seconds, `[from, to)`) if
+    /// given, and only those of a `ride_types` type if any; at most `limit` items.
+    fn query_rides(
+        &self,
+        imei: &str,
+        ride_starts: Option<(i64, i64)>,
+        ride_types: &[String],
+        limit: Option<usize>,
+        projection: &str,
+        meter: &CapacityMeter,
+    ) -> impl Future<Output = Result<Vec<Item>, SdkError<QueryError>>> + Send;
+
+    /// Writes the rows, with one outcome per row in order.
+    fn put_rows(
+        &self,
+        rows: &[&CustomOutput],
+        options: &WriteOptions<'_>,
+        meter: &CapacityMeter,
+    ) -> impl Future<Output = anyhow::Result<Vec<PutOutcome>>> + Send;
+
+    /// The client of the DynamoDB tables behind the store, if it has any.
+    fn dynamodb(&self) -> Option<&Client>;
+}
+
+impl RideStore for Client {
+    async fn query_rides(
+        &self,
+        imei: &str,
+        ride_starts: Option<(i64, i64)>,
+        ride_types: &[String],
+        limit: Option<usize>,
+        projection: &str,
+        meter: &CapacityMeter,
+    ) -> Result<Vec<Item>, SdkError<QueryError>> {
+        crate::query_ride_new(self, imei, ride_starts, ride_types, limit, projection, meter).await
+    }
+
+    async fn put_rows(&self, rows: &[&CustomOutput], options: &WriteOptions<'_>, meter: &CapacityMeter) -> anyhow::Result<Vec<PutOutcome>> {
+        aggregates::put_rows(self, rows, options, meter).await
+    }
+
+    fn dynamodb(&self) -> Option<&Client> {
+        Some(self)
+    }
+}
+
+/// Rides already read for many devices (through the date index or a scan), served to each
+/// device's aggregation as its own query's would be; rows are written to DynamoDB.
+pub struct MonthRides<'a> {
+    client: &'a Client,
+    rides: HashMap<String, Vec<Item>>,
+}
+
+impl<'a> MonthRides<'a> {
+    /// Keeps the rides of `identities`, in `ride_start` order like a query's.
+    pub fn new(client: &'a Client, items: Vec<Item>, identities: &[String]) -> MonthRides<'a> {
+        let wanted: HashSet<&str> = identities.iter().map(String::as_str).collect();
+        let mut rides: HashMap<String, Vec<Item>> = HashMap::new();
+        for item in items {
+            let Some(imei) = item.get("imei").and_then(|imei| imei.as_s().ok()).filter(|imei| wanted.contains(imei.as_str())) else {
+                continue;
+            };
+            rides.entry(imei.clone()).or_default().push(item);
+        }
+        for items in rides.values_mut() {
+            items.sort_by_key(|item| item.get("ride_start").and_then(|start| start.as_n().ok()?.parse::<i64>().ok()));
+        }
+        MonthRides { client, rides }
+    }
+}
+
+impl RideStore for MonthRides<'_> {
+    async fn query_rides(
+        &self,
+        imei: &str,
+        _ride_starts: Option<(i64, i64)>,
+        _ride_types: &[String],
+        _limit: Option<usize>,
+        _projection: &str,
+        _meter: &CapacityMeter,
+    ) -> Result<Vec<Item>, SdkError<QueryError>> {
+        Ok(self.rides.get(imei).cloned().unwrap_or_default())
+    }
+
+    async fn put_rows(&self, rows: &[&CustomOutput], options: &WriteOptions<'_>, meter: &CapacityMeter) -> anyhow::Result<Vec<PutOutcome>> {
+        aggregates::put_rows(self.client, rows, options, meter).await
+    }
+
+    fn dynamodb(&self) -> Option<&Client> {
+        Some(self.client)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aws_sdk_dynamodb::types::AttributeValue;
+    use chrono::{TimeZone, Utc};
+
+    use std::collections::BTreeSet;
+    use std::sync::Mutex;
+
+    use crate::clock::Clock;
+    use crate::ride::RideTypes;
+    use crate::{aggregate_ride_data, corrections, monthly_stats, time, Action, CustomEvent, StatsQuery};
+
+    /// Rides per IMEI; written rows are kept in `written`.
+    struct MemoryStore {
+        rides: HashMap<String, Vec<Item>>,
+        written: Mutex<Vec<CustomOutput>>,
+    }
+
+    impl MemoryStore {
+        fn with_rides(imei: &str, rides: &[(i64, f64)]) -> MemoryStore {
+            let items = rides.iter().map(|&(ride_start, distance)| trip(imei, ride_start, distance)).collect();
+            MemoryStore { rides: HashMap::from([(imei.to_string(), items)]), written: Mutex::new(Vec::new()) }
+        }
+    }
+
+    impl RideStore for MemoryStore {
+        async fn query_rides(
+            &self,
+            imei: &str,
+            ride_starts: Option<(i64, i64)>,
+            _ride_types: &[String],
+            limit: Option<usize>,
+            _projection: &str,
+            _meter: &CapacityMeter,
+        ) -> Result<Vec<Item>, SdkError<QueryError>> {
+            let start = |item: &Item| item["ride_start"].as_n().unwrap().parse::<i64>().unwrap();
+            Ok(self.rides.get(imei).into_iter().flatten()
+                .filter(|item| ride_starts.is_none_or(|(from, to)| (from..to).contains(&start(item))))
+                .take(limit.unwrap_or(usize::MAX))
+                .cloned()
+                .collect())
+        }
+
+        async fn put_rows(&self, rows: &[&CustomOutput], _options: &WriteOptions<'_>, _meter: &CapacityMeter) -> anyhow::Result<Vec<PutOutcome>> {
+            self.written.lock().unwrap().extend(rows.iter().map(|row| (*row).clone()));
+            Ok(rows.iter().map(|_| PutOutcome::default()).collect())
+        }
+
+        fn dynamodb(&self) -> Option<&Client> {
+            None
+        }
+    }
+
+    fn trip(imei: &str, ride_start: i64, distance: f64) -> Item {
+        HashMap::from([
+            ("imei".to_string(), AttributeValue::S(imei.to_string())),
+            ("ride_start".to_string(), AttributeValue::N(ride_start.to_string())),
+            ("ride_type".to_string(), AttributeValue::S("trip".to_string())),
+            ("ride_stats".to_string(), AttributeValue::M(HashMap::from([
+                ("ride_distance".to_string(), AttributeValue::S(distance.to_string())),
+            ]))),
+        ])
+    }
+
+    fn utc(y: i32, m: u32, d: u32, h: u32, min: u32) -> i64 {
+        Utc.with_ymd_and_hms(y, m, d, h, min, 0).unwrap().timestamp()
+    }
+
+    fn query<'a>(input_ride_month: Option<&'a str>, ride_types: &'a RideTypes) -> StatsQuery<'a> {
+        StatsQuery {
+            input_ride_month,
+            date_range: None,
+            granularity: time::Granularity::Monthly,
+            breakdowns: &[],
+            max_rides: None,
+            now: Utc.with_ymd_and_hms(2024, 6, 1, 0, 0, 0).unwrap(),
+            signatures: None,
+            fraud_checks: false,
+            legacy_compat: None,
+            ride_types,
+            type_filter: &[],
+            metrics: &[],
+        }
+    }
+
+    #[tokio::test]
+    async fn buckets_rides_by_ist_month() {
+        // 23:50 and 00:10 IST on either side of April 1st.
+        let store = MemoryStore::with_rides("123", &[(utc(2024, 3, 31, 18, 20), 2.0), (utc(2024, 3, 31, 18, 40), 3.0)]);
+        let stats = monthly_stats(&store, "123", &query(None, &RideTypes::default()), &CapacityMeter::default()).await.unwrap();
+        assert_eq!(stats.months["2024-03"].distance, 2.0);
+        assert_eq!(stats.months["2024-04"].distance, 3.0);
+    }
+
+    #[tokio::test]
+    async fn only_counts_the_requested_month() {
+        let rides = [(utc(2024, 2, 15, 6, 0), 1.0), (utc(2024, 3, 15, 6, 0), 2.0), (utc(2024, 4, 15, 6, 0), 4.0)];
+        let store = MemoryStore::with_rides("123", &rides);
+        let stats = monthly_stats(&store, "123", &query(Some("2024-03"), &RideTypes::default()), &CapacityMeter::default()).await.unwrap();
+        assert_eq!(stats.months.len(), 1);
+        assert_eq!(stats.months["2024-03"].distance, 2.0);
+        assert_eq!(stats.items_read, 1);
+    }
+
+    #[tokio::test]
+    async fn sums_distances_within_a_month() {
+        let rides = [(utc(2024, 3, 1, 6, 0), 1.25), (utc(2024, 3, 10, 6, 0), 2.5), (utc(2024, 3, 20, 6, 0), 4.0)];
+        let store = MemoryStore::with_rides("123", &rides);
+        let stats = monthly_stats(&store, "123", &query(None, &RideTypes::default()), &CapacityMeter::default()).await.unwrap();
+        let march = &stats.months["2024-03"];
+        assert_eq!(march.explain.rides_included, 3);
+        assert!((march.distance - 7.75).abs() < 1e-9);
+    }
+
+    #[tokio::test]
+    async fn excludes_rides_beyond_the_clock_skew_tolerance() {
+        // The query's now is 2024-06-01 00:00 UTC; the default tolerance is five minutes.
+        let rides = [(utc(2024, 5, 31, 23, 58), 1.0), (utc(2024, 6, 1, 0, 4), 2.0), (utc(2024, 6, 1, 3, 0), 4.0)];
+        let store = MemoryStore::with_rides("123", &rides);
+        let stats = monthly_stats(&store, "123", &query(None, &RideTypes::default()), &CapacityMeter::default()).await.unwrap();
+        assert_eq!(stats.months["2024-06"].distance, 3.0);
+        assert_eq!(stats.months["2024-06"].explain.future_rides, 1);
+        assert!(stats.warnings.iter().any(|warning| warning.code() == "CLOCK_SKEW"));
+    }
+
+    fn totals(rows: &[CustomOutput]) -> Vec<(String, String, f64)> {
+        rows.iter().map(|row| (row.imei.clone(), row.ride_month.clone(), row.total_distance)).collect()
+    }
+
+    #[tokio::test]
+    async fn aggregates_and_writes_each_device_month() {
+        let mut store = MemoryStore::with_rides("111", &[(utc(2024, 4, 2, 6, 0), 2.0), (utc(2024, 4, 20, 6, 0), 3.5), (utc(2024, 5, 1, 6, 0), 1.0)]);
+        store.rides.insert("222".to_string(), vec![trip("222", utc(2024, 4, 3, 6, 0), 4.0)]);
+        let payload = CustomEvent { imeis: vec!["111".to_string(), "222".to_string()], input_ride_month: Some("2024-04".to_string()), ..Default::default() };
+        let clock = Clock::resolve(Some("2024-06-01T00:00:00Z")).unwrap();
+        let aggregation = aggregate_ride_data(&store, &payload, &payload.imeis, "run", &clock, None, &CapacityMeter::default()).await.unwrap();
+
+        let expected = [("111", "2024-04", 5.5), ("222", "2024-04", 4.0)].map(|(imei, month, km)| (imei.to_string(), month.to_string(), km));
+        assert_eq!(totals(&aggregation.rows), expected);
+        assert_eq!(aggregation.rows[0].applied_rides, BTreeSet::from([utc(2024, 4, 2, 6, 0) as u64, utc(2024, 4, 20, 6, 0) as u64]));
+        assert!(aggregation.rows.iter().all(|row| row.write_skipped.is_none() && !row.month_to_date));
+        let mut written = store.written.lock().unwrap().clone();
+        written.sort_by(|a, b| a.imei.cmp(&b.imei));
+        assert_eq!(totals(&written), expected);
+    }
+
+    #[tokio::test]
+    async fn weeks_across_the_end_of_a_month_scoped_run_are_not_written() {
+        let store = MemoryStore::with_rides("111", &[(utc(2024, 4, 10, 6, 0), 2.0), (utc(2024, 4, 29, 6, 0), 3.0)]);
+        let payload = CustomEvent {
+            imeis: vec!["111".to_string()],
+            input_ride_month: Some("2024-04".to_string()),
+            granularity: Some(time::Granularity::Weekly),
+            ..Default::default()
+        };
+        let clock = Clock::resolve(Some("2024-06-01T00:00:00Z")).unwrap();
+        let aggregation = aggregate_ride_data(&store, &payload, &payload.imeis, "run", &clock, None, &CapacityMeter::default()).await.unwrap();
+
+        let skipped: Vec<(&str, Option<aggregates::SkipReason>)> = aggregation.rows.iter().map(|row| (row.ride_month.as_str(), row.write_skipped)).collect();
+        assert_eq!(skipped, [("2024-04-08", None), ("2024-04-29", Some(aggregates::SkipReason::PartialPeriod))]);
+        assert_eq!(totals(&store.written.lock().unwrap()), [("111".to_string(), "2024-04-08".to_string(), 2.0)]);
+    }
+
+    #[tokio::test]
+    async fn a_dry_run_writes_nothing() {
+        let store = MemoryStore::with_rides("111", &[(utc(2024, 4, 2, 6, 0), 2.0)]);
+        let payload = CustomEvent { imeis: vec!["111".to_string()], input_ride_month: Some("2024-04".to_string()), dry_run: Some(true), ..Default::default() };
+        let clock = Clock::resolve(Some("2024-06-01T00:00:00Z")).unwrap();
+        let aggregation = aggregate_ride_data(&store, &payload, &payload.imeis, "run", &clock, None, &CapacityMeter::default()).await.unwrap();
+
+        assert_eq!(aggregation.rows[0].write_skipped, Some(aggregates::SkipReason::DryRun));
+        assert_eq!(aggregation.write_plan.len(), 1);
+        assert!(store.written.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_voided_ride_is_recounted_out_of_every_stat() {
+        let mut store = MemoryStore::with_rides("111", &[(utc(2024, 4, 2, 6, 0), 2.0), (utc(2024, 4, 20, 6, 0), 3.0)]);
+        let rides = store.rides.get_mut("111").unwrap();
+        for (ride, seconds) in rides.iter_mut().zip(["600", "1800"]) {
+            let Some(AttributeValue::M(stats)) = ride.get_mut("ride_stats") else { unreachable!() };
+            stats.insert("ride_duration".to_string(), AttributeValue::S(seconds.to_string()));
+        }
+        rides[1].insert("deleted".to_string(), AttributeValue::Bool(true));
+        let correction = corrections::RideCorrection { ride_id: "r2".to_string(), imei: "111".to_string(), ride_start: utc(2024, 4, 20, 6, 0) as u64, old_distance: 3.0, new_distance: 0.0 };
+        let payload = CustomEvent { action: Action::RideVoided, correction: Some(correction.clone()), ..Default::default() };
+        let clock = Clock::resolve(Some("2024-06-01T00:00:00Z")).unwrap();
+        let outcome = corrections::recount_month(&store, &payload, &correction, "2024-04", "run", &clock).await.unwrap();
+
+        assert_eq!(outcome, aggregates::AdjustOutcome::Applied { total_distance: 2.0 });
+        let written = store.written.lock().unwrap();
+        assert_eq!(totals(&written), [("111".to_string(), "2024-04".to_string(), 2.0)]);
+        assert_eq!((written[0].ride_count, written[0].total_duration, written[0].average_speed), (Some(1), Some(600.0), Some(12.0)));
+        assert_eq!(written[0].explain.voided_rides, 1);
+    }
+}
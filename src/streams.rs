@@ -0,0 +1,399 @@
+//! Incremental aggregation from the ride table's DynamoDB Stream: each new ride (an `INSERT`
+//! record) that counts is added to its device's monthly row with an atomic `ADD`, instead of
+//! re-aggregating the month. Only `trip` rides and monthly rows are handled; daily and weekly rows
+//! and the other metrics are left to the next full aggregation.
+//!
+//! Stream records are delivered at least once, so the event source mapping must enable
+//! `ReportBatchItemFailures`: on the first failure the rest of the batch is reported as failed and
+//! retried from there. A redelivered record is not added twice: each row lists the rides (by
+//! `ride_start`) added to it in `applied_rides`, and the `ADD` is conditional on the ride not being
+//! listed. A full aggregation that replaces the row replaces the list with the rides it counted,
+//! so a ride it already counted is not added again either. Each increment also moves the `FLEET#*`
+//! rollup by its distance.
+//!
+//! With `STREAM_FLUSH_SECS` set, a batch's increments are instead buffered, one per device-month,
+//! and written once the oldest is that many seconds old or `STREAM_FLUSH_RECORDS` records (default
+//! 1000) are buffered, and before the batch is answered. Nothing buffered outlives the invocation: a
+//! record is only acknowledged once its increment is written, and after a failed write the records
+//! from the earliest one still buffered are reported as failed, so Lambda redelivers them.
+//!
+//! Records for a ride already applied in the last `STREAM_DEDUPE_SECS` seconds (default 300, 0 to
+//! turn it off) are dropped before any write, so duplicates delivered across shards to the same
+//! environment cost nothing. The window remembers up to `STREAM_DEDUPE_CAPACITY` rides (default
+//! 10000), dropping the oldest first; a ride is only remembered once applied or buffered, so a
+//! record that failed is still applied when it is retried.
+//!
+//! With `AGGREGATE_CACHE=true`, every device-month a record inserts, modifies or removes a ride of
+//! is also stamped as changed, so cached totals for it are recomputed.
+
+use aws_sdk_dynamodb::types::AttributeValue;
+use aws_sdk_dynamodb::Client;
+use chrono::SecondsFormat;
+use lambda_runtime::Error;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{error, info, warn};
+
+use crate::aggregates::{self, AdjustOutcome};
+use crate::clock::Clock;
+use crate::pagination::Item;
+use crate::ride::{self, RideTypes};
+use crate::{anomalies, cache, config, normalize, retries, time};
+
+/// When buffered increments are written.
+#[derive(Debug, Clone, Copy)]
+pub struct FlushPolicy {
+    every: Duration,
+    records: usize,
+}
+
+impl FlushPolicy {
+    /// None, writing each record as it arrives, unless `STREAM_FLUSH_SECS` is set.
+    pub fn from_env() -> Option<FlushPolicy> {
+        let config = config::get();
+        Some(FlushPolicy { every: config.stream_flush_every?, records: config.stream_flush_records })
+    }
+}
+
+struct Increment {
+    /// Distance per ride id, so a ride redelivered before the flush is buffered once.
+    rides: BTreeMap<String, f64>,
+    month_to_date: bool,
+    as_of: String,
+    /// Position in the batch of the first record buffered here, retried from if the write fails.
+    first_record: usize,
+}
+
+/// The batch's increments not yet written, per `(imei, ride_month)`.
+#[derive(Default)]
+struct Buffer {
+    increments: BTreeMap<(String, String), Increment>,
+    records: usize,
+    since: Option<Instant>,
+}
+
+static DEDUPE: LazyLock<Mutex<Dedupe>> = LazyLock::new(|| Mutex::new(Dedupe::from_env()));
+
+/// Rides applied recently, by `(imei, ride_start)`, each remembered for `window`.
+pub struct Dedupe {
+    pub window: Duration,
+    pub capacity: usize,
+    seen: HashMap<(String, u64), Instant>,
+    order: VecDeque<((String, u64), Instant)>,
+}
+
+impl Dedupe {
+    pub fn from_env() -> Dedupe {
+        Dedupe::new(config::get().stream_dedupe_window, config::get().stream_dedupe_capacity)
+    }
+
+    fn new(window: Duration, capacity: usize) -> Dedupe {
+        Dedupe { window, capacity, seen: HashMap::new(), order: VecDeque::new() }
+    }
+
+    /// Whether the ride was remembered within the window at `now`.
+    fn contains(&mut self, key: &(String, u64), now: Instant) -> bool {
+        self.evict(now);
+        self.seen.contains_key(key)
+    }
+
+    fn remember(&mut self, key: (String, u64), now: Instant) {
+        if self.window.is_zero() {
+            return;
+        }
+        self.seen.insert(key.clone(), now);
+        self.order.push_back((key, now));
+        self.evict(now);
+    }
+
+    /// Forgets rides older than the window, then the oldest beyond the capacity. An entry in `order`
+    /// superseded by a later `remember` of the same ride leaves that one alone.
+    fn evict(&mut self, now: Instant) {
+        while let Some((key, at)) = self.order.front() {
+            if now.duration_since(*at) < self.window && self.seen.len() <= self.capacity {
+                break;
+            }
+            if self.seen.get(key) == Some(at) {
+                self.seen.remove(key);
+            }
+            self.order.pop_front();
+        }
+    }
+}
+
+impl Buffer {
+    /// Buffers `(ride id, distance)` from the batch's `record`th record for the device-month; a ride
+    /// already buffered is not counted again.
+    fn add(&mut self, imei: &str, ride_month: &str, (ride, distance): (&str, f64), month_to_date: bool, as_of: &str, record: usize) {
+        let increment = self.increments
+            .entry((imei.to_string(), ride_month.to_string()))
+            .or_insert(Increment { rides: BTreeMap::new(), month_to_date, as_of: String::new(), first_record: record });
+        if increment.rides.insert(ride.to_string(), distance).is_none() {
+            self.records += 1;
+        }
+        increment.month_to_date = month_to_date;
+        increment.as_of = as_of.to_string();
+        self.since.get_or_insert_with(Instant::now);
+    }
+
+    /// The first record of the batch whose increment is not written yet.
+    fn first_unwritten(&self) -> Option<usize> {
+        self.increments.values().map(|increment| increment.first_record).min()
+    }
+
+    fn due(&self, policy: FlushPolicy) -> bool {
+        self.records >= policy.records || self.since.is_some_and(|since| since.elapsed() >= policy.every)
+    }
+
+    /// Writes the increments, one `UpdateItem` per row, returning how many were written; after a
+    /// failed write the rest stay buffered, and [`Buffer::first_unwritten`] is where to retry from.
+    async fn flush(&mut self, client: &Client, invocation_id: &str) -> usize {
+        let mut written = 0;
+        while let Some(entry) = self.increments.first_entry() {
+            let ((imei, ride_month), increment) = (entry.key(), entry.get());
+            match aggregates::add_ride_distance(client, imei, ride_month, &increment.rides, increment.month_to_date, &increment.as_of, invocation_id).await {
+                Ok(AdjustOutcome::Finalized) => info!("Kept finalized row for imei {} month {}", imei, ride_month),
+                Ok(outcome) => {
+                    if outcome != AdjustOutcome::AlreadyApplied {
+                        written += 1;
+                    }
+                    let mut dedupe = DEDUPE.lock().unwrap();
+                    for ride_start in increment.rides.keys().filter_map(|ride| ride.parse().ok()) {
+                        dedupe.remember((imei.clone(), ride_start), Instant::now());
+                    }
+                }
+                Err(err) => {
+                    error!("Error flushing buffered increments for imei {} month {}: {:?}", imei, ride_month, err);
+                    return written;
+                }
+            }
+            entry.remove();
+        }
+        (self.records, self.since) = (0, None);
+        written
+    }
+}
+
+/// Applies the batch's new rides, answering with the records Lambda should retry.
+pub async fn apply(shared_config: &aws_config::SdkConfig, records: Vec<Value>, run_id: &str) -> Result<Value, Error> {
+    let client = retries::dynamodb(shared_config);
+    let now = Clock::resolve(None)?.now();
+    let as_of = now.to_rfc3339_opts(SecondsFormat::Millis, true);
+    let current_month = time::month_of(now).ok();
+    let ride_types = RideTypes::default();
+    let rules = anomalies::Rules::from_env();
+    let policy = FlushPolicy::from_env();
+    let mut buffer = Buffer::default();
+
+    let mut failed = None;
+    let mut changed = BTreeSet::new();
+    let (mut applied, mut ignored, mut buffered) = (0, 0, 0);
+    for (i, record) in records.iter().enumerate() {
+        if let Some(key) = ride_key(record).and_then(|(imei, ride_start)| Some((imei.to_string(), ride::ride_month(ride_start)?))).filter(|_| cache::enabled()) {
+            changed.insert(key);
+        }
+        if record["eventName"] != "INSERT" {
+            ignored += 1;
+            continue;
+        }
+        let Some(mut item) = record["dynamodb"]["NewImage"].as_object().and_then(image) else {
+            warn!("Stream record without a readable NewImage: {}", record["eventID"]);
+            ignored += 1;
+            continue;
+        };
+        normalize::normalize(std::slice::from_mut(&mut item));
+        let decision = ride::classify(&item, None, &ride_types);
+        if let Some(reason) = rules.check(&decision, now.timestamp()).filter(|_| rules.action == anomalies::Action::Exclude) {
+            warn!("Ignoring stream record {}: {:?}", record["eventID"], reason);
+            ignored += 1;
+            continue;
+        }
+        let (Some(imei), Some((_, ride_start)), None, Some(ride_month), Some(distance)) =
+            (item.get("imei").and_then(|v| v.as_s().ok()), ride_key(record), decision.exclusion, &decision.ride_month, decision.distance)
+        else {
+            ignored += 1;
+            continue;
+        };
+
+        let key = (imei.to_string(), ride_start);
+        if DEDUPE.lock().unwrap().contains(&key, Instant::now()) {
+            info!("Dropping stream record {}: ride already applied", record["eventID"]);
+            ignored += 1;
+            continue;
+        }
+        let ride = ride_start.to_string();
+        let month_to_date = current_month.as_ref() == Some(ride_month);
+        if let Some(policy) = policy {
+            buffer.add(imei, ride_month, (&ride, distance), month_to_date, &as_of, i);
+            buffered += 1;
+            if buffer.due(policy) && !flush(&client, &mut buffer, run_id).await {
+                break;
+            }
+            continue;
+        }
+        match aggregates::add_ride_distance(&client, imei, ride_month, &BTreeMap::from([(ride, distance)]), month_to_date, &as_of, run_id).await {
+            Ok(AdjustOutcome::Finalized) => {
+                info!("Kept finalized row for imei {} month {}", imei, ride_month);
+                ignored += 1;
+            }
+            Ok(AdjustOutcome::AlreadyApplied) => {
+                info!("Stream record {} was already applied", record["eventID"]);
+                DEDUPE.lock().unwrap().remember(key, Instant::now());
+                ignored += 1;
+            }
+            Ok(_) => {
+                DEDUPE.lock().unwrap().remember(key, Instant::now());
+                applied += 1;
+            }
+            Err(err) => {
+                error!("Error applying stream record {}: {:?}", record["eventID"], err);
+                failed = Some(i);
+                break;
+            }
+        }
+    }
+
+    if !buffer.increments.is_empty() {
+        flush(&client, &mut buffer, run_id).await;
+    }
+    failed = failed.into_iter().chain(buffer.first_unwritten()).min();
+
+    for (imei, ride_month) in &changed {
+        if let Err(err) = cache::record_change(&client, imei, ride_month, now.timestamp_millis()).await {
+            error!("Error stamping imei {} month {} as changed: {:?}", imei, ride_month, err);
+        }
+    }
+
+    let failures = batch_item_failures(&records, failed);
+    info!(records = records.len(), applied, ignored, buffered, failed = failures.len(), "Applied stream batch");
+    Ok(json!({ "batchItemFailures": failures }))
+}
+
+/// Flushes the buffer, returning whether every increment was written.
+async fn flush(client: &Client, buffer: &mut Buffer, run_id: &str) -> bool {
+    let rows = buffer.increments.len();
+    let written = buffer.flush(client, run_id).await;
+    info!(rows, written, "Flushed stream buffer");
+    buffer.increments.is_empty()
+}
+
+/// The records Lambda should retry after the one at `failed` failed: it and all after it, as Lambda
+/// resumes the shard from the first reported sequence number.
+fn batch_item_failures(records: &[Value], failed: Option<usize>) -> Vec<Value> {
+    let Some(failed) = failed else {
+        return Vec::new();
+    };
+    records[failed..].iter()
+        .filter_map(|record| record["dynamodb"]["SequenceNumber"].as_str())
+        .map(|sequence| json!({ "itemIdentifier": sequence }))
+        .collect()
+}
+
+/// The device and `ride_start` of the ride a record changes, from its keys.
+fn ride_key(record: &Value) -> Option<(&str, u64)> {
+    let keys = &record["dynamodb"]["Keys"];
+    Some((keys["imei"]["S"].as_str()?, keys["ride_start"]["N"].as_str()?.parse().ok()?))
+}
+
+/// A stream image in DynamoDB JSON as an item, or None if an attribute has an unknown type.
+fn image(image: &serde_json::Map<String, Value>) -> Option<Item> {
+    image.iter().map(|(name, value)| Some((name.clone(), attribute(value)?))).collect()
+}
+
+fn attribute(value: &Value) -> Option<AttributeValue> {
+    let (kind, value) = value.as_object()?.iter().next()?;
+    let strings = || value.as_array()?.iter().map(|v| v.as_str().map(str::to_string)).collect::<Option<Vec<_>>>();
+    Some(match kind.as_str() {
+        "S" => AttributeValue::S(value.as_str()?.to_string()),
+        "N" => AttributeValue::N(value.as_str()?.to_string()),
+        "BOOL" => AttributeValue::Bool(value.as_bool()?),
+        "NULL" => AttributeValue::Null(true),
+        "SS" => AttributeValue::Ss(strings()?),
+        "NS" => AttributeValue::Ns(strings()?),
+        "L" => AttributeValue::L(value.as_array()?.iter().map(attribute).collect::<Option<_>>()?),
+        "M" => AttributeValue::M(image(value.as_object()?)?),
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buffers_each_ride_once_per_device_month() {
+        let mut buffer = Buffer::default();
+        buffer.add("350000000000001", "2024-04", ("1712000000", 2.0), true, "2024-04-02T00:00:00Z", 0);
+        buffer.add("350000000000001", "2024-04", ("1712003600", 3.0), true, "2024-04-02T01:00:00Z", 1);
+        buffer.add("350000000000001", "2024-04", ("1712000000", 2.0), true, "2024-04-02T02:00:00Z", 2);
+        buffer.add("350000000000002", "2024-03", ("1711000000", 1.0), false, "2024-04-02T02:00:00Z", 3);
+        assert_eq!(buffer.records, 3);
+        assert_eq!(buffer.increments.len(), 2);
+        let increment = &buffer.increments[&("350000000000001".to_string(), "2024-04".to_string())];
+        assert_eq!(increment.rides.values().sum::<f64>(), 5.0);
+        assert_eq!(increment.as_of, "2024-04-02T02:00:00Z");
+        assert_eq!(increment.first_record, 0);
+    }
+
+    #[tokio::test]
+    async fn records_whose_increment_is_unwritten_are_not_acknowledged() {
+        let records: Vec<Value> = ["100", "200", "300"].iter().map(|sequence| json!({ "dynamodb": { "SequenceNumber": sequence } })).collect();
+        let mut buffer = Buffer::default();
+        buffer.add("350000000000002", "2024-04", ("1712000000", 2.0), true, "2024-04-02T00:00:00Z", 1);
+        buffer.add("350000000000002", "2024-04", ("1712003600", 1.0), true, "2024-04-02T00:00:00Z", 2);
+        // No region is configured, so every write fails.
+        let client = Client::from_conf(aws_sdk_dynamodb::Config::builder().behavior_version_latest().build());
+        assert!(!flush(&client, &mut buffer, "run").await);
+        assert_eq!(buffer.first_unwritten(), Some(1));
+        assert_eq!(batch_item_failures(&records, buffer.first_unwritten()), [json!({ "itemIdentifier": "200" }), json!({ "itemIdentifier": "300" })]);
+        assert!(!DEDUPE.lock().unwrap().contains(&("350000000000002".to_string(), 1712000000), Instant::now()));
+    }
+
+    #[test]
+    fn a_buffer_is_due_by_records_or_age() {
+        let mut buffer = Buffer::default();
+        let hourly = FlushPolicy { every: Duration::from_secs(3600), records: 2 };
+        assert!(!buffer.due(hourly));
+        buffer.add("350000000000001", "2024-04", ("1712000000", 2.0), true, "2024-04-02T00:00:00Z", 0);
+        assert!(!buffer.due(hourly));
+        assert!(buffer.due(FlushPolicy { every: Duration::ZERO, records: 1000 }));
+        buffer.add("350000000000001", "2024-04", ("1712003600", 2.0), true, "2024-04-02T00:00:00Z", 1);
+        assert!(buffer.due(hourly));
+    }
+
+    #[test]
+    fn a_failure_retries_the_rest_of_the_batch() {
+        let records: Vec<Value> = ["100", "200", "300"].iter().map(|sequence| json!({ "dynamodb": { "SequenceNumber": sequence } })).collect();
+        assert!(batch_item_failures(&records, None).is_empty());
+        assert_eq!(batch_item_failures(&records, Some(1)), [json!({ "itemIdentifier": "200" }), json!({ "itemIdentifier": "300" })]);
+        assert_eq!(batch_item_failures(&records, Some(2)), [json!({ "itemIdentifier": "300" })]);
+    }
+
+    #[test]
+    fn rides_are_remembered_for_the_window_and_up_to_the_capacity() {
+        let start = Instant::now();
+        let ride = |n: u64| ("350000000000001".to_string(), n);
+        let mut dedupe = Dedupe::new(Duration::from_secs(60), 2);
+        assert!(!dedupe.contains(&ride(1), start));
+        dedupe.remember(ride(1), start);
+        assert!(dedupe.contains(&ride(1), start + Duration::from_secs(59)));
+        assert!(!dedupe.contains(&ride(1), start + Duration::from_secs(60)));
+        dedupe.remember(ride(1), start);
+        dedupe.remember(ride(2), start);
+        dedupe.remember(ride(3), start);
+        assert!(!dedupe.contains(&ride(1), start));
+        assert!(dedupe.contains(&ride(2), start) && dedupe.contains(&ride(3), start));
+        let mut off = Dedupe::new(Duration::ZERO, 2);
+        off.remember(ride(1), start);
+        assert!(!off.contains(&ride(1), start));
+    }
+
+    #[test]
+    fn records_are_keyed_by_ride_start() {
+        let record = json!({ "dynamodb": { "Keys": { "imei": { "S": "350000000000001" }, "ride_start": { "N": "1712000000" } } } });
+        assert_eq!(ride_key(&record), Some(("350000000000001", 1712000000)));
+        assert_eq!(ride_key(&json!({ "dynamodb": { "Keys": { "imei": { "S": "350000000000001" } } } })), None);
+    }
+}
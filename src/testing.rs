@@ -0,0 +1,111 @@
+//! A fake AWS endpoint for tests: each request an SDK client sends is recorded and answered by a
+//! handler, so code taking a DynamoDB client or an `SdkConfig` runs without a network.
+
+use aws_sdk_dynamodb::config::{Credentials, Region};
+use aws_smithy_runtime_api::client::http::{http_client_fn, HttpConnector, HttpConnectorFuture, SharedHttpClient, SharedHttpConnector};
+use aws_smithy_runtime_api::client::orchestrator::{HttpRequest, HttpResponse};
+use aws_smithy_types::body::SdkBody;
+use serde_json::Value;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+/// One request as sent.
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: String,
+    pub uri: String,
+    /// The DynamoDB operation, e.g. `UpdateItem`, from `X-Amz-Target`.
+    pub operation: Option<String>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    pub fn json(&self) -> Value {
+        serde_json::from_slice(&self.body).unwrap_or(Value::Null)
+    }
+
+    pub fn is(&self, operation: &str) -> bool {
+        self.operation.as_deref() == Some(operation)
+    }
+}
+
+/// A status and body.
+pub type Response = (u16, String);
+
+/// A 200 with a JSON body.
+pub fn ok(body: Value) -> Response {
+    (200, body.to_string())
+}
+
+/// A DynamoDB error response, e.g. `error("ConditionalCheckFailedException", json!({"Item": ...}))`.
+pub fn error(kind: &str, mut body: Value) -> Response {
+    body["__type"] = Value::String(format!("com.amazonaws.dynamodb.v20120810#{}", kind));
+    body["message"] = Value::String(kind.to_string());
+    (400, body.to_string())
+}
+
+type Handler = dyn Fn(&Request) -> Response + Send + Sync;
+
+#[derive(Clone)]
+pub struct Fake {
+    handler: Arc<Handler>,
+    requests: Arc<Mutex<Vec<Request>>>,
+}
+
+impl fmt::Debug for Fake {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Fake")
+    }
+}
+
+impl HttpConnector for Fake {
+    fn call(&self, request: HttpRequest) -> HttpConnectorFuture {
+        let request = Request {
+            method: request.method().to_string(),
+            uri: request.uri().to_string(),
+            operation: request.headers().get("x-amz-target").and_then(|target| Some(target.split_once('.')?.1.to_string())),
+            body: request.body().bytes().unwrap_or_default().to_vec(),
+        };
+        let (status, body) = (self.handler)(&request);
+        self.requests.lock().unwrap().push(request);
+        HttpConnectorFuture::ready(Ok(HttpResponse::new(status.try_into().unwrap(), SdkBody::from(body))))
+    }
+}
+
+impl Fake {
+    pub fn new(handler: impl Fn(&Request) -> Response + Send + Sync + 'static) -> Fake {
+        Fake { handler: Arc::new(handler), requests: Arc::default() }
+    }
+
+    fn http_client(&self) -> SharedHttpClient {
+        let fake = self.clone();
+        http_client_fn(move |_, _| SharedHttpConnector::new(fake.clone()))
+    }
+
+    pub fn sdk_config(&self) -> aws_config::SdkConfig {
+        aws_config::SdkConfig::builder()
+            .behavior_version(aws_config::BehaviorVersion::latest())
+            .region(Region::new("eu-west-1"))
+            .credentials_provider(aws_credential_provider())
+            .http_client(self.http_client())
+            .build()
+    }
+
+    pub fn dynamodb(&self) -> aws_sdk_dynamodb::Client {
+        aws_sdk_dynamodb::Client::new(&self.sdk_config())
+    }
+
+    /// The requests sent so far.
+    pub fn requests(&self) -> Vec<Request> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    /// The bodies of the DynamoDB `operation` requests sent so far.
+    pub fn sent(&self, operation: &str) -> Vec<Value> {
+        self.requests().iter().filter(|request| request.is(operation)).map(Request::json).collect()
+    }
+}
+
+fn aws_credential_provider() -> aws_sdk_dynamodb::config::SharedCredentialsProvider {
+    aws_sdk_dynamodb::config::SharedCredentialsProvider::new(Credentials::new("id", "secret", None, None, "test"))
+}
@@ -0,0 +1,183 @@
+//! Soft quotas on monthly distance, e.g. a lease contract's km limit. `ride_data_distance_thresholds`
+//! is keyed by `subject`, an IMEI or a fleet summary's `FLEET#<fleet_group_id>`, with a
+//! `thresholds_km` list of numbers and an optional `label` (the contract, say). With
+//! `DISTANCE_ALERT_TOPIC_ARN` set, every monthly row an aggregation run writes is checked against
+//! its subject's thresholds, and each one the month's total reaches is published once to the topic.
+//!
+//! Whether a (subject, month, threshold) has alerted is kept in `ride_data_distance_alerts`, keyed
+//! by `subject` and `alert` (`<month>#<km>`), and set by a conditional write, so concurrent or
+//! repeated runs alert once. A correction taking the total back under the threshold re-arms it only
+//! once it is `DISTANCE_ALERT_HYSTERESIS_PCT` (default 5) below, so a total hovering at the limit
+//! does not alert on every run.
+
+use anyhow::Result;
+use aws_sdk_dynamodb::operation::update_item::UpdateItemError;
+use aws_sdk_dynamodb::types::AttributeValue;
+use aws_sdk_dynamodb::Client;
+use schemars::JsonSchema;
+use serde::Serialize;
+use serde_json::json;
+use std::collections::{BTreeSet, HashMap};
+use tracing::{error, info};
+use utoipa::ToSchema;
+
+use crate::cost::CapacityMeter;
+use crate::{batch, config, metrics, time, CustomOutput};
+
+pub const TABLE_NAME: &str = "ride_data_distance_thresholds";
+pub const STATE_TABLE: &str = "ride_data_distance_alerts";
+
+pub fn topic_arn() -> Option<String> {
+    config::get().distance_alert_topic_arn.clone()
+}
+
+pub fn hysteresis_percent() -> f64 {
+    config::get().distance_alert_hysteresis_pct
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, JsonSchema, ToSchema)]
+pub struct DistanceAlert {
+    /// The IMEI, or `FLEET#<fleet_group_id>`.
+    subject: String,
+    ride_month: String,
+    threshold_km: f64,
+    total_distance: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    label: Option<String>,
+}
+
+struct Thresholds {
+    km: Vec<f64>,
+    label: Option<String>,
+}
+
+fn thresholds(item: &HashMap<String, AttributeValue>) -> Thresholds {
+    let km = match item.get("thresholds_km") {
+        Some(AttributeValue::Ns(numbers)) => numbers.iter().filter_map(|n| n.parse().ok()).collect(),
+        Some(AttributeValue::L(values)) => values.iter().filter_map(|v| v.as_n().ok()?.parse().ok()).collect(),
+        _ => Vec::new(),
+    };
+    Thresholds { km, label: item.get("label").and_then(|v| v.as_s().ok()).cloned() }
+}
+
+/// Whether a threshold should now be marked alerted (`Some(true)`) or re-armed (`Some(false)`);
+/// None leaves it as it is.
+pub fn transition(total_km: f64, threshold_km: f64, hysteresis_percent: f64) -> Option<bool> {
+    if total_km >= threshold_km {
+        Some(true)
+    } else if total_km < threshold_km * (1.0 - hysteresis_percent / 100.0) {
+        Some(false)
+    } else {
+        None
+    }
+}
+
+/// Sets the threshold's alerted flag, returning whether this call changed it.
+async fn set_alerted(client: &Client, subject: &str, alert: &str, alerted: bool, total_km: f64, run_id: &str) -> Result<bool> {
+    let condition = if alerted { "attribute_not_exists(alerted) OR alerted = :false" } else { "alerted = :true" };
+    let result = client.update_item()
+        .table_name(STATE_TABLE)
+        .key("subject", AttributeValue::S(subject.to_string()))
+        .key("alert", AttributeValue::S(alert.to_string()))
+        .update_expression("SET alerted = :alerted, total_distance = :total, run_id = :run_id, updated_at = :now")
+        .condition_expression(condition)
+        .expression_attribute_values(":alerted", AttributeValue::Bool(alerted))
+        .expression_attribute_values(":total", AttributeValue::N(total_km.to_string()))
+        .expression_attribute_values(":run_id", AttributeValue::S(run_id.to_string()))
+        .expression_attribute_values(":now", AttributeValue::S(chrono::Utc::now().to_rfc3339()))
+        .expression_attribute_values(if alerted { ":false" } else { ":true" }, AttributeValue::Bool(!alerted))
+        .send()
+        .await;
+    match result {
+        Ok(_) => Ok(true),
+        Err(err) => match err.as_service_error() {
+            Some(UpdateItemError::ConditionalCheckFailedException(_)) => Ok(false),
+            _ => {
+                metrics::dynamodb_error("update_item");
+                Err(err.into())
+            }
+        },
+    }
+}
+
+/// Checks the written monthly rows against their subjects' thresholds and publishes the alerts
+/// they newly reach; nothing when `DISTANCE_ALERT_TOPIC_ARN` is unset.
+pub async fn check<'a>(
+    shared_config: &aws_config::SdkConfig,
+    client: &Client,
+    rows: impl Iterator<Item = &'a CustomOutput>,
+    run_id: &str,
+) -> Result<Vec<DistanceAlert>> {
+    let Some(topic_arn) = topic_arn() else {
+        return Ok(Vec::new());
+    };
+    let rows: Vec<&CustomOutput> = rows
+        .filter(|row| row.granularity == time::Granularity::Monthly && row.write_skipped.is_none() && !row.truncated)
+        .collect();
+    let subjects: BTreeSet<&str> = rows.iter().map(|row| row.imei.as_str()).collect();
+    if subjects.is_empty() {
+        return Ok(Vec::new());
+    }
+    let keys = subjects.iter().map(|subject| HashMap::from([("subject".to_string(), AttributeValue::S(subject.to_string()))])).collect();
+    let configured: HashMap<String, Thresholds> = batch::get(client, TABLE_NAME, keys, &CapacityMeter::default()).await?
+        .into_iter()
+        .filter_map(|item| Some((item.get("subject")?.as_s().ok()?.clone(), thresholds(&item))))
+        .collect();
+
+    let hysteresis = hysteresis_percent();
+    let mut alerts = Vec::new();
+    for row in rows {
+        let Some(thresholds) = configured.get(&row.imei) else {
+            continue;
+        };
+        for &threshold_km in &thresholds.km {
+            let Some(alerted) = transition(row.total_distance, threshold_km, hysteresis) else {
+                continue;
+            };
+            let alert = format!("{}#{}", row.ride_month, threshold_km);
+            if set_alerted(client, &row.imei, &alert, alerted, row.total_distance, run_id).await? && alerted {
+                alerts.push((alert, DistanceAlert {
+                    subject: row.imei.clone(),
+                    ride_month: row.ride_month.clone(),
+                    threshold_km,
+                    total_distance: row.total_distance,
+                    label: thresholds.label.clone(),
+                }));
+            }
+        }
+    }
+
+    let sns = aws_sdk_sns::Client::new(shared_config);
+    let mut published = Vec::with_capacity(alerts.len());
+    for (alert, distance_alert) in alerts {
+        let sent = sns.publish()
+            .topic_arn(&topic_arn)
+            .subject("Monthly distance threshold reached")
+            .message(json!({ "run_id": run_id, "alert": distance_alert }).to_string())
+            .send()
+            .await;
+        if let Err(err) = sent {
+            error!("Error publishing the distance alert for {} {}: {}", distance_alert.subject, alert, aws_sdk_sns::error::DisplayErrorContext(&err));
+            // Re-arm it, so the next run alerts again.
+            set_alerted(client, &distance_alert.subject, &alert, false, distance_alert.total_distance, run_id).await?;
+            continue;
+        }
+        published.push(distance_alert);
+    }
+    info!("Published {} distance threshold alerts", published.len());
+    Ok(published)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rearms_only_below_the_hysteresis_band() {
+        assert_eq!(transition(1000.0, 1000.0, 5.0), Some(true));
+        assert_eq!(transition(990.0, 1000.0, 5.0), None);
+        assert_eq!(transition(950.0, 1000.0, 5.0), None);
+        assert_eq!(transition(949.9, 1000.0, 5.0), Some(false));
+        assert_eq!(transition(999.9, 1000.0, 0.0), Some(false));
+    }
+}
@@ -0,0 +1,328 @@
+//! Month bucketing in the reporting timezone (`REPORTING_UTC_OFFSET`, IST by default) without panicking on out-of-range input.
+
+use chrono::{DateTime, Datelike, Days, FixedOffset, Months, NaiveDate, Utc};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use utoipa::ToSchema;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TimeError {
+    TimestampOutOfRange(i64),
+    /// Not a `YYYY-MM` month, or one whose bounds cannot be represented.
+    InvalidMonth(String),
+    /// A `start_date` / `end_date` whose local midnight cannot be represented.
+    InvalidDate(NaiveDate),
+    /// Whole-range totals are labelled by the request's range, not by an instant.
+    RangePeriod,
+}
+
+impl fmt::Display for TimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TimeError::TimestampOutOfRange(secs) => write!(f, "timestamp {} is out of range", secs),
+            TimeError::InvalidMonth(month) => write!(f, "{:?} is not a YYYY-MM month", month),
+            TimeError::InvalidDate(date) => write!(f, "{} is out of range", date),
+            TimeError::RangePeriod => write!(f, "range totals have no period of their own"),
+        }
+    }
+}
+
+impl std::error::Error for TimeError {}
+
+/// What an aggregation given neither `input_ride_month` nor a date range covers, from
+/// `DEFAULT_RIDE_MONTH` (default `all`, every month in the ride window).
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, JsonSchema, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum DefaultMonth {
+    CurrentMonth,
+    PreviousMonth,
+    #[default]
+    All,
+}
+
+impl DefaultMonth {
+    pub fn from_env() -> DefaultMonth {
+        crate::config::get().default_ride_month
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            DefaultMonth::CurrentMonth => "current_month",
+            DefaultMonth::PreviousMonth => "previous_month",
+            DefaultMonth::All => "all",
+        }
+    }
+
+    /// The `YYYY-MM` month to aggregate at `now`, or None for every month.
+    pub fn month(self, now: DateTime<Utc>) -> Result<Option<String>, TimeError> {
+        let current = month_of(now)?;
+        match self {
+            DefaultMonth::CurrentMonth => Ok(Some(current)),
+            DefaultMonth::PreviousMonth => month_start(&current, 0)?
+                .checked_sub_months(Months::new(1))
+                .map(|start| Some(start.format("%Y-%m").to_string()))
+                .ok_or(TimeError::InvalidMonth(current)),
+            DefaultMonth::All => Ok(None),
+        }
+    }
+}
+
+/// What a symbolic `input_ride_month` stands for at a given instant.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ResolvedMonth {
+    Month(String),
+    Range(DateRange),
+}
+
+/// Resolves `current_month`, `previous_month` and `last_<n>_months` (the n months ending with the
+/// current one) in the reporting offset; None for anything else, such as a plain `YYYY-MM`.
+pub fn resolve_month_keyword(value: &str, now: DateTime<Utc>) -> Result<Option<ResolvedMonth>, TimeError> {
+    let months = match value {
+        "current_month" => return Ok(DefaultMonth::CurrentMonth.month(now)?.map(ResolvedMonth::Month)),
+        "previous_month" => return Ok(DefaultMonth::PreviousMonth.month(now)?.map(ResolvedMonth::Month)),
+        _ => match value.strip_prefix("last_").and_then(|rest| rest.strip_suffix("_months")).map(str::parse::<u32>) {
+            Some(Ok(months)) if months > 0 => months,
+            Some(_) => return Err(TimeError::InvalidMonth(value.to_string())),
+            None => return Ok(None),
+        },
+    };
+    let current = month_of(now)?;
+    let invalid = || TimeError::InvalidMonth(value.to_string());
+    let start = month_start(&current, 0)?.date_naive().checked_sub_months(Months::new(months - 1)).ok_or_else(invalid)?;
+    let end = month_start(&current, 1)?.date_naive().pred_opt().ok_or_else(invalid)?;
+    Ok(Some(ResolvedMonth::Range(DateRange { start, end })))
+}
+
+/// Echoed in the response when [`DefaultMonth`] chose what was aggregated.
+#[derive(Debug, Clone, Serialize, JsonSchema, ToSchema)]
+pub struct DefaultedMonth {
+    pub default: DefaultMonth,
+    /// The month chosen; absent for `all`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub input_ride_month: Option<String>,
+}
+
+/// Length of the period an aggregate row covers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Deserialize, Serialize, JsonSchema, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Granularity {
+    Daily,
+    /// Weeks start on Monday and are labelled by that day.
+    Weekly,
+    #[default]
+    Monthly,
+    /// One total over the whole `start_date`..`end_date` range; never stored.
+    Range,
+}
+
+impl Granularity {
+    pub fn parse(granularity: &str) -> Option<Granularity> {
+        [Granularity::Daily, Granularity::Weekly, Granularity::Monthly].into_iter().find(|g| g.as_str() == granularity)
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Granularity::Daily => "daily",
+            Granularity::Weekly => "weekly",
+            Granularity::Monthly => "monthly",
+            Granularity::Range => "range",
+        }
+    }
+
+    /// Label of the period an instant falls in: `YYYY-MM-DD` for days and weeks, `YYYY-MM` for months.
+    pub fn period_of(self, instant: DateTime<Utc>) -> Result<String, TimeError> {
+        let date = instant.with_timezone(&offset()).date_naive();
+        let start = match self {
+            Granularity::Daily => date,
+            Granularity::Weekly => date
+                .checked_sub_days(Days::new(date.weekday().num_days_from_monday().into()))
+                .ok_or(TimeError::TimestampOutOfRange(instant.timestamp()))?,
+            Granularity::Monthly => return Ok(date.format("%Y-%m").to_string()),
+            Granularity::Range => return Err(TimeError::RangePeriod),
+        };
+        Ok(start.format("%Y-%m-%d").to_string())
+    }
+
+    pub fn period_of_epoch(self, secs: i64) -> Result<String, TimeError> {
+        self.period_of(instant(secs)?)
+    }
+
+    /// First and last day of the period labelled `period`.
+    fn days(self, period: &str) -> Option<(NaiveDate, NaiveDate)> {
+        match self {
+            Granularity::Daily => NaiveDate::parse_from_str(period, "%Y-%m-%d").ok().map(|day| (day, day)),
+            Granularity::Weekly => NaiveDate::parse_from_str(period, "%Y-%m-%d").ok()
+                .and_then(|monday| Some((monday, monday.checked_add_days(Days::new(6))?))),
+            Granularity::Monthly => {
+                let first = NaiveDate::parse_from_str(&format!("{}-01", period), "%Y-%m-%d").ok()?;
+                Some((first, first.checked_add_months(Months::new(1))?.pred_opt()?))
+            }
+            Granularity::Range => None,
+        }
+    }
+}
+
+/// A `YYYY-MM` month and the epoch seconds `[from, to)` of it to read.
+pub type MonthSpan = (String, (i64, i64));
+
+/// Local days `start..=end` to aggregate over, from `start_date` / `end_date`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DateRange {
+    pub start: NaiveDate,
+    pub end: NaiveDate,
+}
+
+impl DateRange {
+    /// The days of a `YYYY-MM` month.
+    pub fn month(month: &str) -> Option<DateRange> {
+        let (start, end) = Granularity::Monthly.days(month)?;
+        Some(DateRange { start, end })
+    }
+
+    /// Epoch seconds `[from, to)` from local midnight on `start` to the midnight after `end`.
+    pub fn bounds(self) -> Result<(i64, i64), TimeError> {
+        let after_end = self.end.succ_opt().ok_or(TimeError::InvalidDate(self.end))?;
+        Ok((midnight(self.start)?, midnight(after_end)?))
+    }
+
+    /// `YYYY-MM-DD..YYYY-MM-DD`, the `ride_month` of whole-range totals.
+    pub fn label(self) -> String {
+        format!("{}..{}", self.start, self.end)
+    }
+
+    /// Whether the whole period lies in the range, so its total is complete.
+    pub fn covers(self, granularity: Granularity, period: &str) -> bool {
+        granularity.days(period).is_some_and(|(first, last)| self.start <= first && last <= self.end)
+    }
+
+    /// `YYYY-MM` months the range touches, each with its epoch seconds clipped to the range.
+    pub fn months(self) -> Result<Vec<MonthSpan>, TimeError> {
+        let (from, to) = self.bounds()?;
+        let mut months = Vec::new();
+        let mut month = self.start.format("%Y-%m").to_string();
+        loop {
+            let (month_from, month_to) = month_range(&month)?;
+            months.push((month.clone(), (month_from.max(from), month_to.min(to))));
+            if month_to >= to {
+                return Ok(months);
+            }
+            month = month_start(&month, 1)?.format("%Y-%m").to_string();
+        }
+    }
+}
+
+/// Epoch seconds of local midnight at the start of `date`.
+pub fn midnight(date: NaiveDate) -> Result<i64, TimeError> {
+    date.and_hms_opt(0, 0, 0)
+        .and_then(|midnight| midnight.and_local_timezone(offset()).single())
+        .map(|midnight| midnight.timestamp())
+        .ok_or(TimeError::InvalidDate(date))
+}
+
+pub fn offset() -> FixedOffset {
+    crate::config::get().utc_offset
+}
+
+/// The instant of a timestamp in epoch seconds; an error instead of chrono's panic when out of range.
+pub fn instant(secs: i64) -> Result<DateTime<Utc>, TimeError> {
+    DateTime::from_timestamp(secs, 0).ok_or(TimeError::TimestampOutOfRange(secs))
+}
+
+/// [`instant`] in the reporting offset.
+pub fn local(secs: i64) -> Result<DateTime<FixedOffset>, TimeError> {
+    Ok(instant(secs)?.with_timezone(&offset()))
+}
+
+/// `YYYY-MM` month an instant falls in.
+pub fn month_of(instant: DateTime<Utc>) -> Result<String, TimeError> {
+    Granularity::Monthly.period_of(instant)
+}
+
+/// `YYYY-MM` month of a timestamp in epoch seconds.
+pub fn month_of_epoch(secs: i64) -> Result<String, TimeError> {
+    Granularity::Monthly.period_of_epoch(secs)
+}
+
+/// Local midnight on the first day of `month`, plus `later` months.
+pub fn month_start(month: &str, later: u32) -> Result<DateTime<FixedOffset>, TimeError> {
+    let invalid = || TimeError::InvalidMonth(month.to_string());
+    NaiveDate::parse_from_str(&format!("{}-01", month), "%Y-%m-%d")
+        .map_err(|_| invalid())?
+        .checked_add_months(Months::new(later))
+        .and_then(|date| date.and_hms_opt(0, 0, 0))
+        .and_then(|midnight| midnight.and_local_timezone(offset()).single())
+        .ok_or_else(invalid)
+}
+
+/// `value` as a `YYYY-MM` month, rejecting anything [`month_start`] would read loosely (`2024-3`).
+pub fn parse_month(value: &str) -> Result<String, TimeError> {
+    let bytes = value.as_bytes();
+    let shaped = bytes.len() == 7 && bytes[4] == b'-' && bytes.iter().enumerate().all(|(i, b)| i == 4 || b.is_ascii_digit());
+    if !shaped {
+        return Err(TimeError::InvalidMonth(value.to_string()));
+    }
+    month_start(value, 0)?;
+    Ok(value.to_string())
+}
+
+/// Every `YYYY-MM` month from `first` to `last`, inclusive; empty if `last` is earlier.
+pub fn months_between(first: &str, last: &str) -> Result<Vec<String>, TimeError> {
+    month_start(last, 0)?;
+    let mut months = Vec::new();
+    let mut month = month_start(first, 0)?.format("%Y-%m").to_string();
+    while month.as_str() <= last {
+        let next = month_start(&month, 1)?.format("%Y-%m").to_string();
+        months.push(std::mem::replace(&mut month, next));
+    }
+    Ok(months)
+}
+
+/// Epoch seconds `[from, to)` covered by `month`.
+pub fn month_range(month: &str) -> Result<(i64, i64), TimeError> {
+    Ok((month_start(month, 0)?.timestamp(), month_start(month, 1)?.timestamp()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn months_must_be_yyyy_mm() {
+        assert_eq!(parse_month("2024-03").unwrap(), "2024-03");
+        for invalid in ["2024-13", "march", "2024-3", "202403", "2024-03\r\nBcc: x", ""] {
+            assert_eq!(parse_month(invalid), Err(TimeError::InvalidMonth(invalid.to_string())), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn out_of_range_timestamps_are_errors() {
+        assert_eq!(local(1_711_911_600).unwrap().to_rfc3339(), "2024-04-01T00:30:00+05:30");
+        assert_eq!(instant(i64::MAX), Err(TimeError::TimestampOutOfRange(i64::MAX)));
+        assert_eq!(month_of_epoch(i64::MIN), Err(TimeError::TimestampOutOfRange(i64::MIN)));
+    }
+
+    #[test]
+    fn rides_are_bucketed_by_local_period() {
+        // 2024-03-31T19:00Z is 00:30 on Monday 1 April in IST.
+        let secs = 1_711_911_600;
+        assert_eq!(Granularity::Daily.period_of_epoch(secs).unwrap(), "2024-04-01");
+        assert_eq!(Granularity::Weekly.period_of_epoch(secs).unwrap(), "2024-04-01");
+        assert_eq!(Granularity::Monthly.period_of_epoch(secs).unwrap(), "2024-04");
+        // Sunday 7 April still belongs to that week.
+        assert_eq!(Granularity::Weekly.period_of_epoch(secs + 6 * 86_400).unwrap(), "2024-04-01");
+        assert_eq!(Granularity::Range.period_of_epoch(secs), Err(TimeError::RangePeriod));
+        assert_eq!(Granularity::parse("weekly"), Some(Granularity::Weekly));
+        assert_eq!(Granularity::parse("range"), None);
+    }
+
+    #[test]
+    fn a_range_covers_only_whole_periods() {
+        let april = DateRange { start: NaiveDate::from_ymd_opt(2024, 4, 1).unwrap(), end: NaiveDate::from_ymd_opt(2024, 4, 30).unwrap() };
+        assert!(april.covers(Granularity::Monthly, "2024-04"));
+        assert!(april.covers(Granularity::Weekly, "2024-04-22"));
+        assert!(!april.covers(Granularity::Weekly, "2024-04-29"));
+        assert!(april.covers(Granularity::Daily, "2024-04-30"));
+        assert!(!april.covers(Granularity::Monthly, "2024-05"));
+    }
+}
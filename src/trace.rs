@@ -0,0 +1,72 @@
+use aws_sdk_dynamodb::Client;
+use lambda_runtime::Error;
+use schemars::JsonSchema;
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::cost::CapacityMeter;
+use crate::ride::{self, Exclusion};
+use crate::{metrics, normalize, query_ride_new, time, CustomEvent, ErrorOutput, RIDE_PROJECTION};
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct TraceStep {
+    ride_start: Option<u64>,
+    ride_type: String,
+    ride_month: Option<String>,
+    distance: Option<f64>,
+    included: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<Exclusion>,
+    running_total: f64,
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct TraceOutput {
+    imei: String,
+    ride_month: String,
+    total_distance: f64,
+    rides_seen: usize,
+    rides_included: usize,
+    steps: Vec<TraceStep>,
+}
+
+/// `action: "debug_trace"`: replays one IMEI's rides for one month and explains every decision, without writing.
+pub async fn debug_trace(client: &Client, payload: &CustomEvent) -> Result<Value, Error> {
+    let (Some(ride_month), [imei]) = (&payload.input_ride_month, payload.imeis.as_slice()) else {
+        return Ok(json!(ErrorOutput { error: "debug_trace needs exactly one imei and input_ride_month".to_string() }));
+    };
+
+    let ride_starts = time::month_range(ride_month).ok();
+    let mut items = query_ride_new(client, imei, ride_starts, &[], None, RIDE_PROJECTION, &CapacityMeter::default()).await
+        .inspect_err(|_| metrics::dynamodb_error("query"))?;
+
+    normalize::normalize(&mut items);
+    let ride_types = payload.ride_types();
+    let mut running_total = 0.0;
+    let mut steps = Vec::with_capacity(items.len());
+    for item in &items {
+        let decision = ride::classify(item, Some(ride_month), &ride_types);
+        let included = decision.exclusion.is_none();
+        if included {
+            running_total += decision.distance.unwrap_or(0.0);
+        }
+        steps.push(TraceStep {
+            ride_start: decision.ride_start,
+            ride_type: decision.ride_type,
+            ride_month: decision.ride_month,
+            distance: decision.distance,
+            included,
+            reason: decision.exclusion,
+            running_total,
+        });
+    }
+
+    Ok(json!(TraceOutput {
+        imei: imei.to_string(),
+        ride_month: ride_month.clone(),
+        total_distance: running_total,
+        rides_seen: items.len(),
+        rides_included: steps.iter().filter(|step| step.included).count(),
+        steps,
+    }))
+}
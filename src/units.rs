@@ -0,0 +1,136 @@
+//! Units of `ride_stats.ride_distance`, which firmware versions have reported in meters as well as
+//! kilometers. A ride's distance is in the unit of its `distance_unit` attribute (`km`, `m` or `mi`),
+//! else the one `FIRMWARE_DISTANCE_UNITS` maps its `firmware_version` to (`2.1.0=m,2.1.1=m`), else
+//! km; everything is converted to km before it is counted. Rows are stored in km, and `output_unit`
+//! only changes the unit results are reported in.
+
+use aws_sdk_dynamodb::types::AttributeValue;
+use rust_decimal::prelude::ToPrimitive;
+use rust_decimal::Decimal;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
+use utoipa::ToSchema;
+
+use crate::CustomOutput;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DistanceUnit {
+    Km,
+    M,
+    Mi,
+}
+
+impl DistanceUnit {
+    pub fn parse(name: &str) -> Option<DistanceUnit> {
+        match name {
+            "km" => Some(DistanceUnit::Km),
+            "m" => Some(DistanceUnit::M),
+            "mi" => Some(DistanceUnit::Mi),
+            _ => None,
+        }
+    }
+
+    /// Kilometers in one of the unit.
+    fn km(self) -> Decimal {
+        match self {
+            DistanceUnit::Km => Decimal::ONE,
+            DistanceUnit::M => Decimal::new(1, 3),
+            DistanceUnit::Mi => Decimal::new(1_609_344, 6),
+        }
+    }
+}
+
+/// What results report distances (and speeds) in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize, JsonSchema, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputUnit {
+    #[default]
+    Km,
+    Mi,
+}
+
+/// Parses `FIRMWARE_DISTANCE_UNITS`: comma-separated `firmware_version=unit` pairs.
+pub fn firmware_units(spec: &str) -> Result<BTreeMap<String, DistanceUnit>, String> {
+    spec.split(',')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (firmware, unit) = pair.split_once('=').ok_or_else(|| format!("{:?} is not firmware_version=unit", pair))?;
+            let unit = DistanceUnit::parse(unit.trim()).ok_or_else(|| format!("{:?} is not one of km, m, mi", unit.trim()))?;
+            Ok((firmware.trim().to_string(), unit))
+        })
+        .collect()
+}
+
+/// The unit a ride's distance is in; None when its `distance_unit` is not one of those known.
+pub fn unit_of(item: &HashMap<String, AttributeValue>) -> Option<DistanceUnit> {
+    if let Some(unit) = item.get("distance_unit").and_then(|v| v.as_s().ok()) {
+        return DistanceUnit::parse(unit);
+    }
+    let firmware = item.get("firmware_version").and_then(|v| v.as_s().ok());
+    Some(firmware.and_then(|firmware| crate::config::get().firmware_units.get(firmware)).copied().unwrap_or(DistanceUnit::Km))
+}
+
+/// A stored distance in km. The conversion is done in decimal, so `1500` m reads as exactly 1.5.
+pub fn to_km(distance: &str, unit: DistanceUnit) -> Option<f64> {
+    let parsed = distance.parse::<f64>().ok()?;
+    if unit == DistanceUnit::Km {
+        return Some(parsed);
+    }
+    match Decimal::from_str(distance) {
+        Ok(decimal) => (decimal * unit.km()).to_f64(),
+        // Exponents, NaN and infinities: left for the outlier check.
+        Err(_) => Some(parsed * unit.km().to_f64().unwrap_or(1.0)),
+    }
+}
+
+/// Converts a result row's distances and speeds from km to `unit`.
+pub fn convert(row: &mut CustomOutput, unit: OutputUnit) {
+    if unit == OutputUnit::Km {
+        return;
+    }
+    let miles = |km: f64| (Decimal::from_f64_retain(km).unwrap_or_default() / DistanceUnit::Mi.km()).to_f64().unwrap_or(km);
+    row.total_distance = miles(row.total_distance);
+    row.average_speed = row.average_speed.map(miles);
+    row.max_speed = row.max_speed.map(miles);
+    for stats in row.breakdowns.values_mut().flat_map(|values| values.values_mut()) {
+        stats.total_distance = miles(stats.total_distance);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_meters_exactly() {
+        assert_eq!(to_km("1500", DistanceUnit::M), Some(1.5));
+        assert_eq!(to_km("0.1", DistanceUnit::M), Some(0.0001));
+        assert_eq!(to_km("2.5", DistanceUnit::Km), Some(2.5));
+        assert_eq!(to_km("1", DistanceUnit::Mi), Some(1.609344));
+        assert_eq!(to_km("not a number", DistanceUnit::M), None);
+    }
+
+    #[test]
+    fn parses_firmware_units() {
+        let units = firmware_units("2.1.0=m, 2.1.1 = mi,").unwrap();
+        assert_eq!(units["2.1.0"], DistanceUnit::M);
+        assert_eq!(units["2.1.1"], DistanceUnit::Mi);
+        assert!(firmware_units("2.1.0=feet").is_err());
+        assert!(firmware_units("2.1.0").is_err());
+    }
+
+    #[test]
+    fn reads_the_unit_attribute_before_the_firmware() {
+        let item = HashMap::from([
+            ("distance_unit".to_string(), AttributeValue::S("m".to_string())),
+            ("firmware_version".to_string(), AttributeValue::S("1.0.0".to_string())),
+        ]);
+        assert_eq!(unit_of(&item), Some(DistanceUnit::M));
+        assert_eq!(unit_of(&HashMap::new()), Some(DistanceUnit::Km));
+        let unknown = HashMap::from([("distance_unit".to_string(), AttributeValue::S("yd".to_string()))]);
+        assert_eq!(unit_of(&unknown), None);
+    }
+}
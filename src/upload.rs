@@ -0,0 +1,244 @@
+//! Large JSON bodies streamed to S3 as they are serialized, instead of built as a `serde_json::Value`
+//! (or a string of one) first: the serializer runs on a blocking thread and hands over
+//! `PART_BYTES` at a time, each uploaded as a multipart part, so a few parts are in memory at most.
+//! A body that fits in one part is written with a single `PutObject`.
+//!
+//! Exports larger than `MULTIPART_THRESHOLD_BYTES` (default 64 MiB) are uploaded in parts too. Up to
+//! `UPLOAD_CONCURRENCY` parts (default 4) are uploaded at a time, and an upload that fails part way
+//! is aborted, so no orphaned parts are left to be billed.
+//!
+//! An aggregation response with more than `RESPONSE_SPILL_ROWS` rows (default 5000) is written this
+//! way to `RESPONSE_BUCKET`, when set, under `responses/`; the invocation returns its URI instead.
+
+use anyhow::{anyhow, Result};
+use aws_sdk_s3::primitives::ByteStream;
+use aws_sdk_s3::types::{CompletedMultipartUpload, CompletedPart};
+use futures::stream::{self, Stream, StreamExt, TryStreamExt};
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::io::{self, Write};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+
+use crate::config;
+use crate::kms::Encryption;
+
+/// Bytes per part; S3 needs at least 5 MiB in all but the last.
+const PART_BYTES: usize = 8 * 1024 * 1024;
+
+/// Bodies above this many bytes are uploaded in parts, from `MULTIPART_THRESHOLD_BYTES`.
+pub fn multipart_threshold() -> usize {
+    config::get().multipart_threshold_bytes
+}
+
+/// Parts uploaded at a time, from `UPLOAD_CONCURRENCY`.
+pub fn concurrency() -> usize {
+    config::get().upload_concurrency
+}
+
+/// What a spilled aggregation returns in place of its response.
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct SpilledResponse {
+    /// `s3://` URI of the full response.
+    response_uri: String,
+    /// Presigned GET URL of the full response.
+    response_url: String,
+    rows: usize,
+    bytes: usize,
+}
+
+/// `RESPONSE_BUCKET`, if a response with `rows` rows should be spilled to it.
+pub fn spill_bucket(rows: usize) -> Option<String> {
+    config::get().response_bucket.clone().filter(|_| rows > config::get().response_spill_rows)
+}
+
+/// Writes `response` to `bucket` under `responses/<name>.json`, presigning it for `expiry`.
+pub async fn spill<T: Serialize + Send + 'static>(
+    s3: &aws_sdk_s3::Client,
+    bucket: &str,
+    name: &str,
+    rows: usize,
+    response: T,
+    expiry: Duration,
+    encryption: &Encryption,
+) -> Result<SpilledResponse> {
+    let key = format!("responses/{}.json", name);
+    let bytes = put_json(s3, bucket, &key, response, encryption).await?;
+    let response_uri = format!("s3://{}/{}", bucket, key);
+    let response_url = crate::s3::presign(s3, &response_uri, expiry).await?;
+    Ok(SpilledResponse { response_uri, response_url, rows, bytes })
+}
+
+/// Hands the serialized bytes over a part at a time.
+struct PartWriter {
+    buffer: Vec<u8>,
+    parts: mpsc::Sender<Vec<u8>>,
+}
+
+impl PartWriter {
+    fn send(&mut self) -> io::Result<()> {
+        let part = std::mem::take(&mut self.buffer);
+        self.parts.blocking_send(part).map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "upload stopped"))
+    }
+}
+
+impl Write for PartWriter {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(bytes);
+        if self.buffer.len() >= PART_BYTES {
+            self.send()?;
+        }
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Serializes `value` as JSON into `s3://<bucket>/<key>`, returning the bytes written.
+pub async fn put_json<T: Serialize + Send + 'static>(s3: &aws_sdk_s3::Client, bucket: &str, key: &str, value: T, encryption: &Encryption) -> Result<usize> {
+    let (parts, mut received) = mpsc::channel(1);
+    let serializer = tokio::task::spawn_blocking(move || -> Result<()> {
+        let mut writer = PartWriter { buffer: Vec::with_capacity(PART_BYTES), parts };
+        serde_json::to_writer(&mut writer, &value)?;
+        if !writer.buffer.is_empty() {
+            writer.send()?;
+        }
+        Ok(())
+    });
+
+    let first = received.recv().await.unwrap_or_default();
+    let Some(second) = received.recv().await else {
+        serializer.await??;
+        let bytes = first.len();
+        s3.put_object()
+            .bucket(bucket)
+            .key(key)
+            .content_type("application/json")
+            .set_server_side_encryption(encryption.server_side_encryption())
+            .set_ssekms_key_id(encryption.key_id())
+            .set_ssekms_encryption_context(encryption.context_header())
+            .set_metadata(encryption.metadata())
+            .body(ByteStream::from(first))
+            .send()
+            .await?;
+        return Ok(bytes);
+    };
+
+    let rest = stream::unfold(received, |mut received| async move { received.recv().await.map(|part| (part, received)) });
+    let upload = Multipart::start(s3, bucket, key, "application/json", encryption).await?;
+    let result = async {
+        let (parts, bytes) = upload.parts(stream::iter([first, second]).chain(rest)).await?;
+        // Parts end early when serializing fails, and must not be completed into an object then.
+        serializer.await??;
+        upload.complete(parts, false).await?;
+        Ok(bytes)
+    }
+    .await;
+    if result.is_err() {
+        upload.abort().await;
+    }
+    result
+}
+
+/// Uploads `body` to `s3://<bucket>/<key>` in parts, with `If-None-Match: *` if `if_none_match`.
+pub async fn put_parts(
+    s3: &aws_sdk_s3::Client,
+    bucket: &str,
+    key: &str,
+    content_type: &str,
+    body: Vec<u8>,
+    if_none_match: bool,
+    encryption: &Encryption,
+) -> Result<()> {
+    let upload = Multipart::start(s3, bucket, key, content_type, encryption).await?;
+    let result = async {
+        let (parts, bytes) = upload.parts(stream::iter(body.chunks(PART_BYTES).map(<[u8]>::to_vec))).await?;
+        upload.complete(parts, if_none_match).await?;
+        info!(key, bytes, "Uploaded in parts");
+        Ok(())
+    }
+    .await;
+    if result.is_err() {
+        upload.abort().await;
+    }
+    result
+}
+
+/// A multipart upload in progress.
+struct Multipart<'a> {
+    s3: &'a aws_sdk_s3::Client,
+    bucket: &'a str,
+    key: &'a str,
+    upload_id: String,
+}
+
+impl<'a> Multipart<'a> {
+    async fn start(s3: &'a aws_sdk_s3::Client, bucket: &'a str, key: &'a str, content_type: &str, encryption: &Encryption) -> Result<Multipart<'a>> {
+        let created = s3.create_multipart_upload()
+            .bucket(bucket)
+            .key(key)
+            .content_type(content_type)
+            .set_server_side_encryption(encryption.server_side_encryption())
+            .set_ssekms_key_id(encryption.key_id())
+            .set_ssekms_encryption_context(encryption.context_header())
+            .set_metadata(encryption.metadata())
+            .send()
+            .await?;
+        let upload_id = created.upload_id().ok_or_else(|| anyhow!("no upload id for {}", key))?.to_string();
+        Ok(Multipart { s3, bucket, key, upload_id })
+    }
+
+    /// Uploads the parts, numbered in order, [`concurrency`] at a time; returns them and their total size.
+    async fn parts(&self, parts: impl Stream<Item = Vec<u8>>) -> Result<(Vec<CompletedPart>, usize)> {
+        let mut uploaded: Vec<(CompletedPart, usize)> = parts
+            .enumerate()
+            .map(|(i, body)| async move {
+                let part_number = i as i32 + 1;
+                let bytes = body.len();
+                let uploaded = self.s3.upload_part()
+                    .bucket(self.bucket)
+                    .key(self.key)
+                    .upload_id(&self.upload_id)
+                    .part_number(part_number)
+                    .body(ByteStream::from(body))
+                    .send()
+                    .await?;
+                let part = CompletedPart::builder().part_number(part_number).set_e_tag(uploaded.e_tag().map(str::to_string)).build();
+                Ok::<_, anyhow::Error>((part, bytes))
+            })
+            .buffer_unordered(concurrency())
+            .try_collect()
+            .await?;
+        uploaded.sort_by_key(|(part, _)| part.part_number());
+        let bytes = uploaded.iter().map(|(_, bytes)| bytes).sum();
+        Ok((uploaded.into_iter().map(|(part, _)| part).collect(), bytes))
+    }
+
+    async fn complete(&self, parts: Vec<CompletedPart>, if_none_match: bool) -> Result<()> {
+        self.s3.complete_multipart_upload()
+            .bucket(self.bucket)
+            .key(self.key)
+            .upload_id(&self.upload_id)
+            .multipart_upload(CompletedMultipartUpload::builder().set_parts(Some(parts)).build())
+            .set_if_none_match(if_none_match.then(|| "*".to_string()))
+            .send()
+            .await?;
+        Ok(())
+    }
+
+    /// Discards the parts uploaded so far; failing that, they are left to the bucket's lifecycle rules.
+    async fn abort(&self) {
+        let aborted = self.s3.abort_multipart_upload()
+            .bucket(self.bucket)
+            .key(self.key)
+            .upload_id(&self.upload_id)
+            .send()
+            .await;
+        if let Err(err) = aborted {
+            warn!("Error aborting the multipart upload of {}: {:?}", self.key, err);
+        }
+    }
+}
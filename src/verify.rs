@@ -0,0 +1,37 @@
+//! `verify_writes`: read back a random sample of the rows a run just wrote and fail the run if any
+//! stored total differs from the computed one, as an end-to-end check for billing runs.
+
+use aws_sdk_dynamodb::Client;
+use anyhow::{bail, Result};
+use tracing::{error, info};
+
+use crate::cost::CapacityMeter;
+use crate::{aggregates, CustomOutput};
+
+/// Stored totals may differ from computed ones by float formatting only.
+const TOLERANCE_KM: f64 = 1e-9;
+
+/// Reads up to `sample` written rows back and errors if any is missing or holds a different total.
+pub async fn verify_writes(client: &Client, rows: &[CustomOutput], sample: usize, meter: &CapacityMeter) -> Result<()> {
+    let mut written: Vec<&CustomOutput> = rows.iter().filter(|row| row.write_skipped.is_none()).collect();
+    fastrand::shuffle(&mut written);
+    written.truncate(sample);
+
+    let mut mismatches = 0;
+    for row in &written {
+        let stored = aggregates::get_distance(client, &row.imei, row.granularity, &row.ride_month, meter).await?;
+        match stored {
+            Some(stored) if (stored - row.total_distance).abs() <= TOLERANCE_KM => {}
+            _ => {
+                mismatches += 1;
+                error!("Write verification failed for imei {} period {}: computed {}, stored {:?}", row.imei, row.ride_month, row.total_distance, stored);
+            }
+        }
+    }
+
+    if mismatches > 0 {
+        bail!("write verification failed: {} of {} sampled rows differ from the computed totals", mismatches, written.len());
+    }
+    info!("Verified {} written rows", written.len());
+    Ok(())
+}
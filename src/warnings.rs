@@ -0,0 +1,61 @@
+//! Non-fatal findings of a run, returned with their context in `warnings` and counted in
+//! `ride_data_warnings_total` by code.
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+use utoipa::ToSchema;
+
+use crate::metrics;
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize, JsonSchema, ToSchema)]
+#[serde(tag = "code", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum Warning {
+    /// A ride's distance was negative or not a finite number, so it was left out.
+    OutlierDropped { imei: String, ride_start: u64, distance: f64 },
+    /// Rides in the month had no `ride_stats`, so their distance is unknown.
+    MissingStats { imei: String, ride_month: String, rides: u64 },
+    /// A ride overlapped a longer ride of the same device and was dropped as a double log.
+    DuplicateRide { imei: String, ride_start: u64 },
+    /// The IMEI's rides were cut off at a query limit after `items_read` items (0 when none were read).
+    TruncatedResults { imei: String, items_read: usize },
+    /// A ride starts more than `CLOCK_SKEW_TOLERANCE_SECS` after the run's current time, so the device
+    /// clock is probably wrong; the ride was not counted.
+    ClockSkew { imei: String, ride_start: u64, now: i64 },
+    /// A ride's device signature did not verify.
+    InvalidSignature { imei: String, ride_start: u64 },
+    /// The row's batch write was still unprocessed after retries, so the stored row is unchanged.
+    UnprocessedWrite { imei: String, ride_month: String },
+    /// The IMEI was requested `occurrences` times; it was aggregated once.
+    DuplicateImei { imei: String, occurrences: usize },
+    /// Entries of `imeis` had surrounding whitespace trimmed, or were empty and dropped.
+    ImeiListCleaned { trimmed: usize, dropped_empty: usize },
+    /// The device's `ride_start_local` was off the reporting timezone by more than
+    /// `TIMEZONE_DRIFT_THRESHOLD_MINS` on `drifted_rides` rides, `shifted_month_rides` of them
+    /// showing the rider another month than they were counted in.
+    TimezoneDrift { imei: String, rides_checked: u64, drifted_rides: u64, max_offset_minutes: i64, shifted_month_rides: u64 },
+}
+
+impl Warning {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Warning::OutlierDropped { .. } => "OUTLIER_DROPPED",
+            Warning::MissingStats { .. } => "MISSING_STATS",
+            Warning::DuplicateRide { .. } => "DUPLICATE_RIDE",
+            Warning::TruncatedResults { .. } => "TRUNCATED_RESULTS",
+            Warning::ClockSkew { .. } => "CLOCK_SKEW",
+            Warning::InvalidSignature { .. } => "INVALID_SIGNATURE",
+            Warning::UnprocessedWrite { .. } => "UNPROCESSED_WRITE",
+            Warning::DuplicateImei { .. } => "DUPLICATE_IMEI",
+            Warning::ImeiListCleaned { .. } => "IMEI_LIST_CLEANED",
+            Warning::TimezoneDrift { .. } => "TIMEZONE_DRIFT",
+        }
+    }
+}
+
+/// Logs and counts a warning, then keeps it for the response.
+pub fn push(warnings: &mut Vec<Warning>, warning: Warning) {
+    warn!(code = warning.code(), "{:?}", warning);
+    metrics::WARNINGS.with_label_values(&[warning.code()]).inc();
+    warnings.push(warning);
+}
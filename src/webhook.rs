@@ -0,0 +1,113 @@
+use anyhow::{bail, Result};
+use serde_json::{json, Value};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WebhookKind {
+    Slack,
+    Teams,
+}
+
+/// Incoming-webhook target read from `SUMMARY_WEBHOOK_URL`, with
+/// `SUMMARY_WEBHOOK_KIND` (`slack` or `teams`, default `slack`) picking the payload format.
+pub struct WebhookSettings {
+    pub url: String,
+    pub kind: WebhookKind,
+}
+
+impl WebhookSettings {
+    pub fn from_env() -> Option<WebhookSettings> {
+        let config = crate::config::get();
+        Some(WebhookSettings { url: config.summary_webhook_url.clone()?, kind: config.summary_webhook_kind })
+    }
+}
+
+pub struct RunSummary {
+    pub period: String,
+    pub devices: usize,
+    pub total_km: f64,
+    pub failures: Vec<String>,
+}
+
+impl RunSummary {
+    fn title(&self) -> String {
+        if self.failures.is_empty() {
+            format!("Ride data run {} succeeded", self.period)
+        } else {
+            format!(":rotating_light: Ride data run {} FAILED", self.period)
+        }
+    }
+
+    fn text(&self) -> String {
+        let mut text = format!(
+            "Devices processed: {}\nTotal distance: {:.2} km\nFailures: {}",
+            self.devices, self.total_km, self.failures.len()
+        );
+        for failure in &self.failures {
+            text.push_str(&format!("\n• {}", failure));
+        }
+        text
+    }
+}
+
+pub fn build_payload(kind: WebhookKind, summary: &RunSummary) -> Value {
+    let failed = !summary.failures.is_empty();
+    match kind {
+        WebhookKind::Slack => json!({
+            "text": summary.title(),
+            "attachments": [{
+                "color": if failed { "danger" } else { "good" },
+                "text": summary.text(),
+            }],
+        }),
+        WebhookKind::Teams => json!({
+            "@type": "MessageCard",
+            "@context": "https://schema.org/extensions",
+            "themeColor": if failed { "D00000" } else { "2EB886" },
+            "title": summary.title(),
+            "text": summary.text().replace('\n', "<br>"),
+        }),
+    }
+}
+
+pub async fn post_summary(settings: &WebhookSettings, summary: &RunSummary) -> Result<()> {
+    let resp = reqwest::Client::new()
+        .post(&settings.url)
+        .json(&build_payload(settings.kind, summary))
+        .send()
+        .await?;
+
+    if !resp.status().is_success() {
+        bail!("webhook returned {}", resp.status());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn summary(failures: &[&str]) -> RunSummary {
+        RunSummary { period: "2024-03".to_string(), devices: 2, total_km: 12.345, failures: failures.iter().map(|f| f.to_string()).collect() }
+    }
+
+    #[test]
+    fn slack_summaries_highlight_failures() {
+        assert_eq!(build_payload(WebhookKind::Slack, &summary(&[])), json!({
+            "text": "Ride data run 2024-03 succeeded",
+            "attachments": [{"color": "good", "text": "Devices processed: 2\nTotal distance: 12.35 km\nFailures: 0"}],
+        }));
+        let failed = build_payload(WebhookKind::Slack, &summary(&["359000000000001: throttled"]));
+        assert_eq!(failed["text"], ":rotating_light: Ride data run 2024-03 FAILED");
+        assert_eq!(failed["attachments"][0]["color"], "danger");
+        assert!(failed["attachments"][0]["text"].as_str().unwrap().ends_with("Failures: 1\n• 359000000000001: throttled"));
+    }
+
+    #[test]
+    fn teams_cards_break_lines_with_html() {
+        let card = build_payload(WebhookKind::Teams, &summary(&["359000000000001: throttled"]));
+        assert_eq!(card["@type"], "MessageCard");
+        assert_eq!(card["themeColor"], "D00000");
+        assert_eq!(card["text"], "Devices processed: 2<br>Total distance: 12.35 km<br>Failures: 1<br>• 359000000000001: throttled");
+        assert_eq!(build_payload(WebhookKind::Teams, &summary(&[]))["themeColor"], "2EB886");
+    }
+}
@@ -0,0 +1,83 @@
+//! `report_skipped_years`: rides starting outside `RIDE_WINDOW_START` / `RIDE_WINDOW_END`, counted
+//! per device and (IST) year. Aggregations never read them, so a device whose clock writes rides
+//! in 1970 or 2106 otherwise goes unnoticed. Each IMEI costs a query per bounded side of the
+//! window, projecting only `ride_start`; with an unrestricted window nothing is skipped or read.
+
+use aws_sdk_dynamodb::types::{AttributeValue, ReturnConsumedCapacity};
+use aws_sdk_dynamodb::Client;
+use futures::stream::{self, StreamExt, TryStreamExt};
+use lambda_runtime::Error;
+use std::collections::BTreeMap;
+use tracing::info;
+
+use crate::cost::CapacityMeter;
+use crate::{config, imei_concurrency_from_env, metrics, ride};
+
+/// Device IMEI -> year -> rides.
+pub type SkippedYears = BTreeMap<String, BTreeMap<String, u64>>;
+
+/// Counts the rides outside the window of each `(device, identity)`, totalled per device.
+pub async fn skipped_years(client: &Client, identities: Vec<(String, String)>, meter: &CapacityMeter) -> Result<SkippedYears, Error> {
+    let Some((from, to)) = ride::window().bounds() else {
+        return Ok(SkippedYears::new());
+    };
+    let sides: Vec<(&str, i64)> = [("ride_start < :bound", from), ("ride_start >= :bound", to)]
+        .into_iter()
+        .filter(|(_, bound)| *bound > 0 && *bound < i64::MAX)
+        .collect();
+    let sides = &sides;
+    let per_identity: Vec<(String, Vec<u64>)> = stream::iter(identities)
+        .map(|(device, identity)| async move {
+            let mut starts = Vec::new();
+            for (condition, bound) in sides {
+                starts.extend(ride_starts(client, &identity, condition, *bound, meter).await?);
+            }
+            Ok::<_, Error>((device, starts))
+        })
+        .buffered(imei_concurrency_from_env())
+        .try_collect()
+        .await?;
+
+    let mut skipped = SkippedYears::new();
+    for (device, starts) in per_identity {
+        for start in starts {
+            *skipped.entry(device.clone()).or_default().entry(year(start)).or_default() += 1;
+        }
+    }
+    for (device, years) in &skipped {
+        for (year, rides) in years {
+            metrics::SKIPPED_YEAR_RIDES.with_label_values(&[year]).inc_by(*rides);
+        }
+        info!(imei = %device, ?years, "Rides outside the ride window");
+    }
+    Ok(skipped)
+}
+
+/// `ride_start` of every ride of `imei` matching the key condition on `:bound`.
+async fn ride_starts(client: &Client, imei: &str, condition: &str, bound: i64, meter: &CapacityMeter) -> Result<Vec<u64>, Error> {
+    let mut pages = client
+        .query()
+        .table_name(&config::get().ride_table)
+        .key_condition_expression(format!("#imei = :imei AND {}", condition))
+        .expression_attribute_names("#imei", "imei")
+        .expression_attribute_values(":imei", AttributeValue::S(imei.to_string()))
+        .expression_attribute_values(":bound", AttributeValue::N(bound.to_string()))
+        .projection_expression("ride_start")
+        .return_consumed_capacity(ReturnConsumedCapacity::Total)
+        .into_paginator()
+        .send();
+    let mut starts = Vec::new();
+    while let Some(page) = pages.next().await {
+        let page = page.inspect_err(|_| metrics::dynamodb_error("query"))?;
+        meter.read(page.consumed_capacity());
+        starts.extend(page.items().iter().filter_map(|item| item.get("ride_start")?.as_n().ok()?.parse::<u64>().ok()));
+    }
+    Ok(starts)
+}
+
+/// The IST year a ride starts in, or `unknown` beyond what dates represent.
+fn year(ride_start: u64) -> String {
+    ride::ride_month(ride_start)
+        .and_then(|month| month.rsplit_once('-').map(|(year, _)| year.trim_start_matches('+').to_string()))
+        .unwrap_or_else(|| "unknown".to_string())
+}